@@ -0,0 +1,290 @@
+//! Commands for inspecting and controlling the isolate of a guild.
+
+use std::error::Error;
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+
+use mizu_hal_types::memory::PAGE_SIZE;
+use runtime::elf::Symbols;
+use runtime::profiler::{Profiler, DEFAULT_SAMPLE_INTERVAL, MIN_SAMPLE_INTERVAL};
+use twilight_http::Client;
+use twilight_model::channel::message::AllowedMentions;
+use twilight_model::guild::Permissions;
+use twilight_model::http::attachment::Attachment;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker, UserMarker};
+use twilight_model::id::Id;
+
+use crate::execution_context::ExecutionContext;
+
+const MESSAGE_LIMIT: usize = 1900;
+const DEFAULT_LOG_LINES: usize = 20;
+/// Power state transitions shown by `!status`.
+const POWER_TRANSITIONS: usize = 5;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+  Status,
+  Stop,
+  Restart,
+  Pause,
+  Resume,
+  Detach,
+  Logs(usize),
+  Profile(ProfileCommand),
+}
+
+/// `!profile start [interval]`, `!profile stop` and `!profile`, which uploads the samples.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProfileCommand {
+  Start(u64),
+  Stop,
+  Dump,
+}
+
+impl Command {
+  pub fn parse(content: &str) -> Option<Command> {
+    let mut arguments = content.split_whitespace();
+    let command = match arguments.next()? {
+      "!status" => Command::Status,
+      "!stop" | "!kill" => Command::Stop,
+      "!restart" => Command::Restart,
+      "!pause" => Command::Pause,
+      "!resume" => Command::Resume,
+      "!detach" => Command::Detach,
+      "!logs" => Command::Logs(arguments.next().and_then(|count| count.parse().ok()).unwrap_or(DEFAULT_LOG_LINES)),
+      "!profile" => Command::Profile(match arguments.next() {
+        Some("start") => ProfileCommand::Start(
+          arguments
+            .next()
+            .and_then(|interval| interval.parse().ok())
+            .unwrap_or(DEFAULT_SAMPLE_INTERVAL),
+        ),
+        Some("stop") => ProfileCommand::Stop,
+        _ => ProfileCommand::Dump,
+      }),
+      _ => return None,
+    };
+    Some(command)
+  }
+}
+
+/// Whether `user_id` may manage the isolate of `guild_id`: the server owner, or a member whose roles grant
+/// `ADMINISTRATOR` or `MANAGE_GUILD`.
+pub async fn is_admin(http: &Client, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>) -> Result<bool, Box<dyn Error + Send + Sync>> {
+  let guild = http.guild(guild_id).await?.model().await?;
+  if guild.owner_id == user_id {
+    return Ok(true);
+  }
+
+  let member = http.guild_member(guild_id, user_id).await?.model().await?;
+  // The @everyone role has the ID of the guild
+  let permissions = guild
+    .roles
+    .iter()
+    .filter(|role| role.id.get() == guild_id.get() || member.roles.contains(&role.id))
+    .fold(Permissions::empty(), |permissions, role| permissions | role.permissions);
+  Ok(permissions.intersects(Permissions::ADMINISTRATOR | Permissions::MANAGE_GUILD))
+}
+
+/// Runs every command except [Command::Restart], which needs the whole environment to boot.
+pub async fn handle(
+  command: Command,
+  context: &ExecutionContext,
+  http: &Client,
+  channel_id: Id<ChannelMarker>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let content = match command {
+    Command::Status => status(context).await,
+    Command::Stop => {
+      let isolate = context.isolate.lock().await.take();
+      match isolate {
+        Some(isolate) => {
+          isolate.stop().await;
+          "isolate stopped".to_owned()
+        }
+        None => "no isolate is running".to_owned(),
+      }
+    }
+    Command::Pause | Command::Resume => match context.isolate.lock().await.as_ref() {
+      Some(isolate) if command == Command::Pause => {
+        isolate.pause();
+        "isolate paused".to_owned()
+      }
+      Some(isolate) => {
+        isolate.resume();
+        "isolate resumed".to_owned()
+      }
+      None => "no isolate is running".to_owned(),
+    },
+    Command::Detach => match context.channel_id.lock().await.take() {
+      Some(channel_id) => format!("detached from <#{}>, output is kept for `!logs`", channel_id),
+      None => "already detached".to_owned(),
+    },
+    Command::Logs(count) => {
+      let lines = context.outbox.history(count);
+      if lines.is_empty() {
+        "no log lines".to_owned()
+      } else {
+        let text = lines.join("\n");
+        if text.len() > MESSAGE_LIMIT {
+          let attachments = [Attachment::from_bytes("log.txt".to_owned(), text.into_bytes(), 1)];
+          http
+            .create_message(channel_id)
+            .content(&format!("last {} log lines", lines.len()))?
+            .attachments(&attachments)?
+            .await?;
+          return Ok(());
+        }
+        format!("```\n{}```", text.replace("```", "`\u{200b}``"))
+      }
+    }
+    Command::Profile(command) => {
+      let isolate = context.isolate.lock().await.clone();
+      let Some(isolate) = isolate else {
+        return send(http, channel_id, "no isolate is running").await;
+      };
+      match command {
+        ProfileCommand::Start(interval) if interval < MIN_SAMPLE_INTERVAL => {
+          format!("the interval must be at least `{}` instructions", MIN_SAMPLE_INTERVAL)
+        }
+        ProfileCommand::Start(interval) => {
+          isolate.profiler.reset();
+          isolate.profiler.start(interval);
+          format!("profiling every `{}` instructions", interval)
+        }
+        ProfileCommand::Stop => {
+          isolate.profiler.stop();
+          format!("profiler stopped with `{}` samples", isolate.profiler.samples())
+        }
+        ProfileCommand::Dump if isolate.profiler.samples() == 0 => "no samples, start with `!profile start [interval]`".to_owned(),
+        ProfileCommand::Dump => {
+          let symbols = context.image.lock().await.as_ref().map(|image| image.symbols.clone()).unwrap_or_default();
+          let attachments = profile_attachments(&isolate.profiler, &symbols);
+          http
+            .create_message(channel_id)
+            .content(&format!("`{}` samples", isolate.profiler.samples()))?
+            .attachments(&attachments)?
+            .await?;
+          return Ok(());
+        }
+      }
+    }
+    Command::Restart => unreachable!("restart is handled by the caller"),
+  };
+
+  send(http, channel_id, &content).await
+}
+
+async fn send(http: &Client, channel_id: Id<ChannelMarker>, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+  http
+    .create_message(channel_id)
+    .content(content)?
+    .allowed_mentions(Some(&AllowedMentions::default()))
+    .await?;
+  Ok(())
+}
+
+/// Collapsed stacks for flamegraphs and a pprof profile.
+pub fn profile_attachments(profiler: &Profiler, symbols: &Symbols) -> Vec<Attachment> {
+  vec![
+    Attachment::from_bytes("profile.folded".to_owned(), profiler.collapsed(symbols).into_bytes(), 1),
+    Attachment::from_bytes("profile.pb".to_owned(), profiler.pprof(symbols), 2),
+  ]
+}
+
+async fn status(context: &ExecutionContext) -> String {
+  let mut status = String::new();
+  match *context.channel_id.lock().await {
+    Some(channel_id) => writeln!(status, "channel: <#{}>", channel_id).unwrap(),
+    None => writeln!(status, "channel: detached").unwrap(),
+  }
+  if let Some(image) = context.image.lock().await.as_ref() {
+    writeln!(status, "image: `{}` bytes, entry `{:#x}`", image.code.len(), image.entry).unwrap();
+  }
+  writeln!(status, "verbosity: `{:?}`", context.verbosity()).unwrap();
+
+  let Some(isolate) = context.isolate.lock().await.clone() else {
+    status.push_str("isolate: none");
+    return status;
+  };
+
+  let state = if isolate.exploded.load(Ordering::Acquire) {
+    "exploded"
+  } else if isolate.paused.get() {
+    "paused"
+  } else {
+    "running"
+  };
+  writeln!(status, "isolate: `{}`", state).unwrap();
+
+  if isolate.bus.is_swapped_out() {
+    status.push_str("memory: swapped out\n");
+  } else {
+    let dram = isolate.bus.dram.read().unwrap();
+    let used = dram.used_pages() * PAGE_SIZE as usize;
+    writeln!(status, "memory: `{} KiB / {} KiB`", used / 1024, dram.dram.len() / 1024).unwrap();
+  }
+  writeln!(
+    status,
+    "mapped memory: `{} KiB / {} KiB`",
+    isolate.bus.mapped_bytes() / 1024,
+    isolate.bus.memory_limit() / 1024
+  )
+  .unwrap();
+  writeln!(status, "http requests: `{}`", context.http_requests.load(Ordering::Acquire)).unwrap();
+  if let Some(scheduler) = isolate.scheduler() {
+    let (queued, parked) = scheduler.counts();
+    writeln!(
+      status,
+      "scheduler: `{}` workers, `{}` queued, `{}` parked harts",
+      scheduler.config().workers,
+      queued,
+      parked
+    )
+    .unwrap();
+  }
+  let transitions = isolate.bus.power.audit();
+  writeln!(
+    status,
+    "power bypass: `{}`, last transitions: `{}`",
+    if isolate.bus.power.allows_bypass() { "granted" } else { "denied" },
+    match transitions.len() {
+      0 => "none".to_owned(),
+      count => transitions[count.saturating_sub(POWER_TRANSITIONS)..]
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", "),
+    }
+  )
+  .unwrap();
+  match isolate.profiler.interval() {
+    0 => writeln!(status, "profiler: stopped, `{}` samples", isolate.profiler.samples()).unwrap(),
+    interval => writeln!(status, "profiler: every `{}` instructions, `{}` samples", interval, isolate.profiler.samples()).unwrap(),
+  }
+
+  let cores = isolate.cores.lock().unwrap().clone();
+  status.push_str("```c\n");
+  for (id, core) in cores.iter().enumerate() {
+    // A core holds its lock while it is executing
+    let Ok(cpu) = core.try_lock() else {
+      writeln!(status, "cpu {}: executing", id).unwrap();
+      continue;
+    };
+    writeln!(
+      status,
+      "cpu {}: pc={:#010x} wfi={} halt={} instret={} loads={} stores={} cpu_time={:?}",
+      cpu.id,
+      cpu.pc,
+      cpu.wfi.get(),
+      cpu.halt,
+      cpu.perf.instructions_retired.load(Ordering::Acquire),
+      cpu.perf.loads.load(Ordering::Acquire),
+      cpu.perf.stores.load(Ordering::Acquire),
+      cpu.perf.cpu_time.lock().unwrap(),
+    )
+    .unwrap();
+  }
+  status.push_str("```");
+  status
+}
@@ -0,0 +1,279 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use mizu_hal_discord::discord::discord_ex_request::DiscordExRequestUnion;
+use mizu_hal_types::discord::{operation, subscription};
+use thiserror::Error;
+use twilight_model::channel::message::{AllowedMentions, MentionType};
+use twilight_model::id::marker::ChannelMarker;
+use twilight_model::id::Id;
+
+/// Discord REST operations a guest may call.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Operation {
+  SendMessage,
+  EditMessage,
+  DeleteMessage,
+  ReadMessages,
+  Pins,
+  React,
+  Threads,
+  Typing,
+  ReadChannel,
+  ReadGuild,
+  ReadMembers,
+  ReadUsers,
+  ManageRoles,
+  Commands,
+  Interactions,
+}
+
+impl Operation {
+  pub const ALL: [Operation; 15] = [
+    Operation::SendMessage,
+    Operation::EditMessage,
+    Operation::DeleteMessage,
+    Operation::ReadMessages,
+    Operation::Pins,
+    Operation::React,
+    Operation::Threads,
+    Operation::Typing,
+    Operation::ReadChannel,
+    Operation::ReadGuild,
+    Operation::ReadMembers,
+    Operation::ReadUsers,
+    Operation::ManageRoles,
+    Operation::Commands,
+    Operation::Interactions,
+  ];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Operation::SendMessage => "send",
+      Operation::EditMessage => "edit",
+      Operation::DeleteMessage => "delete",
+      Operation::ReadMessages => "read",
+      Operation::Pins => "pins",
+      Operation::React => "react",
+      Operation::Threads => "threads",
+      Operation::Typing => "typing",
+      Operation::ReadChannel => "channels",
+      Operation::ReadGuild => "guild",
+      Operation::ReadMembers => "members",
+      Operation::ReadUsers => "users",
+      Operation::ManageRoles => "roles",
+      Operation::Commands => "commands",
+      Operation::Interactions => "interactions",
+    }
+  }
+
+  /// Code reported to guests of the legacy calls when the operation is denied.
+  pub fn code(&self) -> u64 {
+    match self {
+      Operation::SendMessage => operation::SEND_MESSAGE,
+      Operation::EditMessage => operation::EDIT_MESSAGE,
+      Operation::DeleteMessage => operation::DELETE_MESSAGE,
+      Operation::ReadMessages => operation::READ_MESSAGES,
+      Operation::Pins => operation::PINS,
+      Operation::React => operation::REACT,
+      Operation::Threads => operation::THREADS,
+      Operation::Typing => operation::TYPING,
+      Operation::ReadChannel => operation::READ_CHANNEL,
+      Operation::ReadGuild => operation::READ_GUILD,
+      Operation::ReadMembers => operation::READ_MEMBERS,
+      Operation::ReadUsers => operation::READ_USERS,
+      Operation::ManageRoles => operation::MANAGE_ROLES,
+      Operation::Commands => operation::COMMANDS,
+      Operation::Interactions => operation::INTERACTIONS,
+    }
+  }
+
+  /// Operation and target channel of a `DiscordExRequest`.
+  pub fn of(request: &DiscordExRequestUnion) -> (Operation, Option<u64>) {
+    match request {
+      DiscordExRequestUnion::CreateMessageRequest(request) => (Operation::SendMessage, Some(request.channel_id)),
+      DiscordExRequestUnion::EditMessageRequest(request) => (Operation::EditMessage, Some(request.channel_id)),
+      DiscordExRequestUnion::RegisterCommandsRequest(_) => (Operation::Commands, None),
+      DiscordExRequestUnion::InteractionResponseRequest(_) | DiscordExRequestUnion::FollowupRequest(_) => (Operation::Interactions, None),
+      DiscordExRequestUnion::DeleteMessageRequest(request) => (Operation::DeleteMessage, Some(request.channel_id)),
+      DiscordExRequestUnion::BulkDeleteMessagesRequest(request) => (Operation::DeleteMessage, Some(request.channel_id)),
+      DiscordExRequestUnion::GetMessageRequest(request) => (Operation::ReadMessages, Some(request.channel_id)),
+      DiscordExRequestUnion::GetMessagesRequest(request) => (Operation::ReadMessages, Some(request.channel_id)),
+      DiscordExRequestUnion::PinMessageRequest(request) => (Operation::Pins, Some(request.channel_id)),
+      DiscordExRequestUnion::GetPinsRequest(request) => (Operation::Pins, Some(request.channel_id)),
+      DiscordExRequestUnion::CreateThreadRequest(request) => (Operation::Threads, Some(request.channel_id)),
+      DiscordExRequestUnion::TypingRequest(request) => (Operation::Typing, Some(request.channel_id)),
+      DiscordExRequestUnion::GetChannelRequest(request) => (Operation::ReadChannel, Some(request.channel_id)),
+      DiscordExRequestUnion::GetGuildRequest(_) => (Operation::ReadGuild, None),
+      DiscordExRequestUnion::GetMemberRequest(_) | DiscordExRequestUnion::GetRolesRequest(_) => (Operation::ReadMembers, None),
+      DiscordExRequestUnion::MemberRoleRequest(_) => (Operation::ManageRoles, None),
+      DiscordExRequestUnion::CreateReactionRequest(request) => (Operation::React, Some(request.channel_id)),
+      DiscordExRequestUnion::GetUserRequest(_) => (Operation::ReadUsers, None),
+    }
+  }
+}
+
+impl fmt::Display for Operation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+impl FromStr for Operation {
+  type Err = ();
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    Operation::ALL.into_iter().find(|operation| operation.name() == value).ok_or(())
+  }
+}
+
+pub const EVENTS: [(&str, u64); 9] = [
+  ("message_create", subscription::MESSAGE_CREATE),
+  ("reaction_add", subscription::REACTION_ADD),
+  ("message_update", subscription::MESSAGE_UPDATE),
+  ("message_delete", subscription::MESSAGE_DELETE),
+  ("reaction_remove", subscription::REACTION_REMOVE),
+  ("member_add", subscription::MEMBER_ADD),
+  ("member_remove", subscription::MEMBER_REMOVE),
+  ("thread_create", subscription::THREAD_CREATE),
+  ("interaction_create", subscription::INTERACTION_CREATE),
+];
+
+#[derive(Error, Debug)]
+pub enum CapabilityError {
+  #[error("operation `{0}` is not allowed")]
+  Operation(Operation),
+  #[error("channel {0} is not allowed")]
+  Channel(u64),
+}
+
+/// What the guest of an execution context may do in Discord.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+  /// Channels the guest may act in besides the one it was loaded from.
+  pub channels: HashSet<Id<ChannelMarker>>,
+  /// Whether messages may ping users, roles and everyone.
+  pub allow_mentions: bool,
+  /// Events the guest may subscribe to, see [subscription].
+  pub events: u64,
+  pub operations: HashSet<Operation>,
+  /// Whether the guest may lift the CPU time limit with the bypass power state.
+  pub power_bypass: bool,
+}
+
+impl Default for Capabilities {
+  fn default() -> Self {
+    Self {
+      channels: HashSet::new(),
+      allow_mentions: false,
+      events: subscription::ALL & !(subscription::MEMBER_ADD | subscription::MEMBER_REMOVE),
+      operations: HashSet::from([
+        Operation::SendMessage,
+        Operation::EditMessage,
+        Operation::ReadMessages,
+        Operation::React,
+        Operation::Typing,
+        Operation::ReadChannel,
+        Operation::ReadGuild,
+        Operation::Commands,
+        Operation::Interactions,
+      ]),
+      power_bypass: false,
+    }
+  }
+}
+
+impl Capabilities {
+  /// Checks an operation on `channel_id`, `home` is the channel the guest was loaded from.
+  pub fn check(&self, operation: Operation, channel_id: Option<u64>, home: Option<Id<ChannelMarker>>) -> Result<(), CapabilityError> {
+    if !self.operations.contains(&operation) {
+      return Err(CapabilityError::Operation(operation));
+    }
+
+    if let Some(channel_id) = channel_id {
+      let allowed = home.is_some_and(|home| home.get() == channel_id) || self.channels.iter().any(|channel| channel.get() == channel_id);
+      if !allowed {
+        return Err(CapabilityError::Channel(channel_id));
+      }
+    }
+
+    Ok(())
+  }
+
+  pub fn allowed_mentions(&self) -> AllowedMentions {
+    if self.allow_mentions {
+      AllowedMentions {
+        parse: vec![MentionType::Users, MentionType::Roles, MentionType::Everyone],
+        replied_user: true,
+        ..Default::default()
+      }
+    } else {
+      AllowedMentions::default()
+    }
+  }
+
+  /// Applies a `!caps allow|deny` argument: an operation, an event, `mentions`, `bypass` or a channel mention.
+  pub fn set(&mut self, target: &str, allow: bool) -> Result<(), String> {
+    if let Ok(operation) = target.parse::<Operation>() {
+      if allow {
+        self.operations.insert(operation);
+      } else {
+        self.operations.remove(&operation);
+      }
+    } else if let Some((_, bit)) = EVENTS.iter().find(|(name, _)| *name == target) {
+      if allow {
+        self.events |= bit;
+      } else {
+        self.events &= !bit;
+      }
+    } else if target == "mentions" {
+      self.allow_mentions = allow;
+    } else if target == "bypass" {
+      self.power_bypass = allow;
+    } else if let Some(channel_id) = target
+      .strip_prefix("<#")
+      .and_then(|target| target.strip_suffix('>'))
+      .and_then(|id| id.parse::<u64>().ok())
+      .and_then(Id::new_checked)
+    {
+      if allow {
+        self.channels.insert(channel_id);
+      } else {
+        self.channels.remove(&channel_id);
+      }
+    } else {
+      return Err(format!("unknown capability `{}`", target));
+    }
+    Ok(())
+  }
+
+  pub fn describe(&self) -> String {
+    let mut description = String::new();
+    let operations = Operation::ALL
+      .iter()
+      .map(|operation| format!("{}{}", if self.operations.contains(operation) { "+" } else { "-" }, operation))
+      .collect::<Vec<_>>();
+    writeln!(description, "operations: `{}`", operations.join(" ")).unwrap();
+
+    let events = EVENTS
+      .iter()
+      .map(|(name, bit)| format!("{}{}", if self.events & bit != 0 { "+" } else { "-" }, name))
+      .collect::<Vec<_>>();
+    writeln!(description, "events: `{}`", events.join(" ")).unwrap();
+
+    writeln!(description, "mentions: `{}`", if self.allow_mentions { "allowed" } else { "suppressed" }).unwrap();
+    writeln!(description, "power bypass: `{}`", if self.power_bypass { "allowed" } else { "denied" }).unwrap();
+
+    let channels = self.channels.iter().map(|channel| format!("<#{}>", channel)).collect::<Vec<_>>();
+    write!(
+      description,
+      "channels: home{}{}",
+      if channels.is_empty() { "" } else { ", " },
+      channels.join(", ")
+    )
+    .unwrap();
+    description
+  }
+}
@@ -0,0 +1,561 @@
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::fs;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+
+/// Environment variables passed to the build, everything else is cleared.
+const PASSTHROUGH_ENV: [&str; 5] = ["PATH", "HOME", "CARGO_HOME", "RUSTUP_HOME", "RUSTUP_TOOLCHAIN"];
+/// Files copied from the template crate into every job workspace.
+const TEMPLATE_FILES: [&str; 4] = ["Cargo.toml", "link.x", "eh_frame.ld", "src/prelude.rs"];
+const TARGET: &str = "riscv64g-unknown-mizu-elf";
+/// Flags for C and assembly guests, matching the Rust target.
+const CLANG_FLAGS: [&str; 11] = [
+  "--target=riscv64",
+  "-march=rv64g",
+  "-mabi=lp64d",
+  "-mcmodel=medany",
+  "-mno-relax",
+  "-O2",
+  // The headers define their functions as plain `inline`, emit them once in the guest
+  "-fgnu89-inline",
+  "-ffreestanding",
+  "-nostdlib",
+  "-fuse-ld=lld",
+  "-fdiagnostics-color=never",
+];
+/// Sandbox used unless `MIZU_COMPILE_SANDBOX` is set: a fresh bubblewrap namespace without network, with a read-only
+/// minimal root and its own `/proc`, so the build cannot read the files or the environment of the bot, nor the files of
+/// other guilds.
+const DEFAULT_SANDBOX: [&str; 40] = [
+  "bwrap",
+  "--unshare-all",
+  "--die-with-parent",
+  "--new-session",
+  "--ro-bind",
+  "/usr",
+  "/usr",
+  "--symlink",
+  "usr/bin",
+  "/bin",
+  "--symlink",
+  "usr/lib",
+  "/lib",
+  "--symlink",
+  "usr/lib64",
+  "/lib64",
+  "--ro-bind-try",
+  "/etc/ld.so.cache",
+  "/etc/ld.so.cache",
+  "--proc",
+  "/proc",
+  "--dev",
+  "/dev",
+  "--tmpfs",
+  "/tmp",
+  "--ro-bind",
+  "{sources}",
+  "{sources}",
+  "--ro-bind",
+  "{cargo_home}",
+  "{cargo_home}",
+  "--ro-bind",
+  "{rustup_home}",
+  "{rustup_home}",
+  "--bind",
+  "{workspace}",
+  "{workspace}",
+  "--chdir",
+  "{workspace}",
+  "--",
+];
+
+/// Language of a `!vm` code block.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Language {
+  Rust,
+  C,
+  Asm,
+}
+
+impl Language {
+  /// Parses a code block language tag.
+  pub fn from_tag(tag: &str) -> Option<Language> {
+    match tag {
+      "rs" | "rust" => Some(Language::Rust),
+      "c" => Some(Language::C),
+      "asm" | "s" => Some(Language::Asm),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompilerConfig {
+  /// Guest crate every job workspace is created from.
+  pub template: PathBuf,
+  /// Headers, startup code and linker script of C and assembly guests.
+  pub hal_c: PathBuf,
+  /// Job workspaces and the target directory cache live here.
+  pub work_dir: PathBuf,
+  /// Number of builds running at once.
+  pub concurrency: usize,
+  /// Maximum number of queued and running builds.
+  pub queue_limit: usize,
+  pub timeout: Duration,
+  /// Address space limit of the build processes in bytes.
+  pub memory_limit: u64,
+  /// Command the build is wrapped in, [DEFAULT_SANDBOX] by default. `{workspace}` is replaced with the only path the
+  /// build writes to, which holds the `{target}` directory, and `{cargo_home}` and `{rustup_home}` with the toolchain.
+  /// An option followed by `{sources}` arguments is repeated for every file or directory of the repository it reads.
+  /// Builds are refused when it is empty.
+  pub sandbox: Vec<String>,
+}
+
+impl Default for CompilerConfig {
+  fn default() -> Self {
+    Self {
+      template: PathBuf::from("temp"),
+      hal_c: PathBuf::from("hal-c"),
+      work_dir: env::temp_dir().join("mizu-builds"),
+      concurrency: 2,
+      queue_limit: 16,
+      timeout: Duration::from_secs(120),
+      memory_limit: 4 * 1024 * 1024 * 1024,
+      sandbox: DEFAULT_SANDBOX.iter().map(|argument| argument.to_string()).collect(),
+    }
+  }
+}
+
+impl CompilerConfig {
+  pub fn from_env() -> Self {
+    let mut config = Self::default();
+    if let Ok(value) = env::var("MIZU_COMPILE_DIR") {
+      config.work_dir = PathBuf::from(value);
+    }
+    if let Some(value) = env::var("MIZU_COMPILE_JOBS").ok().and_then(|value| value.parse::<usize>().ok()) {
+      config.concurrency = value.max(1);
+    }
+    if let Some(value) = env::var("MIZU_COMPILE_QUEUE").ok().and_then(|value| value.parse().ok()) {
+      config.queue_limit = value;
+    }
+    if let Some(value) = env::var("MIZU_COMPILE_TIMEOUT_SECS").ok().and_then(|value| value.parse().ok()) {
+      config.timeout = Duration::from_secs(value);
+    }
+    if let Some(value) = env::var("MIZU_COMPILE_MEMORY_MB").ok().and_then(|value| value.parse::<u64>().ok()) {
+      config.memory_limit = value * 1024 * 1024;
+    }
+    if let Ok(value) = env::var("MIZU_COMPILE_SANDBOX") {
+      config.sandbox = value.split_whitespace().map(ToOwned::to_owned).collect();
+    }
+    config
+  }
+}
+
+#[derive(Error, Debug)]
+pub enum CompileError {
+  #[error("too many builds are queued")]
+  QueueFull,
+  #[error("no build sandbox is configured")]
+  NoSandbox,
+  #[error("compilation took longer than {0:?}")]
+  Timeout(Duration),
+  #[error("compilation failed")]
+  Failed(String),
+  #[error("io error: {0}")]
+  Io(#[from] io::Error),
+}
+
+/// Builds guest programs in isolated, throwaway workspaces.
+///
+/// Every job gets a fresh copy of the template crate and its own target directory, seeded with a copy of a cache that
+/// only trusted builds write to, so that the dependencies and the standard library are only built once. Builds run
+/// offline in the sandbox and their own process group with a cleared environment, an address space limit and a
+/// wall-clock timeout.
+pub struct CompileService {
+  config: CompilerConfig,
+  /// Absolute path of the template crate.
+  template: PathBuf,
+  /// Name of the template crate, which is also the name of the executable.
+  package: String,
+  /// Absolute path of the C HAL.
+  hal_c: PathBuf,
+  /// Path dependencies of the template crate and the target specification, mounted read-only in the sandbox.
+  sources: Vec<PathBuf>,
+  /// Absolute paths of the toolchain, mounted read-only in the sandbox.
+  cargo_home: PathBuf,
+  rustup_home: PathBuf,
+  /// Target directory built by [CompileService::warm], copied into every job.
+  cache: PathBuf,
+  permits: Semaphore,
+  queued: AtomicUsize,
+  next_job: AtomicU64,
+}
+
+/// Decrements the queue length when the job is done.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::AcqRel);
+  }
+}
+
+impl CompileService {
+  pub fn new(mut config: CompilerConfig) -> io::Result<Self> {
+    // The paths are mounted in the sandbox, which needs them to be absolute
+    config.work_dir = env::current_dir()?.join(&config.work_dir);
+    let template = config.template.canonicalize()?;
+    let hal_c = config.hal_c.canonicalize()?;
+    let manifest = std::fs::read_to_string(template.join("Cargo.toml"))?;
+    let package = manifest
+      .lines()
+      .find_map(|line| line.strip_prefix("name = \"")?.strip_suffix('"'))
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "template manifest has no package name"))?
+      .to_owned();
+    let cache = config.work_dir.join("target");
+    let mut sources = path_dependencies(&template)?;
+    sources.push(template.parent().unwrap().join(format!("{TARGET}.json")));
+    let home = |name: &str, default: &str| {
+      env::var_os(name)
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(default)))
+        .unwrap_or_default()
+    };
+    Ok(Self {
+      permits: Semaphore::new(config.concurrency),
+      config,
+      template,
+      package,
+      hal_c,
+      sources,
+      cargo_home: home("CARGO_HOME", ".cargo"),
+      rustup_home: home("RUSTUP_HOME", ".rustup"),
+      cache,
+      queued: AtomicUsize::new(0),
+      next_job: AtomicU64::new(0),
+    })
+  }
+
+  /// Builds `source` into the cache, so that later jobs only compile the guest crate.
+  ///
+  /// Unlike guest builds, this runs outside of the sandbox and may download the dependencies, so `source` must be
+  /// trusted.
+  pub async fn warm(self: Arc<Self>, source: String) {
+    match self.run(&source, Language::Rust, false).await {
+      Ok(_) => info!("build cache is warm"),
+      Err(error) => warn!("failed to warm build cache: {}", error),
+    }
+  }
+
+  /// Compiles `source` and returns the ELF executable.
+  ///
+  /// Rust is compiled as the `main.rs` of the guest crate, C and assembly with clang against `hal-c`, linked with its
+  /// `crt0.s` that calls `main`.
+  pub async fn compile(&self, source: &str, language: Language) -> Result<Vec<u8>, CompileError> {
+    self.run(source, language, true).await
+  }
+
+  async fn run(&self, source: &str, language: Language, sandboxed: bool) -> Result<Vec<u8>, CompileError> {
+    if sandboxed && self.config.sandbox.is_empty() {
+      return Err(CompileError::NoSandbox);
+    }
+    if self.queued.fetch_add(1, Ordering::AcqRel) >= self.config.queue_limit {
+      self.queued.fetch_sub(1, Ordering::AcqRel);
+      return Err(CompileError::QueueFull);
+    }
+    let _queued = Queued(&self.queued);
+
+    let _permit = self.permits.acquire().await.unwrap();
+    let id = self.next_job.fetch_add(1, Ordering::AcqRel);
+    let workspace = self.config.work_dir.join("jobs").join(id.to_string());
+    // Guest builds never write to the cache, so they cannot change what the next guild links
+    let target_dir = if sandboxed { workspace.join("target") } else { self.cache.clone() };
+    debug!("compiling job {} in {}", id, workspace.display());
+
+    let result = match language {
+      Language::Rust => self.build_rust(&workspace, &target_dir, source, sandboxed).await,
+      Language::C | Language::Asm => self.build_native(&workspace, source, language).await,
+    };
+    if let Err(error) = fs::remove_dir_all(&workspace).await {
+      warn!("failed to remove workspace {}: {}", workspace.display(), error);
+    }
+    result
+  }
+
+  /// Creates an empty job workspace.
+  async fn create_workspace(workspace: &Path) -> io::Result<()> {
+    // Left over from a previous run of the bot
+    if fs::metadata(workspace).await.is_ok() {
+      fs::remove_dir_all(workspace).await?;
+    }
+    fs::create_dir_all(workspace).await
+  }
+
+  async fn prepare(&self, workspace: &Path, source: &str) -> io::Result<()> {
+    Self::create_workspace(workspace).await?;
+    fs::create_dir_all(workspace.join("src")).await?;
+    fs::create_dir_all(workspace.join(".cargo")).await?;
+
+    let parent = self.template.parent().unwrap();
+    for file in TEMPLATE_FILES {
+      let contents = fs::read_to_string(self.template.join(file)).await?;
+      // Relative dependencies point into the repository
+      let mut contents = contents.replace("path = \"../", &format!("path = \"{}/", parent.display()));
+      if file == "Cargo.toml" {
+        // Not a member of the repository workspace
+        contents.push_str("\n[workspace]\n");
+      }
+      fs::write(workspace.join(file), contents).await?;
+    }
+    // Build with the same dependency versions the cache was built with
+    if let Err(error) = fs::copy(parent.join("Cargo.lock"), workspace.join("Cargo.lock")).await {
+      debug!("no lock file to copy: {}", error);
+    }
+
+    let config = format!(
+      r#"[build]
+target = "{parent}/{TARGET}.json"
+
+[unstable]
+build-std = ["core", "compiler_builtins", "alloc"]
+
+[target.{TARGET}]
+rustflags = ["-Clink-arg=-T{workspace}/link.x", "-Ctarget-feature=-c", "-Copt-level=z", "-Cforce-unwind-tables", "-Clink-arg=-T{workspace}/eh_frame.ld"]
+linker = "ld.lld"
+"#,
+      parent = parent.display(),
+      workspace = workspace.display(),
+    );
+    fs::write(workspace.join(".cargo/config.toml"), config).await?;
+    fs::write(workspace.join("src/main.rs"), source).await?;
+    Ok(())
+  }
+
+  /// Wraps `program` in the sandbox unless it is trusted, and applies the resource limits. `sources` are the only files
+  /// of the repository the build can read.
+  fn command(&self, workspace: &Path, target_dir: &Path, sources: &[PathBuf], program: &[&str], sandboxed: bool) -> Command {
+    let substitute = |argument: &String| {
+      argument
+        .replace("{workspace}", &workspace.display().to_string())
+        .replace("{target}", &target_dir.display().to_string())
+        .replace("{cargo_home}", &self.cargo_home.display().to_string())
+        .replace("{rustup_home}", &self.rustup_home.display().to_string())
+    };
+    let sandbox = if sandboxed { self.config.sandbox.as_slice() } else { &[] };
+    let mut arguments = Vec::new();
+    let mut index = 0;
+    while index < sandbox.len() {
+      let end = (index + 1..sandbox.len())
+        .find(|&end| !sandbox[end].contains("{sources}"))
+        .unwrap_or(sandbox.len());
+      if end == index + 1 {
+        arguments.push(substitute(&sandbox[index]));
+      } else {
+        for source in sources {
+          let source = source.display().to_string();
+          arguments.extend(sandbox[index..end].iter().map(|argument| substitute(argument).replace("{sources}", &source)));
+        }
+      }
+      index = end;
+    }
+    arguments.extend(program.iter().map(|argument| argument.to_string()));
+
+    let mut command = Command::new(&arguments[0]);
+    command
+      .args(&arguments[1..])
+      .current_dir(workspace)
+      .env_clear()
+      .envs(PASSTHROUGH_ENV.iter().filter_map(|name| Some((name, env::var(name).ok()?))))
+      .env("CARGO_TARGET_DIR", target_dir)
+      .env("CC", "/usr/bin/clang")
+      .env("CXX", "/usr/bin/clang++")
+      .stdin(Stdio::null())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .kill_on_drop(true);
+
+    let memory_limit = self.config.memory_limit;
+    // SAFETY: only async-signal-safe calls between fork and exec
+    unsafe {
+      command.pre_exec(move || {
+        // Own process group, so that rustc and build scripts are killed with cargo
+        if libc::setsid() == -1 {
+          return Err(io::Error::last_os_error());
+        }
+        let limit = libc::rlimit {
+          rlim_cur: memory_limit,
+          rlim_max: memory_limit,
+        };
+        if libc::setrlimit(libc::RLIMIT_AS, &limit) == -1 {
+          return Err(io::Error::last_os_error());
+        }
+        Ok(())
+      });
+    }
+    command
+  }
+
+  async fn build_rust(&self, workspace: &Path, target_dir: &Path, source: &str, sandboxed: bool) -> Result<Vec<u8>, CompileError> {
+    self.prepare(workspace, source).await?;
+    if sandboxed {
+      self.seed(target_dir).await?;
+    } else {
+      fs::create_dir_all(target_dir).await?;
+    }
+
+    let mut program = vec!["cargo", "+nightly", "build"];
+    if sandboxed {
+      program.push("--offline");
+    }
+    self.execute(self.command(workspace, target_dir, &self.sources, &program, sandboxed)).await?;
+    Ok(fs::read(target_dir.join(TARGET).join("debug").join(&self.package)).await?)
+  }
+
+  async fn build_native(&self, workspace: &Path, source: &str, language: Language) -> Result<Vec<u8>, CompileError> {
+    Self::create_workspace(workspace).await?;
+    let file = match language {
+      Language::C => "main.c",
+      _ => "main.s",
+    };
+    fs::write(workspace.join(file), source).await?;
+
+    let include = format!("-I{}", self.hal_c.display());
+    let linker_script = format!("-Wl,-T{}", self.hal_c.join("memmap.ld").display());
+    let crt0 = self.hal_c.join("crt0.s").display().to_string();
+    let mut program = vec!["clang"];
+    program.extend(CLANG_FLAGS);
+    program.extend([include.as_str(), linker_script.as_str(), "-o", "guest", crt0.as_str(), file]);
+
+    // Absolute `#include`s and `.incbin`s only see the headers, not the files of the bot
+    self.execute(self.command(workspace, workspace, std::slice::from_ref(&self.hal_c), &program, true)).await?;
+    Ok(fs::read(workspace.join("guest")).await?)
+  }
+
+  /// Creates the target directory of a job from a copy of the cache, if it was built.
+  async fn seed(&self, target_dir: &Path) -> io::Result<()> {
+    if fs::metadata(&self.cache).await.is_err() {
+      return fs::create_dir_all(target_dir).await;
+    }
+    // Keeps the timestamps, so that cargo considers the copied dependencies fresh
+    let status = Command::new("cp").arg("-a").arg("--reflink=auto").arg(&self.cache).arg(target_dir).status().await?;
+    if !status.success() {
+      return Err(io::Error::other(format!("failed to copy the build cache: {}", status)));
+    }
+    Ok(())
+  }
+
+  /// Runs a build command to completion, killing it when it takes too long.
+  async fn execute(&self, mut command: Command) -> Result<(), CompileError> {
+    let child = command.spawn()?;
+    let process_group = child.id();
+    let output = match timeout(self.config.timeout, child.wait_with_output()).await {
+      Ok(output) => output?,
+      Err(_) => {
+        if let Some(process_group) = process_group {
+          // SAFETY: signals the process group created in pre_exec
+          unsafe {
+            libc::kill(-(process_group as i32), libc::SIGKILL);
+          }
+        }
+        return Err(CompileError::Timeout(self.config.timeout));
+      }
+    };
+
+    if !output.status.success() {
+      return Err(CompileError::Failed(String::from_utf8_lossy(&output.stderr).to_string()));
+    }
+    Ok(())
+  }
+}
+
+/// Returns the directories of the path dependencies of the crate at `root`, transitively.
+fn path_dependencies(root: &Path) -> io::Result<Vec<PathBuf>> {
+  let mut dependencies = Vec::new();
+  let mut pending = vec![root.to_path_buf()];
+  while let Some(package) = pending.pop() {
+    let manifest = std::fs::read_to_string(package.join("Cargo.toml"))?;
+    for line in manifest.lines() {
+      let Some(path) = line.split_once("path = \"").and_then(|(_, path)| path.split_once('"')).map(|(path, _)| path) else {
+        continue;
+      };
+      // Also matches the paths of targets, which are not crates
+      let Ok(dependency) = package.join(path).canonicalize() else {
+        continue;
+      };
+      if dependency.join("Cargo.toml").is_file() && !dependencies.contains(&dependency) {
+        dependencies.push(dependency.clone());
+        pending.push(dependency);
+      }
+    }
+  }
+  Ok(dependencies)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn service() -> CompileService {
+    CompileService::new(CompilerConfig {
+      template: PathBuf::from("../temp"),
+      hal_c: PathBuf::from("../hal-c"),
+      ..Default::default()
+    })
+    .unwrap()
+  }
+
+  fn arguments(command: &Command) -> Vec<String> {
+    let command = command.as_std();
+    std::iter::once(command.get_program())
+      .chain(command.get_args())
+      .map(|argument| argument.to_string_lossy().to_string())
+      .collect()
+  }
+
+  #[test]
+  fn mounts_only_the_sources() {
+    let service = service();
+    let repository = Path::new("..").canonicalize().unwrap();
+    let hal = repository.join("hal-rs/hal");
+    assert!(service.sources.contains(&hal));
+    assert!(service.sources.contains(&repository.join("hal-rs/types")));
+    assert!(!service.sources.contains(&repository));
+
+    let workspace = service.config.work_dir.join("jobs/0");
+    let target = workspace.join("target");
+    let arguments = arguments(&service.command(&workspace, &target, &service.sources, &["cargo"], true));
+    let mounted = arguments
+      .windows(3)
+      .filter(|window| window[0] == "--ro-bind")
+      .map(|window| window[1].clone())
+      .collect::<Vec<_>>();
+    for source in &service.sources {
+      assert!(mounted.contains(&source.display().to_string()));
+    }
+    assert!(!mounted.contains(&repository.display().to_string()));
+    assert!(!arguments.iter().any(|argument| argument.contains("{")));
+
+    // The only writable mount is the job workspace, which holds the target directory
+    let writable = arguments.windows(2).filter(|window| window[0] == "--bind").map(|window| window[1].clone()).collect::<Vec<_>>();
+    assert_eq!(writable, vec![workspace.display().to_string()]);
+    assert_eq!(arguments.last().unwrap(), "cargo");
+  }
+
+  #[test]
+  fn runs_trusted_builds_directly() {
+    let service = service();
+    let workspace = service.config.work_dir.join("jobs/0");
+    let arguments = arguments(&service.command(&workspace, &service.cache, &service.sources, &["cargo", "build"], false));
+    assert_eq!(arguments, vec!["cargo", "build"]);
+  }
+}
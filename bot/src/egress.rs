@@ -0,0 +1,362 @@
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use mizu_hal_types::http::error;
+use reqwest::redirect::Policy;
+use reqwest::{Client, Method, Url};
+use thiserror::Error;
+use tokio::net::lookup_host;
+use tracing::{debug, warn};
+
+/// Size of the guest-visible response buffer, `HARDWARE_BASE + 0x9900..HARDWARE_BASE + 0x16000`.
+pub const RESPONSE_BUFFER_SIZE: usize = 0x16000 - 0x9900 - 1;
+
+/// Outbound network policy applied to every guest HTTP request.
+#[derive(Debug, Clone)]
+pub struct EgressPolicy {
+  /// If not empty, only these domains (and their subdomains) may be contacted.
+  pub allow_domains: Vec<String>,
+  /// Domains (and their subdomains) that may never be contacted.
+  pub deny_domains: Vec<String>,
+  /// Reject loopback, private, link-local and other non-global addresses after DNS resolution.
+  pub block_private: bool,
+  pub max_redirects: usize,
+  pub max_response_size: usize,
+  /// Maximum number of requests a single isolate may make.
+  pub request_budget: u64,
+  pub timeout: Duration,
+}
+
+impl Default for EgressPolicy {
+  fn default() -> Self {
+    Self {
+      allow_domains: Vec::new(),
+      deny_domains: Vec::new(),
+      block_private: true,
+      max_redirects: 5,
+      max_response_size: RESPONSE_BUFFER_SIZE,
+      request_budget: 100,
+      timeout: Duration::from_secs(10),
+    }
+  }
+}
+
+#[derive(Error, Debug)]
+pub enum EgressError {
+  #[error("invalid url: {0}")]
+  InvalidUrl(String),
+  #[error("scheme {0} is not allowed")]
+  SchemeNotAllowed(String),
+  #[error("host {0} is not allowed")]
+  HostDenied(String),
+  #[error("host {0} resolves to a non-public address {1}")]
+  PrivateAddress(String, IpAddr),
+  #[error("failed to resolve {0}")]
+  Resolve(String),
+  #[error("too many redirects")]
+  TooManyRedirects,
+  #[error("response is larger than {0} bytes")]
+  ResponseTooLarge(usize),
+  #[error("request budget of {0} requests exhausted")]
+  BudgetExceeded(u64),
+  #[error("request failed: {0}")]
+  Request(#[from] reqwest::Error),
+}
+
+impl EgressError {
+  /// Error code reported to the guest in `http_response_t::error`.
+  #[must_use]
+  pub fn code(&self) -> u64 {
+    match self {
+      EgressError::InvalidUrl(_) => error::INVALID_URL,
+      EgressError::SchemeNotAllowed(_) | EgressError::HostDenied(_) => error::HOST_DENIED,
+      EgressError::PrivateAddress(_, _) => error::PRIVATE_ADDRESS,
+      EgressError::Resolve(_) => error::RESOLVE_FAILED,
+      EgressError::TooManyRedirects => error::TOO_MANY_REDIRECTS,
+      EgressError::ResponseTooLarge(_) => error::RESPONSE_TOO_LARGE,
+      EgressError::BudgetExceeded(_) => error::BUDGET_EXCEEDED,
+      EgressError::Request(_) => error::REQUEST_FAILED,
+    }
+  }
+}
+
+pub struct EgressResponse {
+  pub status: u16,
+  pub body: Vec<u8>,
+}
+
+impl EgressPolicy {
+  /// Reads the policy from `MIZU_HTTP_*` environment variables, falling back to [EgressPolicy::default].
+  pub fn from_env() -> Self {
+    let mut policy = Self::default();
+    if let Ok(value) = env::var("MIZU_HTTP_ALLOW") {
+      policy.allow_domains = parse_domains(&value);
+    }
+    if let Ok(value) = env::var("MIZU_HTTP_DENY") {
+      policy.deny_domains = parse_domains(&value);
+    }
+    if let Ok(value) = env::var("MIZU_HTTP_ALLOW_PRIVATE") {
+      policy.block_private = !matches!(value.as_str(), "1" | "true" | "yes");
+    }
+    if let Some(value) = parse_env("MIZU_HTTP_MAX_REDIRECTS") {
+      policy.max_redirects = value;
+    }
+    if let Some(value) = parse_env::<usize>("MIZU_HTTP_MAX_RESPONSE_SIZE") {
+      policy.max_response_size = value.min(RESPONSE_BUFFER_SIZE);
+    }
+    if let Some(value) = parse_env("MIZU_HTTP_REQUEST_BUDGET") {
+      policy.request_budget = value;
+    }
+    if let Some(value) = parse_env("MIZU_HTTP_TIMEOUT_SECS") {
+      policy.timeout = Duration::from_secs(value);
+    }
+    policy
+  }
+
+  pub fn check_host(&self, host: &str) -> Result<(), EgressError> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if self.deny_domains.iter().any(|domain| domain_matches(&host, domain)) {
+      return Err(EgressError::HostDenied(host));
+    }
+    if !self.allow_domains.is_empty() && !self.allow_domains.iter().any(|domain| domain_matches(&host, domain)) {
+      return Err(EgressError::HostDenied(host));
+    }
+    Ok(())
+  }
+
+  /// Validates the URL and resolves its host, returning the addresses the connection must be pinned to.
+  pub async fn resolve(&self, url: &Url) -> Result<Vec<SocketAddr>, EgressError> {
+    if !matches!(url.scheme(), "http" | "https") {
+      return Err(EgressError::SchemeNotAllowed(url.scheme().to_owned()));
+    }
+
+    let host = url.host_str().ok_or_else(|| EgressError::InvalidUrl(url.to_string()))?;
+    self.check_host(host)?;
+
+    let port = url.port_or_known_default().ok_or_else(|| EgressError::InvalidUrl(url.to_string()))?;
+    let addresses = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+      Ok(ip) => vec![SocketAddr::new(ip, port)],
+      Err(_) => lookup_host((host, port))
+        .await
+        .map_err(|_| EgressError::Resolve(host.to_owned()))?
+        .collect::<Vec<_>>(),
+    };
+    if addresses.is_empty() {
+      return Err(EgressError::Resolve(host.to_owned()));
+    }
+
+    if self.block_private {
+      // Reject if any of the addresses is not public, the connection may use either of them
+      if let Some(address) = addresses.iter().find(|address| !is_global(address.ip())) {
+        return Err(EgressError::PrivateAddress(host.to_owned(), address.ip()));
+      }
+    }
+
+    Ok(addresses)
+  }
+
+  /// Performs a GET request, following redirects manually so that every hop is checked against the policy.
+  pub async fn get(&self, url: &str) -> Result<EgressResponse, EgressError> {
+    let mut url = Url::parse(url).map_err(|_| EgressError::InvalidUrl(url.to_owned()))?;
+    let mut redirects = 0;
+    loop {
+      let addresses = self.resolve(&url).await?;
+      let host = url.host_str().unwrap().to_owned();
+      debug!("egress: {} -> {:?}", url, addresses);
+
+      // Pin the connection to the validated addresses to avoid DNS rebinding between the check and the connect
+      let client = Client::builder()
+        .redirect(Policy::none())
+        .timeout(self.timeout)
+        .resolve_to_addrs(&host, &addresses)
+        .build()?;
+      let mut response = client.request(Method::GET, url.clone()).send().await?;
+
+      if response.status().is_redirection() {
+        if let Some(location) = response.headers().get(reqwest::header::LOCATION) {
+          redirects += 1;
+          if redirects > self.max_redirects {
+            return Err(EgressError::TooManyRedirects);
+          }
+
+          let location = location.to_str().map_err(|_| EgressError::InvalidUrl(format!("{:?}", location)))?;
+          url = url.join(location).map_err(|_| EgressError::InvalidUrl(location.to_owned()))?;
+          continue;
+        }
+      }
+
+      if let Some(length) = response.content_length() {
+        if length as usize > self.max_response_size {
+          return Err(EgressError::ResponseTooLarge(self.max_response_size));
+        }
+      }
+
+      let status = response.status().as_u16();
+      let mut body = Vec::new();
+      while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > self.max_response_size {
+          warn!("egress: response from {} exceeds {} bytes", url, self.max_response_size);
+          return Err(EgressError::ResponseTooLarge(self.max_response_size));
+        }
+        body.extend_from_slice(&chunk);
+      }
+
+      return Ok(EgressResponse { status, body });
+    }
+  }
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str) -> Option<T> {
+  env::var(name).ok().and_then(|value| value.parse().ok())
+}
+
+fn parse_domains(value: &str) -> Vec<String> {
+  value
+    .split(',')
+    .map(|domain| domain.trim().trim_end_matches('.').to_ascii_lowercase())
+    .filter(|domain| !domain.is_empty())
+    .collect()
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+  host == domain || host.strip_suffix(domain).is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+fn is_global(ip: IpAddr) -> bool {
+  match ip {
+    IpAddr::V4(ip) => is_global_v4(ip),
+    IpAddr::V6(ip) => match embedded_v4(ip) {
+      Some(ip) => is_global_v4(ip),
+      None => is_global_v6(ip),
+    },
+  }
+}
+
+/// IPv4 address an IPv6 address reaches, which is what the policy must be applied to.
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+  let segments = ip.segments();
+  let octets = ip.octets();
+  let last = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+  match segments {
+    // IPv4-mapped (::ffff:0:0/96)
+    [0, 0, 0, 0, 0, 0xffff, _, _] => Some(last),
+    // IPv4-compatible (::/96), except for the unspecified and loopback addresses
+    [0, 0, 0, 0, 0, 0, _, _] if !ip.is_unspecified() && !ip.is_loopback() => Some(last),
+    // NAT64 (64:ff9b::/96)
+    [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(last),
+    // 6to4 (2002::/16)
+    [0x2002, _, _, _, _, _, _, _] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+    _ => None,
+  }
+}
+
+fn is_global_v4(ip: Ipv4Addr) -> bool {
+  let [a, b, ..] = ip.octets();
+  !(ip.is_unspecified()
+    || ip.is_loopback()
+    || ip.is_private()
+    || ip.is_link_local()
+    || ip.is_broadcast()
+    || ip.is_documentation()
+    || ip.is_multicast()
+    || a == 0
+    // Shared address space (100.64.0.0/10)
+    || (a == 100 && (b & 0b1100_0000) == 0b0100_0000)
+    // Benchmarking (198.18.0.0/15)
+    || (a == 198 && (b & 0xfe) == 18)
+    // Reserved (240.0.0.0/4)
+    || a >= 240)
+}
+
+fn is_global_v6(ip: Ipv6Addr) -> bool {
+  let segment = ip.segments()[0];
+  !(ip.is_unspecified()
+    || ip.is_loopback()
+    || ip.is_multicast()
+    // Unique local (fc00::/7)
+    || (segment & 0xfe00) == 0xfc00
+    // Link-local (fe80::/10)
+    || (segment & 0xffc0) == 0xfe80
+    // Site-local (fec0::/10), deprecated but still routed locally
+    || (segment & 0xffc0) == 0xfec0
+    // Local-use NAT64 (64:ff9b:1::/48)
+    || (segment == 0x64 && ip.segments()[1] == 0xff9b && ip.segments()[2] == 1)
+    // Documentation (2001:db8::/32)
+    || (segment == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn global(address: &str) -> bool {
+    is_global(address.parse().unwrap())
+  }
+
+  #[test]
+  fn rejects_non_global_v4() {
+    for address in [
+      "127.0.0.1",
+      "10.1.2.3",
+      "172.16.0.1",
+      "192.168.1.1",
+      "169.254.169.254",
+      "100.64.0.1",
+      "0.0.0.0",
+      "255.255.255.255",
+    ] {
+      assert!(!global(address), "{}", address);
+    }
+    assert!(global("1.1.1.1"));
+    assert!(global("93.184.216.34"));
+  }
+
+  #[test]
+  fn rejects_non_global_v6() {
+    for address in ["::", "::1", "fc00::1", "fe80::1", "fec0::1", "2001:db8::1", "ff02::1", "64:ff9b:1::1"] {
+      assert!(!global(address), "{}", address);
+    }
+    assert!(global("2606:4700:4700::1111"));
+  }
+
+  #[test]
+  fn applies_v4_rules_to_embedded_addresses() {
+    for address in [
+      "::ffff:127.0.0.1",
+      "::127.0.0.1",
+      "::10.0.0.1",
+      "64:ff9b::7f00:1",
+      "64:ff9b::a9fe:a9fe",
+      "2002:7f00:1::",
+      "2002:c0a8:101::1",
+    ] {
+      assert!(!global(address), "{}", address);
+    }
+    for address in ["::ffff:1.1.1.1", "64:ff9b::101:101", "2002:101:101::1"] {
+      assert!(global(address), "{}", address);
+    }
+  }
+
+  #[test]
+  fn matches_domains_and_subdomains() {
+    assert!(domain_matches("example.com", "example.com"));
+    assert!(domain_matches("api.example.com", "example.com"));
+    assert!(!domain_matches("badexample.com", "example.com"));
+    assert!(!domain_matches("example.com.evil", "example.com"));
+    assert!(!domain_matches("com", "example.com"));
+  }
+
+  #[test]
+  fn checks_hosts_against_the_lists() {
+    let policy = EgressPolicy {
+      allow_domains: vec!["example.com".to_owned()],
+      deny_domains: vec!["internal.example.com".to_owned()],
+      ..EgressPolicy::default()
+    };
+    assert!(policy.check_host("Example.COM.").is_ok());
+    assert!(policy.check_host("api.example.com").is_ok());
+    assert!(policy.check_host("db.internal.example.com").is_err());
+    assert!(policy.check_host("example.org").is_err());
+  }
+}
@@ -1,40 +1,44 @@
 use std::ffi::c_char;
+use std::fmt::Display;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use mizu_hal_types::discord::{action, discord_create_message_t, discord_create_reaction_t, discord_get_user_t, discord_message_t, discord_user_t};
+use mizu_hal_types::discord::{
+  action, discord_create_message_t, discord_create_reaction_t, discord_get_user_t, discord_message_t, discord_user_t, status, subscription,
+};
 use mizu_hal_types::StringPtr;
 use runtime::bus::{Bus, BusMemoryExt};
 use runtime::cpu::{Cpu, InterruptHandler};
-use runtime::memory::HARDWARE_BASE;
-use tracing::debug;
+use runtime::exception::Exception;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
 use twilight_http::request::channel::reaction::RequestReactionType;
+use twilight_http::Client;
 use twilight_model::channel::message::MessageFlags;
-use twilight_model::gateway::event::Event;
-use twilight_model::id::marker::{GuildMarker, StickerMarker};
+use twilight_model::id::marker::StickerMarker;
 use twilight_model::id::Id;
-use twilight_standby::Standby;
 
+use crate::capabilities::{CapabilityError, Operation};
 use crate::execution_context::ExecutionContext;
+use crate::platform::{HostPlatform, OutgoingMessage};
 
 pub struct DiscordInterruptHandler {
   pub context: Arc<ExecutionContext>,
-  pub guild_id: Id<GuildMarker>,
-  pub standby: Arc<Standby>,
 }
 
 pub trait MemoryObject<T> {
-  fn read(&self, bus: &Bus) -> T;
-  fn write(&self, bus: &Bus, value: &T);
+  fn read(&self, bus: &Bus) -> Result<T, Exception>;
+  fn write(&self, bus: &Bus, value: &T) -> Result<(), Exception>;
 }
 
 impl MemoryObject<String> for StringPtr {
-  fn read(&self, bus: &Bus) -> String {
-    bus.read_string(self.0 as u64).unwrap().to_str().unwrap().to_owned()
+  fn read(&self, bus: &Bus) -> Result<String, Exception> {
+    Ok(bus.read_string(self.0 as u64)?.to_string_lossy().into_owned())
   }
 
-  fn write(&self, bus: &Bus, value: &String) {
-    bus.write_string(self.0 as u64, value).unwrap();
+  fn write(&self, bus: &Bus, value: &String) -> Result<(), Exception> {
+    bus.write_string(self.0 as u64, value)
   }
 }
 
@@ -44,107 +48,217 @@ impl InterruptHandler for DiscordInterruptHandler {
     let id = cpu.regs[10];
     let address = cpu.regs[11];
     debug!("discord call: id={} address=0x{:x}", id, address);
+    cpu.regs[11] = status::OK;
 
-    let http = self.context.http.lock().await.as_ref().unwrap().clone();
+    let Some(platform) = self.context.platform.lock().await.clone() else {
+      warn!("discord call without a frontend");
+      fail(cpu, status::UNAVAILABLE);
+      return;
+    };
+    let capabilities = self.context.capabilities.lock().await.clone();
+    let home = *self.context.channel_id.lock().await;
     match id {
       action::CREATE_MESSAGE => {
-        let request = cpu.bus.read_struct::<discord_create_message_t>(address).unwrap();
+        let request = cpu.bus.read_struct::<discord_create_message_t>(address);
+        let Some(request) = check(cpu, request, status::INVALID_ARGUMENT) else {
+          return;
+        };
         debug!("request: {:?}", request);
-
-        let mut builder = http.create_message(Id::new(request.channel_id));
+        if denied(cpu, capabilities.check(Operation::SendMessage, Some(request.channel_id), home)) {
+          return;
+        }
 
         let content = if !request.content.is_null() {
-          Some(request.content.read(&cpu.bus))
+          let content = request.content.read(&cpu.bus);
+          let Some(content) = check(cpu, content, status::INVALID_ARGUMENT) else {
+            return;
+          };
+          Some(content)
         } else {
           None
         };
         debug!("content: {:?}", content);
-        if let Some(content) = &content {
-          builder = builder.content(content).unwrap();
-        }
-
-        builder = builder.flags(MessageFlags::from_bits(request.flags).unwrap());
-
-        let stickers = request.stickers.iter().filter_map(|it| *it).map(Id::<StickerMarker>::from).collect::<Vec<_>>();
-        builder = builder.sticker_ids(&stickers).unwrap();
 
-        if let Some(reply) = request.reply {
-          builder = builder.reply(Id::from(reply));
+        let Some(flags) = check(cpu, MessageFlags::from_bits(request.flags).ok_or("unknown message flags"), status::INVALID_ARGUMENT) else {
+          return;
+        };
+        let message = OutgoingMessage {
+          content,
+          reply: request.reply.map(Id::from),
+          flags: Some(flags),
+          stickers: request.stickers.iter().filter_map(|it| *it).map(Id::<StickerMarker>::from).collect(),
+          allowed_mentions: Some(capabilities.allowed_mentions()),
+          ..Default::default()
+        };
+        let Some(channel_id) = checked_id(cpu, request.channel_id) else {
+          return;
+        };
+        if check(cpu, self.context.outbox.acquire().await, status::FAILED).is_none() {
+          return;
         }
-
-        let response = builder.await.unwrap().model().await.unwrap();
+        let Some(response) = check(cpu, platform.send_message(channel_id, message).await, status::FAILED) else {
+          return;
+        };
 
         let ffi_message = discord_message_t {
           id: response.id.get(),
           channel_id: response.channel_id.get(),
-          author_id: response.author.id.get(),
-          content: StringPtr((HARDWARE_BASE + 0x9900) as *const c_char),
+          author_id: response.author_id.get(),
+          content: StringPtr((cpu.hardware + 0x9900) as *const c_char),
         };
-
-        ffi_message.content.write(&cpu.bus, &response.content);
-        cpu.bus.write_struct(HARDWARE_BASE + 0x6000, &ffi_message).unwrap();
-        cpu.regs[10] = HARDWARE_BASE + 0x6000;
+        write_result(cpu, &ffi_message, |bus| ffi_message.content.write(bus, &response.content));
       }
       action::CREATE_REACTION => {
-        let request = cpu.bus.read_struct::<discord_create_reaction_t>(address).unwrap();
+        let request = cpu.bus.read_struct::<discord_create_reaction_t>(address);
+        let Some(request) = check(cpu, request, status::INVALID_ARGUMENT) else {
+          return;
+        };
         debug!("request: {:?}", request);
+        if denied(cpu, capabilities.check(Operation::React, Some(request.channel_id), home)) {
+          return;
+        }
+        let Some(http) = discord_only(cpu, platform.as_ref()) else {
+          return;
+        };
+        let (Some(channel_id), Some(message_id)) = (checked_id(cpu, request.channel_id), checked_id(cpu, request.message_id)) else {
+          return;
+        };
+        let emoji = request.emoji.read(&cpu.bus);
+        let Some(emoji) = check(cpu, emoji, status::INVALID_ARGUMENT) else {
+          return;
+        };
 
-        http
-          .create_reaction(Id::new(request.channel_id), Id::new(request.message_id), &RequestReactionType::Unicode {
-            name: &request.emoji.read(&cpu.bus),
-          })
-          .await
-          .unwrap();
-        cpu.regs[10] = 0;
+        let reaction = RequestReactionType::Unicode { name: &emoji };
+        if check(cpu, http.create_reaction(channel_id, message_id, &reaction).await, status::FAILED).is_some() {
+          cpu.regs[10] = 0;
+        }
       }
       action::GET_USER => {
-        let request = cpu.bus.read_struct::<discord_get_user_t>(address).unwrap();
+        let request = cpu.bus.read_struct::<discord_get_user_t>(address);
+        let Some(request) = check(cpu, request, status::INVALID_ARGUMENT) else {
+          return;
+        };
         debug!("request: {:?}", request);
+        if denied(cpu, capabilities.check(Operation::ReadUsers, None, home)) {
+          return;
+        }
 
-        let response = http.user(Id::new(request.user_id)).await.unwrap().model().await.unwrap();
+        let Some(user_id) = checked_id(cpu, request.user_id) else {
+          return;
+        };
+        let Some(response) = check(cpu, platform.user(user_id).await, status::FAILED) else {
+          return;
+        };
 
         let ffi_user = discord_user_t {
-          id: response.id.get(),
-          name: StringPtr((HARDWARE_BASE + 0x8800) as *const c_char),
-          global_name: StringPtr((HARDWARE_BASE + 0x9900) as *const c_char),
+          id: response.user.id,
+          name: StringPtr((cpu.hardware + 0x8800) as *const c_char),
+          global_name: StringPtr((cpu.hardware + 0x9900) as *const c_char),
         };
-
-        ffi_user.name.write(&cpu.bus, &response.name);
-        ffi_user.global_name.write(&cpu.bus, response.global_name.as_ref().unwrap());
-        cpu.bus.write_struct(HARDWARE_BASE + 0x6000, &ffi_user).unwrap();
-        cpu.regs[10] = HARDWARE_BASE + 0x6000;
+        let global_name = response.global_name.as_ref().unwrap_or(&response.user.username);
+        write_result(cpu, &ffi_user, |bus| {
+          ffi_user.name.write(bus, &response.user.username)?;
+          ffi_user.global_name.write(bus, global_name)
+        });
       }
-      10 => {
-        let message = self
-          .standby
-          .wait_for(self.guild_id, |event: &Event| {
-            if let Event::MessageCreate(message) = event {
-              !message.author.bot
-            } else {
-              false
+      action::SET_EVENT_MASK => {
+        let mask = address & subscription::ALL;
+        debug!("event mask: {:#x}", mask);
+        cpu.regs[10] = self.context.event_mask.swap(mask, Ordering::AcqRel);
+      }
+      action::POLL_MESSAGE => {
+        if denied(cpu, capabilities.check(Operation::ReadMessages, None, home)) {
+          return;
+        }
+
+        // Fed with the new messages of every frontend by the event loop
+        let mut messages = self.context.messages.subscribe();
+        let message = loop {
+          match messages.recv().await {
+            Ok(message) => break message,
+            Err(RecvError::Lagged(skipped)) => debug!("skipped {} messages while polling", skipped),
+            Err(RecvError::Closed) => {
+              fail(cpu, status::FAILED);
+              return;
             }
-          })
-          .await
-          .unwrap();
-        let message = if let Event::MessageCreate(message) = message {
-          message
-        } else {
-          unreachable!()
+          }
         };
         debug!("got message: {:?}", message);
 
         let ffi_message = discord_message_t {
-          id: message.id.get(),
-          channel_id: message.channel_id.get(),
-          author_id: message.author.id.get(),
-          content: StringPtr((HARDWARE_BASE + 0x9900) as *const c_char),
+          id: message.id,
+          channel_id: message.channel_id,
+          author_id: message.author.as_ref().map_or(0, |author| author.id),
+          content: StringPtr((cpu.hardware + 0x9900) as *const c_char),
         };
-
-        ffi_message.content.write(&cpu.bus, &message.content);
-        cpu.bus.write_struct(HARDWARE_BASE + 0x6000, &ffi_message).unwrap();
-        cpu.regs[10] = HARDWARE_BASE + 0x6000;
+        write_result(cpu, &ffi_message, |bus| ffi_message.content.write(bus, &message.content));
+      }
+      _ => {
+        warn!("unknown discord call {}", id);
+        fail(cpu, status::INVALID_ARGUMENT);
       }
-      _ => unimplemented!(),
     }
   }
 }
+
+/// Writes `result` and the data it points to, then returns its address in `a0`.
+fn write_result<T>(cpu: &mut Cpu, result: &T, write: impl FnOnce(&Bus) -> Result<(), Exception>) {
+  let address = cpu.hardware + 0x6000;
+  let written = write(&cpu.bus).and_then(|_| cpu.bus.write_struct(address, result));
+  if check(cpu, written, status::FAILED).is_some() {
+    cpu.regs[10] = address;
+  }
+}
+
+/// Reports a failed call to the guest with `status`.
+fn check<T, E: Display>(cpu: &mut Cpu, result: Result<T, E>, status: u64) -> Option<T> {
+  match result {
+    Ok(value) => Some(value),
+    Err(error) => {
+      warn!("discord call failed: {}", error);
+      fail(cpu, status);
+      None
+    }
+  }
+}
+
+/// Reports a call the frontend has no equivalent for to the guest as unavailable.
+fn discord_only(cpu: &mut Cpu, platform: &dyn HostPlatform) -> Option<Arc<Client>> {
+  let http = platform.discord();
+  if http.is_none() {
+    warn!("discord call not available on {}", platform.name());
+    fail(cpu, status::UNAVAILABLE);
+  }
+  http
+}
+
+/// Reports a failed call to the guest with a null result and a status code.
+fn fail(cpu: &mut Cpu, status: u64) {
+  cpu.regs[10] = 0;
+  cpu.regs[11] = status;
+}
+
+/// Converts an ID supplied by the guest, a zero ID is reported as an invalid argument.
+fn checked_id<T>(cpu: &mut Cpu, value: u64) -> Option<Id<T>> {
+  let id = Id::new_checked(value);
+  if id.is_none() {
+    warn!("discord call with a zero id");
+    fail(cpu, status::INVALID_ARGUMENT);
+  }
+  id
+}
+
+/// Reports a denied call to the guest along with the operation or channel that is not allowed.
+fn denied(cpu: &mut Cpu, result: Result<(), CapabilityError>) -> bool {
+  let Err(error) = result else {
+    return false;
+  };
+  warn!("discord call denied: {}", error);
+  let (code, subject) = match error {
+    CapabilityError::Operation(operation) => (status::DENIED, operation.code()),
+    CapabilityError::Channel(channel_id) => (status::CHANNEL_DENIED, channel_id),
+  };
+  fail(cpu, code);
+  cpu.regs[12] = subject;
+  true
+}
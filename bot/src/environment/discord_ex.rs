@@ -1,21 +1,127 @@
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use mizu_hal_discord::discord;
 use mizu_hal_discord::discord::discord_ex_request::DiscordExRequestUnion;
-use mizu_hal_discord::discord::DiscordExRequest;
-use mizu_hal_discord::prost::Message;
+use mizu_hal_discord::discord::discord_ex_response::DiscordExResponseUnion;
+use mizu_hal_discord::discord::{DiscordExRequest, DiscordExResponse, ErrorKind};
+use mizu_hal_discord::prost::{DecodeError, Message};
+use mizu_hal_types::discord::subscription;
 use runtime::bus::BusMemoryExt;
 use runtime::cpu::{Cpu, InterruptHandler};
-use tracing::debug;
+use thiserror::Error;
+use tracing::{debug, warn};
+use twilight_http::api_error::ApiError;
+use twilight_http::error::ErrorType;
+use twilight_http::request::channel::reaction::RequestReactionType;
+use twilight_http::response::DeserializeBodyError;
+use twilight_model::application::command::{Command, CommandOption, CommandOptionChoice, CommandOptionChoiceValue, CommandOptionType, CommandType};
+use twilight_model::channel::message::component::{ActionRow, Button, ButtonStyle, Component, SelectMenu, SelectMenuOption, TextInput, TextInputStyle};
+use twilight_model::channel::message::MessageFlags;
+use twilight_model::channel::ChannelType;
 use twilight_model::http::attachment::Attachment;
+use twilight_model::http::interaction::{InteractionResponse, InteractionResponseData, InteractionResponseType};
+use twilight_model::id::marker::ApplicationMarker;
 use twilight_model::id::Id;
 
+use crate::capabilities::{CapabilityError, Operation};
+use crate::event;
 use crate::execution_context::ExecutionContext;
+use crate::outbox::OutboxError;
+use crate::platform::{OutgoingMessage, PlatformError};
+
+/// Size of the guest-visible response buffer, `HARDWARE_BASE + 0x9900..HARDWARE_BASE + 0x16000`.
+const RESPONSE_BUFFER_SIZE: usize = 0x16000 - 0x9900;
 
 pub struct DiscordExInterruptHandler {
   pub context: Arc<ExecutionContext>,
 }
 
+#[derive(Error, Debug)]
+pub enum DiscordExError {
+  #[error("malformed request: {0}")]
+  Decode(#[from] DecodeError),
+  #[error("invalid request: {0}")]
+  Validation(String),
+  #[error("{0}")]
+  Http(Box<twilight_http::Error>),
+  #[error("malformed response: {0}")]
+  Deserialize(#[from] DeserializeBodyError),
+  #[error("not available: {0}")]
+  Unavailable(&'static str),
+  #[error("not found")]
+  NotFound,
+  #[error("denied: {0}")]
+  Denied(#[from] CapabilityError),
+  #[error("{0}")]
+  Outbox(#[from] OutboxError),
+}
+
+impl From<twilight_http::Error> for DiscordExError {
+  fn from(error: twilight_http::Error) -> Self {
+    DiscordExError::Http(Box::new(error))
+  }
+}
+
+impl From<PlatformError> for DiscordExError {
+  fn from(error: PlatformError) -> Self {
+    match error {
+      PlatformError::Http(error) => DiscordExError::Http(Box::new(error)),
+      PlatformError::Deserialize(error) => DiscordExError::Deserialize(error),
+      PlatformError::Validation(error) => DiscordExError::Validation(error),
+      PlatformError::NotFound => DiscordExError::NotFound,
+    }
+  }
+}
+
+impl DiscordExError {
+  fn validation(error: impl ToString) -> Self {
+    DiscordExError::Validation(error.to_string())
+  }
+
+  pub fn to_proto(&self) -> discord::DiscordError {
+    let (kind, status, code) = match self {
+      DiscordExError::Decode(_) | DiscordExError::Validation(_) => (ErrorKind::InvalidRequest, 0, 0),
+      DiscordExError::Deserialize(_) => (ErrorKind::Unknown, 0, 0),
+      DiscordExError::Unavailable(_) => (ErrorKind::Unavailable, 0, 0),
+      DiscordExError::NotFound => (ErrorKind::NotFound, 404, 0),
+      DiscordExError::Denied(_) => (ErrorKind::Denied, 0, 0),
+      DiscordExError::Outbox(OutboxError::Busy) => (ErrorKind::RateLimited, 0, 0),
+      DiscordExError::Outbox(OutboxError::BudgetExhausted(_)) => (ErrorKind::BudgetExhausted, 0, 0),
+      DiscordExError::Http(error) => match error.kind() {
+        ErrorType::Response { error, status, .. } => {
+          let status = status.get();
+          let code = match error {
+            ApiError::General(error) => error.code,
+            _ => 0,
+          };
+          let kind = match status {
+            400 => ErrorKind::InvalidRequest,
+            401 | 403 => ErrorKind::Forbidden,
+            404 => ErrorKind::NotFound,
+            429 => ErrorKind::RateLimited,
+            500..=599 => ErrorKind::ServerError,
+            _ => ErrorKind::Unknown,
+          };
+          (kind, status as u32, code)
+        }
+        ErrorType::Unauthorized => (ErrorKind::Forbidden, 401, 0),
+        ErrorType::ServiceUnavailable { .. } => (ErrorKind::Unavailable, 503, 0),
+        ErrorType::RequestTimedOut | ErrorType::RequestError | ErrorType::RequestCanceled => (ErrorKind::Unavailable, 0, 0),
+        _ => (ErrorKind::Unknown, 0, 0),
+      },
+    };
+
+    discord::DiscordError {
+      kind: kind as i32,
+      status,
+      code,
+      message: self.to_string(),
+    }
+  }
+}
+
 #[async_trait]
 impl InterruptHandler for DiscordExInterruptHandler {
   async fn handle(&self, cpu: &mut Cpu) {
@@ -23,39 +129,421 @@ impl InterruptHandler for DiscordExInterruptHandler {
     let address = cpu.regs[11];
     debug!("discord call: length={} address=0x{:x}", length, address);
 
-    let request = cpu.bus.read(address, length).unwrap();
-    debug!("request: {:?}", request);
+    let response = match cpu.bus.read(address, length) {
+      Ok(request) => {
+        debug!("request: {:?}", request);
+        self.execute(&request).await
+      }
+      Err(_) => Err(DiscordExError::validation("request is not in guest memory")),
+    };
+    let response = match response {
+      Ok(response) => response,
+      Err(error) => {
+        warn!("discord request failed: {}", error);
+        DiscordExResponseUnion::Error(error.to_proto())
+      }
+    };
+
+    let mut encoded = DiscordExResponse {
+      discord_ex_response_union: Some(response),
+    }
+    .encode_to_vec();
+    if encoded.len() > RESPONSE_BUFFER_SIZE {
+      encoded = DiscordExResponse {
+        discord_ex_response_union: Some(DiscordExResponseUnion::Error(discord::DiscordError {
+          kind: ErrorKind::ResponseTooLarge as i32,
+          message: format!("response is larger than {} bytes", RESPONSE_BUFFER_SIZE),
+          ..Default::default()
+        })),
+      }
+      .encode_to_vec();
+    }
 
-    let http = self.context.http.lock().await.as_ref().unwrap().clone();
-    let request = DiscordExRequest::decode(&*request).unwrap();
-    match request.discord_ex_request_union.unwrap() {
-      DiscordExRequestUnion::CreateMessageRequest(create_message) => {
-        let mut builder = http.create_message(Id::new(create_message.channel_id));
-        if let Some(content) = create_message.content.as_deref() {
-          builder = builder.content(content).unwrap();
-        }
+    cpu.bus.write(cpu.hardware + 0x9900, &encoded).unwrap();
+    cpu.regs[10] = encoded.len() as u64;
+    cpu.regs[11] = cpu.hardware + 0x9900;
+  }
+}
 
-        if let Some(reference_id) = create_message.reference_id {
-          builder = builder.reply(Id::new(reference_id));
-        }
+impl DiscordExInterruptHandler {
+  async fn execute(&self, request: &[u8]) -> Result<DiscordExResponseUnion, DiscordExError> {
+    let platform = self.context.platform.lock().await.clone().ok_or(DiscordExError::Unavailable("platform"))?;
+    let guild_id = self.context.guild_id.lock().await.ok_or(DiscordExError::Unavailable("guild"))?;
+    let request = DiscordExRequest::decode(request)?
+      .discord_ex_request_union
+      .ok_or_else(|| DiscordExError::validation("empty request"))?;
 
-        let attachments = create_message
-          .attachments
-          .iter()
-          .map(|attachment| Attachment::from_bytes(attachment.name.clone(), attachment.data.clone(), 1))
-          .collect::<Vec<_>>();
-        builder = builder.attachments(&attachments).unwrap();
+    let capabilities = self.context.capabilities.lock().await.clone();
+    let (operation, channel_id) = Operation::of(&request);
+    capabilities.check(operation, channel_id, *self.context.channel_id.lock().await)?;
+    let allowed_mentions = capabilities.allowed_mentions();
+    if matches!(
+      request,
+      DiscordExRequestUnion::CreateMessageRequest(_) | DiscordExRequestUnion::InteractionResponseRequest(_) | DiscordExRequestUnion::FollowupRequest(_)
+    ) {
+      self.context.outbox.acquire().await?;
+    }
 
-        let response = builder.await.unwrap().model().await.unwrap();
-        cpu.regs[10] = response.id.get();
+    // Requests every frontend supports
+    let request = match request {
+      DiscordExRequestUnion::CreateMessageRequest(create_message) => {
+        let message = OutgoingMessage {
+          content: create_message.content,
+          reply: create_message.reference_id.map(id).transpose()?,
+          attachments: create_message
+            .attachments
+            .into_iter()
+            .map(|attachment| Attachment::from_bytes(attachment.name, attachment.data, 1))
+            .collect(),
+          allowed_mentions: Some(allowed_mentions),
+          ..Default::default()
+        };
+        let response = platform.send_message(id(create_message.channel_id)?, message).await?;
+        return Ok(DiscordExResponseUnion::Id(response.id.get()));
       }
       DiscordExRequestUnion::EditMessageRequest(edit_message) => {
-        let mut builder = http.update_message(Id::new(edit_message.channel_id), Id::new(edit_message.message_id));
-        builder = builder.content(edit_message.content.as_deref()).unwrap();
+        let response = platform
+          .edit_message(
+            id(edit_message.channel_id)?,
+            id(edit_message.message_id)?,
+            edit_message.content.as_deref(),
+            Some(allowed_mentions),
+          )
+          .await?;
+        return Ok(DiscordExResponseUnion::Id(response.id.get()));
+      }
+      DiscordExRequestUnion::GetUserRequest(get_user) => {
+        let user = platform.user(id(get_user.user_id)?).await?;
+        return Ok(DiscordExResponseUnion::User(user.user));
+      }
+      request => request,
+    };
+
+    let http = platform.discord().ok_or(DiscordExError::Unavailable("discord"))?;
+    let response = match request {
+      DiscordExRequestUnion::RegisterCommandsRequest(register_commands) => {
+        let application_id = self.application_id().await?;
+
+        let commands = register_commands.commands.iter().map(command).collect::<Vec<_>>();
+        let response = http.interaction(application_id).set_guild_commands(guild_id, &commands).await?.models().await?;
 
-        let response = builder.await.unwrap().model().await.unwrap();
-        cpu.regs[10] = response.id.get();
+        // Registering commands implies interest in their invocations
+        self.context.event_mask.fetch_or(subscription::INTERACTION_CREATE, Ordering::AcqRel);
+        DiscordExResponseUnion::Id(response.len() as u64)
       }
-    }
+      DiscordExRequestUnion::InteractionResponseRequest(interaction_response) => {
+        let application_id = self.application_id().await?;
+
+        let kind = match interaction_response.r#type {
+          5 => InteractionResponseType::DeferredChannelMessageWithSource,
+          6 => InteractionResponseType::DeferredUpdateMessage,
+          7 => InteractionResponseType::UpdateMessage,
+          9 => InteractionResponseType::Modal,
+          _ => InteractionResponseType::ChannelMessageWithSource,
+        };
+        let response = InteractionResponse {
+          kind,
+          data: Some(InteractionResponseData {
+            allowed_mentions: Some(allowed_mentions),
+            content: interaction_response.content.clone(),
+            flags: interaction_response.ephemeral.then_some(MessageFlags::EPHEMERAL),
+            components: (!interaction_response.components.is_empty()).then(|| components(&interaction_response.components)),
+            custom_id: (!interaction_response.custom_id.is_empty()).then(|| interaction_response.custom_id.clone()),
+            title: (!interaction_response.title.is_empty()).then(|| interaction_response.title.clone()),
+            ..Default::default()
+          }),
+        };
+
+        http
+          .interaction(application_id)
+          .create_response(id(interaction_response.interaction_id)?, &interaction_response.token, &response)
+          .await?;
+        DiscordExResponseUnion::Empty(discord::Empty {})
+      }
+      DiscordExRequestUnion::FollowupRequest(followup) => {
+        let application_id = self.application_id().await?;
+        let interaction = http.interaction(application_id);
+
+        let rows = components(&followup.components);
+        let mut builder = interaction
+          .create_followup(&followup.token)
+          .allowed_mentions(Some(&allowed_mentions))
+          .components(&rows)
+          .map_err(DiscordExError::validation)?;
+        if let Some(content) = followup.content.as_deref() {
+          builder = builder.content(content).map_err(DiscordExError::validation)?;
+        }
+        if followup.ephemeral {
+          builder = builder.flags(MessageFlags::EPHEMERAL);
+        }
+
+        let response = builder.await?.model().await?;
+        DiscordExResponseUnion::Id(response.id.get())
+      }
+      DiscordExRequestUnion::DeleteMessageRequest(delete_message) => {
+        http
+          .delete_message(id(delete_message.channel_id)?, id(delete_message.message_id)?)
+          .await?;
+        DiscordExResponseUnion::Empty(discord::Empty {})
+      }
+      DiscordExRequestUnion::BulkDeleteMessagesRequest(bulk_delete) => {
+        let message_ids = bulk_delete.message_ids.iter().map(|message_id| id(*message_id)).collect::<Result<Vec<_>, _>>()?;
+        http
+          .delete_messages(id(bulk_delete.channel_id)?, &message_ids)
+          .map_err(DiscordExError::validation)?
+          .await?;
+        DiscordExResponseUnion::Empty(discord::Empty {})
+      }
+      DiscordExRequestUnion::GetMessageRequest(get_message) => {
+        let message = http
+          .message(id(get_message.channel_id)?, id(get_message.message_id)?)
+          .await?
+          .model()
+          .await?;
+        DiscordExResponseUnion::Message(event::message(&message))
+      }
+      DiscordExRequestUnion::GetMessagesRequest(get_messages) => {
+        let channel_id = id(get_messages.channel_id)?;
+        let limit = if get_messages.limit == 0 { 50 } else { get_messages.limit.min(100) as u16 };
+        let builder = http.channel_messages(channel_id);
+        let response = if let Some(before) = get_messages.before {
+          builder.before(id(before)?).limit(limit).map_err(DiscordExError::validation)?.await?
+        } else if let Some(after) = get_messages.after {
+          builder.after(id(after)?).limit(limit).map_err(DiscordExError::validation)?.await?
+        } else if let Some(around) = get_messages.around {
+          builder.around(id(around)?).limit(limit).map_err(DiscordExError::validation)?.await?
+        } else {
+          builder.limit(limit).map_err(DiscordExError::validation)?.await?
+        };
+
+        let messages = response.models().await?;
+        DiscordExResponseUnion::Messages(discord::MessageList {
+          messages: messages.iter().map(event::message).collect(),
+        })
+      }
+      DiscordExRequestUnion::PinMessageRequest(pin_message) => {
+        let (channel_id, message_id) = (id(pin_message.channel_id)?, id(pin_message.message_id)?);
+        if pin_message.unpin {
+          http.delete_pin(channel_id, message_id).await?;
+        } else {
+          http.create_pin(channel_id, message_id).await?;
+        }
+        DiscordExResponseUnion::Empty(discord::Empty {})
+      }
+      DiscordExRequestUnion::GetPinsRequest(get_pins) => {
+        let messages = http.pins(id(get_pins.channel_id)?).await?.models().await?;
+        DiscordExResponseUnion::Messages(discord::MessageList {
+          messages: messages.iter().map(event::message).collect(),
+        })
+      }
+      DiscordExRequestUnion::CreateThreadRequest(create_thread) => {
+        let channel_id = id(create_thread.channel_id)?;
+        let thread = match create_thread.message_id {
+          Some(message_id) => {
+            http
+              .create_thread_from_message(channel_id, id(message_id)?, &create_thread.name)
+              .map_err(DiscordExError::validation)?
+              .await?
+          }
+          None => {
+            let kind = if create_thread.private {
+              ChannelType::PrivateThread
+            } else {
+              ChannelType::PublicThread
+            };
+            http
+              .create_thread(channel_id, &create_thread.name, kind)
+              .map_err(DiscordExError::validation)?
+              .await?
+          }
+        }
+        .model()
+        .await?;
+        DiscordExResponseUnion::Channel(event::channel(&thread))
+      }
+      DiscordExRequestUnion::TypingRequest(typing) => {
+        http.create_typing_trigger(id(typing.channel_id)?).await?;
+        DiscordExResponseUnion::Empty(discord::Empty {})
+      }
+      DiscordExRequestUnion::GetChannelRequest(get_channel) => {
+        let channel = http.channel(id(get_channel.channel_id)?).await?.model().await?;
+        DiscordExResponseUnion::Channel(event::channel(&channel))
+      }
+      DiscordExRequestUnion::GetGuildRequest(_) => {
+        let guild = http.guild(guild_id).with_counts(true).await?.model().await?;
+        DiscordExResponseUnion::Guild(event::guild(&guild))
+      }
+      DiscordExRequestUnion::GetMemberRequest(get_member) => {
+        let member = http.guild_member(guild_id, id(get_member.user_id)?).await?.model().await?;
+        DiscordExResponseUnion::Member(event::member(&member))
+      }
+      DiscordExRequestUnion::GetRolesRequest(_) => {
+        let roles = http.roles(guild_id).await?.models().await?;
+        DiscordExResponseUnion::Roles(discord::RoleList {
+          roles: roles.iter().map(event::role).collect(),
+        })
+      }
+      DiscordExRequestUnion::MemberRoleRequest(member_role) => {
+        let (user_id, role_id) = (id(member_role.user_id)?, id(member_role.role_id)?);
+        if member_role.remove {
+          http.remove_guild_member_role(guild_id, user_id, role_id).await?;
+        } else {
+          http.add_guild_member_role(guild_id, user_id, role_id).await?;
+        }
+        DiscordExResponseUnion::Empty(discord::Empty {})
+      }
+      DiscordExRequestUnion::CreateReactionRequest(create_reaction) => {
+        let emoji = create_reaction.emoji.ok_or_else(|| DiscordExError::validation("missing emoji"))?;
+        let reaction = match emoji.id {
+          Some(emoji_id) => RequestReactionType::Custom {
+            id: id(emoji_id)?,
+            name: Some(&emoji.name),
+          },
+          None => RequestReactionType::Unicode { name: &emoji.name },
+        };
+        http
+          .create_reaction(id(create_reaction.channel_id)?, id(create_reaction.message_id)?, &reaction)
+          .await?;
+        DiscordExResponseUnion::Empty(discord::Empty {})
+      }
+      DiscordExRequestUnion::CreateMessageRequest(_) | DiscordExRequestUnion::EditMessageRequest(_) | DiscordExRequestUnion::GetUserRequest(_) => {
+        unreachable!("handled by the platform")
+      }
+    };
+
+    Ok(response)
+  }
+
+  async fn application_id(&self) -> Result<Id<ApplicationMarker>, DiscordExError> {
+    self.context.application_id.lock().await.ok_or(DiscordExError::Unavailable("application"))
+  }
+}
+
+/// Converts an ID supplied by the guest, which may be zero.
+fn id<T>(value: u64) -> Result<Id<T>, DiscordExError> {
+  Id::new_checked(value).ok_or_else(|| DiscordExError::validation("id must not be zero"))
+}
+
+fn command(command: &discord::Command) -> Command {
+  Command {
+    application_id: None,
+    default_member_permissions: None,
+    dm_permission: None,
+    description: command.description.clone(),
+    description_localizations: None,
+    guild_id: None,
+    id: None,
+    kind: CommandType::ChatInput,
+    name: command.name.clone(),
+    name_localizations: None,
+    nsfw: None,
+    options: command.options.iter().map(command_option).collect(),
+    version: Id::new(1),
+  }
+}
+
+fn command_option(option: &discord::CommandOption) -> CommandOption {
+  let kind = match option.r#type {
+    1 => CommandOptionType::SubCommand,
+    2 => CommandOptionType::SubCommandGroup,
+    4 => CommandOptionType::Integer,
+    5 => CommandOptionType::Boolean,
+    6 => CommandOptionType::User,
+    7 => CommandOptionType::Channel,
+    8 => CommandOptionType::Role,
+    9 => CommandOptionType::Mentionable,
+    10 => CommandOptionType::Number,
+    11 => CommandOptionType::Attachment,
+    _ => CommandOptionType::String,
+  };
+  let is_subcommand = matches!(kind, CommandOptionType::SubCommand | CommandOptionType::SubCommandGroup);
+
+  CommandOption {
+    autocomplete: None,
+    channel_types: None,
+    choices: (!option.choices.is_empty()).then(|| {
+      option
+        .choices
+        .iter()
+        .map(|choice| CommandOptionChoice {
+          name: choice.name.clone(),
+          name_localizations: None,
+          value: CommandOptionChoiceValue::String(choice.value.clone()),
+        })
+        .collect()
+    }),
+    description: option.description.clone(),
+    description_localizations: None,
+    kind,
+    max_length: None,
+    max_value: None,
+    min_length: None,
+    min_value: None,
+    name: option.name.clone(),
+    name_localizations: None,
+    options: is_subcommand.then(|| option.options.iter().map(command_option).collect()),
+    required: (!is_subcommand).then_some(option.required),
+  }
+}
+
+fn components(rows: &[discord::ActionRow]) -> Vec<Component> {
+  rows
+    .iter()
+    .map(|row| {
+      Component::ActionRow(ActionRow {
+        components: row.components.iter().map(component).collect(),
+      })
+    })
+    .collect()
+}
+
+fn component(component: &discord::Component) -> Component {
+  let non_empty = |value: &String| (!value.is_empty()).then(|| value.clone());
+  match component.r#type {
+    3 => Component::SelectMenu(SelectMenu {
+      custom_id: component.custom_id.clone(),
+      disabled: component.disabled,
+      max_values: component.max_values.map(|value| value as u8),
+      min_values: component.min_values.map(|value| value as u8),
+      options: component
+        .options
+        .iter()
+        .map(|option| SelectMenuOption {
+          default: option.default,
+          description: non_empty(&option.description),
+          emoji: None,
+          label: option.label.clone(),
+          value: option.value.clone(),
+        })
+        .collect(),
+      placeholder: non_empty(&component.placeholder),
+    }),
+    4 => Component::TextInput(TextInput {
+      custom_id: component.custom_id.clone(),
+      label: component.label.clone(),
+      max_length: None,
+      min_length: None,
+      placeholder: non_empty(&component.placeholder),
+      required: Some(component.required),
+      style: match component.style {
+        2 => TextInputStyle::Paragraph,
+        _ => TextInputStyle::Short,
+      },
+      value: non_empty(&component.value),
+    }),
+    _ => Component::Button(Button {
+      custom_id: non_empty(&component.custom_id),
+      disabled: component.disabled,
+      emoji: None,
+      label: non_empty(&component.label),
+      style: match component.style {
+        2 => ButtonStyle::Secondary,
+        3 => ButtonStyle::Success,
+        4 => ButtonStyle::Danger,
+        5 => ButtonStyle::Link,
+        _ => ButtonStyle::Primary,
+      },
+      url: non_empty(&component.url),
+    }),
   }
 }
@@ -6,7 +6,9 @@ use runtime::bus::BusMemoryExt;
 use runtime::cpu::{Cpu, InterruptHandler};
 use runtime::interrupt::Interrupt;
 
+use crate::admin::profile_attachments;
 use crate::execution_context::ExecutionContext;
+use crate::platform::OutgoingMessage;
 
 pub struct DumpPerformanceHandler {
   pub context: Arc<ExecutionContext>,
@@ -15,15 +17,19 @@ pub struct DumpPerformanceHandler {
 #[async_trait]
 impl InterruptHandler for DumpPerformanceHandler {
   async fn handle(&self, cpu: &mut Cpu) {
-    let http = self.context.http.lock().await.as_ref().unwrap().clone();
-    let channel_id = self.context.channel_id.lock().await.unwrap();
-
-    http
-      .create_message(channel_id)
-      .content(&format!("performance dump: ```c\nperf={:?}\npc = 0x{:x}```", cpu.perf, cpu.pc))
-      .unwrap()
-      .await
-      .unwrap();
+    let platform = self.context.platform.lock().await.as_ref().unwrap().clone();
+    let channel_id = *self.context.channel_id.lock().await;
+
+    if let Some(channel_id) = channel_id {
+      let mut message = OutgoingMessage::text(format!("performance dump: ```c\nperf={:?}\npc = 0x{:x}```", cpu.perf, cpu.pc));
+      // Samples of the profiler, if it was started with `!profile start`
+      let isolate = cpu.isolate.as_ref().and_then(|isolate| isolate.upgrade());
+      if let Some(profiler) = isolate.as_ref().map(|isolate| &isolate.profiler).filter(|profiler| profiler.samples() > 0) {
+        let symbols = self.context.image.lock().await.as_ref().map(|image| image.symbols.clone()).unwrap_or_default();
+        message.attachments = profile_attachments(profiler, &symbols);
+      }
+      platform.send_message(channel_id, message).await.unwrap();
+    }
     cpu.perf.reset();
 
     cpu.regs[10] = 0x50;
@@ -39,11 +45,11 @@ impl InterruptHandler for DumpPerformanceHandler {
     cpu.saved_regs.fill(0);
     cpu.bus.write_string(ptr, "the fog is coming shit").unwrap();
 
-    http
-      .create_message(channel_id)
-      .content(&format!("allocated: `0x{:x}`", ptr))
-      .unwrap()
-      .await
-      .unwrap();
+    if let Some(channel_id) = channel_id {
+      platform
+        .send_message(channel_id, OutgoingMessage::text(format!("allocated: `0x{:x}`", ptr)))
+        .await
+        .unwrap();
+    }
   }
 }
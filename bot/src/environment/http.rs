@@ -1,17 +1,19 @@
 use std::ffi::c_char;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use reqwest::Method;
+use mizu_hal_types::http::error;
 use runtime::bus::BusMemoryExt;
 use runtime::cpu::{Cpu, InterruptHandler};
-use runtime::memory::HARDWARE_BASE;
-use tracing::debug;
+use tracing::{debug, warn};
 
+use crate::egress::{EgressError, EgressPolicy, RESPONSE_BUFFER_SIZE};
 use crate::execution_context::ExecutionContext;
 
 pub struct HttpHandler {
   pub context: Arc<ExecutionContext>,
+  pub policy: Arc<EgressPolicy>,
 }
 
 #[repr(C)]
@@ -27,6 +29,7 @@ unsafe impl Send for http_request_t {}
 pub struct http_response_t {
   pub status_code: u16,
   pub body: *const c_char,
+  pub error: u64,
 }
 
 unsafe impl Send for http_response_t {}
@@ -41,16 +44,44 @@ impl InterruptHandler for HttpHandler {
     let url = cpu.bus.read_string(request.url as u64).unwrap().to_string_lossy().to_string();
     debug!("url: {}", url);
 
-    let client = reqwest::Client::new();
-    let response = client.request(Method::GET, url).send().await.unwrap();
+    let result = if self.context.http_requests.fetch_add(1, Ordering::AcqRel) >= self.policy.request_budget {
+      Err(EgressError::BudgetExceeded(self.policy.request_budget))
+    } else {
+      self.policy.get(&url).await
+    };
 
-    let ffi_response = http_response_t {
-      status_code: response.status().as_u16(),
-      body: (HARDWARE_BASE + 0x9900) as *const c_char,
+    let (ffi_response, mut body) = match result {
+      Ok(response) => (
+        http_response_t {
+          status_code: response.status,
+          body: (cpu.hardware + 0x9900) as *const c_char,
+          error: error::NONE,
+        },
+        String::from_utf8_lossy(&response.body).to_string(),
+      ),
+      Err(error) => {
+        warn!("http request to {} denied: {}", url, error);
+        (
+          http_response_t {
+            status_code: 0,
+            body: (cpu.hardware + 0x9900) as *const c_char,
+            error: error.code(),
+          },
+          error.to_string(),
+        )
+      }
     };
 
-    cpu.bus.write_string(ffi_response.body as u64, &response.text().await.unwrap()).unwrap();
-    cpu.bus.write_struct(HARDWARE_BASE + 0x6000, &ffi_response).unwrap();
-    cpu.regs[10] = HARDWARE_BASE + 0x6000;
+    if body.len() > RESPONSE_BUFFER_SIZE {
+      let mut end = RESPONSE_BUFFER_SIZE;
+      while !body.is_char_boundary(end) {
+        end -= 1;
+      }
+      body.truncate(end);
+    }
+
+    cpu.bus.write_string(ffi_response.body as u64, &body).unwrap();
+    cpu.bus.write_struct(cpu.hardware + 0x6000, &ffi_response).unwrap();
+    cpu.regs[10] = cpu.hardware + 0x6000;
   }
 }
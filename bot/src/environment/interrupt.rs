@@ -1,13 +1,18 @@
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use mizu_hal_types::hart::status;
 use runtime::apic::INTERRUPT_PRIORITY_NORMAL;
 use runtime::cpu::{Cpu, InterruptHandler};
 use runtime::interrupt::Interrupt;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 use crate::execution_context::ExecutionContext;
 
+/// Sends a machine software interrupt to core `a0` through its APIC, returns a status code in `a0`.
+///
+/// The target keeps its registers, data goes through the mailboxes and the CLINT raises interrupts that respect
+/// MIE.MSIE.
 pub struct IntHandler {
   pub context: Arc<ExecutionContext>,
 }
@@ -15,18 +20,23 @@ pub struct IntHandler {
 #[async_trait]
 impl InterruptHandler for IntHandler {
   async fn handle(&self, cpu: &mut Cpu) {
-    let src_regs = &cpu.regs;
     let id = cpu.regs[10] as u16;
     debug!("send interrupt to core {}...", id);
 
     let isolate = cpu.isolate.as_ref().unwrap().upgrade().unwrap();
-    let cpu = isolate.get_core(id);
-    let mut cpu = cpu.lock().await;
-
-    info!("dispatching machine software interrupt");
-    cpu.regs[10] = src_regs[10];
-    cpu.apic.dispatch(Interrupt::MachineSoftwareInterrupt, INTERRUPT_PRIORITY_NORMAL);
-    info!("resetting wfi");
-    cpu.wfi.set(false);
+    let delivered = isolate.post(
+      id,
+      Box::new(|cpu| {
+        info!("dispatching machine software interrupt");
+        cpu.apic.dispatch(Interrupt::MachineSoftwareInterrupt, INTERRUPT_PRIORITY_NORMAL);
+        cpu.wfi.set(false);
+      }),
+    );
+    cpu.regs[10] = if delivered {
+      status::OK
+    } else {
+      warn!("interrupt to missing core {} dropped", id);
+      status::INVALID_HART
+    };
   }
 }
@@ -1,35 +1,50 @@
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use mizu_hal_types::log::status;
 use runtime::bus::BusMemoryExt;
 use runtime::cpu::{Cpu, InterruptHandler};
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::execution_context::ExecutionContext;
+use crate::outbox::OutboxError;
 
 pub struct LogHandler {
   pub context: Arc<ExecutionContext>,
 }
 
+/// Queues the message in the guild outbox, blocks while the outbox is full.
 #[async_trait]
 impl InterruptHandler for LogHandler {
   async fn handle(&self, cpu: &mut Cpu) {
-    let http = self.context.http.lock().await.as_ref().unwrap().clone();
-    let channel_id = self.context.channel_id.lock().await.unwrap();
-
     let address = cpu.regs[10];
     debug!("log address: 0x{:x}", address);
-    let message = cpu.bus.read_string(address).unwrap().to_string_lossy().to_string();
+    let Ok(message) = cpu.bus.read_string(address) else {
+      warn!("log message at 0x{:x} is not readable", address);
+      cpu.regs[10] = status::INVALID_ADDRESS;
+      return;
+    };
+    let message = message.to_string_lossy().to_string();
     debug!("log message: {}", message);
 
-    let cpu_id = cpu.id;
-    tokio::spawn(async move {
-      http
-        .create_message(channel_id)
-        .content(&format!("sys_print cpu {}: `{}`", cpu_id, message))
-        .unwrap()
-        .await
-        .unwrap();
-    });
+    let line = format!("sys_print cpu {}: {}", cpu.id, message);
+    let channel_id = *self.context.channel_id.lock().await;
+    let platform = self.context.platform.lock().await.clone();
+    let (Some(channel_id), Some(platform)) = (channel_id, platform) else {
+      // Detached, only kept for `!logs`
+      self.context.outbox.record(line);
+      cpu.regs[10] = status::OK;
+      return;
+    };
+    cpu.regs[10] = match self.context.outbox.log(platform, channel_id, line).await {
+      Ok(()) => status::OK,
+      Err(error) => {
+        warn!("log message dropped: {}", error);
+        match error {
+          OutboxError::Busy => status::BUSY,
+          OutboxError::BudgetExhausted(_) => status::BUDGET_EXHAUSTED,
+        }
+      }
+    };
   }
 }
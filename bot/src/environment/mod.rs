@@ -1,11 +1,8 @@
 pub mod discord;
 pub mod discord_ex;
 pub mod dump_performance;
-pub mod halt;
 pub mod http;
 pub mod interrupt;
 pub mod log;
-pub mod object_storage;
 pub mod sipi;
-pub mod time;
 pub mod png;
@@ -1,12 +1,9 @@
-use std::time::SystemTime;
-
 use async_trait::async_trait;
 use image::codecs::png::PngEncoder;
-use image::{ImageEncoder, RgbImage};
+use image::RgbImage;
 use tracing::debug;
 use runtime::bus::BusMemoryExt;
 use runtime::cpu::{Cpu, InterruptHandler};
-use runtime::memory::HARDWARE_BASE;
 
 pub struct PngHandler {}
 
@@ -29,8 +26,8 @@ impl InterruptHandler for PngHandler {
     let image = RgbImage::from_raw(width as u32, height as u32, pixels).unwrap();
     image.write_with_encoder(encoder).unwrap();
 
-    cpu.bus.write(HARDWARE_BASE + 0x16000, &output).unwrap();
+    cpu.bus.write(cpu.hardware + 0x16000, &output).unwrap();
     cpu.regs[10] = output.len() as u64;
-    cpu.regs[11] = HARDWARE_BASE + 0x16000;
+    cpu.regs[11] = cpu.hardware + 0x16000;
   }
 }
@@ -2,22 +2,28 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use mizu_hal_types::syscall;
+use runtime::async_call::AsyncCallHandler;
 use runtime::cpu::{Cpu, InterruptHandler};
-use tokio::sync::oneshot;
-use tracing::{debug, info};
+use runtime::halt::HaltHandler;
+use runtime::hart::HartHandler;
+use runtime::mailbox::MailboxHandler;
+use runtime::mmap::MemoryHandler;
+use runtime::ring::RingHandler;
+use runtime::time::TimeHandler;
+use tracing::{debug, info, warn};
 
 use crate::environment::discord_ex::DiscordExInterruptHandler;
 use crate::environment::dump_performance::DumpPerformanceHandler;
-use crate::environment::halt::HaltHandler;
 use crate::environment::http::HttpHandler;
 use crate::environment::interrupt::IntHandler;
 use crate::environment::log::LogHandler;
 use crate::environment::png::PngHandler;
-use crate::environment::time::TimeHandler;
+use crate::egress::EgressPolicy;
 use crate::execution_context::ExecutionContext;
 
 pub struct SipiHandler {
   pub context: Arc<ExecutionContext>,
+  pub egress_policy: Arc<EgressPolicy>,
 }
 
 #[async_trait]
@@ -28,11 +34,13 @@ impl InterruptHandler for SipiHandler {
 
     let isolate = cpu.isolate.as_ref().unwrap().upgrade().unwrap();
     let id = isolate.cores.lock().unwrap().len() as u16;
+    let tracer = cpu.tracer.clone();
     let cpu = isolate.add_core(Cpu::new(id, isolate.bus.clone(), Some(Arc::downgrade(&isolate))));
 
     {
       let mut cpu = cpu.lock().await;
       cpu.pc = pc;
+      cpu.tracer = tracer;
       cpu.ivt.insert(
         syscall::SYSCALL_DISCORD_EX,
         Arc::new(Box::new(DiscordExInterruptHandler { context: self.context.clone() })),
@@ -41,31 +49,41 @@ impl InterruptHandler for SipiHandler {
         syscall::SYSCALL_PERF_DUMP,
         Arc::new(Box::new(DumpPerformanceHandler { context: self.context.clone() })),
       );
-      cpu
-        .ivt
-        .insert(syscall::SYSCALL_HTTP, Arc::new(Box::new(HttpHandler { context: self.context.clone() })));
+      cpu.ivt.insert(
+        syscall::SYSCALL_HTTP,
+        Arc::new(Box::new(HttpHandler {
+          context: self.context.clone(),
+          policy: self.egress_policy.clone(),
+        })),
+      );
       cpu
         .ivt
         .insert(syscall::SYSCALL_LOG, Arc::new(Box::new(LogHandler { context: self.context.clone() })));
       cpu.ivt.insert(syscall::SYSCALL_HALT, Arc::new(Box::new(HaltHandler {})));
       cpu.ivt.insert(syscall::SYSCALL_TIME, Arc::new(Box::new(TimeHandler {})));
-      cpu
-        .ivt
-        .insert(syscall::SYSCALL_SIPI, Arc::new(Box::new(SipiHandler { context: self.context.clone() })));
+      cpu.ivt.insert(
+        syscall::SYSCALL_SIPI,
+        Arc::new(Box::new(SipiHandler {
+          context: self.context.clone(),
+          egress_policy: self.egress_policy.clone(),
+        })),
+      );
       cpu
         .ivt
         .insert(syscall::SYSCALL_INT, Arc::new(Box::new(IntHandler { context: self.context.clone() })));
       cpu.ivt.insert(syscall::SYSCALL_PNG, Arc::new(Box::new(PngHandler {})));
+      cpu.ivt.insert(syscall::SYSCALL_ASYNC, Arc::new(Box::new(AsyncCallHandler::new())));
+      cpu.ivt.insert(syscall::SYSCALL_RING, Arc::new(Box::new(RingHandler::new())));
+      cpu.ivt.insert(syscall::SYSCALL_MEMORY, Arc::new(Box::new(MemoryHandler {})));
+      cpu.ivt.insert(syscall::SYSCALL_HART, Arc::new(Box::new(HartHandler {})));
+      cpu.ivt.insert(syscall::SYSCALL_MAILBOX, Arc::new(Box::new(MailboxHandler {})));
     }
 
-    let (cpu_ready_tx, cpu_ready_rx) = oneshot::channel::<()>();
-
-    let context = self.context.clone();
-    tokio::spawn(async move {
-      context.run_core(cpu, Some(cpu_ready_tx)).await.unwrap();
-    });
-
-    cpu_ready_rx.await.unwrap();
+    let Some(scheduler) = isolate.scheduler() else {
+      warn!("core {} not started: isolate is not scheduled", id);
+      return;
+    };
+    self.context.run_core(&scheduler, &isolate, cpu).await;
     info!("core {} ready", id);
   }
 }
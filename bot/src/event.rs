@@ -0,0 +1,314 @@
+//! Conversions from gateway events and REST models to the guest-visible `discord.proto` messages.
+
+use mizu_hal_discord::discord;
+use twilight_model::application::interaction::application_command::{CommandDataOption, CommandOptionValue};
+use twilight_model::application::interaction::{Interaction, InteractionData, InteractionType};
+use twilight_model::channel::message::{Embed, Mention, ReactionType};
+use twilight_model::channel::{Attachment, Channel, Message};
+use twilight_model::gateway::payload::incoming::{MemberAdd, MessageUpdate};
+use twilight_model::gateway::GatewayReaction;
+use twilight_model::guild::{Guild, Member, Role};
+use twilight_model::user::User;
+
+pub fn user(user: &User) -> discord::User {
+  discord::User {
+    id: user.id.get(),
+    username: user.name.clone(),
+    discriminator: format!("{:04}", user.discriminator),
+    avatar: user.avatar.map(|avatar| avatar.to_string()).unwrap_or_default(),
+    bot: user.bot,
+    system: user.system.unwrap_or_default(),
+    mfa_enabled: user.mfa_enabled.unwrap_or_default(),
+    locale: user.locale.clone().unwrap_or_default(),
+    verified: user.verified.unwrap_or_default(),
+    email: user.email.clone().unwrap_or_default(),
+    flags: user.public_flags.map(|flags| flags.bits() as i32).unwrap_or_default(),
+    premium_type: user.premium_type.map(|premium_type| u8::from(premium_type) as i32).unwrap_or_default(),
+  }
+}
+
+fn mention(mention: &Mention) -> discord::User {
+  discord::User {
+    id: mention.id.get(),
+    username: mention.name.clone(),
+    discriminator: format!("{:04}", mention.discriminator),
+    avatar: mention.avatar.map(|avatar| avatar.to_string()).unwrap_or_default(),
+    bot: mention.bot,
+    flags: mention.public_flags.bits() as i32,
+    ..Default::default()
+  }
+}
+
+fn attachment(attachment: &Attachment) -> discord::Attachment {
+  discord::Attachment {
+    id: attachment.id.get(),
+    filename: attachment.filename.clone(),
+    content_type: attachment.content_type.clone().unwrap_or_default(),
+    size: attachment.size as i32,
+    url: attachment.url.clone(),
+    proxy_url: attachment.proxy_url.clone(),
+    height: attachment.height.unwrap_or_default() as i32,
+    width: attachment.width.unwrap_or_default() as i32,
+  }
+}
+
+fn embed(embed: &Embed) -> discord::Embed {
+  discord::Embed {
+    title: embed.title.clone().unwrap_or_default(),
+    description: embed.description.clone().unwrap_or_default(),
+    url: embed.url.clone().unwrap_or_default(),
+    color: embed.color.map(|color| format!("#{:06x}", color)).unwrap_or_default(),
+    fields: embed
+      .fields
+      .iter()
+      .map(|field| discord::EmbedField {
+        name: field.name.clone(),
+        value: field.value.clone(),
+        inline: field.inline,
+      })
+      .collect(),
+    image_url: embed.image.as_ref().map(|image| image.url.clone()).unwrap_or_default(),
+    thumbnail_url: embed.thumbnail.as_ref().map(|thumbnail| thumbnail.url.clone()).unwrap_or_default(),
+    footer_text: embed.footer.as_ref().map(|footer| footer.text.clone()).unwrap_or_default(),
+    footer_icon_url: embed.footer.as_ref().and_then(|footer| footer.icon_url.clone()).unwrap_or_default(),
+  }
+}
+
+fn emoji(emoji: &ReactionType) -> discord::Emoji {
+  match emoji {
+    ReactionType::Custom { id, name, animated } => discord::Emoji {
+      id: Some(id.get()),
+      name: name.clone().unwrap_or_default(),
+      animated: *animated,
+    },
+    ReactionType::Unicode { name } => discord::Emoji {
+      id: None,
+      name: name.clone(),
+      animated: false,
+    },
+  }
+}
+
+pub fn message(message: &Message) -> discord::IncomingMessage {
+  discord::IncomingMessage {
+    id: message.id.get(),
+    channel_id: message.channel_id.get(),
+    guild_id: message.guild_id.map(|id| id.get()),
+    author: Some(user(&message.author)),
+    content: message.content.clone(),
+    attachments: message.attachments.iter().map(attachment).collect(),
+    embeds: message.embeds.iter().map(embed).collect(),
+    timestamp: message.timestamp.iso_8601().to_string(),
+    edited_timestamp: message
+      .edited_timestamp
+      .map(|timestamp| timestamp.iso_8601().to_string())
+      .unwrap_or_default(),
+    tts: message.tts,
+    webhook_id: message.webhook_id.map(|id| id.get()),
+    mentions: message.mentions.iter().map(mention).collect(),
+    mention_everyone: message.mention_everyone,
+    // Only the IDs are known without a role cache
+    mentioned_roles: message
+      .mention_roles
+      .iter()
+      .map(|id| discord::Role {
+        id: id.get(),
+        ..Default::default()
+      })
+      .collect(),
+    r#type: u8::from(message.kind) as i32,
+  }
+}
+
+pub fn message_update(update: &MessageUpdate) -> discord::IncomingMessage {
+  discord::IncomingMessage {
+    id: update.id.get(),
+    channel_id: update.channel_id.get(),
+    guild_id: update.guild_id.map(|id| id.get()),
+    author: update.author.as_ref().map(user),
+    content: update.content.clone().unwrap_or_default(),
+    attachments: update.attachments.iter().flatten().map(attachment).collect(),
+    embeds: update.embeds.iter().flatten().map(embed).collect(),
+    timestamp: update.timestamp.map(|timestamp| timestamp.iso_8601().to_string()).unwrap_or_default(),
+    edited_timestamp: update
+      .edited_timestamp
+      .map(|timestamp| timestamp.iso_8601().to_string())
+      .unwrap_or_default(),
+    tts: update.tts.unwrap_or_default(),
+    webhook_id: None,
+    mentions: update.mentions.iter().flatten().map(mention).collect(),
+    mention_everyone: update.mention_everyone.unwrap_or_default(),
+    mentioned_roles: update
+      .mention_roles
+      .iter()
+      .flatten()
+      .map(|id| discord::Role {
+        id: id.get(),
+        ..Default::default()
+      })
+      .collect(),
+    r#type: update.kind.map(|kind| u8::from(kind) as i32).unwrap_or_default(),
+  }
+}
+
+pub fn reaction_create(reaction: &GatewayReaction) -> discord::ReactionCreate {
+  discord::ReactionCreate {
+    user_id: reaction.user_id.get(),
+    channel_id: reaction.channel_id.get(),
+    message_id: reaction.message_id.get(),
+    guild_id: reaction.guild_id.map(|id| id.get()),
+    emoji: Some(emoji(&reaction.emoji)),
+  }
+}
+
+pub fn reaction_remove(reaction: &GatewayReaction) -> discord::ReactionRemove {
+  discord::ReactionRemove {
+    user_id: reaction.user_id.get(),
+    channel_id: reaction.channel_id.get(),
+    message_id: reaction.message_id.get(),
+    guild_id: reaction.guild_id.map(|id| id.get()),
+    emoji: Some(emoji(&reaction.emoji)),
+  }
+}
+
+pub fn member_add(member: &MemberAdd) -> discord::MemberAdd {
+  discord::MemberAdd {
+    guild_id: member.guild_id.get(),
+    user: Some(user(&member.user)),
+    nick: member.nick.clone(),
+    roles: member.roles.iter().map(|id| id.get()).collect(),
+    joined_at: member.joined_at.iso_8601().to_string(),
+  }
+}
+
+pub fn thread(thread: &Channel) -> discord::Thread {
+  discord::Thread {
+    id: thread.id.get(),
+    guild_id: thread.guild_id.map(|id| id.get()),
+    parent_id: thread.parent_id.map(|id| id.get()),
+    owner_id: thread.owner_id.map(|id| id.get()),
+    name: thread.name.clone().unwrap_or_default(),
+    r#type: u8::from(thread.kind) as i32,
+  }
+}
+
+pub fn channel(channel: &Channel) -> discord::Channel {
+  discord::Channel {
+    id: channel.id.get(),
+    guild_id: channel.guild_id.map(|id| id.get()),
+    parent_id: channel.parent_id.map(|id| id.get()),
+    name: channel.name.clone().unwrap_or_default(),
+    r#type: u8::from(channel.kind) as i32,
+    topic: channel.topic.clone().unwrap_or_default(),
+    position: channel.position.unwrap_or_default(),
+    nsfw: channel.nsfw.unwrap_or_default(),
+  }
+}
+
+pub fn guild(guild: &Guild) -> discord::Guild {
+  discord::Guild {
+    id: guild.id.get(),
+    name: guild.name.clone(),
+    icon: guild.icon.map(|icon| icon.to_string()).unwrap_or_default(),
+    owner_id: guild.owner_id.get(),
+    member_count: guild.approximate_member_count.unwrap_or_default(),
+    description: guild.description.clone().unwrap_or_default(),
+  }
+}
+
+pub fn member(member: &Member) -> discord::Member {
+  discord::Member {
+    user: Some(user(&member.user)),
+    nick: member.nick.clone(),
+    roles: member.roles.iter().map(|id| id.get()).collect(),
+    joined_at: member.joined_at.iso_8601().to_string(),
+  }
+}
+
+pub fn role(role: &Role) -> discord::Role {
+  discord::Role {
+    id: role.id.get(),
+    name: role.name.clone(),
+    color: role.color as i32,
+    hoist: role.hoist,
+    position: role.position as i32,
+    permissions: role.permissions.bits(),
+    managed: role.managed,
+    mentionable: role.mentionable,
+  }
+}
+
+fn interaction_option(option: &CommandDataOption) -> discord::InteractionOption {
+  let (kind, value, options) = match &option.value {
+    CommandOptionValue::SubCommand(options) => (1, String::new(), options.iter().map(interaction_option).collect()),
+    CommandOptionValue::SubCommandGroup(options) => (2, String::new(), options.iter().map(interaction_option).collect()),
+    CommandOptionValue::String(value) => (3, value.clone(), Vec::new()),
+    CommandOptionValue::Integer(value) => (4, value.to_string(), Vec::new()),
+    CommandOptionValue::Boolean(value) => (5, value.to_string(), Vec::new()),
+    CommandOptionValue::User(id) => (6, id.to_string(), Vec::new()),
+    CommandOptionValue::Channel(id) => (7, id.to_string(), Vec::new()),
+    CommandOptionValue::Role(id) => (8, id.to_string(), Vec::new()),
+    CommandOptionValue::Mentionable(id) => (9, id.to_string(), Vec::new()),
+    CommandOptionValue::Number(value) => (10, value.to_string(), Vec::new()),
+    CommandOptionValue::Attachment(id) => (11, id.to_string(), Vec::new()),
+    CommandOptionValue::Focused(value, _) => (3, value.clone(), Vec::new()),
+  };
+
+  discord::InteractionOption {
+    name: option.name.clone(),
+    r#type: kind,
+    value,
+    options,
+  }
+}
+
+fn interaction_type(kind: InteractionType) -> i32 {
+  match kind {
+    InteractionType::Ping => 1,
+    InteractionType::ApplicationCommand => 2,
+    InteractionType::MessageComponent => 3,
+    InteractionType::ApplicationCommandAutocomplete => 4,
+    InteractionType::ModalSubmit => 5,
+    _ => 0,
+  }
+}
+
+pub fn interaction(interaction: &Interaction) -> discord::Interaction {
+  let mut event = discord::Interaction {
+    id: interaction.id.get(),
+    application_id: interaction.application_id.get(),
+    r#type: interaction_type(interaction.kind),
+    guild_id: interaction.guild_id.map(|id| id.get()),
+    channel_id: interaction.channel.as_ref().map(|channel| channel.id.get()),
+    user: interaction.author().map(user),
+    token: interaction.token.clone(),
+    message_id: interaction.message.as_ref().map(|message| message.id.get()),
+    ..Default::default()
+  };
+
+  match &interaction.data {
+    Some(InteractionData::ApplicationCommand(data)) => {
+      event.name = data.name.clone();
+      event.options = data.options.iter().map(interaction_option).collect();
+    }
+    Some(InteractionData::MessageComponent(data)) => {
+      event.custom_id = data.custom_id.clone();
+      event.values = data.values.clone();
+    }
+    Some(InteractionData::ModalSubmit(data)) => {
+      event.custom_id = data.custom_id.clone();
+      event.fields = data
+        .components
+        .iter()
+        .flat_map(|row| &row.components)
+        .map(|component| discord::ModalField {
+          custom_id: component.custom_id.clone(),
+          value: component.value.clone().unwrap_or_default(),
+        })
+        .collect();
+    }
+    _ => {}
+  }
+
+  event
+}
@@ -1,23 +1,72 @@
+use std::env;
 use std::error::Error;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 
+use async_trait::async_trait;
+use mizu_hal_discord::discord::IncomingMessage;
+use mizu_hal_types::discord::subscription;
 use runtime::cpu::Cpu;
+use runtime::elf::Image;
 use runtime::isolate::Isolate;
 use runtime::perf_counter::CPU_TIME_LIMIT;
-use tokio::sync::{oneshot, Mutex};
-use tracing::{debug, info};
-use twilight_http::Client;
-use twilight_model::id::marker::ChannelMarker;
+use runtime::scheduler::{Hart, HartObserver, Scheduler};
+use runtime::tick::TickResult;
+use tokio::sync::{broadcast, Mutex};
+use tracing::{debug, warn};
+use twilight_model::id::marker::{ApplicationMarker, ChannelMarker, GuildMarker};
 use twilight_model::id::Id;
 use runtime::exception::Exception;
 
-use crate::{CpuExt, TickResult};
+use crate::capabilities::Capabilities;
+use crate::outbox::{Outbox, OutboxPolicy};
+use crate::platform::{HostPlatform, OutgoingMessage};
+
+/// Which host status messages are posted to the channel.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+  /// Only exceptions.
+  Quiet = 0,
+  /// Exceptions, halts and the end of execution.
+  Normal = 1,
+  /// Also every wait for interrupt.
+  Verbose = 2,
+}
+
+impl FromStr for Verbosity {
+  type Err = ();
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    match value {
+      "quiet" => Ok(Verbosity::Quiet),
+      "normal" => Ok(Verbosity::Normal),
+      "verbose" => Ok(Verbosity::Verbose),
+      _ => Err(()),
+    }
+  }
+}
 
 pub struct ExecutionContext {
-  pub http: Mutex<Option<Arc<Client>>>,
+  /// Frontend the context is attached to.
+  pub platform: Mutex<Option<Arc<dyn HostPlatform>>>,
   pub channel_id: Mutex<Option<Id<ChannelMarker>>>,
+  pub guild_id: Mutex<Option<Id<GuildMarker>>>,
+  pub application_id: Mutex<Option<Id<ApplicationMarker>>>,
   pub isolate: Mutex<Option<Arc<Isolate>>>,
+  /// Image the current isolate was loaded from, used by `!restart`.
+  pub image: Mutex<Option<Image>>,
+  /// Number of HTTP requests made by the current isolate.
+  pub http_requests: AtomicU64,
+  /// Discord events the current isolate is subscribed to, see [subscription].
+  pub event_mask: AtomicU64,
+  /// Kept across reloads, changed with `!caps`.
+  pub capabilities: Mutex<Capabilities>,
+  pub outbox: Arc<Outbox>,
+  /// New messages for `POLL_MESSAGE`, on every frontend.
+  pub messages: broadcast::Sender<IncomingMessage>,
+  verbosity: AtomicU8,
 }
 
 impl Default for ExecutionContext {
@@ -28,93 +77,178 @@ impl Default for ExecutionContext {
 
 impl ExecutionContext {
   pub fn new() -> Self {
+    Self::with_outbox_policy(OutboxPolicy::from_env())
+  }
+
+  /// Context whose messages are limited by `policy` instead of the `MIZU_MESSAGE_*` variables.
+  pub fn with_outbox_policy(policy: OutboxPolicy) -> Self {
     Self {
-      http: Mutex::new(None),
+      platform: Mutex::new(None),
       channel_id: Mutex::new(None),
+      guild_id: Mutex::new(None),
+      application_id: Mutex::new(None),
       isolate: Mutex::new(None),
+      image: Mutex::new(None),
+      http_requests: AtomicU64::new(0),
+      event_mask: AtomicU64::new(subscription::DEFAULT),
+      capabilities: Mutex::new(Capabilities::default()),
+      outbox: Arc::new(Outbox::new(policy)),
+      messages: broadcast::channel(16).0,
+      verbosity: AtomicU8::new(
+        env::var("MIZU_VERBOSITY")
+          .ok()
+          .and_then(|value| value.parse().ok())
+          .unwrap_or(Verbosity::Normal) as u8,
+      ),
     }
   }
 
-  pub async fn run_core(&self, cpu: Arc<Mutex<Cpu>>, cpu_ready: Option<oneshot::Sender<()>>) -> Result<(), Box<dyn Error + Send + Sync>> {
-    debug!("starting core loop");
-    let channel_id = self.channel_id.lock().await.unwrap();
-    let http = self.http.lock().await.clone().unwrap();
+  pub fn verbosity(&self) -> Verbosity {
+    match self.verbosity.load(Ordering::Acquire) {
+      0 => Verbosity::Quiet,
+      1 => Verbosity::Normal,
+      _ => Verbosity::Verbose,
+    }
+  }
 
-    let (cpu_id, wfi) = {
-      let cpu = cpu.lock().await;
-      (cpu.id, cpu.wfi.clone())
-    };
+  pub fn set_verbosity(&self, verbosity: Verbosity) {
+    self.verbosity.store(verbosity as u8, Ordering::Release);
+  }
+
+  /// Whether the isolate is subscribed to `subscription` and allowed to receive it.
+  pub async fn is_subscribed(&self, subscription: u64) -> bool {
+    let allowed_events = self.capabilities.lock().await.events;
+    self.event_mask.load(Ordering::Acquire) & allowed_events & subscription != 0
+  }
+
+  /// Whether an event of `subscription` would be delivered, as an interrupt or to a pending `POLL_MESSAGE`.
+  pub async fn wants(&self, subscription: u64) -> bool {
+    (subscription == subscription::MESSAGE_CREATE && self.messages.receiver_count() > 0) || self.is_subscribed(subscription).await
+  }
+
+  /// Binds the context to a channel and resets the per-isolate state before a new isolate is loaded.
+  pub async fn attach(
+    &self,
+    platform: Arc<dyn HostPlatform>,
+    channel_id: Id<ChannelMarker>,
+    guild_id: Id<GuildMarker>,
+    application_id: Option<Id<ApplicationMarker>>,
+  ) {
+    *self.platform.lock().await = Some(platform);
+    *self.channel_id.lock().await = Some(channel_id);
+    *self.guild_id.lock().await = Some(guild_id);
+    *self.application_id.lock().await = application_id;
+    self.http_requests.store(0, Ordering::Release);
+    self.event_mask.store(subscription::DEFAULT, Ordering::Release);
+    self.outbox.reset();
+  }
 
-    if let Some(cpu_ready) = cpu_ready {
-      cpu_ready.send(()).unwrap();
+  /// Posts a host message to the channel through the budget of the outbox, or only keeps it in the log history when
+  /// detached or out of budget.
+  async fn post(&self, platform: &dyn HostPlatform, content: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let channel_id = *self.channel_id.lock().await;
+    match channel_id {
+      Some(channel_id) => {
+        if let Err(error) = self.outbox.acquire().await {
+          self.outbox.record(content);
+          return Err(error.into());
+        }
+        platform.send_message(channel_id, OutgoingMessage::text(content)).await?;
+      }
+      None => self.outbox.record(content),
     }
+    Ok(())
+  }
 
-    info!("started core loop");
-    'wfi: loop {
-      wfi.wait_for(|wfi| !(*wfi)).await;
-      // http.create_message(channel_id).content(&format!("cpu {}/wfi: reset", cpu_id))?.await?;
-
-      let mut cpu = cpu.lock().await;
-      loop {
-        match cpu.run_tick().await? {
-          TickResult::Continue => continue,
-          TickResult::Exception(exception) => {
-            if let Exception::Explosion(pc) = exception {
-              self.isolate.lock().await.as_mut().unwrap().exploded.store(true, Ordering::Release);
-
-              http
-                .create_message(channel_id)
-                .content(&format!("execution context exploded at `{:#08x}` in cpu {}: ```c\n{}```", pc, cpu_id, cpu.dump()))?
-                .await?;
-            } else {
-              http
-                .create_message(channel_id)
-                .content(&format!("cpu {}: exception: {} ```c\n{}```", cpu_id, exception, cpu.dump()))?
-                .await?;
-            }
-          }
-          TickResult::Explosion => {
-            // Initiator should have already printed an error message
-          }
-          TickResult::Eof => {
-            http
-              .create_message(channel_id)
-              .content(&format!("cpu {}: execution finished ```c\n{}```", cpu_id, cpu.dump()))?
-              .await?;
-          }
-          TickResult::Halt => {
-            http
-              .create_message(channel_id)
-              .content(&format!("cpu {}: execution halted ```c\n{}```", cpu_id, cpu.dump()))?
-              .await?;
-          }
-          TickResult::TimeLimit => {
-            http
-              .create_message(channel_id)
-              .content(&format!(
-                "cpu {}: running too long without yield: `{:?} > {:?}`",
-                cpu.id, cpu.perf.cpu_time, CPU_TIME_LIMIT
-              ))?
-              .await?;
+  /// Schedules `cpu` in the group of the guild, results are posted to the channel.
+  pub async fn run_core(self: &Arc<Self>, scheduler: &Arc<Scheduler>, isolate: &Arc<Isolate>, cpu: Arc<Mutex<Cpu>>) -> Arc<Hart> {
+    let group = self.guild_id.lock().await.map_or(0, |guild_id| guild_id.get());
+    scheduler.spawn(isolate, cpu, group, self.clone()).await
+  }
+
+  async fn report(&self, platform: &Arc<dyn HostPlatform>, cpu: &mut Cpu, result: &TickResult) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let cpu_id = cpu.id;
+    match result {
+      TickResult::Continue | TickResult::Paused => {}
+      TickResult::Exception(exception) => {
+        if let Exception::Explosion(pc) = exception {
+          if let Some(isolate) = self.isolate.lock().await.as_ref() {
+            isolate.exploded.store(true, Ordering::Release);
           }
-          TickResult::WaitForInterrupt => {
-            let http = http.clone();
-            let pc = cpu.pc;
-            tokio::spawn(async move {
-              http
-                .create_message(channel_id)
-                .content(&format!("cpu {}/wfi: waiting for interrupt at `{:#08x}`", cpu_id, pc))
-                .unwrap()
-                .await
-                .unwrap();
-            });
-            continue 'wfi;
+
+          self
+            .post(
+              platform.as_ref(),
+              format!("execution context exploded at `{:#08x}` in cpu {}: ```c\n{}```", pc, cpu_id, cpu.dump()),
+            )
+            .await?;
+        } else {
+          self
+            .post(platform.as_ref(), format!("cpu {}: exception: {} ```c\n{}```", cpu_id, exception, cpu.dump()))
+            .await?;
+        }
+      }
+      TickResult::Explosion => {
+        // Initiator should have already printed an error message
+      }
+      TickResult::Eof => {
+        if self.verbosity() >= Verbosity::Normal {
+          self
+            .post(platform.as_ref(), format!("cpu {}: execution finished ```c\n{}```", cpu_id, cpu.dump()))
+            .await?;
+        }
+      }
+      TickResult::Halt => {
+        if self.verbosity() >= Verbosity::Normal {
+          self
+            .post(platform.as_ref(), format!("cpu {}: execution halted ```c\n{}```", cpu_id, cpu.dump()))
+            .await?;
+        }
+      }
+      TickResult::TimeLimit => {
+        self
+          .post(
+            platform.as_ref(),
+            format!(
+              "cpu {}: running too long without yield: `{:?} > {:?}`",
+              cpu.id, cpu.perf.cpu_time, CPU_TIME_LIMIT
+            ),
+          )
+          .await?;
+      }
+      TickResult::WaitForInterrupt => {
+        if self.verbosity() >= Verbosity::Verbose {
+          let line = format!("cpu {}/wfi: waiting for interrupt at {:#08x}", cpu_id, cpu.pc);
+          match *self.channel_id.lock().await {
+            Some(channel_id) => {
+              let (outbox, platform) = (self.outbox.clone(), platform.clone());
+              tokio::spawn(async move {
+                if let Err(error) = outbox.log(platform, channel_id, line).await {
+                  debug!("wfi message dropped: {}", error);
+                }
+              });
+            }
+            None => self.outbox.record(line),
           }
         }
-        break 'wfi;
       }
     }
-
     Ok(())
   }
 }
+
+#[async_trait]
+impl HartObserver for ExecutionContext {
+  async fn on_result(&self, cpu: &mut Cpu, result: &TickResult) {
+    let Some(platform) = self.platform.lock().await.clone() else {
+      return;
+    };
+    if let Err(error) = self.report(&platform, cpu, result).await {
+      warn!("cpu {}: failed to report result: {}", cpu.id, error);
+    }
+  }
+
+  async fn on_error(&self, cpu: &mut Cpu, error: &(dyn Error + Send + Sync)) {
+    warn!("cpu {}: stopped: {}", cpu.id, error);
+  }
+}
@@ -0,0 +1,12 @@
+//! Host environment of the guests, shared by the bot and the `mizu` CLI.
+
+pub mod admin;
+pub mod capabilities;
+pub mod compiler;
+pub mod egress;
+pub mod environment;
+pub mod event;
+pub mod execution_context;
+pub mod outbox;
+pub mod platform;
+pub mod registry;
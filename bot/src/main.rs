@@ -1,30 +1,49 @@
-mod environment;
-mod execution_context;
-
 use std::collections::HashMap;
 use std::env;
 use std::error::Error;
-use std::sync::atomic::Ordering;
+use std::fs;
+use std::path::Path;
 use std::sync::Arc;
-
-use async_trait::async_trait;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bot::capabilities::Capabilities;
+use bot::compiler::{CompileError, CompileService, CompilerConfig, Language};
+use bot::egress::EgressPolicy;
+use bot::environment::discord::DiscordInterruptHandler;
+use bot::environment::discord_ex::DiscordExInterruptHandler;
+use bot::environment::dump_performance::DumpPerformanceHandler;
+use bot::environment::http::HttpHandler;
+use bot::environment::interrupt::IntHandler;
+use bot::environment::log::LogHandler;
+use bot::environment::png::PngHandler;
+use bot::environment::sipi::SipiHandler;
+use bot::execution_context::{ExecutionContext, Verbosity};
+use bot::platform::discord::DiscordPlatform;
+use bot::platform::terminal::{TerminalPlatform, TERMINAL_CHANNEL, TERMINAL_GUILD};
+use bot::platform::{HostEvent, HostPlatform};
+use bot::registry::{ProgramRegistry, DEFAULT_PROGRAM};
+use bot::{admin, event, registry};
 use mizu_hal_discord::discord::discord_ex_event::DiscordExEventUnion;
-use mizu_hal_discord::discord::{DiscordExEvent, Emoji, IncomingMessage, ReactionCreate};
+use mizu_hal_discord::discord::{DiscordExEvent, MemberRemove, MessageDelete};
 use mizu_hal_discord::prost::Message as ProstMessage;
+use mizu_hal_types::discord::subscription;
 use mizu_hal_types::syscall;
-use regex::{Captures, Regex};
-use runtime::apic::INTERRUPT_PRIORITY_NORMAL;
-use runtime::bus::{Bus, BusMemoryExt};
+use runtime::async_call::AsyncCallHandler;
+use runtime::bus::Bus;
 use runtime::cpu::Cpu;
-use runtime::csr;
-use runtime::exception::Exception;
-use runtime::interrupt::Interrupt;
+use runtime::elf::Image;
+use runtime::halt::HaltHandler;
+use runtime::hart::HartHandler;
 use runtime::isolate::Isolate;
+use runtime::mailbox::MailboxHandler;
 use runtime::memory::HARDWARE_BASE;
-use runtime::perf_counter::CPU_TIME_LIMIT;
-use thiserror::Error;
-use tokio::fs;
-use tokio::process::Command;
+use runtime::mmap::{memory_limit_from_env, MemoryHandler};
+use runtime::object_storage::{ObjectStorage, ObjectStorageHandler};
+use runtime::power::swap_directory_from_env;
+use runtime::replay::{record_limit_from_env, ReplayError, Session};
+use runtime::ring::RingHandler;
+use runtime::scheduler::{Scheduler, SchedulerConfig};
+use runtime::time::TimeHandler;
 use tokio::sync::RwLock;
 use tracing::{debug, error, info, warn};
 use tracing_subscriber::layer::SubscriberExt;
@@ -33,38 +52,51 @@ use tracing_subscriber::{fmt, EnvFilter};
 use twilight_cache_inmemory::{InMemoryCache, ResourceType};
 use twilight_gateway::Shard;
 use twilight_http::Client;
-use twilight_model::channel::message::ReactionType;
+use twilight_model::channel::message::AllowedMentions;
 use twilight_model::channel::Message;
 use twilight_model::gateway::event::Event;
 use twilight_model::gateway::{Intents, ShardId};
 use twilight_model::http::attachment::Attachment;
-use twilight_model::id::marker::GuildMarker;
+use twilight_model::id::marker::{ApplicationMarker, GuildMarker};
 use twilight_model::id::Id;
-use twilight_standby::Standby;
-
-use crate::environment::discord::DiscordInterruptHandler;
-use crate::environment::discord_ex::DiscordExInterruptHandler;
-use crate::environment::dump_performance::DumpPerformanceHandler;
-use crate::environment::halt::HaltHandler;
-use crate::environment::http::HttpHandler;
-use crate::environment::interrupt::IntHandler;
-use crate::environment::log::LogHandler;
-use crate::environment::object_storage::{ObjectStorage, ObjectStorageHandler};
-use crate::environment::png::PngHandler;
-use crate::environment::sipi::SipiHandler;
-use crate::environment::time::TimeHandler;
-use crate::execution_context::ExecutionContext;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-  tracing_subscriber::registry().with(fmt::layer()).with(EnvFilter::from_default_env()).init();
+  // Stdout is the terminal frontend
+  tracing_subscriber::registry()
+    .with(fmt::layer().with_writer(std::io::stderr))
+    .with(EnvFilter::from_default_env())
+    .init();
+
+  let egress_policy = Arc::new(EgressPolicy::from_env());
+  info!("http egress policy: {:?}", egress_policy);
+  let host = Arc::new(Host {
+    scheduler: Scheduler::start(SchedulerConfig::from_env()),
+    object_storage: Arc::new(ObjectStorage::from_env()),
+    egress_policy,
+    contexts: Arc::new(Contexts::new()),
+  });
+
+  if env::var("MIZU_PLATFORM").is_ok_and(|platform| platform == "terminal") {
+    let path = env::args().nth(1).ok_or("usage: MIZU_PLATFORM=terminal bot <program>")?;
+    return run_terminal(&path, host).await;
+  }
 
   let token = env::var("DISCORD_TOKEN")?;
   info!("starting...");
 
   // Specify intents requesting events about things like new and updated
   // messages in a guild and direct messages.
-  let intents = Intents::GUILD_MESSAGES | Intents::DIRECT_MESSAGES | Intents::MESSAGE_CONTENT | Intents::GUILD_MESSAGE_REACTIONS;
+  let mut intents = Intents::GUILDS
+    | Intents::GUILD_MESSAGES
+    | Intents::DIRECT_MESSAGES
+    | Intents::MESSAGE_CONTENT
+    | Intents::GUILD_MESSAGE_REACTIONS;
+  // Member events need the privileged intent to be enabled in the developer portal, the gateway closes with 4014
+  // otherwise
+  if env::var("MIZU_MEMBER_EVENTS").is_ok_and(|value| value == "1") {
+    intents |= Intents::GUILD_MEMBERS;
+  }
 
   // Create a single shard.
   let mut shard = Shard::new(ShardId::ONE, token.clone(), intents);
@@ -72,16 +104,17 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
   // The http client is separate from the gateway, so startup a new
   // one, also use Arc such that it can be cloned to other threads.
   let http = Arc::new(Client::new(token));
+  let application_id = http.current_user_application().await?.model().await?.id;
+  let platform = Arc::new(DiscordPlatform::new(http.clone()));
+  tokio::spawn(run_events(platform.clone(), host.contexts.clone()));
 
   // Since we only care about messages, make the cache only process messages.
   let cache = InMemoryCache::builder().resource_types(ResourceType::MESSAGE).build();
 
-  let standby = Arc::new(Standby::new());
+  let registry = Arc::new(ProgramRegistry::from_env());
 
-  let object_storage = Arc::new(ObjectStorage::new());
-  object_storage.put("amongus", "да я люблю сосать член".as_bytes());
-
-  let contexts = Arc::new(Contexts::new());
+  let compiler = Arc::new(CompileService::new(CompilerConfig::from_env())?);
+  tokio::spawn(compiler.clone().warm(guest_source("#[no_mangle]\npub unsafe fn main() {}", Language::Rust)));
 
   // Startup the event loop to process each event in the event stream as they
   // come in.
@@ -90,25 +123,39 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
       Ok(event) => event,
       Err(error) => {
         warn!(source = ?error, "error receiving event");
+        if error.is_fatal() {
+          // Also reached with close code 4014 when MIZU_MEMBER_EVENTS is set but the intent is not enabled
+          error!("gateway connection closed fatally: {:?}", shard.status());
+          return Err(error.into());
+        }
         continue;
       }
     };
 
     // Update the cache.
     cache.update(&event);
-    standby.process(&event);
 
     // Spawn a new task to handle the event
     tokio::spawn(handle_event(
       event,
       Arc::clone(&http),
-      Arc::clone(&standby),
-      Arc::clone(&object_storage),
-      Arc::clone(&contexts),
+      Arc::clone(&platform),
+      application_id,
+      Arc::clone(&host),
+      Arc::clone(&registry),
+      Arc::clone(&compiler),
     ));
   }
 }
 
+/// Services shared by the isolates of every guild.
+pub struct Host {
+  pub scheduler: Arc<Scheduler>,
+  pub object_storage: Arc<ObjectStorage>,
+  pub egress_policy: Arc<EgressPolicy>,
+  pub contexts: Arc<Contexts>,
+}
+
 pub struct Contexts {
   pub contexts: RwLock<HashMap<Id<GuildMarker>, Arc<ExecutionContext>>>,
 }
@@ -125,6 +172,11 @@ impl Contexts {
       contexts: RwLock::new(HashMap::new()),
     }
   }
+
+  pub async fn get_or_create(&self, guild_id: Id<GuildMarker>) -> Arc<ExecutionContext> {
+    let mut contexts = self.contexts.write().await;
+    contexts.entry(guild_id).or_insert_with(|| Arc::new(ExecutionContext::new())).clone()
+  }
 }
 
 pub const ENABLE_DISCORD_INTERRUPTS: bool = true;
@@ -132,241 +184,256 @@ pub const ENABLE_DISCORD_INTERRUPTS: bool = true;
 async fn handle_event(
   event: Event,
   http: Arc<Client>,
-  standby: Arc<Standby>,
-  object_storage: Arc<ObjectStorage>,
-  contexts: Arc<Contexts>,
+  platform: Arc<DiscordPlatform>,
+  application_id: Id<ApplicationMarker>,
+  host: Arc<Host>,
+  registry: Arc<ProgramRegistry>,
+  compiler: Arc<CompileService>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
   match event {
     Event::MessageCreate(msg) if msg.content.starts_with("!load") => {
-      let context = {
-        let mut contexts = contexts.contexts.write().await;
-        let context = contexts.entry(msg.guild_id.unwrap());
-        context.or_insert_with(|| Arc::new(ExecutionContext::new())).clone()
+      let Some(guild_id) = msg.guild_id else {
+        return Ok(());
       };
-      *context.http.lock().await = Some(http.clone());
-      *context.channel_id.lock().await = Some(msg.channel_id);
-
-      let code = fs::read("target/riscv64g-unknown-mizu-elf/debug/temp.bin").await?;
-      let bus = Arc::new(Bus::new(code));
-      let isolate = context.isolate.lock().await.insert(Isolate::new(bus)).clone();
-
-      // Initialize environment
-      {
-        let cpu = isolate.get_bootstrap_core();
-        let mut cpu = cpu.lock().await;
-        cpu.ivt.insert(
-          syscall::SYSCALL_DISCORD,
-          Arc::new(Box::new(DiscordInterruptHandler {
-            context: context.clone(),
-            guild_id: msg.guild_id.unwrap(),
-            standby: standby.clone(),
-          })),
-        );
-        cpu.ivt.insert(
-          syscall::SYSCALL_DISCORD_EX,
-          Arc::new(Box::new(DiscordExInterruptHandler { context: context.clone() })),
-        );
-        cpu.ivt.insert(
-          syscall::SYSCALL_PERF_DUMP,
-          Arc::new(Box::new(DumpPerformanceHandler { context: context.clone() })),
-        );
-        cpu
-          .ivt
-          .insert(syscall::SYSCALL_HTTP, Arc::new(Box::new(HttpHandler { context: context.clone() })));
-        cpu.ivt.insert(
-          syscall::SYSCALL_OBJECT_STORAGE,
-          Arc::new(Box::new(ObjectStorageHandler {
-            context: context.clone(),
-            object_storage: object_storage.clone(),
-          })),
-        );
-        cpu
-          .ivt
-          .insert(syscall::SYSCALL_LOG, Arc::new(Box::new(LogHandler { context: context.clone() })));
-        cpu.ivt.insert(syscall::SYSCALL_HALT, Arc::new(Box::new(HaltHandler {})));
-        cpu.ivt.insert(syscall::SYSCALL_TIME, Arc::new(Box::new(TimeHandler {})));
-        cpu
-          .ivt
-          .insert(syscall::SYSCALL_SIPI, Arc::new(Box::new(SipiHandler { context: context.clone() })));
-        cpu
-          .ivt
-          .insert(syscall::SYSCALL_INT, Arc::new(Box::new(IntHandler { context: context.clone() })));
-        cpu.ivt.insert(syscall::SYSCALL_PNG, Arc::new(Box::new(PngHandler {})));
-      }
 
-      context.run_core(isolate.get_bootstrap_core(), None).await?;
+      let image = match load_program(&msg, guild_id, &http, &registry).await {
+        Ok(image) => image,
+        Err(error) => {
+          http.create_message(msg.channel_id).content(&error.to_string())?.await?;
+          return Ok(());
+        }
+      };
+
+      let context = host.contexts.get_or_create(guild_id).await;
+      context.attach(platform.clone(), msg.channel_id, guild_id, Some(application_id)).await;
+      boot(context, image, guild_id, &host).await?;
     }
     Event::MessageCreate(msg) if msg.content.starts_with("!vm") => {
-      let code = msg.content.trim_start_matches("!vm").trim_start();
-      let code = code.trim_start_matches("```").trim_start_matches("rs\n").trim_end_matches("```").trim();
-      let code = format!(
-        r#"#![feature(lang_items)]
-#![feature(naked_functions)]
-#![allow(unused, internal_features)]
+      let Some(guild_id) = msg.guild_id else {
+        return Ok(());
+      };
 
-#![no_std]
-#![no_main]
+      let (language, code) = parse_vm(&msg.content);
+      let code = guest_source(code, language);
+      debug!("running {:?} code: {}", language, code);
 
-#[macro_use]
-extern crate alloc;
+      let context = host.contexts.get_or_create(guild_id).await;
+      context.attach(platform.clone(), msg.channel_id, guild_id, Some(application_id)).await;
 
-mod prelude;
+      let code = compile(&code, language, &msg, &http, &compiler).await?;
+      boot(context, Image::parse(&code)?, guild_id, &host).await?;
+    }
+    Event::MessageCreate(msg) if msg.content.starts_with("!caps") => {
+      let Some(guild_id) = msg.guild_id else {
+        return Ok(());
+      };
 
-use prelude::*;
+      let mut arguments = msg.content.split_whitespace().skip(1);
+      let command = arguments.next();
+      if command.is_some() && !admin::is_admin(&http, guild_id, msg.author.id).await? {
+        http
+          .create_message(msg.channel_id)
+          .content("only members with the Manage Server permission can change capabilities")?
+          .await?;
+        return Ok(());
+      }
 
-{}
-"#,
-        code
-      );
-      debug!("running code: {}", code);
-
-      let context = {
-        let mut contexts = contexts.contexts.write().await;
-        let context = contexts.entry(msg.guild_id.unwrap());
-        context.or_insert_with(|| Arc::new(ExecutionContext::new())).clone()
+      let context = host.contexts.get_or_create(guild_id).await;
+      let content = {
+        let mut capabilities = context.capabilities.lock().await;
+        let result = match command {
+          None => Ok(()),
+          Some("reset") => {
+            *capabilities = Capabilities::default();
+            Ok(())
+          }
+          Some(command @ ("allow" | "deny")) => arguments.try_for_each(|target| capabilities.set(target, command == "allow")),
+          Some(command) => Err(format!("unknown command `{}`, expected `allow`, `deny` or `reset`", command)),
+        };
+
+        if let Some(isolate) = context.isolate.lock().await.as_ref() {
+          isolate.bus.power.set_allow_bypass(capabilities.power_bypass);
+        }
+        match result {
+          Ok(()) => capabilities.describe(),
+          Err(error) => error,
+        }
+      };
+      http
+        .create_message(msg.channel_id)
+        .content(&content)?
+        .allowed_mentions(Some(&AllowedMentions::default()))
+        .await?;
+    }
+    Event::MessageCreate(msg) if msg.content.starts_with("!verbosity") => {
+      let Some(guild_id) = msg.guild_id else {
+        return Ok(());
+      };
+
+      let context = host.contexts.get_or_create(guild_id).await;
+      let content = match msg.content.split_whitespace().nth(1) {
+        None => format!("verbosity: `{:?}`", context.verbosity()),
+        Some(_) if !admin::is_admin(&http, guild_id, msg.author.id).await? => {
+          "only members with the Manage Server permission can change the verbosity".to_owned()
+        }
+        Some(value) => match value.parse::<Verbosity>() {
+          Ok(verbosity) => {
+            context.set_verbosity(verbosity);
+            format!("verbosity set to `{:?}`", verbosity)
+          }
+          Err(()) => "expected `quiet`, `normal` or `verbose`".to_owned(),
+        },
+      };
+      http.create_message(msg.channel_id).content(&content)?.await?;
+    }
+    Event::MessageCreate(msg) if msg.content.starts_with("!program") => {
+      let Some(guild_id) = msg.guild_id else {
+        return Ok(());
+      };
+
+      let mut arguments = msg.content.split_whitespace().skip(1);
+      let command = arguments.next().unwrap_or("list");
+      if command != "list" && !admin::is_admin(&http, guild_id, msg.author.id).await? {
+        http
+          .create_message(msg.channel_id)
+          .content("only members with the Manage Server permission can manage programs")?
+          .await?;
+        return Ok(());
+      }
+
+      let content = match manage_programs(command, arguments.next(), guild_id, &registry).await {
+        Ok(content) => content,
+        Err(error) => error.to_string(),
       };
-      *context.http.lock().await = Some(http.clone());
-      *context.channel_id.lock().await = Some(msg.channel_id);
-
-      let code = compile(&code, &msg, &http).await?;
-      let bus = Arc::new(Bus::new(code));
-      let isolate = context.isolate.lock().await.insert(Isolate::new(bus)).clone();
-
-      // Initialize environment
-      {
-        let cpu = isolate.get_bootstrap_core();
-        let mut cpu = cpu.lock().await;
-        cpu.ivt.insert(
-          syscall::SYSCALL_DISCORD,
-          Arc::new(Box::new(DiscordInterruptHandler {
-            context: context.clone(),
-            guild_id: msg.guild_id.unwrap(),
-            standby: standby.clone(),
-          })),
-        );
-        cpu.ivt.insert(
-          syscall::SYSCALL_DISCORD_EX,
-          Arc::new(Box::new(DiscordExInterruptHandler { context: context.clone() })),
-        );
-        cpu.ivt.insert(
-          syscall::SYSCALL_PERF_DUMP,
-          Arc::new(Box::new(DumpPerformanceHandler { context: context.clone() })),
-        );
-        cpu
-          .ivt
-          .insert(syscall::SYSCALL_HTTP, Arc::new(Box::new(HttpHandler { context: context.clone() })));
-        cpu.ivt.insert(
-          syscall::SYSCALL_OBJECT_STORAGE,
-          Arc::new(Box::new(ObjectStorageHandler {
-            context: context.clone(),
-            object_storage: object_storage.clone(),
-          })),
-        );
-        cpu
-          .ivt
-          .insert(syscall::SYSCALL_LOG, Arc::new(Box::new(LogHandler { context: context.clone() })));
-        cpu.ivt.insert(syscall::SYSCALL_HALT, Arc::new(Box::new(HaltHandler {})));
-        cpu.ivt.insert(syscall::SYSCALL_TIME, Arc::new(Box::new(TimeHandler {})));
-        cpu
-          .ivt
-          .insert(syscall::SYSCALL_SIPI, Arc::new(Box::new(SipiHandler { context: context.clone() })));
-        cpu
-          .ivt
-          .insert(syscall::SYSCALL_INT, Arc::new(Box::new(IntHandler { context: context.clone() })));
-        cpu.ivt.insert(syscall::SYSCALL_PNG, Arc::new(Box::new(PngHandler {})));
+      http.create_message(msg.channel_id).content(&content)?.await?;
+    }
+    Event::MessageCreate(msg) if admin::Command::parse(&msg.content).is_some() => {
+      let Some(guild_id) = msg.guild_id else {
+        return Ok(());
+      };
+      let command = admin::Command::parse(&msg.content).unwrap();
+
+      if !admin::is_admin(&http, guild_id, msg.author.id).await? {
+        http
+          .create_message(msg.channel_id)
+          .content("only members with the Manage Server permission can manage the isolate")?
+          .await?;
+        return Ok(());
+      }
+
+      let context = host.contexts.get_or_create(guild_id).await;
+      if command != admin::Command::Restart {
+        return admin::handle(command, &context, &http, msg.channel_id).await;
       }
 
-      context.run_core(isolate.get_bootstrap_core(), None).await?;
+      let Some(image) = context.image.lock().await.clone() else {
+        http.create_message(msg.channel_id).content("nothing to restart")?.await?;
+        return Ok(());
+      };
+      context.attach(platform.clone(), msg.channel_id, guild_id, Some(application_id)).await;
+      boot(context, image, guild_id, &host).await?;
     }
     Event::MessageCreate(msg) => {
       debug!("create message: {:?}", msg.id);
       if msg.author.bot || msg.content.len() > 1200 || msg.content.starts_with("//") {
         return Ok(());
       }
+      let Some(guild_id) = msg.guild_id else {
+        return Ok(());
+      };
 
-      if !ENABLE_DISCORD_INTERRUPTS {
+      publish(&platform, &host.contexts, guild_id, subscription::MESSAGE_CREATE, || {
+        DiscordExEventUnion::MessageCreate(event::message(&msg))
+      })
+      .await;
+    }
+    Event::MessageUpdate(update) => {
+      debug!("update message: {:?}", update.id);
+      if update.author.as_ref().is_some_and(|author| author.bot) {
         return Ok(());
       }
+      let Some(guild_id) = update.guild_id else {
+        return Ok(());
+      };
 
-      dispatch_interrupt(&contexts, msg.guild_id.unwrap(), |context, cpu| {
-        cpu.ivt.insert(
-          syscall::SYSCALL_DISCORD,
-          Arc::new(Box::new(DiscordInterruptHandler {
-            context: context.clone(),
-            guild_id: msg.guild_id.unwrap(),
-            standby: standby.clone(),
-          })),
-        );
-        cpu
-          .ivt
-          .insert(syscall::SYSCALL_DISCORD_EX, Arc::new(Box::new(DiscordExInterruptHandler { context })));
-
-        let data = IncomingMessage {
-          id: msg.id.get(),
-          channel_id: 0,
-          guild_id: None,
-          author: None,
-          content: msg.content.to_owned(),
-          attachments: vec![],
-          embeds: vec![],
-          timestamp: "".to_string(),
-          edited_timestamp: "".to_string(),
-          tts: false,
-          webhook_id: None,
-          mentions: vec![],
-          mention_everyone: false,
-          mentioned_roles: vec![],
-          r#type: 0,
-        };
-        let event = DiscordExEvent {
-          discord_ex_event_union: Some(DiscordExEventUnion::MessageCreate(data)),
-        };
-        event.encode_to_vec()
+      publish(&platform, &host.contexts, guild_id, subscription::MESSAGE_UPDATE, || {
+        DiscordExEventUnion::MessageUpdate(event::message_update(&update))
+      })
+      .await;
+    }
+    Event::MessageDelete(delete) => {
+      debug!("delete message: {:?}", delete.id);
+      let Some(guild_id) = delete.guild_id else {
+        return Ok(());
+      };
+
+      publish(&platform, &host.contexts, guild_id, subscription::MESSAGE_DELETE, || {
+        DiscordExEventUnion::MessageDelete(MessageDelete {
+          id: delete.id.get(),
+          channel_id: delete.channel_id.get(),
+          guild_id: Some(guild_id.get()),
+        })
       })
       .await;
     }
     Event::ReactionAdd(reaction) => {
       debug!("add reaction: {:?}", reaction);
+      let Some(guild_id) = reaction.guild_id else {
+        return Ok(());
+      };
 
-      if !ENABLE_DISCORD_INTERRUPTS {
+      publish(&platform, &host.contexts, guild_id, subscription::REACTION_ADD, || {
+        DiscordExEventUnion::ReactionCreate(event::reaction_create(&reaction))
+      })
+      .await;
+    }
+    Event::ReactionRemove(reaction) => {
+      debug!("remove reaction: {:?}", reaction);
+      let Some(guild_id) = reaction.guild_id else {
         return Ok(());
-      }
+      };
 
-      dispatch_interrupt(&contexts, reaction.guild_id.unwrap(), |context, cpu| {
-        cpu.ivt.insert(
-          syscall::SYSCALL_DISCORD,
-          Arc::new(Box::new(DiscordInterruptHandler {
-            context: context.clone(),
-            guild_id: reaction.guild_id.unwrap(),
-            standby: standby.clone(),
-          })),
-        );
-        cpu
-          .ivt
-          .insert(syscall::SYSCALL_DISCORD_EX, Arc::new(Box::new(DiscordExInterruptHandler { context })));
-
-        let data = ReactionCreate {
-          user_id: reaction.user_id.get(),
-          channel_id: reaction.channel_id.get(),
-          message_id: reaction.message_id.get(),
-          guild_id: reaction.guild_id.map(|id| id.get()),
-          emoji: match &reaction.emoji {
-            ReactionType::Custom { id, name, animated } => Some(Emoji {
-              id: Some(id.get()),
-              name: name.clone().unwrap(),
-              animated: *animated,
-            }),
-            ReactionType::Unicode { name } => Some(Emoji {
-              id: None,
-              name: name.clone(),
-              animated: false,
-            }),
-          },
-        };
-        let event = DiscordExEvent {
-          discord_ex_event_union: Some(DiscordExEventUnion::ReactionCreate(data)),
-        };
-        event.encode_to_vec()
+      publish(&platform, &host.contexts, guild_id, subscription::REACTION_REMOVE, || {
+        DiscordExEventUnion::ReactionRemove(event::reaction_remove(&reaction))
+      })
+      .await;
+    }
+    Event::MemberAdd(member) => {
+      debug!("add member: {:?}", member.user.id);
+      publish(&platform, &host.contexts, member.guild_id, subscription::MEMBER_ADD, || {
+        DiscordExEventUnion::MemberAdd(event::member_add(&member))
+      })
+      .await;
+    }
+    Event::MemberRemove(member) => {
+      debug!("remove member: {:?}", member.user.id);
+      publish(&platform, &host.contexts, member.guild_id, subscription::MEMBER_REMOVE, || {
+        DiscordExEventUnion::MemberRemove(MemberRemove {
+          guild_id: member.guild_id.get(),
+          user: Some(event::user(&member.user)),
+        })
+      })
+      .await;
+    }
+    Event::ThreadCreate(thread) => {
+      debug!("create thread: {:?}", thread.id);
+      let Some(guild_id) = thread.guild_id else {
+        return Ok(());
+      };
+
+      publish(&platform, &host.contexts, guild_id, subscription::THREAD_CREATE, || {
+        DiscordExEventUnion::ThreadCreate(event::thread(&thread))
+      })
+      .await;
+    }
+    Event::InteractionCreate(interaction) => {
+      debug!("create interaction: {:?}", interaction.id);
+      let Some(guild_id) = interaction.guild_id else {
+        return Ok(());
+      };
+
+      publish(&platform, &host.contexts, guild_id, subscription::INTERACTION_CREATE, || {
+        DiscordExEventUnion::InteractionCreate(event::interaction(&interaction))
       })
       .await;
     }
@@ -379,215 +446,425 @@ use prelude::*;
   Ok(())
 }
 
-#[derive(Error, Debug)]
-pub enum CompileError {
-  #[error("compilation failed")]
-  Error,
+/// Queues a gateway event, the event is only built if the isolate of the guild is subscribed to it or polls for it.
+async fn publish(
+  platform: &DiscordPlatform,
+  contexts: &Contexts,
+  guild_id: Id<GuildMarker>,
+  subscription: u64,
+  event: impl FnOnce() -> DiscordExEventUnion,
+) {
+  let Some(context) = contexts.contexts.read().await.get(&guild_id).cloned() else {
+    return;
+  };
+  if context.wants(subscription).await {
+    platform.publish(guild_id, subscription, event());
+  }
 }
 
-async fn compile(code: &str, msg: &Message, http: &Client) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
-  let code_filename = "temp/src/main.rs";
-  fs::write(code_filename, code).await?;
-  let (binary_filename, compile_error, success) = generate_rv_obj().await;
-  if !success {
-    let attachments = if compile_error.len() > 1600 {
-      vec![Attachment::from_bytes("error.log".to_owned(), compile_error.as_bytes().to_owned(), 1)]
-    } else {
-      vec![]
-    };
+/// Image for `!load [name[@version]]`: an attached program is published to the registry as a new version of `name`
+/// and installed, otherwise `name` or the installed program is read from the registry.
+async fn load_program(
+  msg: &Message,
+  guild_id: Id<GuildMarker>,
+  http: &Client,
+  registry: &ProgramRegistry,
+) -> Result<Image, Box<dyn Error + Send + Sync>> {
+  let reference = msg.content.split_whitespace().nth(1);
+  if (reference.is_some() || !msg.attachments.is_empty()) && !admin::is_admin(http, guild_id, msg.author.id).await? {
+    return Err("only members with the Manage Server permission can publish or install programs".into());
+  }
 
-    if compile_error.len() > 1800 {
-      http
-        .create_message(msg.channel_id)
-        .content("compilation failed")
-        .unwrap()
-        .attachments(&attachments)?
-        .await?;
-    } else {
-      http
-        .create_message(msg.channel_id)
-        .content(&format!("compilation failed: ```c\n{}```", compile_error))?
-        .await?;
-    }
-    return Err(Box::new(CompileError::Error));
+  if let Some(attachment) = msg.attachments.first() {
+    let name = reference.unwrap_or(DEFAULT_PROGRAM);
+    let data = registry.download(&attachment.url, attachment.size).await?;
+    let version = registry.publish(guild_id, name, &data).await?;
+    registry.install(guild_id, name, Some(version.version)).await?;
+    http
+      .create_message(msg.channel_id)
+      .content(&format!(
+        "published `{}@{}` (`{}` bytes, sha256 `{}`)",
+        name,
+        version.version,
+        version.size,
+        &version.hash[..16]
+      ))?
+      .await?;
+    return Ok(registry.read(guild_id, name, Some(version.version)).await?.1);
   }
 
-  let assembly = get_disassembled(&binary_filename).await;
-  let _assembly = Regex::new(r"(?m)^ffffffff80[0-9a-f]{6}")
-    .unwrap()
-    .replace_all(&assembly, |captures: &Captures| {
-      let address = u64::from_str_radix(captures.get(0).unwrap().as_str(), 16).unwrap();
-      let base_address = 0xffffffff80000000u64;
-      format!("${:04x}", address - base_address)
-    });
-  // http.create_message(msg.channel_id).content(&format!(
-  //   "compilation successful: ```mips\n{}```",
-  //   if assembly.len() > 1800 { "; too long" } else { &assembly }
-  // ))?.await?;
-  // debug!("{}", assembly);
-
-  generate_rv_binary(&binary_filename).await;
-  Ok(fs::read(format!("{}.bin", binary_filename)).await?)
+  match reference {
+    Some(reference) => {
+      let (name, version) = registry::parse_reference(reference)?;
+      let version = registry.install(guild_id, name, version).await?;
+      Ok(registry.read(guild_id, name, Some(version.version)).await?.1)
+    }
+    None => {
+      let installed = registry.installed(guild_id).await?.ok_or(registry::RegistryError::NotInstalled)?;
+      Ok(registry.read(guild_id, &installed.name, Some(installed.version)).await?.1)
+    }
+  }
 }
 
-async fn dispatch_interrupt(contexts: &Arc<Contexts>, guild_id: Id<GuildMarker>, block: impl FnOnce(Arc<ExecutionContext>, &mut Cpu) -> Vec<u8>) {
-  info!("dispatch int");
-  let context = {
-    let mut contexts = contexts.contexts.write().await;
-    let context = contexts.entry(guild_id);
-    context.or_insert_with(|| Arc::new(ExecutionContext::new())).clone()
+/// `!program list|install <name[@version]>|rollback|pin|unpin`.
+async fn manage_programs(
+  command: &str,
+  argument: Option<&str>,
+  guild_id: Id<GuildMarker>,
+  registry: &ProgramRegistry,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+  let content = match command {
+    "list" => {
+      let installed = registry.installed(guild_id).await?;
+      let mut content = String::new();
+      for name in registry.programs(guild_id).await? {
+        let versions = registry.versions(guild_id, &name).await?;
+        let versions = versions
+          .iter()
+          .rev()
+          .take(5)
+          .map(|version| {
+            let current = installed
+              .as_ref()
+              .is_some_and(|installed| installed.name == name && installed.version == version.version);
+            format!("{}{} `{}`", if current { "*" } else { "" }, version.version, &version.hash[..12])
+          })
+          .collect::<Vec<_>>();
+        content.push_str(&format!("`{}`: {}\n", name, versions.join(", ")));
+      }
+      match installed {
+        Some(installed) => content.push_str(&format!(
+          "installed: `{}@{}`{}",
+          installed.name,
+          installed.version,
+          if installed.pinned { " (pinned)" } else { "" }
+        )),
+        None if content.is_empty() => content.push_str("no programs, attach an elf or bin file to `!load [name]`"),
+        None => content.push_str("installed: none"),
+      }
+      content
+    }
+    "install" => {
+      let (name, version) = registry::parse_reference(argument.ok_or("expected `!program install <name[@version]>`")?)?;
+      let version = registry.install(guild_id, name, version).await?;
+      format!("installed `{}@{}`, `!load` to run it", name, version.version)
+    }
+    "rollback" => {
+      let installed = registry.rollback(guild_id).await?;
+      format!("rolled back to `{}@{}`, `!load` to run it", installed.name, installed.version)
+    }
+    "pin" | "unpin" => {
+      registry.pin(guild_id, command == "pin").await?;
+      format!("installed program {}", if command == "pin" { "pinned" } else { "unpinned" })
+    }
+    _ => format!("unknown command `{}`, expected `list`, `install`, `rollback`, `pin` or `unpin`", command),
   };
-  let isolate = context.isolate.lock().await;
-  let cpu = isolate.as_ref().unwrap().get_bootstrap_core();
-  let mut cpu = cpu.lock().await;
+  Ok(content)
+}
 
-  cpu.halt = false;
+/// Runs the program at `path` with the terminal as the chat frontend until the bootstrap core stops.
+async fn run_terminal(path: &str, host: Arc<Host>) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let image = Image::parse(&tokio::fs::read(path).await?)?;
+  let platform: Arc<dyn HostPlatform> = Arc::new(TerminalPlatform::new());
+  tokio::spawn(run_events(platform.clone(), host.contexts.clone()));
 
-  let data = block(context.clone(), &mut cpu);
-  cpu.bus.write(HARDWARE_BASE + 0x16000, &data).unwrap();
-  cpu.regs[10] = data.len() as u64;
-  cpu.regs[11] = HARDWARE_BASE + 0x16000;
+  let context = host.contexts.get_or_create(TERMINAL_GUILD).await;
+  context.attach(platform, TERMINAL_CHANNEL, TERMINAL_GUILD, None).await;
+  boot(context, image, TERMINAL_GUILD, &host).await
+}
 
-  info!("dispatching interrupt");
-  cpu.apic.dispatch(Interrupt::PlatformDefined17, INTERRUPT_PRIORITY_NORMAL);
-  info!("resetting wfi");
-  cpu.wfi.set(false);
+/// Loads `image` into a new isolate of `context`, stopping the previous one, and runs the bootstrap core until it
+/// stops.
+async fn boot(
+  context: Arc<ExecutionContext>,
+  image: Image,
+  guild_id: Id<GuildMarker>,
+  host: &Host,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let bus = Arc::new(Bus::new(image.code.clone()));
+  bus.set_memory_limit(memory_limit_from_env());
+  bus.power.set_swap_directory(swap_directory_from_env());
+  bus.power.set_allow_bypass(context.capabilities.lock().await.power_bypass);
+  if let Ok(directory) = env::var("MIZU_RECORD_DIR") {
+    record(&bus, Path::new(&directory), guild_id);
+  }
+  let isolate = Isolate::new(bus);
+  isolate.get_bootstrap_core().lock().await.pc = image.entry;
+  *context.image.lock().await = Some(image);
+  let previous = context.isolate.lock().await.replace(isolate.clone());
+  if let Some(previous) = previous {
+    previous.stop().await;
+  }
 
-  let isolate = cpu.isolate.as_ref().unwrap().upgrade().unwrap();
-  isolate.wake();
-}
+  // Initialize environment
+  {
+    let cpu = isolate.get_bootstrap_core();
+    let mut cpu = cpu.lock().await;
+    cpu.ivt.insert(
+      syscall::SYSCALL_DISCORD,
+      Arc::new(Box::new(DiscordInterruptHandler { context: context.clone() })),
+    );
+    cpu.ivt.insert(
+      syscall::SYSCALL_DISCORD_EX,
+      Arc::new(Box::new(DiscordExInterruptHandler { context: context.clone() })),
+    );
+    cpu.ivt.insert(
+      syscall::SYSCALL_PERF_DUMP,
+      Arc::new(Box::new(DumpPerformanceHandler { context: context.clone() })),
+    );
+    cpu.ivt.insert(
+      syscall::SYSCALL_HTTP,
+      Arc::new(Box::new(HttpHandler {
+        context: context.clone(),
+        policy: host.egress_policy.clone(),
+      })),
+    );
+    cpu.ivt.insert(
+      syscall::SYSCALL_OBJECT_STORAGE,
+      Arc::new(Box::new(ObjectStorageHandler {
+        object_storage: host.object_storage.clone(),
+        namespace: guild_id.get(),
+      })),
+    );
+    cpu
+      .ivt
+      .insert(syscall::SYSCALL_LOG, Arc::new(Box::new(LogHandler { context: context.clone() })));
+    cpu.ivt.insert(syscall::SYSCALL_HALT, Arc::new(Box::new(HaltHandler {})));
+    cpu.ivt.insert(syscall::SYSCALL_TIME, Arc::new(Box::new(TimeHandler {})));
+    cpu.ivt.insert(
+      syscall::SYSCALL_SIPI,
+      Arc::new(Box::new(SipiHandler {
+        context: context.clone(),
+        egress_policy: host.egress_policy.clone(),
+      })),
+    );
+    cpu
+      .ivt
+      .insert(syscall::SYSCALL_INT, Arc::new(Box::new(IntHandler { context: context.clone() })));
+    cpu.ivt.insert(syscall::SYSCALL_PNG, Arc::new(Box::new(PngHandler {})));
+    cpu.ivt.insert(syscall::SYSCALL_ASYNC, Arc::new(Box::new(AsyncCallHandler::new())));
+    cpu.ivt.insert(syscall::SYSCALL_RING, Arc::new(Box::new(RingHandler::new())));
+    cpu.ivt.insert(syscall::SYSCALL_MEMORY, Arc::new(Box::new(MemoryHandler {})));
+    cpu.ivt.insert(syscall::SYSCALL_HART, Arc::new(Box::new(HartHandler {})));
+    cpu.ivt.insert(syscall::SYSCALL_MAILBOX, Arc::new(Box::new(MailboxHandler {})));
+  }
 
-#[async_trait]
-pub trait CpuExt {
-  async fn run_tick(&mut self) -> Result<TickResult, Box<dyn Error + Send + Sync>>;
+  let hart = context.run_core(&host.scheduler, &isolate, isolate.get_bootstrap_core()).await;
+  hart.join().await;
+  Ok(())
 }
 
-pub enum TickResult {
-  Continue,
-  Exception(Exception),
-  Eof,
-  Halt,
-  TimeLimit,
-  WaitForInterrupt,
-  Explosion,
+/// Records the execution into `<directory>/<guild>-<timestamp>.mizurec`, to be replayed with `mizu --replay`.
+fn record(bus: &Bus, directory: &Path, guild_id: Id<GuildMarker>) {
+  let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+  let path = directory.join(format!("{}-{}.mizurec", guild_id, timestamp));
+  let session = fs::create_dir_all(directory).map_err(ReplayError::from).and_then(|_| Session::record(&path, record_limit_from_env()));
+  match session {
+    Ok(session) => {
+      bus.attach_session(Arc::new(session));
+      info!("recording execution to {}", path.display());
+    }
+    Err(error) => warn!("failed to record execution to {}: {}", path.display(), error),
+  }
 }
 
-#[async_trait]
-impl CpuExt for Cpu {
-  async fn run_tick(&mut self) -> Result<TickResult, Box<dyn Error + Send + Sync>> {
-    if let Some(isolate) = self.isolate.as_ref().map(|isolate| isolate.upgrade()).flatten() {
-      if isolate.exploded.load(Ordering::Acquire) {
-        return Ok(TickResult::Explosion);
-      }
+/// Splits a `!vm [c|asm]` message into the language and the code, which may be in a code block. The language of the
+/// code block takes precedence, Rust is the default.
+fn parse_vm(content: &str) -> (Language, &str) {
+  let mut code = content.trim_start_matches("!vm").trim_start();
+  let mut language = Language::Rust;
+
+  if let Some((tag, rest)) = code.split_once(char::is_whitespace) {
+    if let Some(tag) = Language::from_tag(tag) {
+      language = tag;
+      code = rest.trim_start();
     }
+  }
 
-    if self.wfi.get() {
-      return Ok(TickResult::WaitForInterrupt);
+  if let Some(block) = code.strip_prefix("```") {
+    let (tag, body) = block.split_once('\n').unwrap_or(("", block));
+    if let Some(tag) = Language::from_tag(tag.trim()) {
+      language = tag;
+      code = body;
+    } else if tag.trim().is_empty() {
+      code = body;
+    } else {
+      code = block;
     }
+  }
 
-    let inst = match self.fetch() {
-      Ok(inst) => inst,
-      Err(exception) => {
-        self.handle_exception(exception);
-        if matches!(&exception, Exception::InstructionAccessFault(0)) {
-          return Ok(TickResult::Eof);
-        }
-        if exception.is_fatal() {
-          error!("fetch failed: {:?}", exception);
-          return Ok(TickResult::Exception(exception));
-        }
+  (language, code.trim_end_matches("```").trim())
+}
 
-        return Ok(TickResult::Exception(exception));
-      }
-    };
+/// Wraps the code of a `!vm` message: Rust into the `main.rs` of the guest crate, C gets the `hal-c` headers and
+/// assembly is expected to define `main`.
+fn guest_source(code: &str, language: Language) -> String {
+  match language {
+    Language::Rust => format!(
+      r#"#![feature(lang_items)]
+#![feature(naked_functions)]
+#![allow(unused, internal_features)]
 
-    match self.execute(inst).await {
-      Ok(new_pc) => self.pc = new_pc,
-      Err(exception) => {
-        if let Exception::Explosion(_) = exception {
-          error!("execute failed: {:?}", exception);
-          return Ok(TickResult::Exception(exception));
-        }
+#![no_std]
+#![no_main]
 
-        self.handle_exception(exception);
-        if exception.is_fatal() {
-          error!("execute failed: {:?}", exception);
-          return Ok(TickResult::Exception(exception));
-        }
+#[macro_use]
+extern crate alloc;
 
-        return Ok(TickResult::Exception(exception));
-      }
-    };
+mod prelude;
 
-    self.perf.instructions_retired.fetch_add(1, Ordering::AcqRel);
-    if self.halt {
-      return Ok(TickResult::Halt);
-    }
+use prelude::*;
 
-    if self.csr.load(csr::machine::POWERSTATE) == 1 && *self.perf.cpu_time.lock().unwrap() > CPU_TIME_LIMIT {
-      error!(
-        "running too long without yield: {:?} > {:?}",
-        self.perf.cpu_time.lock().unwrap(),
-        CPU_TIME_LIMIT
-      );
-      return Ok(TickResult::TimeLimit);
-    }
+{}
+"#,
+      code
+    ),
+    Language::C => format!(
+      r#"#include "hal.h"
+#include "discord.h"
+#include "http.h"
+#include "object_storage.h"
+#include "async.h"
+#include "ring.h"
+
+{}
+"#,
+      code
+    ),
+    Language::Asm => format!("  .text\n  .globl main\n{}\n", code),
+  }
+}
 
-    // if self.csr.load(MCAUSE) == 0 {
-    //   error!("exited from trap");
-    //   self.saved_regs.fill(0);
-    //   return Ok(TickResult::Continue); // Exited from trap
-    // }
+/// Compiles guest code to an ELF executable, reporting failures to the channel.
+async fn compile(
+  code: &str,
+  language: Language,
+  msg: &Message,
+  http: &Client,
+  compiler: &CompileService,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+  let error = match compiler.compile(code, language).await {
+    Ok(elf) => return Ok(elf),
+    Err(error) => error,
+  };
 
-    if let Some(interrupt) = self.check_pending_interrupt() {
-      self.handle_interrupt(interrupt);
+  match &error {
+    CompileError::Failed(compile_error) if compile_error.len() > 1800 => {
+      let attachments = [Attachment::from_bytes("error.log".to_owned(), compile_error.as_bytes().to_owned(), 1)];
+      http
+        .create_message(msg.channel_id)
+        .content("compilation failed")?
+        .attachments(&attachments)?
+        .await?;
+    }
+    CompileError::Failed(compile_error) => {
+      http
+        .create_message(msg.channel_id)
+        .content(&format!("compilation failed: ```c\n{}```", compile_error))?
+        .await?;
+    }
+    error => {
+      http.create_message(msg.channel_id).content(&error.to_string())?.await?;
     }
+  }
+  Err(Box::new(error))
+}
 
-    Ok(TickResult::Continue)
+/// Delivers the events of a frontend to the isolates, each one in its own task.
+async fn run_events(platform: Arc<dyn HostPlatform>, contexts: Arc<Contexts>) {
+  while let Some(event) = platform.next_event().await {
+    let contexts = contexts.clone();
+    tokio::spawn(async move { dispatch_event(&contexts, event).await });
   }
+  info!("{} frontend closed", platform.name());
 }
 
-async fn generate_rv_obj() -> (String, String, bool) {
-  let output = Command::new("cargo")
-    .env("CC", "/usr/bin/clang")
-    .env("CXX", "/usr/bin/clang++")
-    .current_dir("temp")
-    .args(["+nightly", "build"])
-    .output()
-    .await
-    .expect("Failed to generate rv object");
-  let stderr = String::from_utf8_lossy(&output.stderr);
-  println!("{}", stderr);
-  (
-    "target/riscv64g-unknown-mizu-elf/debug/temp".to_owned(),
-    stderr.to_string(),
-    output.status.success(),
-  )
+/// Delivers an event to the isolate of the guild if it is subscribed to it, new messages also to a pending
+/// `POLL_MESSAGE`.
+async fn dispatch_event(contexts: &Arc<Contexts>, event: HostEvent) {
+  let HostEvent {
+    guild_id,
+    subscription,
+    event,
+  } = event;
+  if let DiscordExEventUnion::MessageCreate(message) = &event {
+    if let Some(context) = contexts.contexts.read().await.get(&guild_id) {
+      // Fails when nobody polls
+      let _ = context.messages.send(message.clone());
+    }
+  }
+  if !ENABLE_DISCORD_INTERRUPTS {
+    return;
+  }
+
+  dispatch_interrupt(contexts, guild_id, subscription, move |context, cpu| {
+    cpu.ivt.insert(
+      syscall::SYSCALL_DISCORD,
+      Arc::new(Box::new(DiscordInterruptHandler { context: context.clone() })),
+    );
+    cpu
+      .ivt
+      .insert(syscall::SYSCALL_DISCORD_EX, Arc::new(Box::new(DiscordExInterruptHandler { context })));
+
+    let event = DiscordExEvent {
+      discord_ex_event_union: Some(event),
+    };
+    event.encode_to_vec()
+  })
+  .await;
 }
 
-async fn generate_rv_binary(obj: &str) {
-  let objcopy = "llvm-objcopy";
-  let output = Command::new(objcopy)
-    .args(["-O", "binary", obj, &format!("{}.bin", obj.to_owned())])
-    .output()
-    .await
-    .expect("Failed to generate rv binary");
-  println!("{}", String::from_utf8_lossy(&output.stderr));
+async fn dispatch_interrupt(
+  contexts: &Arc<Contexts>,
+  guild_id: Id<GuildMarker>,
+  subscription: u64,
+  block: impl FnOnce(Arc<ExecutionContext>, &mut Cpu) -> Vec<u8> + Send + 'static,
+) {
+  let Some(context) = contexts.contexts.read().await.get(&guild_id).cloned() else {
+    return;
+  };
+  if !context.is_subscribed(subscription).await {
+    debug!("not subscribed to {:#x}", subscription);
+    return;
+  }
+
+  let isolate = context.isolate.lock().await;
+  let Some(isolate) = isolate.as_ref() else {
+    return;
+  };
+  info!("dispatching interrupt");
+  let context = context.clone();
+  isolate.post(
+    0,
+    Box::new(move |cpu| {
+      let data = block(context, cpu);
+      cpu.raise_event(HARDWARE_BASE + 0x16000, &data);
+    }),
+  );
 }
 
-async fn get_disassembled(obj: &str) -> String {
-  let objcopy = "riscv64-unknown-elf-objdump";
-  let output = Command::new(objcopy)
-    .args([
-      "--disassemble=_start",
-      "--no-show-raw-insn",
-      // "--visualize-jumps",
-      // "--source",
-      "-C",
-      obj,
-    ])
-    .output()
-    .await
-    .expect("Failed to disassemble rv binary");
-  return String::from_utf8_lossy(&output.stdout).to_string();
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_vm_commands() {
+    assert_eq!(parse_vm("!vm fn main() {}"), (Language::Rust, "fn main() {}"));
+    assert_eq!(parse_vm("!vm c int main() {}"), (Language::C, "int main() {}"));
+    assert_eq!(parse_vm("!vm asm main: ret"), (Language::Asm, "main: ret"));
+    assert_eq!(parse_vm("!vm\n```\nfn main() {}\n```"), (Language::Rust, "fn main() {}"));
+    assert_eq!(parse_vm("!vm ```c\nint main() {}\n```"), (Language::C, "int main() {}"));
+  }
+
+  #[test]
+  fn prefers_the_language_of_the_code_block() {
+    assert_eq!(parse_vm("!vm c ```s\nmain: ret\n```"), (Language::Asm, "main: ret"));
+    assert_eq!(parse_vm("!vm rust\n```\nfn main() {}\n```"), (Language::Rust, "fn main() {}"));
+  }
+
+  #[test]
+  fn keeps_unknown_tags_as_code() {
+    assert_eq!(parse_vm("!vm python print()"), (Language::Rust, "python print()"));
+    assert_eq!(parse_vm("!vm ```fn main() {}```"), (Language::Rust, "fn main() {}"));
+  }
 }
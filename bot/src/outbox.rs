@@ -0,0 +1,270 @@
+use std::collections::VecDeque;
+use std::env;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::sync::Notify;
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::{debug, warn};
+use twilight_model::http::attachment::Attachment;
+use twilight_model::id::marker::ChannelMarker;
+use twilight_model::id::Id;
+
+use crate::platform::{HostPlatform, OutgoingMessage};
+
+/// Longer batches are sent as a file attachment.
+const MESSAGE_LIMIT: usize = 1900;
+/// How long log lines are collected before the first message of a batch is sent.
+const COALESCE_DELAY: Duration = Duration::from_millis(500);
+/// Guests writing more than this before it is flushed are blocked.
+const MAX_PENDING_BYTES: usize = 64 * 1024;
+/// How long a blocked guest waits before the call fails with [OutboxError::Busy].
+const BACKPRESSURE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of recent lines kept for `!logs`.
+const HISTORY_LINES: usize = 200;
+
+#[derive(Debug, Copy, Clone)]
+pub struct OutboxPolicy {
+  /// Maximum number of messages a single isolate may send.
+  pub budget: u64,
+  /// Number of messages that may be sent at once.
+  pub burst: u32,
+  /// Time after which another message may be sent.
+  pub interval: Duration,
+}
+
+impl Default for OutboxPolicy {
+  fn default() -> Self {
+    Self {
+      budget: 200,
+      burst: 4,
+      interval: Duration::from_secs(2),
+    }
+  }
+}
+
+impl OutboxPolicy {
+  /// Reads the policy from `MIZU_MESSAGE_*` environment variables, falling back to [OutboxPolicy::default].
+  pub fn from_env() -> Self {
+    let mut policy = Self::default();
+    if let Some(value) = env::var("MIZU_MESSAGE_BUDGET").ok().and_then(|value| value.parse().ok()) {
+      policy.budget = value;
+    }
+    if let Some(value) = env::var("MIZU_MESSAGE_BURST").ok().and_then(|value| value.parse::<u32>().ok()) {
+      policy.burst = value.max(1);
+    }
+    if let Some(value) = env::var("MIZU_MESSAGE_INTERVAL_MS").ok().and_then(|value| value.parse().ok()) {
+      policy.interval = Duration::from_millis(value);
+    }
+    policy
+  }
+}
+
+#[derive(Error, Debug)]
+pub enum OutboxError {
+  #[error("outbox is busy")]
+  Busy,
+  #[error("message budget of {0} messages exhausted")]
+  BudgetExhausted(u64),
+}
+
+struct State {
+  lines: Vec<String>,
+  bytes: usize,
+  /// Whether a flush task is running.
+  scheduled: bool,
+  target: Option<(Arc<dyn HostPlatform>, Id<ChannelMarker>)>,
+  /// Messages sent by the current isolate.
+  sent: u64,
+  tokens: u32,
+  next_refill: Instant,
+  /// Recent lines, including the ones that were dropped or never sent.
+  history: VecDeque<String>,
+}
+
+impl State {
+  fn record(&mut self, line: String) {
+    if self.history.len() == HISTORY_LINES {
+      self.history.pop_front();
+    }
+    self.history.push_back(line);
+  }
+}
+
+/// Per-guild outbound message queue.
+///
+/// Log lines are coalesced into as few messages as possible, all messages (including the ones created by the guest)
+/// go through a token bucket and count against the message budget of the isolate. When the queue is full or the
+/// bucket is empty the caller is blocked for up to [BACKPRESSURE_TIMEOUT].
+pub struct Outbox {
+  policy: OutboxPolicy,
+  state: Mutex<State>,
+  flushed: Notify,
+}
+
+impl Outbox {
+  pub fn new(policy: OutboxPolicy) -> Self {
+    Self {
+      policy,
+      state: Mutex::new(State {
+        lines: Vec::new(),
+        bytes: 0,
+        scheduled: false,
+        target: None,
+        sent: 0,
+        tokens: policy.burst,
+        next_refill: Instant::now() + policy.interval,
+        history: VecDeque::new(),
+      }),
+      flushed: Notify::new(),
+    }
+  }
+
+  /// Resets the budget and drops pending log lines, called when a new isolate is loaded.
+  pub fn reset(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.lines.clear();
+    state.bytes = 0;
+    state.sent = 0;
+    state.history.clear();
+    drop(state);
+    self.flushed.notify_waiters();
+  }
+
+  /// Takes a message from the budget, waiting for the rate limit until `deadline`.
+  async fn take(&self, deadline: Option<Instant>) -> Result<(), OutboxError> {
+    loop {
+      let next_refill = {
+        let mut state = self.state.lock().unwrap();
+        if state.sent >= self.policy.budget {
+          return Err(OutboxError::BudgetExhausted(self.policy.budget));
+        }
+
+        let now = Instant::now();
+        while state.next_refill <= now && state.tokens < self.policy.burst {
+          state.tokens += 1;
+          state.next_refill += self.policy.interval;
+        }
+        if state.tokens == self.policy.burst {
+          state.next_refill = now + self.policy.interval;
+        }
+
+        if state.tokens > 0 {
+          state.tokens -= 1;
+          state.sent += 1;
+          return Ok(());
+        }
+        state.next_refill
+      };
+
+      if deadline.is_some_and(|deadline| next_refill > deadline) {
+        return Err(OutboxError::Busy);
+      }
+      sleep_until(next_refill).await;
+    }
+  }
+
+  /// Waits until a message may be sent.
+  pub async fn acquire(&self) -> Result<(), OutboxError> {
+    self.take(Some(Instant::now() + BACKPRESSURE_TIMEOUT)).await
+  }
+
+  /// Keeps a line in the history without sending it.
+  pub fn record(&self, line: String) {
+    self.state.lock().unwrap().record(line);
+  }
+
+  /// Returns up to `count` most recent lines, oldest first.
+  pub fn history(&self, count: usize) -> Vec<String> {
+    let state = self.state.lock().unwrap();
+    state.history.iter().skip(state.history.len().saturating_sub(count)).cloned().collect()
+  }
+
+  /// Queues a log line to be sent to `channel_id`.
+  pub async fn log(self: &Arc<Self>, platform: Arc<dyn HostPlatform>, channel_id: Id<ChannelMarker>, line: String) -> Result<(), OutboxError> {
+    let deadline = Instant::now() + BACKPRESSURE_TIMEOUT;
+    loop {
+      let flushed = self.flushed.notified();
+      {
+        let mut state = self.state.lock().unwrap();
+        if state.sent >= self.policy.budget {
+          state.record(line);
+          return Err(OutboxError::BudgetExhausted(self.policy.budget));
+        }
+
+        if state.bytes == 0 || state.bytes + line.len() <= MAX_PENDING_BYTES {
+          state.bytes += line.len() + 1;
+          state.record(line.clone());
+          state.lines.push(line);
+          state.target = Some((platform, channel_id));
+          if !state.scheduled {
+            state.scheduled = true;
+            tokio::spawn(self.clone().flush());
+          }
+          return Ok(());
+        }
+      }
+
+      debug!("outbox full, waiting for flush");
+      tokio::select! {
+        _ = flushed => {}
+        _ = sleep_until(deadline) => {
+          self.record(line);
+          return Err(OutboxError::Busy);
+        }
+      }
+    }
+  }
+
+  async fn flush(self: Arc<Self>) {
+    sleep(COALESCE_DELAY).await;
+    loop {
+      let result = self.take(None).await;
+
+      let (lines, target) = {
+        let mut state = self.state.lock().unwrap();
+        state.bytes = 0;
+        (std::mem::take(&mut state.lines), state.target.clone())
+      };
+      self.flushed.notify_waiters();
+
+      match (result, target) {
+        (Ok(()), Some((platform, channel_id))) if !lines.is_empty() => {
+          if let Err(error) = send(platform.as_ref(), channel_id, &lines).await {
+            warn!("failed to send log message: {}", error);
+          }
+        }
+        (Err(error), _) => warn!("dropping {} log lines: {}", lines.len(), error),
+        _ => {}
+      }
+
+      let done = {
+        let mut state = self.state.lock().unwrap();
+        state.scheduled = !state.lines.is_empty();
+        !state.scheduled
+      };
+      if done {
+        break;
+      }
+    }
+  }
+}
+
+async fn send(platform: &dyn HostPlatform, channel_id: Id<ChannelMarker>, lines: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let text = lines.join("\n");
+  // Keep guest output from closing the code block
+  let escaped = text.replace("```", "`\u{200b}``");
+
+  let message = if escaped.len() <= MESSAGE_LIMIT {
+    OutgoingMessage::text(format!("```\n{}```", escaped))
+  } else {
+    OutgoingMessage {
+      attachments: vec![Attachment::from_bytes("log.txt".to_owned(), text.into_bytes(), 1)],
+      ..OutgoingMessage::text(format!("{} log lines", lines.len()))
+    }
+  };
+  platform.send_message(channel_id, message).await?;
+  Ok(())
+}
@@ -0,0 +1,110 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use flume::{Receiver, Sender};
+use mizu_hal_discord::discord::discord_ex_event::DiscordExEventUnion;
+use twilight_http::Client;
+use twilight_model::channel::message::AllowedMentions;
+use twilight_model::channel::Message;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker, MessageMarker, UserMarker};
+use twilight_model::id::Id;
+
+use crate::event;
+use crate::platform::{HostEvent, HostPlatform, OutgoingMessage, PlatformError, PlatformUser, SentMessage};
+
+/// Discord over the REST API, events are published by the gateway loop.
+pub struct DiscordPlatform {
+  pub http: Arc<Client>,
+  events: (Sender<HostEvent>, Receiver<HostEvent>),
+}
+
+impl DiscordPlatform {
+  pub fn new(http: Arc<Client>) -> Self {
+    Self {
+      http,
+      events: flume::unbounded(),
+    }
+  }
+
+  /// Queues a gateway event for [HostPlatform::next_event].
+  pub fn publish(&self, guild_id: Id<GuildMarker>, subscription: u64, event: DiscordExEventUnion) {
+    // The receiver is owned by the platform, so this never fails
+    let _ = self.events.0.send(HostEvent {
+      guild_id,
+      subscription,
+      event,
+    });
+  }
+}
+
+fn sent(message: Message) -> SentMessage {
+  SentMessage {
+    id: message.id,
+    channel_id: message.channel_id,
+    author_id: message.author.id,
+    content: message.content,
+  }
+}
+
+#[async_trait]
+impl HostPlatform for DiscordPlatform {
+  fn name(&self) -> &'static str {
+    "discord"
+  }
+
+  async fn send_message(&self, channel_id: Id<ChannelMarker>, message: OutgoingMessage) -> Result<SentMessage, PlatformError> {
+    let mut builder = self.http.create_message(channel_id).allowed_mentions(message.allowed_mentions.as_ref());
+    if let Some(content) = message.content.as_deref() {
+      builder = builder.content(content).map_err(PlatformError::validation)?;
+    }
+    if let Some(reply) = message.reply {
+      builder = builder.reply(reply);
+    }
+    if let Some(flags) = message.flags {
+      builder = builder.flags(flags);
+    }
+    if !message.stickers.is_empty() {
+      builder = builder.sticker_ids(&message.stickers).map_err(PlatformError::validation)?;
+    }
+    if !message.attachments.is_empty() {
+      builder = builder.attachments(&message.attachments).map_err(PlatformError::validation)?;
+    }
+
+    Ok(sent(builder.await?.model().await?))
+  }
+
+  async fn edit_message(
+    &self,
+    channel_id: Id<ChannelMarker>,
+    message_id: Id<MessageMarker>,
+    content: Option<&str>,
+    allowed_mentions: Option<AllowedMentions>,
+  ) -> Result<SentMessage, PlatformError> {
+    let message = self
+      .http
+      .update_message(channel_id, message_id)
+      .allowed_mentions(allowed_mentions.as_ref())
+      .content(content)
+      .map_err(PlatformError::validation)?
+      .await?
+      .model()
+      .await?;
+    Ok(sent(message))
+  }
+
+  async fn user(&self, user_id: Id<UserMarker>) -> Result<PlatformUser, PlatformError> {
+    let user = self.http.user(user_id).await?.model().await?;
+    Ok(PlatformUser {
+      user: event::user(&user),
+      global_name: user.global_name,
+    })
+  }
+
+  async fn next_event(&self) -> Option<HostEvent> {
+    self.events.1.recv_async().await.ok()
+  }
+
+  fn discord(&self) -> Option<Arc<Client>> {
+    Some(self.http.clone())
+  }
+}
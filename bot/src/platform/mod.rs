@@ -0,0 +1,104 @@
+//! Chat frontends the host can run on.
+//!
+//! A frontend sends and edits messages, looks up users and delivers events for the guests. The guest-visible model is
+//! the one of Discord, the other frontends map onto it.
+
+pub mod discord;
+pub mod terminal;
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use mizu_hal_discord::discord::discord_ex_event::DiscordExEventUnion;
+use thiserror::Error;
+use twilight_http::response::DeserializeBodyError;
+use twilight_http::Client;
+use twilight_model::channel::message::{AllowedMentions, MessageFlags};
+use twilight_model::http::attachment::Attachment;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker, MessageMarker, StickerMarker, UserMarker};
+use twilight_model::id::Id;
+
+#[derive(Error, Debug)]
+pub enum PlatformError {
+  #[error("{0}")]
+  Http(#[from] twilight_http::Error),
+  #[error("malformed response: {0}")]
+  Deserialize(#[from] DeserializeBodyError),
+  #[error("invalid message: {0}")]
+  Validation(String),
+  #[error("not found")]
+  NotFound,
+}
+
+impl PlatformError {
+  pub fn validation(error: impl ToString) -> Self {
+    PlatformError::Validation(error.to_string())
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OutgoingMessage {
+  pub content: Option<String>,
+  pub reply: Option<Id<MessageMarker>>,
+  pub attachments: Vec<Attachment>,
+  pub flags: Option<MessageFlags>,
+  pub stickers: Vec<Id<StickerMarker>>,
+  /// Defaults to the mentions allowed by the frontend.
+  pub allowed_mentions: Option<AllowedMentions>,
+}
+
+impl OutgoingMessage {
+  pub fn text(content: impl Into<String>) -> Self {
+    Self {
+      content: Some(content.into()),
+      ..Default::default()
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+  pub id: Id<MessageMarker>,
+  pub channel_id: Id<ChannelMarker>,
+  pub author_id: Id<UserMarker>,
+  pub content: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlatformUser {
+  pub user: mizu_hal_discord::discord::User,
+  pub global_name: Option<String>,
+}
+
+/// An event for the isolate of `guild_id`, delivered if it is subscribed to `subscription`.
+#[derive(Debug, Clone)]
+pub struct HostEvent {
+  pub guild_id: Id<GuildMarker>,
+  pub subscription: u64,
+  pub event: DiscordExEventUnion,
+}
+
+#[async_trait]
+pub trait HostPlatform: Send + Sync {
+  fn name(&self) -> &'static str;
+
+  async fn send_message(&self, channel_id: Id<ChannelMarker>, message: OutgoingMessage) -> Result<SentMessage, PlatformError>;
+
+  async fn edit_message(
+    &self,
+    channel_id: Id<ChannelMarker>,
+    message_id: Id<MessageMarker>,
+    content: Option<&str>,
+    allowed_mentions: Option<AllowedMentions>,
+  ) -> Result<SentMessage, PlatformError>;
+
+  async fn user(&self, user_id: Id<UserMarker>) -> Result<PlatformUser, PlatformError>;
+
+  /// Waits for the next event, `None` once the frontend is closed.
+  async fn next_event(&self) -> Option<HostEvent>;
+
+  /// REST client for the calls that only exist on Discord.
+  fn discord(&self) -> Option<Arc<Client>> {
+    None
+  }
+}
@@ -0,0 +1,162 @@
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use flume::Receiver;
+use mizu_hal_discord::discord;
+use mizu_hal_discord::discord::discord_ex_event::DiscordExEventUnion;
+use mizu_hal_types::discord::subscription;
+use tokio::io::{stdin, AsyncBufReadExt, BufReader};
+use twilight_model::channel::message::AllowedMentions;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker, MessageMarker, UserMarker};
+use twilight_model::id::Id;
+
+use crate::platform::{HostEvent, HostPlatform, OutgoingMessage, PlatformError, PlatformUser, SentMessage};
+
+/// The single guild of the terminal.
+pub const TERMINAL_GUILD: Id<GuildMarker> = Id::new(1);
+pub const TERMINAL_CHANNEL: Id<ChannelMarker> = Id::new(1);
+/// Author of the lines read from stdin.
+pub const TERMINAL_USER: Id<UserMarker> = Id::new(1);
+/// Author of the messages sent by the host.
+pub const HOST_USER: Id<UserMarker> = Id::new(2);
+
+/// Local frontend: messages are printed to stdout and every line of stdin is a message from [TERMINAL_USER] in
+/// [TERMINAL_CHANNEL], so guests run without a Discord token or network.
+pub struct TerminalPlatform {
+  username: String,
+  next_id: Arc<AtomicU64>,
+  events: Receiver<HostEvent>,
+}
+
+impl Default for TerminalPlatform {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl TerminalPlatform {
+  /// Starts reading stdin, must be called within the runtime.
+  pub fn new() -> Self {
+    let username = env::var("USER").unwrap_or_else(|_| "terminal".to_owned());
+    let next_id = Arc::new(AtomicU64::new(1));
+    let (sender, events) = flume::unbounded();
+
+    let author = user(TERMINAL_USER, &username);
+    let ids = next_id.clone();
+    tokio::spawn(async move {
+      let mut lines = BufReader::new(stdin()).lines();
+      while let Ok(Some(line)) = lines.next_line().await {
+        let message = discord::IncomingMessage {
+          id: ids.fetch_add(1, Ordering::AcqRel),
+          channel_id: TERMINAL_CHANNEL.get(),
+          guild_id: Some(TERMINAL_GUILD.get()),
+          author: Some(author.clone()),
+          content: line,
+          ..Default::default()
+        };
+        let event = HostEvent {
+          guild_id: TERMINAL_GUILD,
+          subscription: subscription::MESSAGE_CREATE,
+          event: DiscordExEventUnion::MessageCreate(message),
+        };
+        if sender.send(event).is_err() {
+          break;
+        }
+      }
+    });
+
+    Self { username, next_id, events }
+  }
+
+  /// Delivers `events` instead of reading stdin, [HostPlatform::next_event] returns `None` once they are consumed.
+  /// Messages sent by the guest get IDs from `first_id` on.
+  pub fn with_events(events: Vec<HostEvent>, first_id: u64) -> Self {
+    let (sender, receiver) = flume::unbounded();
+    for event in events {
+      // The receiver is alive
+      let _ = sender.send(event);
+    }
+    Self {
+      username: env::var("USER").unwrap_or_else(|_| "terminal".to_owned()),
+      next_id: Arc::new(AtomicU64::new(first_id.max(1))),
+      events: receiver,
+    }
+  }
+}
+
+fn user(id: Id<UserMarker>, name: &str) -> discord::User {
+  discord::User {
+    id: id.get(),
+    username: name.to_owned(),
+    discriminator: "0000".to_owned(),
+    bot: id == HOST_USER,
+    ..Default::default()
+  }
+}
+
+#[async_trait]
+impl HostPlatform for TerminalPlatform {
+  fn name(&self) -> &'static str {
+    "terminal"
+  }
+
+  async fn send_message(&self, channel_id: Id<ChannelMarker>, message: OutgoingMessage) -> Result<SentMessage, PlatformError> {
+    let id = Id::new(self.next_id.fetch_add(1, Ordering::AcqRel));
+    let content = message.content.unwrap_or_default();
+    println!("[#{} {}] {}", channel_id, id, content);
+    for attachment in &message.attachments {
+      match std::str::from_utf8(&attachment.file) {
+        Ok(text) => println!("[{}]\n{}", attachment.filename, text),
+        Err(_) => println!("[{}: {} bytes]", attachment.filename, attachment.file.len()),
+      }
+    }
+
+    Ok(SentMessage {
+      id,
+      channel_id,
+      author_id: HOST_USER,
+      content,
+    })
+  }
+
+  async fn edit_message(
+    &self,
+    channel_id: Id<ChannelMarker>,
+    message_id: Id<MessageMarker>,
+    content: Option<&str>,
+    _allowed_mentions: Option<AllowedMentions>,
+  ) -> Result<SentMessage, PlatformError> {
+    if message_id.get() >= self.next_id.load(Ordering::Acquire) {
+      return Err(PlatformError::NotFound);
+    }
+
+    let content = content.unwrap_or_default().to_owned();
+    println!("[#{} {} edited] {}", channel_id, message_id, content);
+    Ok(SentMessage {
+      id: message_id,
+      channel_id,
+      author_id: HOST_USER,
+      content,
+    })
+  }
+
+  async fn user(&self, user_id: Id<UserMarker>) -> Result<PlatformUser, PlatformError> {
+    let name = if user_id == TERMINAL_USER {
+      self.username.as_str()
+    } else if user_id == HOST_USER {
+      "mizu"
+    } else {
+      return Err(PlatformError::NotFound);
+    };
+    Ok(PlatformUser {
+      user: user(user_id, name),
+      global_name: Some(name.to_owned()),
+    })
+  }
+
+  async fn next_event(&self) -> Option<HostEvent> {
+    self.events.recv_async().await.ok()
+  }
+}
@@ -0,0 +1,478 @@
+use std::env;
+use std::fmt::Write;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use runtime::elf::{ElfError, Image};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::fs;
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+use twilight_model::id::marker::GuildMarker;
+use twilight_model::id::Id;
+
+pub const MAX_NAME_LENGTH: usize = 32;
+pub const DEFAULT_PROGRAM: &str = "main";
+const DEFAULT_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+  #[error("invalid program name `{0}`, expected up to 32 characters of `a-z`, `0-9`, `-` and `_`")]
+  InvalidName(String),
+  #[error("program `{0}` not found")]
+  NotFound(String),
+  #[error("program `{0}` has no version {1}")]
+  VersionNotFound(String, u64),
+  #[error("no program is installed")]
+  NotInstalled,
+  #[error("installed program is pinned to `{0}@{1}`")]
+  Pinned(String, u64),
+  #[error("no previous version to roll back to")]
+  NoHistory,
+  #[error("program is larger than {0} bytes")]
+  TooLarge(usize),
+  #[error("invalid program: {0}")]
+  Invalid(#[from] ElfError),
+  #[error("download timed out")]
+  Timeout,
+  #[error("download failed: {0}")]
+  Download(#[from] reqwest::Error),
+  #[error("io error: {0}")]
+  Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramVersion {
+  pub version: u64,
+  /// SHA-256 of the uploaded file.
+  pub hash: String,
+  pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Installed {
+  pub name: String,
+  pub version: u64,
+  pub pinned: bool,
+}
+
+/// Content-addressed store of guest programs.
+///
+/// Uploaded files are stored once under `blobs/<sha256>`, each guild has its own named programs with a list of
+/// versions and an install history, the last entry of which is the program `!load` runs:
+///
+/// ```text
+/// blobs/<sha256>
+/// guilds/<guild id>/<name>.versions  `<version> <sha256> <size>` per line
+/// guilds/<guild id>/installed        `<name> <version>` per line
+/// guilds/<guild id>/pinned           present while the installed program is pinned
+/// ```
+pub struct ProgramRegistry {
+  root: PathBuf,
+  max_size: usize,
+  download_timeout: Duration,
+  /// Serializes updates of the index files.
+  lock: Mutex<()>,
+}
+
+impl ProgramRegistry {
+  pub fn new(root: impl Into<PathBuf>, max_size: usize) -> Self {
+    Self {
+      root: root.into(),
+      max_size,
+      download_timeout: DEFAULT_DOWNLOAD_TIMEOUT,
+      lock: Mutex::new(()),
+    }
+  }
+
+  pub fn from_env() -> Self {
+    let max_size = env::var("MIZU_PROGRAM_MAX_SIZE")
+      .ok()
+      .and_then(|value| value.parse().ok())
+      .unwrap_or(8 * 1024 * 1024);
+    let mut registry = Self::new(env::var("MIZU_PROGRAM_DIR").unwrap_or_else(|_| "programs".to_owned()), max_size);
+    if let Some(value) = env::var("MIZU_DOWNLOAD_TIMEOUT_SECS").ok().and_then(|value| value.parse().ok()) {
+      registry.download_timeout = Duration::from_secs(value);
+    }
+    registry
+  }
+
+  fn guild_path(&self, guild_id: Id<GuildMarker>) -> PathBuf {
+    self.root.join("guilds").join(guild_id.to_string())
+  }
+
+  fn versions_path(&self, guild_id: Id<GuildMarker>, name: &str) -> Result<PathBuf, RegistryError> {
+    validate_name(name)?;
+    Ok(self.guild_path(guild_id).join(format!("{}.versions", name)))
+  }
+
+  fn blob_path(&self, hash: &str) -> PathBuf {
+    self.root.join("blobs").join(hash)
+  }
+
+  /// Downloads an attachment, rejecting it early if Discord or the response reports it as too large.
+  pub async fn download(&self, url: &str, size: u64) -> Result<Vec<u8>, RegistryError> {
+    if size > self.max_size as u64 {
+      return Err(RegistryError::TooLarge(self.max_size));
+    }
+
+    let client = reqwest::Client::builder().timeout(self.download_timeout).build()?;
+    let mut response = client.get(url).send().await.map_err(timeout)?.error_for_status()?;
+    if response.content_length().is_some_and(|length| length > self.max_size as u64) {
+      return Err(RegistryError::TooLarge(self.max_size));
+    }
+    let mut data = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(timeout)? {
+      if data.len() + chunk.len() > self.max_size {
+        return Err(RegistryError::TooLarge(self.max_size));
+      }
+      data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+  }
+
+  /// Validates `data` and adds it as a new version of `name`, uploading the same file twice returns the existing version.
+  /// Uploads are installed, so they are rejected while the installed program is pinned.
+  pub async fn publish(&self, guild_id: Id<GuildMarker>, name: &str, data: &[u8]) -> Result<ProgramVersion, RegistryError> {
+    if data.len() > self.max_size {
+      return Err(RegistryError::TooLarge(self.max_size));
+    }
+    Image::parse(data)?;
+
+    let hash = Sha256::digest(data).iter().fold(String::new(), |mut hash, byte| {
+      write!(hash, "{:02x}", byte).unwrap();
+      hash
+    });
+
+    let _lock = self.lock.lock().await;
+    Self::check_pin(&self.installed(guild_id).await?)?;
+    let blob_path = self.blob_path(&hash);
+    if fs::metadata(&blob_path).await.is_err() {
+      fs::create_dir_all(self.root.join("blobs")).await?;
+      write_atomic(&blob_path, data).await?;
+      debug!("stored blob {}", hash);
+    }
+
+    let mut versions = self.read_versions(guild_id, name).await?;
+    if let Some(latest) = versions.last().filter(|latest| latest.hash == hash) {
+      return Ok(latest.clone());
+    }
+
+    let version = ProgramVersion {
+      version: versions.last().map_or(1, |latest| latest.version + 1),
+      hash,
+      size: data.len() as u64,
+    };
+    versions.push(version.clone());
+    fs::create_dir_all(self.guild_path(guild_id)).await?;
+    let contents = versions
+      .iter()
+      .map(|version| format!("{} {} {}\n", version.version, version.hash, version.size))
+      .collect::<String>();
+    write_atomic(&self.versions_path(guild_id, name)?, contents.as_bytes()).await?;
+
+    info!("published {}@{} for guild {}", name, version.version, guild_id);
+    Ok(version)
+  }
+
+  async fn read_versions(&self, guild_id: Id<GuildMarker>, name: &str) -> Result<Vec<ProgramVersion>, RegistryError> {
+    let contents = match fs::read_to_string(self.versions_path(guild_id, name)?).await {
+      Ok(contents) => contents,
+      Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(error) => return Err(error.into()),
+    };
+
+    Ok(
+      contents
+        .lines()
+        .filter_map(|line| {
+          let mut fields = line.split(' ');
+          Some(ProgramVersion {
+            version: fields.next()?.parse().ok()?,
+            hash: fields.next()?.to_owned(),
+            size: fields.next()?.parse().ok()?,
+          })
+        })
+        .collect(),
+    )
+  }
+
+  pub async fn versions(&self, guild_id: Id<GuildMarker>, name: &str) -> Result<Vec<ProgramVersion>, RegistryError> {
+    let versions = self.read_versions(guild_id, name).await?;
+    if versions.is_empty() {
+      return Err(RegistryError::NotFound(name.to_owned()));
+    }
+    Ok(versions)
+  }
+
+  /// Names of the programs published in the guild.
+  pub async fn programs(&self, guild_id: Id<GuildMarker>) -> Result<Vec<String>, RegistryError> {
+    let mut entries = match fs::read_dir(self.guild_path(guild_id)).await {
+      Ok(entries) => entries,
+      Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(error) => return Err(error.into()),
+    };
+
+    let mut programs = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+      if let Some(name) = entry.file_name().to_str().and_then(|name| name.strip_suffix(".versions")) {
+        programs.push(name.to_owned());
+      }
+    }
+    programs.sort();
+    Ok(programs)
+  }
+
+  async fn version(&self, guild_id: Id<GuildMarker>, name: &str, version: Option<u64>) -> Result<ProgramVersion, RegistryError> {
+    let versions = self.versions(guild_id, name).await?;
+    match version {
+      Some(version) => versions
+        .into_iter()
+        .find(|candidate| candidate.version == version)
+        .ok_or_else(|| RegistryError::VersionNotFound(name.to_owned(), version)),
+      None => Ok(versions.into_iter().last().unwrap()),
+    }
+  }
+
+  /// Reads a version of a program, the latest one if `version` is not set.
+  pub async fn read(&self, guild_id: Id<GuildMarker>, name: &str, version: Option<u64>) -> Result<(ProgramVersion, Image), RegistryError> {
+    let version = self.version(guild_id, name, version).await?;
+    let data = fs::read(self.blob_path(&version.hash)).await?;
+    Ok((version, Image::parse(&data)?))
+  }
+
+  async fn read_history(&self, guild_id: Id<GuildMarker>) -> Result<Vec<(String, u64)>, RegistryError> {
+    let contents = match fs::read_to_string(self.guild_path(guild_id).join("installed")).await {
+      Ok(contents) => contents,
+      Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(error) => return Err(error.into()),
+    };
+
+    Ok(
+      contents
+        .lines()
+        .filter_map(|line| {
+          let (name, version) = line.split_once(' ')?;
+          Some((name.to_owned(), version.parse().ok()?))
+        })
+        .collect(),
+    )
+  }
+
+  async fn write_history(&self, guild_id: Id<GuildMarker>, history: &[(String, u64)]) -> Result<(), RegistryError> {
+    fs::create_dir_all(self.guild_path(guild_id)).await?;
+    let contents = history
+      .iter()
+      .map(|(name, version)| format!("{} {}\n", name, version))
+      .collect::<String>();
+    write_atomic(&self.guild_path(guild_id).join("installed"), contents.as_bytes()).await?;
+    Ok(())
+  }
+
+  pub async fn installed(&self, guild_id: Id<GuildMarker>) -> Result<Option<Installed>, RegistryError> {
+    let pinned = fs::metadata(self.guild_path(guild_id).join("pinned")).await.is_ok();
+    Ok(
+      self
+        .read_history(guild_id)
+        .await?
+        .pop()
+        .map(|(name, version)| Installed { name, version, pinned }),
+    )
+  }
+
+  fn check_pin(installed: &Option<Installed>) -> Result<(), RegistryError> {
+    match installed {
+      Some(installed) if installed.pinned => Err(RegistryError::Pinned(installed.name.clone(), installed.version)),
+      _ => Ok(()),
+    }
+  }
+
+  /// Makes a version of a program the one `!load` runs, the latest one if `version` is not set.
+  pub async fn install(&self, guild_id: Id<GuildMarker>, name: &str, version: Option<u64>) -> Result<ProgramVersion, RegistryError> {
+    let _lock = self.lock.lock().await;
+    Self::check_pin(&self.installed(guild_id).await?)?;
+
+    let version = self.version(guild_id, name, version).await?;
+    let mut history = self.read_history(guild_id).await?;
+    if history.last() != Some(&(name.to_owned(), version.version)) {
+      history.push((name.to_owned(), version.version));
+      self.write_history(guild_id, &history).await?;
+    }
+
+    info!("installed {}@{} for guild {}", name, version.version, guild_id);
+    Ok(version)
+  }
+
+  /// Reinstalls the previously installed program.
+  pub async fn rollback(&self, guild_id: Id<GuildMarker>) -> Result<Installed, RegistryError> {
+    let _lock = self.lock.lock().await;
+    Self::check_pin(&self.installed(guild_id).await?)?;
+
+    let mut history = self.read_history(guild_id).await?;
+    if history.len() < 2 {
+      return Err(RegistryError::NoHistory);
+    }
+    history.pop();
+    self.write_history(guild_id, &history).await?;
+
+    let (name, version) = history.pop().unwrap();
+    info!("rolled back to {}@{} for guild {}", name, version, guild_id);
+    Ok(Installed {
+      name,
+      version,
+      pinned: false,
+    })
+  }
+
+  /// While pinned, the installed program cannot be changed by uploads, `install` or `rollback`.
+  pub async fn pin(&self, guild_id: Id<GuildMarker>, pinned: bool) -> Result<(), RegistryError> {
+    let _lock = self.lock.lock().await;
+    if self.installed(guild_id).await?.is_none() {
+      return Err(RegistryError::NotInstalled);
+    }
+
+    let path = self.guild_path(guild_id).join("pinned");
+    if pinned {
+      fs::write(path, b"").await?;
+    } else if let Err(error) = fs::remove_file(path).await {
+      if error.kind() != ErrorKind::NotFound {
+        return Err(error.into());
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Writes to a temporary file next to `path` and renames it, so readers never see a partial file.
+async fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+  let temp_path = path.with_extension("tmp");
+  fs::write(&temp_path, contents).await?;
+  fs::rename(&temp_path, path).await
+}
+
+fn timeout(error: reqwest::Error) -> RegistryError {
+  if error.is_timeout() {
+    RegistryError::Timeout
+  } else {
+    RegistryError::Download(error)
+  }
+}
+
+fn validate_name(name: &str) -> Result<(), RegistryError> {
+  let valid = !name.is_empty()
+    && name.len() <= MAX_NAME_LENGTH
+    && name
+      .bytes()
+      .all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'-' || byte == b'_');
+  if !valid {
+    return Err(RegistryError::InvalidName(name.to_owned()));
+  }
+  Ok(())
+}
+
+/// Parses `name[@version]`.
+pub fn parse_reference(reference: &str) -> Result<(&str, Option<u64>), RegistryError> {
+  let (name, version) = match reference.split_once('@') {
+    Some((name, version)) => (
+      name,
+      Some(version.parse().map_err(|_| RegistryError::InvalidName(reference.to_owned()))?),
+    ),
+    None => (reference, None),
+  };
+  validate_name(name)?;
+  Ok((name, version))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const GUILD: Id<GuildMarker> = Id::new(1);
+
+  fn registry(name: &str) -> ProgramRegistry {
+    let root = env::temp_dir().join(format!("mizu-registry-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    ProgramRegistry::new(root, 1024)
+  }
+
+  /// Flat binary of `count` `nop` instructions.
+  fn program(count: usize) -> Vec<u8> {
+    [0x13, 0, 0, 0].repeat(count)
+  }
+
+  fn installed(installed: Option<Installed>) -> Option<(String, u64, bool)> {
+    installed.map(|installed| (installed.name, installed.version, installed.pinned))
+  }
+
+  #[tokio::test]
+  async fn publishes_versions() {
+    let registry = registry("publish");
+    assert_eq!(registry.publish(GUILD, "main", &program(1)).await.unwrap().version, 1);
+    assert_eq!(registry.publish(GUILD, "main", &program(1)).await.unwrap().version, 1);
+    let version = registry.publish(GUILD, "main", &program(2)).await.unwrap();
+    assert_eq!((version.version, version.size), (2, 8));
+    registry.publish(GUILD, "tool", &program(1)).await.unwrap();
+
+    assert_eq!(registry.programs(GUILD).await.unwrap(), ["main", "tool"]);
+    assert_eq!(registry.versions(GUILD, "main").await.unwrap().len(), 2);
+    assert_eq!(registry.read(GUILD, "main", Some(1)).await.unwrap().1.code, program(1));
+    assert_eq!(registry.read(GUILD, "main", None).await.unwrap().1.code, program(2));
+    // Both programs share the blob of the same file
+    assert_eq!(std::fs::read_dir(registry.root.join("blobs")).unwrap().count(), 2);
+
+    assert!(matches!(registry.publish(GUILD, "Main", &program(1)).await, Err(RegistryError::InvalidName(_))));
+    assert!(matches!(registry.publish(GUILD, "main", &program(512)).await, Err(RegistryError::TooLarge(1024))));
+    assert!(matches!(registry.publish(GUILD, "main", &[]).await, Err(RegistryError::Invalid(_))));
+    assert!(matches!(registry.versions(GUILD, "other").await, Err(RegistryError::NotFound(_))));
+    let _ = std::fs::remove_dir_all(&registry.root);
+  }
+
+  #[tokio::test]
+  async fn installs_and_rolls_back() {
+    let registry = registry("install");
+    assert!(matches!(registry.rollback(GUILD).await, Err(RegistryError::NoHistory)));
+    registry.publish(GUILD, "main", &program(1)).await.unwrap();
+    registry.publish(GUILD, "main", &program(2)).await.unwrap();
+    registry.publish(GUILD, "tool", &program(3)).await.unwrap();
+
+    assert_eq!(registry.install(GUILD, "main", Some(1)).await.unwrap().version, 1);
+    assert_eq!(registry.install(GUILD, "main", None).await.unwrap().version, 2);
+    registry.install(GUILD, "tool", None).await.unwrap();
+    assert_eq!(installed(registry.installed(GUILD).await.unwrap()), Some(("tool".to_owned(), 1, false)));
+    assert!(matches!(registry.install(GUILD, "main", Some(3)).await, Err(RegistryError::VersionNotFound(_, 3))));
+
+    let rolled_back = registry.rollback(GUILD).await.unwrap();
+    assert_eq!((rolled_back.name.as_str(), rolled_back.version), ("main", 2));
+    registry.rollback(GUILD).await.unwrap();
+    assert_eq!(installed(registry.installed(GUILD).await.unwrap()), Some(("main".to_owned(), 1, false)));
+    assert!(matches!(registry.rollback(GUILD).await, Err(RegistryError::NoHistory)));
+    let _ = std::fs::remove_dir_all(&registry.root);
+  }
+
+  #[tokio::test]
+  async fn pins_the_installed_program() {
+    let registry = registry("pin");
+    assert!(matches!(registry.pin(GUILD, true).await, Err(RegistryError::NotInstalled)));
+    registry.publish(GUILD, "main", &program(1)).await.unwrap();
+    registry.install(GUILD, "main", None).await.unwrap();
+    registry.publish(GUILD, "main", &program(2)).await.unwrap();
+    registry.install(GUILD, "main", None).await.unwrap();
+    registry.pin(GUILD, true).await.unwrap();
+    assert_eq!(installed(registry.installed(GUILD).await.unwrap()), Some(("main".to_owned(), 2, true)));
+
+    // Uploads are rejected before anything is stored
+    assert!(matches!(registry.publish(GUILD, "main", &program(3)).await, Err(RegistryError::Pinned(_, 2))));
+    assert_eq!(registry.versions(GUILD, "main").await.unwrap().len(), 2);
+    assert_eq!(std::fs::read_dir(registry.root.join("blobs")).unwrap().count(), 2);
+    assert!(matches!(registry.install(GUILD, "main", Some(1)).await, Err(RegistryError::Pinned(_, 2))));
+    assert!(matches!(registry.rollback(GUILD).await, Err(RegistryError::Pinned(_, 2))));
+
+    registry.pin(GUILD, false).await.unwrap();
+    registry.pin(GUILD, false).await.unwrap();
+    assert_eq!(registry.publish(GUILD, "main", &program(3)).await.unwrap().version, 3);
+    assert_eq!(registry.rollback(GUILD).await.unwrap().version, 1);
+    let _ = std::fs::remove_dir_all(&registry.root);
+  }
+}
@@ -0,0 +1,272 @@
+//! Discord through the terminal frontend of the bot: events are read from a JSON file and messages sent by the guest are
+//! printed to stdout.
+
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bot::capabilities::Operation;
+use bot::environment::discord::DiscordInterruptHandler;
+use bot::environment::discord_ex::DiscordExInterruptHandler;
+use bot::execution_context::ExecutionContext;
+use bot::outbox::OutboxPolicy;
+use bot::platform::terminal::{TerminalPlatform, TERMINAL_CHANNEL, TERMINAL_GUILD};
+use bot::platform::{HostEvent, HostPlatform};
+use mizu_hal_discord::discord;
+use mizu_hal_discord::discord::discord_ex_event::DiscordExEventUnion;
+use mizu_hal_discord::discord::DiscordExEvent;
+use mizu_hal_discord::prost::Message;
+use mizu_hal_types::discord::subscription;
+use mizu_hal_types::syscall;
+use runtime::cpu::Cpu;
+use runtime::memory::HARDWARE_BASE;
+use serde::Deserialize;
+use tracing::debug;
+
+fn default_id() -> u64 {
+  1
+}
+
+/// An event in the `--events` file, e.g. `{"type": "message_create", "content": "!ping"}`. Channels and users
+/// default to 1, message IDs are assigned in order when missing.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+  MessageCreate {
+    id: Option<u64>,
+    #[serde(default = "default_id")]
+    channel_id: u64,
+    #[serde(default = "default_id")]
+    author_id: u64,
+    username: Option<String>,
+    content: String,
+  },
+  MessageUpdate {
+    id: u64,
+    #[serde(default = "default_id")]
+    channel_id: u64,
+    content: String,
+  },
+  MessageDelete {
+    id: u64,
+    #[serde(default = "default_id")]
+    channel_id: u64,
+  },
+  ReactionAdd {
+    message_id: u64,
+    #[serde(default = "default_id")]
+    channel_id: u64,
+    #[serde(default = "default_id")]
+    user_id: u64,
+    emoji: String,
+  },
+  ReactionRemove {
+    message_id: u64,
+    #[serde(default = "default_id")]
+    channel_id: u64,
+    #[serde(default = "default_id")]
+    user_id: u64,
+    emoji: String,
+  },
+}
+
+fn user(id: u64, username: Option<String>) -> discord::User {
+  discord::User {
+    id,
+    username: username.unwrap_or_else(|| format!("user{}", id)),
+    discriminator: "0000".to_owned(),
+    ..Default::default()
+  }
+}
+
+fn emoji(name: String) -> Option<discord::Emoji> {
+  Some(discord::Emoji {
+    name,
+    ..Default::default()
+  })
+}
+
+/// Reads a JSON array of [Event]s.
+pub fn read_events(path: &Path) -> Result<Vec<Event>, Box<dyn Error + Send + Sync>> {
+  Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+impl Event {
+  /// Converts the event for the isolate, `next_id` is the ID of the next message without one.
+  fn into_host_event(self, next_id: &mut u64) -> HostEvent {
+    let (subscription, event) = match self {
+      Event::MessageCreate {
+        id,
+        channel_id,
+        author_id,
+        username,
+        content,
+      } => (
+        subscription::MESSAGE_CREATE,
+        DiscordExEventUnion::MessageCreate(discord::IncomingMessage {
+          id: id.unwrap_or_else(|| {
+            *next_id += 1;
+            *next_id - 1
+          }),
+          channel_id,
+          guild_id: Some(TERMINAL_GUILD.get()),
+          author: Some(user(author_id, username)),
+          content,
+          ..Default::default()
+        }),
+      ),
+      Event::MessageUpdate { id, channel_id, content } => (
+        subscription::MESSAGE_UPDATE,
+        DiscordExEventUnion::MessageUpdate(discord::IncomingMessage {
+          id,
+          channel_id,
+          guild_id: Some(TERMINAL_GUILD.get()),
+          content,
+          ..Default::default()
+        }),
+      ),
+      Event::MessageDelete { id, channel_id } => (
+        subscription::MESSAGE_DELETE,
+        DiscordExEventUnion::MessageDelete(discord::MessageDelete {
+          id,
+          channel_id,
+          guild_id: Some(TERMINAL_GUILD.get()),
+        }),
+      ),
+      Event::ReactionAdd {
+        message_id,
+        channel_id,
+        user_id,
+        emoji: name,
+      } => (
+        subscription::REACTION_ADD,
+        DiscordExEventUnion::ReactionCreate(discord::ReactionCreate {
+          user_id,
+          channel_id,
+          message_id,
+          guild_id: Some(TERMINAL_GUILD.get()),
+          emoji: emoji(name),
+        }),
+      ),
+      Event::ReactionRemove {
+        message_id,
+        channel_id,
+        user_id,
+        emoji: name,
+      } => (
+        subscription::REACTION_REMOVE,
+        DiscordExEventUnion::ReactionRemove(discord::ReactionRemove {
+          user_id,
+          channel_id,
+          message_id,
+          guild_id: Some(TERMINAL_GUILD.get()),
+          emoji: emoji(name),
+        }),
+      ),
+    };
+    HostEvent {
+      guild_id: TERMINAL_GUILD,
+      subscription,
+      event,
+    }
+  }
+}
+
+/// The terminal frontend of the bot, delivering the events of the `--events` file instead of reading stdin.
+///
+/// The guest gets every capability in the terminal channel and its messages are not rate limited.
+pub struct CliDiscord {
+  pub context: Arc<ExecutionContext>,
+  platform: Arc<TerminalPlatform>,
+  /// Events that arrived while the guest polled for a message.
+  deferred: Mutex<VecDeque<HostEvent>>,
+}
+
+impl CliDiscord {
+  pub async fn new(events: Vec<Event>) -> Self {
+    let mut next_id = 1;
+    let events = events.into_iter().map(|event| event.into_host_event(&mut next_id)).collect();
+    let platform = Arc::new(TerminalPlatform::with_events(events, next_id));
+
+    let context = Arc::new(ExecutionContext::with_outbox_policy(OutboxPolicy {
+      budget: u64::MAX,
+      burst: u32::MAX,
+      interval: Duration::ZERO,
+    }));
+    context.attach(platform.clone(), TERMINAL_CHANNEL, TERMINAL_GUILD, None).await;
+    let mut capabilities = context.capabilities.lock().await;
+    capabilities.events = subscription::ALL;
+    capabilities.operations = HashSet::from(Operation::ALL);
+    drop(capabilities);
+
+    Self {
+      context,
+      platform,
+      deferred: Mutex::new(VecDeque::new()),
+    }
+  }
+
+  pub fn install(&self, cpu: &mut Cpu) {
+    cpu.ivt.insert(
+      syscall::SYSCALL_DISCORD,
+      Arc::new(Box::new(DiscordInterruptHandler { context: self.context.clone() })),
+    );
+    cpu.ivt.insert(
+      syscall::SYSCALL_DISCORD_EX,
+      Arc::new(Box::new(DiscordExInterruptHandler { context: self.context.clone() })),
+    );
+  }
+
+  async fn next_event(&self) -> Option<HostEvent> {
+    let deferred = self.deferred.lock().unwrap().pop_front();
+    match deferred {
+      Some(event) => Some(event),
+      None => self.platform.next_event().await,
+    }
+  }
+
+  /// Raises the interrupt for the next event the guest is subscribed to, returns false once there are none left.
+  pub async fn deliver(&self, cpu: &mut Cpu) -> bool {
+    let event = loop {
+      let Some(HostEvent { subscription, event, .. }) = self.next_event().await else {
+        return false;
+      };
+      if self.context.is_subscribed(subscription).await {
+        break event;
+      }
+      debug!("not subscribed to {:#x}", subscription);
+    };
+
+    let data = DiscordExEvent {
+      discord_ex_event_union: Some(event),
+    }
+    .encode_to_vec();
+    cpu.raise_event(HARDWARE_BASE + 0x16000, &data);
+    true
+  }
+
+  /// Whether the guest waits in `POLL_MESSAGE`.
+  pub fn is_polling(&self) -> bool {
+    self.context.messages.receiver_count() > 0
+  }
+
+  /// Hands the next message to a pending `POLL_MESSAGE`, the events before it are delivered later. Returns false once
+  /// there are no messages left.
+  pub async fn feed_poll(&self) -> bool {
+    loop {
+      let Some(event) = self.platform.next_event().await else {
+        return false;
+      };
+      if let DiscordExEventUnion::MessageCreate(message) = &event.event {
+        // Like the bot, the message is also delivered as an event
+        let _ = self.context.messages.send(message.clone());
+        self.deferred.lock().unwrap().push_back(event);
+        return true;
+      }
+      self.deferred.lock().unwrap().push_back(event);
+    }
+  }
+}
@@ -0,0 +1,24 @@
+use async_trait::async_trait;
+use mizu_hal_types::log::status;
+use runtime::bus::BusMemoryExt;
+use runtime::cpu::{Cpu, InterruptHandler};
+use tracing::warn;
+
+pub struct LogHandler {}
+
+/// Prints the message to stdout, never applies backpressure.
+#[async_trait]
+impl InterruptHandler for LogHandler {
+  async fn handle(&self, cpu: &mut Cpu) {
+    cpu.regs[10] = match cpu.bus.read_string(cpu.regs[10]) {
+      Ok(message) => {
+        println!("cpu {}: {}", cpu.id, message.to_string_lossy());
+        status::OK
+      }
+      Err(_) => {
+        warn!("log message at 0x{:x} is not readable", cpu.regs[10]);
+        status::INVALID_ADDRESS
+      }
+    };
+  }
+}
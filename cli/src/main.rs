@@ -0,0 +1,533 @@
+mod discord;
+mod log;
+
+use std::env;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use bot::egress::EgressPolicy;
+use bot::environment::interrupt::IntHandler;
+use bot::environment::sipi::SipiHandler;
+use mizu_hal_types::syscall;
+use runtime::bus::Bus;
+use runtime::cpu::Cpu;
+use runtime::elf::Image;
+use runtime::exception::Exception;
+use runtime::halt::HaltHandler;
+use runtime::hart::HartHandler;
+use runtime::isolate::Isolate;
+use runtime::mailbox::MailboxHandler;
+use runtime::mmap::{MemoryHandler, DEFAULT_MEMORY_LIMIT};
+use runtime::object_storage::{ObjectStorage, ObjectStorageHandler, StorageQuota};
+use runtime::profiler::{DEFAULT_SAMPLE_INTERVAL, MIN_SAMPLE_INTERVAL};
+use runtime::replay::{ReplayError, Session};
+use runtime::scheduler::{Scheduler, SchedulerConfig};
+use runtime::tick::{CpuExt, TickResult};
+use runtime::time::TimeHandler;
+use runtime::trace::{self, TraceFilter, Tracer};
+use tokio::time::sleep;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+use crate::discord::CliDiscord;
+use crate::log::LogHandler;
+
+const USAGE: &str = "usage: mizu [--syscalls log,time,halt,storage,memory,hart,discord] [--storage DIR] [--events FILE]
+            [--record FILE | --replay FILE] [--trace FILE [--trace-harts LIST] [--trace-range START-END]...
+            [--trace-address START-END]...]
+            [--profile FILE [--profile-interval N]] [--memory-limit BYTES] [--allow-bypass] PROGRAM
+       mizu decode-trace [--chrome] TRACE
+
+Runs a guest ELF or flat binary until its bootstrap core stops, then prints the exit status, the performance counters
+and the registers of that core. The guest is idle once it waits for an interrupt, no events are left and no other core
+runs.
+
+  --syscalls LIST  handlers to install, all by default
+  --storage DIR    object storage directory, `storage` by default
+  --events FILE    JSON array of Discord events delivered while the guest waits for interrupts
+  --record FILE    record the nondeterministic inputs of the execution into a trace file
+  --replay FILE    replay a trace recorded from the same program, live events are ignored
+  --trace FILE     write executed instructions, register writes, memory accesses and traps into FILE
+  --trace-harts    comma-separated harts to trace, all by default
+  --trace-range    program counter range to trace, may be repeated, everything by default
+  --trace-address  only trace instructions that access this data address range, may be repeated
+  --profile FILE   sample the guest stack and write a pprof profile if FILE ends with `.pb`, collapsed stacks otherwise
+  --profile-interval N
+                   retired instructions between samples
+  --memory-limit BYTES
+                   bytes of memory the guest may use, including the 128 MiB of DRAM
+  --allow-bypass   let the guest lift the CPU time limit with the bypass power state
+
+decode-trace prints an instruction trace as text, or as Chrome trace-event JSON with --chrome.";
+
+/// How long a tick may block before a guest waiting in `POLL_MESSAGE` gets the next message.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Namespace of the object storage, the bot uses guild IDs so this never collides with a real guild.
+const STORAGE_NAMESPACE: u64 = 0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Syscalls {
+  Log,
+  Time,
+  Halt,
+  Storage,
+  Memory,
+  /// Hart state management, mailboxes, starting cores and interrupts between them.
+  Hart,
+  Discord,
+}
+
+impl Syscalls {
+  const ALL: [Syscalls; 7] = [
+    Syscalls::Log,
+    Syscalls::Time,
+    Syscalls::Halt,
+    Syscalls::Storage,
+    Syscalls::Memory,
+    Syscalls::Hart,
+    Syscalls::Discord,
+  ];
+
+  fn parse(name: &str) -> Option<Self> {
+    match name {
+      "log" => Some(Syscalls::Log),
+      "time" => Some(Syscalls::Time),
+      "halt" => Some(Syscalls::Halt),
+      "storage" => Some(Syscalls::Storage),
+      "memory" => Some(Syscalls::Memory),
+      "hart" => Some(Syscalls::Hart),
+      "discord" => Some(Syscalls::Discord),
+      _ => None,
+    }
+  }
+}
+
+struct Options {
+  program: PathBuf,
+  syscalls: Vec<Syscalls>,
+  storage: PathBuf,
+  events: Option<PathBuf>,
+  record: Option<PathBuf>,
+  replay: Option<PathBuf>,
+  trace: Option<PathBuf>,
+  trace_filter: TraceFilter,
+  profile: Option<PathBuf>,
+  profile_interval: u64,
+  memory_limit: u64,
+  allow_bypass: bool,
+}
+
+impl Options {
+  fn parse(mut arguments: impl Iterator<Item = String>) -> Result<Self, String> {
+    let mut program = None;
+    let mut syscalls = Syscalls::ALL.to_vec();
+    let mut storage = PathBuf::from("storage");
+    let mut events = None;
+    let mut record = None;
+    let mut replay = None;
+    let mut trace = None;
+    let mut trace_filter = TraceFilter::default();
+    let mut profile = None;
+    let mut profile_interval = DEFAULT_SAMPLE_INTERVAL;
+    let mut memory_limit = DEFAULT_MEMORY_LIMIT;
+    let mut allow_bypass = false;
+
+    while let Some(argument) = arguments.next() {
+      let mut value = || arguments.next().ok_or_else(|| format!("missing value for {}", argument));
+      match argument.as_str() {
+        "--syscalls" => {
+          syscalls = value()?
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .map(|name| Syscalls::parse(name).ok_or_else(|| format!("unknown syscall handler `{}`", name)))
+            .collect::<Result<_, _>>()?;
+        }
+        "--storage" => storage = value()?.into(),
+        "--events" => events = Some(value()?.into()),
+        "--record" => record = Some(value()?.into()),
+        "--replay" => replay = Some(value()?.into()),
+        "--trace" => trace = Some(value()?.into()),
+        "--profile" => profile = Some(value()?.into()),
+        "--profile-interval" => {
+          let interval = value()?;
+          profile_interval = interval
+            .parse()
+            .ok()
+            .filter(|interval| *interval >= MIN_SAMPLE_INTERVAL)
+            .ok_or_else(|| format!("invalid interval `{}`, the minimum is {}", interval, MIN_SAMPLE_INTERVAL))?;
+        }
+        "--memory-limit" => {
+          let limit = value()?;
+          memory_limit = limit.parse().map_err(|_| format!("invalid memory limit `{}`", limit))?;
+        }
+        "--allow-bypass" => allow_bypass = true,
+        "--trace-harts" => {
+          trace_filter.harts = value()?
+            .split(',')
+            .filter(|hart| !hart.is_empty())
+            .map(|hart| hart.trim().parse().map_err(|_| format!("invalid hart `{}`", hart)))
+            .collect::<Result<_, _>>()?;
+        }
+        "--trace-range" => {
+          let range = value()?;
+          trace_filter
+            .ranges
+            .push(TraceFilter::parse_range(&range).ok_or_else(|| format!("invalid range `{}`", range))?);
+        }
+        "--trace-address" => {
+          let range = value()?;
+          trace_filter
+            .addresses
+            .push(TraceFilter::parse_range(&range).ok_or_else(|| format!("invalid range `{}`", range))?);
+        }
+        "-h" | "--help" => return Err(String::new()),
+        _ if argument.starts_with("--") => return Err(format!("unknown option {}", argument)),
+        _ if program.is_none() => program = Some(PathBuf::from(&argument)),
+        _ => return Err(format!("unexpected argument {}", argument)),
+      }
+    }
+    if record.is_some() && replay.is_some() {
+      return Err("--record and --replay are exclusive".to_owned());
+    }
+
+    Ok(Self {
+      program: program.ok_or("missing program")?,
+      syscalls,
+      storage,
+      events,
+      record,
+      replay,
+      trace,
+      trace_filter,
+      profile,
+      profile_interval,
+      memory_limit,
+      allow_bypass,
+    })
+  }
+}
+
+enum ExitStatus {
+  Halted,
+  Finished,
+  Idle,
+  Replayed,
+  Exception(Exception),
+  TimeLimit,
+  Exploded,
+}
+
+impl ExitStatus {
+  fn code(&self) -> u8 {
+    match self {
+      ExitStatus::Halted | ExitStatus::Finished | ExitStatus::Idle | ExitStatus::Replayed => 0,
+      ExitStatus::Exception(_) | ExitStatus::TimeLimit | ExitStatus::Exploded => 1,
+    }
+  }
+}
+
+impl Display for ExitStatus {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ExitStatus::Halted => write!(f, "halted"),
+      ExitStatus::Finished => write!(f, "finished"),
+      ExitStatus::Idle => write!(f, "idle, no events left"),
+      ExitStatus::Replayed => write!(f, "end of trace"),
+      ExitStatus::Exception(exception) => write!(f, "exception: {}", exception),
+      ExitStatus::TimeLimit => write!(f, "running too long without yield"),
+      ExitStatus::Exploded => write!(f, "exploded"),
+    }
+  }
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode, Box<dyn Error + Send + Sync>> {
+  // Stdout is the guest output
+  tracing_subscriber::registry()
+    .with(fmt::layer().with_writer(std::io::stderr))
+    .with(EnvFilter::from_default_env())
+    .init();
+
+  let mut arguments = env::args().skip(1).peekable();
+  if arguments.peek().map(String::as_str) == Some("decode-trace") {
+    return decode_trace(arguments.skip(1));
+  }
+
+  let options = match Options::parse(arguments) {
+    Ok(options) => options,
+    Err(error) => {
+      if !error.is_empty() {
+        eprintln!("{}\n", error);
+      }
+      eprintln!("{}", USAGE);
+      return Ok(ExitCode::from(2));
+    }
+  };
+
+  let image = Image::parse(&fs::read(&options.program)?)?;
+  let bus = Arc::new(Bus::new(image.code));
+  bus.set_memory_limit(options.memory_limit);
+  bus.power.set_allow_bypass(options.allow_bypass);
+  let session = match (&options.record, &options.replay) {
+    (Some(path), _) => Some(Arc::new(Session::record(path, u64::MAX)?)),
+    (_, Some(path)) => Some(Arc::new(Session::replay(path)?)),
+    (None, None) => None,
+  };
+  if let Some(session) = &session {
+    bus.attach_session(session.clone());
+  }
+  let isolate = Isolate::new(bus);
+  // Cores started by the guest run on the scheduler, the bootstrap core runs here
+  let scheduler = Scheduler::start(SchedulerConfig::from_env());
+  isolate.attach_scheduler(&scheduler);
+  if options.profile.is_some() {
+    isolate.profiler.start(options.profile_interval);
+  }
+  let core = isolate.get_bootstrap_core();
+  let mut cpu = core.lock().await;
+  cpu.pc = image.entry;
+  if let Some(path) = &options.trace {
+    cpu.tracer = Some(Arc::new(Tracer::create(path, options.trace_filter.clone())?));
+  }
+
+  let events = match &options.events {
+    Some(path) => discord::read_events(path)?,
+    None => Vec::new(),
+  };
+  let discord = CliDiscord::new(events).await;
+  *discord.context.isolate.lock().await = Some(isolate.clone());
+  install(&mut cpu, &options, &discord);
+
+  let status = run(&mut cpu, &discord).await?;
+  println!("exit: {}", status);
+  if let (Some(tracer), Some(path)) = (&cpu.tracer, &options.trace) {
+    tracer.flush();
+    println!("instruction trace: {} records in {}", tracer.records(), path.display());
+  }
+  if let Some(path) = &options.profile {
+    let profile = match path.extension().and_then(|extension| extension.to_str()) {
+      Some("pb") => isolate.profiler.pprof(&image.symbols),
+      _ => isolate.profiler.collapsed(&image.symbols).into_bytes(),
+    };
+    fs::write(path, profile)?;
+    println!("profile: {} samples in {}", isolate.profiler.samples(), path.display());
+  }
+  if let (Some(session), Some(path)) = (&session, &options.record) {
+    session.flush();
+    println!("trace: {} records in {}", session.records(), path.display());
+  }
+  println!(
+    "perf: instructions_retired={} loads={} stores={} cpu_time={:?}",
+    cpu.perf.instructions_retired.load(Ordering::Acquire),
+    cpu.perf.loads.load(Ordering::Acquire),
+    cpu.perf.stores.load(Ordering::Acquire),
+    cpu.perf.cpu_time.lock().unwrap(),
+  );
+  print!("{}", cpu.dump());
+  Ok(ExitCode::from(status.code()))
+}
+
+fn decode_trace(arguments: impl Iterator<Item = String>) -> Result<ExitCode, Box<dyn Error + Send + Sync>> {
+  let mut chrome = false;
+  let mut path = None;
+  for argument in arguments {
+    match argument.as_str() {
+      "--chrome" => chrome = true,
+      _ if path.is_none() && !argument.starts_with("--") => path = Some(PathBuf::from(&argument)),
+      _ => {
+        eprintln!("unexpected argument {}\n\n{}", argument, USAGE);
+        return Ok(ExitCode::from(2));
+      }
+    }
+  }
+  let Some(path) = path else {
+    eprintln!("missing trace\n\n{}", USAGE);
+    return Ok(ExitCode::from(2));
+  };
+
+  let mut input = trace::open(&path)?;
+  let mut output = BufWriter::new(io::stdout().lock());
+  if chrome {
+    trace::decode_chrome(&mut input, &mut output)?;
+  } else {
+    trace::decode_text(&mut input, &mut output)?;
+  }
+  output.flush()?;
+  Ok(ExitCode::SUCCESS)
+}
+
+fn install(cpu: &mut Cpu, options: &Options, discord: &CliDiscord) {
+  for syscalls in &options.syscalls {
+    match syscalls {
+      Syscalls::Log => {
+        cpu.ivt.insert(syscall::SYSCALL_LOG, Arc::new(Box::new(LogHandler {})));
+      }
+      Syscalls::Time => {
+        cpu.ivt.insert(syscall::SYSCALL_TIME, Arc::new(Box::new(TimeHandler {})));
+      }
+      Syscalls::Halt => {
+        cpu.ivt.insert(syscall::SYSCALL_HALT, Arc::new(Box::new(HaltHandler {})));
+      }
+      Syscalls::Storage => {
+        cpu.ivt.insert(
+          syscall::SYSCALL_OBJECT_STORAGE,
+          Arc::new(Box::new(ObjectStorageHandler {
+            object_storage: Arc::new(ObjectStorage::new(&options.storage, StorageQuota::default())),
+            namespace: STORAGE_NAMESPACE,
+          })),
+        );
+      }
+      Syscalls::Memory => {
+        cpu.ivt.insert(syscall::SYSCALL_MEMORY, Arc::new(Box::new(MemoryHandler {})));
+      }
+      Syscalls::Hart => {
+        cpu.ivt.insert(syscall::SYSCALL_HART, Arc::new(Box::new(HartHandler {})));
+        cpu.ivt.insert(syscall::SYSCALL_MAILBOX, Arc::new(Box::new(MailboxHandler {})));
+        cpu.ivt.insert(
+          syscall::SYSCALL_SIPI,
+          Arc::new(Box::new(SipiHandler {
+            context: discord.context.clone(),
+            egress_policy: Arc::new(EgressPolicy::from_env()),
+          })),
+        );
+        cpu.ivt.insert(
+          syscall::SYSCALL_INT,
+          Arc::new(Box::new(IntHandler { context: discord.context.clone() })),
+        );
+      }
+      Syscalls::Discord => discord.install(cpu),
+    }
+  }
+}
+
+async fn run(cpu: &mut Cpu, discord: &CliDiscord) -> Result<ExitStatus, Box<dyn Error + Send + Sync>> {
+  let replaying = cpu.bus.session().is_some_and(|session| session.is_replaying());
+  loop {
+    let tick = {
+      let tick = cpu.run_tick();
+      tokio::pin!(tick);
+      loop {
+        tokio::select! {
+          biased;
+          tick = &mut tick => break tick,
+          _ = sleep(POLL_INTERVAL) => {
+            // `POLL_MESSAGE` blocks the tick until a message arrives
+            if discord.is_polling() && !discord.feed_poll().await {
+              return Ok(ExitStatus::Idle);
+            }
+          }
+        }
+      }
+    };
+    let result = match tick {
+      Ok(result) => result,
+      Err(error) if matches!(error.downcast_ref::<ReplayError>(), Some(ReplayError::Exhausted)) => return Ok(ExitStatus::Replayed),
+      Err(error) => return Err(error),
+    };
+    let status = match result {
+      TickResult::Continue | TickResult::Paused => continue,
+      TickResult::WaitForInterrupt => {
+        // Recorded events are delivered by the session
+        if !replaying && discord.deliver(cpu).await {
+          continue;
+        }
+        if cpu.inbox.is_pending() || others_running(cpu) {
+          // Another core may still interrupt this one
+          sleep(POLL_INTERVAL).await;
+          continue;
+        }
+        ExitStatus::Idle
+      }
+      TickResult::Halt => ExitStatus::Halted,
+      TickResult::Eof => ExitStatus::Finished,
+      TickResult::Exception(exception) => ExitStatus::Exception(exception),
+      TickResult::TimeLimit => ExitStatus::TimeLimit,
+      TickResult::Explosion => ExitStatus::Exploded,
+    };
+    return Ok(status);
+  }
+}
+
+/// Whether a core other than `cpu` runs and does not wait for an interrupt.
+fn others_running(cpu: &Cpu) -> bool {
+  let Some(isolate) = cpu.isolate.as_ref().and_then(Weak::upgrade) else {
+    return false;
+  };
+  let count = isolate.cores.lock().unwrap().len() as u16;
+  (0..count)
+    .filter(|id| *id != cpu.id)
+    .filter_map(|id| isolate.link(id))
+    .any(|link| link.lifecycle.is_running() && !link.wfi.get())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(arguments: &str) -> Result<Options, String> {
+    Options::parse(arguments.split_whitespace().map(str::to_owned))
+  }
+
+  #[test]
+  fn parses_defaults() {
+    let options = parse("main.bin").unwrap();
+    assert_eq!(options.program, PathBuf::from("main.bin"));
+    assert_eq!(options.syscalls, Syscalls::ALL);
+    assert_eq!(options.storage, PathBuf::from("storage"));
+    assert!(options.events.is_none() && options.record.is_none() && options.replay.is_none());
+    assert!(options.trace.is_none() && options.profile.is_none());
+    assert_eq!(options.profile_interval, DEFAULT_SAMPLE_INTERVAL);
+    assert_eq!(options.memory_limit, DEFAULT_MEMORY_LIMIT);
+    assert!(!options.allow_bypass);
+  }
+
+  #[test]
+  fn parses_options() {
+    let options = parse(
+      "--syscalls log,halt, --storage data --events events.json --record trace.bin --trace insns.bin --trace-harts 0,2 \
+       --trace-range 0x10-0x20 --trace-range 48-64 --trace-address 0x100-0x1ff --profile out.pb --profile-interval 5000 \
+       --memory-limit 268435456 --allow-bypass main.bin",
+    )
+    .unwrap();
+    assert_eq!(options.program, PathBuf::from("main.bin"));
+    assert_eq!(options.syscalls, [Syscalls::Log, Syscalls::Halt]);
+    assert_eq!(options.storage, PathBuf::from("data"));
+    assert_eq!(options.events, Some(PathBuf::from("events.json")));
+    assert_eq!(options.record, Some(PathBuf::from("trace.bin")));
+    assert_eq!(options.trace, Some(PathBuf::from("insns.bin")));
+    assert_eq!(options.trace_filter.harts, [0, 2]);
+    assert_eq!(options.trace_filter.ranges, [0x10..=0x20, 48..=64]);
+    assert_eq!(options.trace_filter.addresses, [0x100..=0x1ff]);
+    assert_eq!(options.profile, Some(PathBuf::from("out.pb")));
+    assert_eq!(options.profile_interval, 5000);
+    assert_eq!(options.memory_limit, 256 * 1024 * 1024);
+    assert!(options.allow_bypass);
+    assert!(parse("--syscalls , main.bin").unwrap().syscalls.is_empty());
+  }
+
+  #[test]
+  fn rejects_invalid_arguments() {
+    let error = |arguments| parse(arguments).err().unwrap();
+    assert_eq!(error(""), "missing program");
+    assert_eq!(error("main.bin other.bin"), "unexpected argument other.bin");
+    assert_eq!(error("--verbose main.bin"), "unknown option --verbose");
+    assert_eq!(error("main.bin --storage"), "missing value for --storage");
+    assert_eq!(error("--syscalls log,net main.bin"), "unknown syscall handler `net`");
+    assert_eq!(error("--profile-interval 10 main.bin"), "invalid interval `10`, the minimum is 1000");
+    assert_eq!(error("--memory-limit lots main.bin"), "invalid memory limit `lots`");
+    assert_eq!(error("--trace-harts 0,x main.bin"), "invalid hart `x`");
+    assert_eq!(error("--trace-range 10 main.bin"), "invalid range `10`");
+    assert_eq!(error("--trace-address 0x-1 main.bin"), "invalid range `0x-1`");
+    assert_eq!(error("--record a --replay b main.bin"), "--record and --replay are exclusive");
+    assert_eq!(error("--help"), "");
+  }
+}
@@ -0,0 +1,67 @@
+#ifndef MIZU_ASYNC
+#define MIZU_ASYNC
+
+#include "syscalls.h"
+
+#define ASYNC_SETUP   0
+#define ASYNC_SUBMIT  1
+
+#define ASYNC_OK               0
+#define ASYNC_INVALID_ADDRESS  1
+
+// A call that completes into a result window writes the data its result points to there, it stays valid until the
+// completion is consumed.
+#define ASYNC_RESULT_WINDOW_SIZE  0x1a000
+
+typedef struct async_completion {
+  unsigned long ticket;
+  unsigned long a0;
+  unsigned long a1;
+} async_completion_t;
+
+// Followed by `entries` of async_completion_t. The host writes `tail` and `overflow`, the guest writes `head`.
+typedef struct async_queue {
+  volatile unsigned long head;
+  volatile unsigned long tail;
+  volatile unsigned long overflow;
+} async_queue_t;
+
+// `results` is null or points to `entries` windows of ASYNC_RESULT_WINDOW_SIZE bytes. Returns a status code.
+inline unsigned long async_setup(async_queue_t* queue, unsigned long entries, void* results) {
+  register unsigned long a0 asm("a0") = ASYNC_SETUP;
+  register unsigned long a1 asm("a1") = (unsigned long) queue;
+  register unsigned long a2 asm("a2") = entries;
+  register unsigned long a3 asm("a3") = (unsigned long) results;
+  register unsigned long a7 asm("a7") = SYSCALL_ASYNC;
+  asm volatile("ecall" : "+r"(a0) : "r"(a1), "r"(a2), "r"(a3), "r"(a7) : "memory");
+  return a0;
+}
+
+// Arguments are passed to the syscall as a0..a4. Returns the ticket reported in the completion, or 0 if the call was
+// rejected.
+inline unsigned long async_submit(unsigned long number, unsigned long arg0, unsigned long arg1, unsigned long arg2,
+                                  unsigned long arg3, unsigned long arg4) {
+  register unsigned long a0 asm("a0") = ASYNC_SUBMIT;
+  register unsigned long a1 asm("a1") = number;
+  register unsigned long a2 asm("a2") = arg0;
+  register unsigned long a3 asm("a3") = arg1;
+  register unsigned long a4 asm("a4") = arg2;
+  register unsigned long a5 asm("a5") = arg3;
+  register unsigned long a6 asm("a6") = arg4;
+  register unsigned long a7 asm("a7") = SYSCALL_ASYNC;
+  asm volatile("ecall" : "+r"(a0) : "r"(a1), "r"(a2), "r"(a3), "r"(a4), "r"(a5), "r"(a6), "r"(a7) : "memory");
+  return a0;
+}
+
+inline int async_poll(async_queue_t* queue, unsigned long entries, async_completion_t* completion) {
+  if(queue->head == queue->tail) {
+    return 0;
+  }
+
+  async_completion_t* ring = (async_completion_t*) (queue + 1);
+  *completion = ring[queue->head % entries];
+  queue->head++;
+  return 1;
+}
+
+#endif
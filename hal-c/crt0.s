@@ -0,0 +1,10 @@
+# Entry point of C and assembly guests, calls `main` and halts when it returns.
+  .section .start, "ax"
+  .globl _start
+_start:
+  la sp, __stack_top
+  call main
+  li a7, 15 # SYSCALL_HALT
+  ecall
+1:
+  j 1b
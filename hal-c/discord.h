@@ -5,8 +5,29 @@
 
 #define DISCORD_CREATE_MESSAGE   1
 #define DISCORD_CREATE_REACTION  2
+#define DISCORD_SET_EVENT_MASK   4
 #define DISCORD_POLL_EVENT       10
 
+// Status codes returned in a1, a0 is null unless the call succeeded
+#define DISCORD_OK                0
+#define DISCORD_DENIED            1  // a2 holds the code of the denied operation
+#define DISCORD_CHANNEL_DENIED    2  // a2 holds the denied channel ID
+#define DISCORD_INVALID_ARGUMENT  3
+#define DISCORD_UNAVAILABLE       4
+#define DISCORD_FAILED            5
+
+// Event subscription mask bits, only subscribed events raise interrupt 17
+#define DISCORD_EVENT_MESSAGE_CREATE      (1 << 0)
+#define DISCORD_EVENT_REACTION_ADD        (1 << 1)
+#define DISCORD_EVENT_MESSAGE_UPDATE      (1 << 2)
+#define DISCORD_EVENT_MESSAGE_DELETE      (1 << 3)
+#define DISCORD_EVENT_REACTION_REMOVE     (1 << 4)
+#define DISCORD_EVENT_MEMBER_ADD          (1 << 5)
+#define DISCORD_EVENT_MEMBER_REMOVE       (1 << 6)
+#define DISCORD_EVENT_THREAD_CREATE       (1 << 7)
+#define DISCORD_EVENT_INTERACTION_CREATE  (1 << 8)
+#define DISCORD_EVENT_DEFAULT             (DISCORD_EVENT_MESSAGE_CREATE | DISCORD_EVENT_REACTION_ADD)
+
 typedef struct discord_create_message {
   unsigned long channel_id;
   unsigned long flags;
@@ -51,4 +72,9 @@ inline void discord_create_message(discord_create_message_t* message) {
   syscall_discord(DISCORD_CREATE_MESSAGE, message);
 }
 
+// Returns the previous mask.
+inline unsigned long discord_set_event_mask(unsigned long mask) {
+  return syscall_discord(DISCORD_SET_EVENT_MASK, (void*) mask);
+}
+
 #endif
@@ -15,6 +15,22 @@ inline void* memcpy(void* dst, const void* src, unsigned long n) {
   return dst;
 }
 
+inline void* memset(void* dst, int c, unsigned long n) {
+  unsigned char* d = dst;
+  while(n--) {
+    *d++ = (unsigned char) c;
+  }
+  return dst;
+}
+
+inline unsigned long strlen(const char* s) {
+  const char* p = s;
+  while(*p) {
+    ++p;
+  }
+  return p - s;
+}
+
 inline int strcmp(const char* s1, const char* s2) {
   const unsigned char* p1 = (const unsigned char*) s1;
   const unsigned char* p2 = (const unsigned char*) s2;
@@ -7,9 +7,20 @@ typedef struct http_request {
   const char* url;
 } http_request_t;
 
+#define HTTP_ERROR_NONE                0
+#define HTTP_ERROR_INVALID_URL         1
+#define HTTP_ERROR_HOST_DENIED         2
+#define HTTP_ERROR_PRIVATE_ADDRESS     3
+#define HTTP_ERROR_RESOLVE_FAILED      4
+#define HTTP_ERROR_TOO_MANY_REDIRECTS  5
+#define HTTP_ERROR_RESPONSE_TOO_LARGE  6
+#define HTTP_ERROR_BUDGET_EXCEEDED     7
+#define HTTP_ERROR_REQUEST_FAILED      8
+
 typedef struct http_response {
   const unsigned short status_code;
-  const char* body;
+  const char* body; // Response body, or a description of the error
+  const unsigned long error;
 } http_response_t;
 
 inline void syscall_http(http_request_t* request, http_response_t** response) {
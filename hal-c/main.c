@@ -2,3 +2,10 @@
 #include "discord.h"
 #include "http.h"
 #include "object_storage.h"
+#include "async.h"
+#include "ring.h"
+
+int main() {
+  sys_log("hello from c");
+  return 0;
+}
@@ -15,10 +15,75 @@ typedef struct object_storage_put {
   const object_storage_item_t item;
 } object_storage_put_t;
 
-#define OBJECT_STORAGE_GET  1
-#define OBJECT_STORAGE_PUT  2
+typedef struct object_storage_expire {
+  const char* key;
+  // Time to live in milliseconds, 0 removes the expiry.
+  const unsigned long ttl_ms;
+} object_storage_expire_t;
+
+// Written only if the current version equals expected_version, 0 if the object must not exist.
+typedef struct object_storage_cas {
+  const char* key;
+  const unsigned long expected_version;
+  const object_storage_item_t item;
+  const unsigned long ttl_ms;
+} object_storage_cas_t;
+
+typedef struct object_storage_increment {
+  const char* key;
+  const long delta;
+} object_storage_increment_t;
+
+typedef struct object_storage_list {
+  const char* prefix;
+  // Last key of the previous page, or NULL to start from the beginning.
+  const char* cursor;
+  const unsigned long limit;
+} object_storage_list_t;
+
+typedef struct object_storage_list_result {
+  const unsigned long count;
+  const char* const* keys;
+  // NULL if there are no more keys.
+  const char* next_cursor;
+} object_storage_list_result_t;
+
+typedef struct object_storage_metadata {
+  const unsigned long size;
+  const unsigned long modified_ms;
+  const unsigned long version;
+  // 0 if the object never expires.
+  const unsigned long expires_ms;
+} object_storage_metadata_t;
+
+typedef struct object_storage_counter {
+  const long value;
+  const unsigned long version;
+} object_storage_counter_t;
+
+#define OBJECT_STORAGE_GET               1
+#define OBJECT_STORAGE_PUT               2
+#define OBJECT_STORAGE_DELETE            3
+#define OBJECT_STORAGE_LIST              4
+#define OBJECT_STORAGE_STAT              5
+#define OBJECT_STORAGE_COMPARE_AND_SWAP  6
+#define OBJECT_STORAGE_INCREMENT         7
+#define OBJECT_STORAGE_EXPIRE            8
+
+#define OBJECT_STORAGE_LIST_LIMIT  64
+
+#define OBJECT_STORAGE_OK                0
+#define OBJECT_STORAGE_NOT_FOUND         1
+#define OBJECT_STORAGE_INVALID_KEY       2
+#define OBJECT_STORAGE_QUOTA_EXCEEDED    3
+#define OBJECT_STORAGE_IO_ERROR          4
+#define OBJECT_STORAGE_INVALID_VALUE     5
+#define OBJECT_STORAGE_VERSION_MISMATCH  6
+#define OBJECT_STORAGE_VALUE_TOO_LARGE   7
 
-inline void syscall_object_storage(int action, void* request, void** response) {
+// Returns one of OBJECT_STORAGE_* status codes.
+inline unsigned long syscall_object_storage(int action, void* request, void** response) {
+  unsigned long status;
   asm volatile(
     "li a0, %0\n"
     "mv a1, %1" :: "i"(action), "r"(request) : "a0", "a1"
@@ -26,15 +91,41 @@ inline void syscall_object_storage(int action, void* request, void** response) {
   syscall(SYSCALL_OBJECT_STORAGE);
 
   asm volatile("mv %0, a0" : "=r"(*response) :: "a0");
+  asm volatile("mv %0, a1" : "=r"(status) :: "a1");
+  return status;
+}
+
+inline unsigned long object_storage_get(object_storage_get_t* request, object_storage_item_t** item) {
+  return syscall_object_storage(OBJECT_STORAGE_GET, request, (void**) item);
+}
+
+inline unsigned long object_storage_put(object_storage_put_t* request, object_storage_metadata_t** metadata) {
+  return syscall_object_storage(OBJECT_STORAGE_PUT, request, (void**) metadata);
+}
+
+inline unsigned long object_storage_delete(object_storage_get_t* request) {
+  void* result;
+  return syscall_object_storage(OBJECT_STORAGE_DELETE, request, &result);
+}
+
+inline unsigned long object_storage_stat(object_storage_get_t* request, object_storage_metadata_t** metadata) {
+  return syscall_object_storage(OBJECT_STORAGE_STAT, request, (void**) metadata);
+}
+
+inline unsigned long object_storage_list(object_storage_list_t* request, object_storage_list_result_t** result) {
+  return syscall_object_storage(OBJECT_STORAGE_LIST, request, (void**) result);
+}
+
+inline unsigned long object_storage_compare_and_swap(object_storage_cas_t* request, object_storage_metadata_t** metadata) {
+  return syscall_object_storage(OBJECT_STORAGE_COMPARE_AND_SWAP, request, (void**) metadata);
 }
 
-inline void object_storage_get(object_storage_get_t* request, object_storage_item_t** item) {
-  syscall_object_storage(OBJECT_STORAGE_GET, request, (void**) item);
+inline unsigned long object_storage_increment(object_storage_increment_t* request, object_storage_counter_t** counter) {
+  return syscall_object_storage(OBJECT_STORAGE_INCREMENT, request, (void**) counter);
 }
 
-inline void object_storage_put(object_storage_put_t* request) {
-  object_storage_item_t* item;
-  syscall_object_storage(OBJECT_STORAGE_PUT, request, (void**) &item);
+inline unsigned long object_storage_expire(object_storage_expire_t* request, object_storage_metadata_t** metadata) {
+  return syscall_object_storage(OBJECT_STORAGE_EXPIRE, request, (void**) metadata);
 }
 
 #endif
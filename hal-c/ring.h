@@ -0,0 +1,55 @@
+#ifndef MIZU_RING
+#define MIZU_RING
+
+#include "syscalls.h"
+#include "async.h"
+
+#define RING_DOORBELL  ((volatile unsigned long*) 0x13000)
+
+#define RING_OK               0
+#define RING_UNAVAILABLE      1
+#define RING_INVALID_ADDRESS  2
+
+// `results` is 0 or points to `cq_entries` windows of ASYNC_RESULT_WINDOW_SIZE bytes.
+typedef struct ring_setup {
+  unsigned long sq_address;
+  unsigned long sq_entries;
+  unsigned long cq_address;
+  unsigned long cq_entries;
+  unsigned long results;
+} ring_setup_t;
+
+// Arguments are passed to the syscall as a0..a4.
+typedef struct ring_sqe {
+  unsigned long user_data;
+  unsigned long syscall;
+  unsigned long args[5];
+} ring_sqe_t;
+
+// `ticket` holds the `user_data` of the submission.
+typedef async_completion_t ring_cqe_t;
+
+// Both queues start with an async_queue_t header followed by the entries. Returns a status code.
+inline unsigned long ring_setup(ring_setup_t* setup) {
+  register unsigned long a0 asm("a0") = (unsigned long) setup;
+  register unsigned long a7 asm("a7") = SYSCALL_RING;
+  asm volatile("ecall" : "+r"(a0) : "r"(a7) : "memory");
+  return a0;
+}
+
+inline int ring_push(async_queue_t* sq, unsigned long entries, const ring_sqe_t* sqe) {
+  if(sq->tail - sq->head >= entries) {
+    return 0;
+  }
+
+  ring_sqe_t* ring = (ring_sqe_t*) (sq + 1);
+  ring[sq->tail % entries] = *sqe;
+  sq->tail++;
+  return 1;
+}
+
+inline void ring_submit() {
+  *RING_DOORBELL = 1;
+}
+
+#endif
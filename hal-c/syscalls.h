@@ -1,10 +1,28 @@
 #ifndef MIZU_SYSCALL
 #define MIZU_SYSCALL
 
+#define SYSCALL_DISCORD_EX       9
 #define SYSCALL_DISCORD         10
 #define SYSCALL_PERF_DUMP       11
 #define SYSCALL_HTTP            12
 #define SYSCALL_OBJECT_STORAGE  13
+#define SYSCALL_LOG             14
+#define SYSCALL_HALT            15
+#define SYSCALL_TIME            16
+#define SYSCALL_SIPI            17
+#define SYSCALL_INT             18
+#define SYSCALL_PNG             19
+#define SYSCALL_ASYNC           20
+#define SYSCALL_RING            21
+#define SYSCALL_MEMORY          22
+#define SYSCALL_HART            23
+#define SYSCALL_MAILBOX         24
+
+// Status codes returned by sys_log
+#define LOG_OK                0
+#define LOG_BUSY              1
+#define LOG_BUDGET_EXHAUSTED  2
+#define LOG_INVALID_ADDRESS   3
 
 inline void syscall(int num) {
   asm volatile(
@@ -14,7 +32,20 @@ inline void syscall(int num) {
 }
 
 inline void syscall_perf_dump() {
-  syscall(SYSCALL_HTTP);
+  syscall(SYSCALL_PERF_DUMP);
+}
+
+// Sends a line to the channel the isolate was loaded from
+inline unsigned long sys_log(const char* message) {
+  register unsigned long a0 asm("a0") = (unsigned long) message;
+  register unsigned long a7 asm("a7") = SYSCALL_LOG;
+  asm volatile("ecall" : "+r"(a0) : "r"(a7) : "memory");
+  return a0;
+}
+
+inline void sys_halt() {
+  syscall(SYSCALL_HALT);
+  for(;;);
 }
 
 #endif
@@ -0,0 +1,11 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+  // Guests link with `INCLUDE heap.x`
+  let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+  fs::copy("heap.x", out.join("heap.x")).unwrap();
+  println!("cargo:rustc-link-search={}", out.display());
+  println!("cargo:rerun-if-changed=heap.x");
+}
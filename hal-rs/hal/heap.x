@@ -0,0 +1,3 @@
+/* Bounds of the mizu-hal allocator, included by the linker script of the guest */
+__heap_start = 0xffffffff81020000;
+__heap_end = 0xffffffff88000000;
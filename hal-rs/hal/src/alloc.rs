@@ -1,36 +1,135 @@
+//! Global allocator for guests.
+//!
+//! The [Heap] spans `__heap_start..__heap_end`, both defined by `heap.x` of the HAL, and grows by regions mapped with
+//! `SYSCALL_MEMORY`, which are unmapped once they are free.
+//!
+//! The heap is guarded by a spin lock that is held with interrupts disabled, so interrupt handlers may allocate.
+
 use core::alloc::{GlobalAlloc, Layout};
+use core::arch::asm;
+use core::cell::UnsafeCell;
 use core::ffi::c_void;
+use core::hint::spin_loop;
+use core::ptr::{self, addr_of};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-const START: *mut c_void = (0xffffffff81000000u64 + 0x20000u64) as *mut c_void;
+use mizu_hal_types::heap::Heap;
+pub use mizu_hal_types::heap::{HeapStats, GROW_SIZE, MAX_SMALL, MIN_BLOCK};
+use mizu_hwconst::csr::MASK_MIE;
 
-static mut POSITION: *mut c_void = START;
+use crate::memory;
 
-pub fn malloc(size: usize) -> *mut c_void {
-  unsafe {
-    let ptr = POSITION;
-    POSITION = POSITION.add(size);
-    ptr
-  }
+extern "C" {
+  static __heap_start: u8;
+  static __heap_end: u8;
 }
 
 pub struct MizuAllocator {
-  position: *mut c_void,
+  locked: AtomicBool,
+  heap: UnsafeCell<Heap>,
 }
 
 unsafe impl Sync for MizuAllocator {}
 
+impl MizuAllocator {
+  const fn new() -> Self {
+    Self {
+      locked: AtomicBool::new(false),
+      heap: UnsafeCell::new(Heap::new(map, unmap)),
+    }
+  }
+
+  fn with<R>(&self, f: impl FnOnce(&mut Heap) -> R) -> R {
+    // An interrupt handler that allocates would spin on the lock held by the code it interrupted
+    let status: u64;
+    unsafe { asm!("csrrci {0}, mstatus, {1}", out(reg) status, const MASK_MIE) };
+    while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+      spin_loop();
+    }
+    // SAFETY: The lock is held
+    let heap = unsafe { &mut *self.heap.get() };
+    if !heap.is_initialized() {
+      unsafe { heap.init(addr_of!(__heap_start) as usize, addr_of!(__heap_end) as usize) };
+    }
+    let result = f(heap);
+    self.locked.store(false, Ordering::Release);
+    if status & MASK_MIE != 0 {
+      unsafe { asm!("csrsi mstatus, {0}", const MASK_MIE) };
+    }
+    result
+  }
+}
+
 unsafe impl GlobalAlloc for MizuAllocator {
   unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-    let ptr = POSITION;
-    POSITION = POSITION.add(layout.size());
-    ptr as *mut u8
+    let ptr = self.with(|heap| heap.alloc(layout));
+    if !ptr.is_null() {
+      return ptr;
+    }
+
+    // Give the guest a chance to release memory, then retry once
+    if let Some(hook) = oom_hook() {
+      hook(layout);
+      let ptr = self.with(|heap| heap.alloc(layout));
+      if !ptr.is_null() {
+        return ptr;
+      }
+    }
+    FAILED_SIZE.store(layout.size(), Ordering::Release);
+    ptr::null_mut()
   }
 
-  unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    self.with(|heap| heap.dealloc(ptr, layout));
   }
 }
 
+fn map(size: usize) -> Option<usize> {
+  memory::map(size).ok().map(|region| region as usize)
+}
+
+fn unmap(region: usize) -> bool {
+  unsafe { memory::unmap(region as *mut u8).is_ok() }
+}
+
 #[global_allocator]
-static ALLOCATOR: MizuAllocator = MizuAllocator {
-  position: START
-};
+static ALLOCATOR: MizuAllocator = MizuAllocator::new();
+
+static OOM_HOOK: AtomicUsize = AtomicUsize::new(0);
+/// Size of the last allocation that failed, reported by the panic handler.
+static FAILED_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Called when the heap is exhausted, before the allocation is retried once. If it still fails, the allocation error
+/// panics and the panic handler reports the heap usage.
+pub fn set_oom_hook(hook: fn(Layout)) {
+  OOM_HOOK.store(hook as usize, Ordering::Release);
+}
+
+fn oom_hook() -> Option<fn(Layout)> {
+  match OOM_HOOK.load(Ordering::Acquire) {
+    0 => None,
+    // SAFETY: Only set from a `fn(Layout)`
+    hook => Some(unsafe { core::mem::transmute::<usize, fn(Layout)>(hook) }),
+  }
+}
+
+pub fn heap_stats() -> HeapStats {
+  ALLOCATOR.with(|heap| heap.stats())
+}
+
+/// Size of the last allocation that failed since the previous call.
+pub fn take_failed_allocation() -> Option<usize> {
+  match FAILED_SIZE.swap(0, Ordering::AcqRel) {
+    0 => None,
+    size => Some(size),
+  }
+}
+
+pub fn malloc(size: usize) -> *mut c_void {
+  unsafe { ALLOCATOR.alloc(Layout::from_size_align_unchecked(size.max(1), MIN_BLOCK)) as *mut c_void }
+}
+
+/// Frees memory from [malloc], `size` must be the requested size.
+pub unsafe fn free(ptr: *mut c_void, size: usize) {
+  ALLOCATOR.dealloc(ptr as *mut u8, Layout::from_size_align_unchecked(size.max(1), MIN_BLOCK));
+}
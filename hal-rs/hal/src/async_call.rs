@@ -0,0 +1,87 @@
+use core::arch::asm;
+use core::ptr;
+
+pub use mizu_hal_types::async_call::*;
+use mizu_hal_types::syscall::SYSCALL_ASYNC;
+
+/// Memory the host writes the data of a result to.
+#[repr(C, align(8))]
+pub struct ResultWindow(pub [u8; RESULT_WINDOW_SIZE as usize]);
+
+/// Completion queue shared with the host. Completions are also signalled with interrupt 18.
+#[repr(C)]
+pub struct CompletionQueue<const N: usize> {
+  header: async_queue_t,
+  entries: [async_completion_t; N],
+}
+
+impl<const N: usize> CompletionQueue<N> {
+  pub const fn new() -> Self {
+    Self {
+      header: async_queue_t { head: 0, tail: 0, overflow: 0 },
+      entries: [async_completion_t { ticket: 0, a0: 0, a1: 0 }; N],
+    }
+  }
+
+  /// Registers the queue with the host. The queue must not move while it is registered.
+  pub unsafe fn register(&mut self) -> bool {
+    __async_setup(self as *mut Self as u64, N as u64, 0) == status::OK
+  }
+
+  /// Registers the queue along with a result window per entry, see [RESULT_WINDOW_SIZE]. Neither may move while they
+  /// are registered.
+  pub unsafe fn register_with_results(&mut self, results: &mut [ResultWindow; N]) -> bool {
+    __async_setup(self as *mut Self as u64, N as u64, results.as_mut_ptr() as u64) == status::OK
+  }
+
+  pub fn poll(&mut self) -> Option<async_completion_t> {
+    unsafe {
+      let head = ptr::read_volatile(&self.header.head);
+      let tail = ptr::read_volatile(&self.header.tail);
+      if head == tail {
+        return None;
+      }
+
+      let completion = ptr::read_volatile(&self.entries[(head % N as u64) as usize]);
+      ptr::write_volatile(&mut self.header.head, head.wrapping_add(1));
+      Some(completion)
+    }
+  }
+
+  /// Number of completions dropped by the host because the queue was full.
+  pub fn overflow(&self) -> u64 {
+    unsafe { ptr::read_volatile(&self.header.overflow) }
+  }
+}
+
+pub unsafe fn __async_setup(queue: u64, entries: u64, results: u64) -> u64 {
+  let status: u64;
+  asm!(
+  "ecall",
+  inlateout("a0") action::SETUP => status,
+  in("a1") queue,
+  in("a2") entries,
+  in("a3") results,
+  in("a7") SYSCALL_ASYNC,
+  );
+  status
+}
+
+/// Submits syscall `number` with arguments `a0..=a4` for background execution.
+///
+/// Returns the ticket that will be reported in the completion, or 0 if the call was rejected.
+pub unsafe fn __async_submit(number: u64, args: [u64; 5]) -> u64 {
+  let ticket: u64;
+  asm!(
+  "ecall",
+  inlateout("a0") action::SUBMIT => ticket,
+  in("a1") number,
+  in("a2") args[0],
+  in("a3") args[1],
+  in("a4") args[2],
+  in("a5") args[3],
+  in("a6") args[4],
+  in("a7") SYSCALL_ASYNC,
+  );
+  ticket
+}
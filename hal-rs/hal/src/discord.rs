@@ -1,7 +1,8 @@
-use core::{arch::asm, ffi::c_void, ptr};
+use core::{arch::asm, ffi::c_void, ptr, slice};
 use mizu_hal_discord::prost::Message;
 use mizu_hal_discord::discord::discord_ex_request::DiscordExRequestUnion;
-use mizu_hal_discord::discord::DiscordExRequest;
+use mizu_hal_discord::discord::discord_ex_response::DiscordExResponseUnion;
+use mizu_hal_discord::discord::{DiscordError, DiscordExRequest, DiscordExResponse, ErrorKind};
 use mizu_hal_types::syscall::SYSCALL_DISCORD_EX;
 
 use crate::{syscall, SYSCALL_DISCORD};
@@ -40,20 +41,42 @@ pub fn get_user(user_id: u64) -> discord_user_t {
   }
 }
 
+/// Subscribes to the events in `mask` (see [subscription]), returns the previous mask.
+pub fn set_event_mask(mask: u64) -> u64 {
+  unsafe { discord_syscall(action::SET_EVENT_MASK, mask as *const c_void) as u64 }
+}
+
+/// Sends a request and decodes the response from the host buffer.
+pub fn discord_ex(request: DiscordExRequestUnion) -> Result<DiscordExResponseUnion, DiscordError> {
+  let response = unsafe {
+    let (length, address) = __discord_ex(request);
+    slice::from_raw_parts(address, length)
+  };
+
+  match DiscordExResponse::decode(response).ok().and_then(|response| response.discord_ex_response_union) {
+    Some(DiscordExResponseUnion::Error(error)) => Err(error),
+    Some(response) => Ok(response),
+    None => Err(DiscordError {
+      kind: ErrorKind::Unknown as i32,
+      message: "malformed response".into(),
+      ..Default::default()
+    }),
+  }
+}
 
-pub unsafe fn __discord_ex(request: DiscordExRequestUnion) -> *const c_void {
+/// Returns the length and the address of the encoded `DiscordExResponse`.
+pub unsafe fn __discord_ex(request: DiscordExRequestUnion) -> (usize, *const u8) {
   let mut request = DiscordExRequest { discord_ex_request_union: Some(request) };
   let encoded = request.encode_to_vec();
 
+  let length: usize;
+  let address: *const u8;
   asm!(
-  "",
-  in("a0") encoded.len(),
-  in("a1") encoded.as_ptr()
+  "ecall",
+  inlateout("a0") encoded.len() => length,
+  inlateout("a1") encoded.as_ptr() => address,
+  in("a7") SYSCALL_DISCORD_EX,
   );
-  syscall(SYSCALL_DISCORD_EX);
 
-  let result;
-  asm!("", out("a0") result);
-
-  result
+  (length, address)
 }
@@ -0,0 +1,75 @@
+use core::arch::asm;
+use core::ptr;
+
+pub use mizu_hal_types::hart::*;
+use mizu_hal_types::syscall::SYSCALL_HART;
+use mizu_hwconst::csr::MASK_MSIP;
+use mizu_hwconst::memory::CLINT_BASE;
+
+/// Returns the result and the status code.
+pub unsafe fn hart_syscall(action: u64, hart: u64, pc: u64, opaque: u64) -> (u64, u64) {
+  let result: u64;
+  let status: u64;
+  asm!(
+  "ecall",
+  inlateout("a0") action => result,
+  inlateout("a1") hart => status,
+  in("a2") pc,
+  in("a3") opaque,
+  in("a7") SYSCALL_HART,
+  );
+  (result, status)
+}
+
+pub fn id() -> u16 {
+  unsafe { hart_syscall(action::ID, 0, 0, 0).0 as u16 }
+}
+
+pub fn count() -> u16 {
+  unsafe { hart_syscall(action::COUNT, 0, 0, 0).0 as u16 }
+}
+
+/// One of the [state] values.
+pub fn status(hart: u16) -> Result<u64, u64> {
+  match unsafe { hart_syscall(action::STATUS, hart as u64, 0, 0) } {
+    (state, status::OK) => Ok(state),
+    (_, status) => Err(status),
+  }
+}
+
+/// Stops the calling hart until another hart starts it with [start], which does not return here.
+pub fn stop() -> ! {
+  unsafe { hart_syscall(action::STOP, 0, 0, 0) };
+  loop {}
+}
+
+/// Starts a stopped hart at `entry`, which receives the hart ID and `opaque`. The hart keeps its stack pointer.
+pub fn start(hart: u16, entry: unsafe extern "C" fn(u64, u64) -> !, opaque: u64) -> Result<(), u64> {
+  match unsafe { hart_syscall(action::START, hart as u64, entry as u64, opaque) } {
+    (_, status::OK) => Ok(()),
+    (_, status) => Err(status),
+  }
+}
+
+/// Raises a machine software interrupt on `hart` through the CLINT, missing harts are ignored.
+pub fn send_ipi(hart: u16) {
+  unsafe { ptr::write_volatile((CLINT_BASE + 4 * hart as u64) as *mut u32, 1) };
+}
+
+/// Withdraws a software interrupt that `hart` did not take yet.
+pub fn clear_ipi(hart: u16) {
+  unsafe { ptr::write_volatile((CLINT_BASE + 4 * hart as u64) as *mut u32, 0) };
+}
+
+pub fn is_ipi_pending(hart: u16) -> bool {
+  unsafe { ptr::read_volatile((CLINT_BASE + 4 * hart as u64) as *const u32) != 0 }
+}
+
+/// Lets software interrupts from the CLINT be taken and wake the hart from `wfi`.
+pub unsafe fn __enable_software_interrupt() {
+  asm!("csrs mie, {0}", in(reg) MASK_MSIP);
+}
+
+pub unsafe fn __disable_software_interrupt() {
+  asm!("csrc mie, {0}", in(reg) MASK_MSIP);
+}
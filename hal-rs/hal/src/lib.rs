@@ -14,6 +14,12 @@ pub mod power;
 pub mod time;
 pub mod device;
 pub mod perf;
+pub mod async_call;
+pub mod ring;
+pub mod object_storage;
+pub mod memory;
+pub mod hart;
+pub mod mailbox;
 
 pub use mizu_hal_types as types;
 pub use mini_backtrace as mini_backtrace;
@@ -0,0 +1,50 @@
+use core::arch::asm;
+
+pub use mizu_hal_types::mailbox::*;
+use mizu_hal_types::syscall::SYSCALL_MAILBOX;
+
+/// Returns the result, the status code and the sender for the receive actions.
+pub unsafe fn mailbox_syscall(action: u64, a1: u64, a2: u64, a3: u64) -> (u64, u64, u64) {
+  let result: u64;
+  let status: u64;
+  let sender: u64;
+  asm!(
+  "ecall",
+  inlateout("a0") action => result,
+  inlateout("a1") a1 => status,
+  inlateout("a2") a2 => sender,
+  in("a3") a3,
+  in("a7") SYSCALL_MAILBOX,
+  );
+  (result, status, sender)
+}
+
+/// Queues `message` in the mailbox of `hart`, up to [MAX_MESSAGE_SIZE] bytes.
+pub fn send(hart: u16, message: &[u8]) -> Result<(), u64> {
+  match unsafe { mailbox_syscall(action::SEND, hart as u64, message.as_ptr() as u64, message.len() as u64) } {
+    (_, status::OK, _) => Ok(()),
+    (_, status, _) => Err(status),
+  }
+}
+
+/// Returns the size of the message and the sending hart, or [status::EMPTY] if there is none.
+pub fn try_receive(buffer: &mut [u8]) -> Result<(usize, u16), u64> {
+  receive_with(action::TRY_RECEIVE, buffer)
+}
+
+/// Waits for a message, returns its size and the sending hart.
+pub fn receive(buffer: &mut [u8]) -> Result<(usize, u16), u64> {
+  loop {
+    match receive_with(action::RECEIVE, buffer) {
+      Err(status::EMPTY) => continue,
+      result => return result,
+    }
+  }
+}
+
+fn receive_with(action: u64, buffer: &mut [u8]) -> Result<(usize, u16), u64> {
+  match unsafe { mailbox_syscall(action, buffer.as_mut_ptr() as u64, buffer.len() as u64, 0) } {
+    (size, status::OK, sender) => Ok((size as usize, sender as u16)),
+    (_, status, _) => Err(status),
+  }
+}
@@ -0,0 +1,41 @@
+use core::arch::asm;
+
+pub use mizu_hal_types::memory::*;
+use mizu_hal_types::syscall::SYSCALL_MEMORY;
+
+/// Returns the result, the status code and the limit for [action::QUERY].
+pub unsafe fn memory_syscall(action: u64, argument: u64) -> (u64, u64, u64) {
+  let result: u64;
+  let status: u64;
+  let limit: u64;
+  asm!(
+  "ecall",
+  inlateout("a0") action => result,
+  inlateout("a1") argument => status,
+  lateout("a2") limit,
+  in("a7") SYSCALL_MEMORY,
+  );
+  (result, status, limit)
+}
+
+/// Maps a zeroed region of at least `size` bytes, rounded up to [PAGE_SIZE].
+pub fn map(size: usize) -> Result<*mut u8, u64> {
+  match unsafe { memory_syscall(action::MAP, size as u64) } {
+    (address, status::OK, _) => Ok(address as *mut u8),
+    (_, status, _) => Err(status),
+  }
+}
+
+/// `address` must be the start of a mapped region, which must not be used afterwards.
+pub unsafe fn unmap(address: *mut u8) -> Result<(), u64> {
+  match memory_syscall(action::UNMAP, address as u64) {
+    (_, status::OK, _) => Ok(()),
+    (_, status, _) => Err(status),
+  }
+}
+
+/// Bytes mapped by the isolate and its limit.
+pub fn usage() -> (u64, u64) {
+  let (mapped, _, limit) = unsafe { memory_syscall(action::QUERY, 0) };
+  (mapped, limit)
+}
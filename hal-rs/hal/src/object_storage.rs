@@ -0,0 +1,132 @@
+use core::arch::asm;
+use core::ffi::{c_char, c_void, CStr};
+use core::{ptr, slice};
+
+pub use mizu_hal_types::object_storage::*;
+use mizu_hal_types::syscall::SYSCALL_OBJECT_STORAGE;
+use mizu_hal_types::StringPtr;
+
+/// Returns the result pointer and the status code.
+pub unsafe fn object_storage_syscall(action: u64, request: *const c_void) -> (*const c_void, u64) {
+  let result: *const c_void;
+  let status: u64;
+  asm!(
+  "ecall",
+  inlateout("a0") action => result,
+  inlateout("a1") request => status,
+  in("a7") SYSCALL_OBJECT_STORAGE,
+  );
+  (result, status)
+}
+
+unsafe fn call<T, R: Copy>(action: u64, request: &T) -> Result<R, u64> {
+  let (result, status) = object_storage_syscall(action, request as *const T as *const c_void);
+  if status != status::OK {
+    return Err(status);
+  }
+  Ok(ptr::read(result as *const R))
+}
+
+fn key_ptr(key: &CStr) -> StringPtr {
+  StringPtr(key.as_ptr())
+}
+
+fn item(value: &[u8]) -> object_storage_item_t {
+  object_storage_item_t {
+    length: value.len() as u64,
+    data: value.as_ptr() as *const c_char,
+  }
+}
+
+/// The returned slice points into the host buffer and is overwritten by the next host call.
+pub fn get(key: &CStr) -> Result<&'static [u8], u64> {
+  let request = object_storage_get_t { key: key_ptr(key) };
+  unsafe {
+    let (result, status) = object_storage_syscall(action::GET, &request as *const _ as *const c_void);
+    if status != status::OK {
+      return Err(status);
+    }
+    let item = &*(result as *const object_storage_item_t);
+    Ok(slice::from_raw_parts(item.data as *const u8, item.length as usize))
+  }
+}
+
+pub fn put(key: &CStr, value: &[u8]) -> Result<object_storage_metadata_t, u64> {
+  let request = object_storage_put_t { key: key_ptr(key), item: item(value) };
+  unsafe { call(action::PUT, &request) }
+}
+
+pub fn delete(key: &CStr) -> Result<(), u64> {
+  let request = object_storage_get_t { key: key_ptr(key) };
+  unsafe {
+    let (_, status) = object_storage_syscall(action::DELETE, &request as *const _ as *const c_void);
+    if status != status::OK {
+      return Err(status);
+    }
+  }
+  Ok(())
+}
+
+pub fn stat(key: &CStr) -> Result<object_storage_metadata_t, u64> {
+  let request = object_storage_get_t { key: key_ptr(key) };
+  unsafe { call(action::STAT, &request) }
+}
+
+/// Writes `value` only if the current version is `expected_version`, 0 if the object must not exist yet.
+pub fn compare_and_swap(key: &CStr, expected_version: u64, value: &[u8], ttl_ms: u64) -> Result<object_storage_metadata_t, u64> {
+  let request = object_storage_cas_t {
+    key: key_ptr(key),
+    expected_version,
+    item: item(value),
+    ttl_ms,
+  };
+  unsafe { call(action::COMPARE_AND_SWAP, &request) }
+}
+
+pub fn increment(key: &CStr, delta: i64) -> Result<object_storage_counter_t, u64> {
+  let request = object_storage_increment_t { key: key_ptr(key), delta };
+  unsafe { call(action::INCREMENT, &request) }
+}
+
+/// Sets the time to live of an existing object, 0 removes the expiry.
+pub fn expire(key: &CStr, ttl_ms: u64) -> Result<object_storage_metadata_t, u64> {
+  let request = object_storage_expire_t { key: key_ptr(key), ttl_ms };
+  unsafe { call(action::EXPIRE, &request) }
+}
+
+/// Lists up to `limit` keys starting with `prefix`, continuing after `cursor`.
+///
+/// Keys point into the host buffer and are overwritten by the next host call.
+pub fn list(prefix: &CStr, cursor: Option<&CStr>, limit: u64) -> Result<ListPage, u64> {
+  let request = object_storage_list_t {
+    prefix: key_ptr(prefix),
+    cursor: StringPtr(cursor.map_or(ptr::null(), |cursor| cursor.as_ptr())),
+    limit,
+  };
+  unsafe {
+    let (result, status) = object_storage_syscall(action::LIST, &request as *const _ as *const c_void);
+    if status != status::OK {
+      return Err(status);
+    }
+    let result = &*(result as *const object_storage_list_result_t);
+    Ok(ListPage {
+      keys: slice::from_raw_parts(result.keys, result.count as usize),
+      next_cursor: if result.next_cursor.is_null() {
+        None
+      } else {
+        Some(CStr::from_ptr(result.next_cursor.0))
+      },
+    })
+  }
+}
+
+pub struct ListPage {
+  pub keys: &'static [StringPtr],
+  pub next_cursor: Option<&'static CStr>,
+}
+
+impl ListPage {
+  pub fn keys(&self) -> impl Iterator<Item = &'static CStr> {
+    self.keys.iter().map(|key| unsafe { CStr::from_ptr(key.0) })
+  }
+}
@@ -8,6 +8,17 @@ fn panic(info: &PanicInfo) -> ! {
   let mut writer = Writer::new();
   writer.write_fmt(format_args!("{}", info)).unwrap();
 
+  if let Some(size) = crate::alloc::take_failed_allocation() {
+    let stats = crate::alloc::heap_stats();
+    writer.write_fmt(format_args!(
+      "\nout of memory: allocating {} bytes failed, {} of {} heap bytes in use (peak {})",
+      size,
+      stats.used,
+      stats.size(),
+      stats.peak
+    )).unwrap();
+  }
+
   #[cfg(feature = "backtrace")]
   {
     use mini_backtrace::Backtrace;
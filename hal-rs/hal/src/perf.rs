@@ -1,5 +1,8 @@
 use core::arch::asm;
 
+use mizu_hwconst::csr::{machine, unprivileged, MCOUNTEREN, MCOUNTINHIBIT};
+pub use mizu_hwconst::csr::hpm_event;
+
 pub unsafe fn __minstret() -> u64 {
   let instructions_retired: u64;
   asm!(
@@ -8,3 +11,90 @@ pub unsafe fn __minstret() -> u64 {
   );
   instructions_retired
 }
+
+/// Executed instructions, including ones that trapped. Requires `mcounteren` bit 0.
+pub fn cycles() -> u64 {
+  let cycles: u64;
+  unsafe {
+    asm!(
+    "rdcycle {0}",
+    out(reg) cycles
+    );
+  }
+  cycles
+}
+
+/// Requires `mcounteren` bit 2.
+pub fn instructions_retired() -> u64 {
+  let instructions_retired: u64;
+  unsafe {
+    asm!(
+    "rdinstret {0}",
+    out(reg) instructions_retired
+    );
+  }
+  instructions_retired
+}
+
+/// Reads `hpmcounterN`, N is in 3..=31. Requires `mcounteren` bit N.
+pub fn hpm_counter<const N: usize>() -> u64 {
+  const { assert!(N >= 3 && N <= 31) };
+  let value: u64;
+  unsafe {
+    asm!(
+    "csrr {0}, {1}",
+    out(reg) value,
+    const unprivileged::HPMCOUNTER3 + N - 3
+    );
+  }
+  value
+}
+
+/// Selects the [hpm_event] counted by `mhpmcounterN`, the current count is kept.
+pub unsafe fn __set_hpm_event<const N: usize>(event: u64) {
+  const { assert!(N >= 3 && N <= 31) };
+  asm!(
+  "csrw {0}, {1}",
+  const machine::HPMEVENT3 + N - 3,
+  in(reg) event
+  );
+}
+
+pub unsafe fn __set_hpm_counter<const N: usize>(value: u64) {
+  const { assert!(N >= 3 && N <= 31) };
+  asm!(
+  "csrw {0}, {1}",
+  const machine::HPMCOUNTER3 + N - 3,
+  in(reg) value
+  );
+}
+
+/// Bit N enables reading counter N through the unprivileged CSRs below M-mode, all are enabled at reset.
+pub unsafe fn __set_counter_enable(mask: u32) {
+  asm!(
+  "csrw {0}, {1}",
+  const MCOUNTEREN,
+  in(reg) mask as u64
+  );
+}
+
+/// Bit N stops counter N, its value is kept until it is enabled again.
+pub unsafe fn __set_counter_inhibit(mask: u32) {
+  asm!(
+  "csrw {0}, {1}",
+  const MCOUNTINHIBIT,
+  in(reg) mask as u64
+  );
+}
+
+/// Counts `event` on `mhpmcounterN` while running `f`.
+pub fn count<const N: usize, R>(event: u64, f: impl FnOnce() -> R) -> (R, u64) {
+  unsafe {
+    __set_hpm_event::<N>(event);
+    __set_hpm_counter::<N>(0);
+  }
+  let result = f();
+  let count = hpm_counter::<N>();
+  unsafe { __set_hpm_event::<N>(hpm_event::NONE) };
+  (result, count)
+}
@@ -1,9 +1,31 @@
 use core::arch::asm;
 
-pub const POWERSTATE_DEFAULT: u64 = 1;
-pub const POWERSTATE_BYPASS: u64 = 2;
-pub const POWERSTATE_LOW_PRIORITY: u64 = 3;
+use mizu_hwconst::csr::power_state;
 
+pub const POWERSTATE_DEFAULT: u64 = power_state::DEFAULT;
+/// Only applied if the host grants it.
+pub const POWERSTATE_BYPASS: u64 = power_state::BYPASS;
+pub const POWERSTATE_LOW_PRIORITY: u64 = power_state::LOW_PRIORITY;
+pub const POWERSTATE_DEEP_SLEEP: u64 = power_state::DEEP_SLEEP;
+
+/// Unknown or denied states are ignored, read the state back with [__get_power_state] to check.
 pub unsafe fn __set_power_state(state: u64) {
   asm!("csrrw zero, 0x7C0, t0", in("t0") state);
 }
+
+pub fn __get_power_state() -> u64 {
+  let state: u64;
+  unsafe {
+    asm!(
+    "csrr {0}, 0x7C0",
+    out(reg) state
+    );
+  }
+  state
+}
+
+/// Waits for an interrupt and returns in [POWERSTATE_DEFAULT]. The host may write the memory to disk meanwhile,
+/// which makes the first access after waking up slow.
+pub unsafe fn __deep_sleep() {
+  __set_power_state(POWERSTATE_DEEP_SLEEP);
+}
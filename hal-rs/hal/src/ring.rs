@@ -0,0 +1,105 @@
+use core::arch::asm;
+use core::ptr;
+
+use mizu_hal_types::async_call::async_queue_t;
+pub use mizu_hal_types::ring::*;
+use mizu_hal_types::syscall::SYSCALL_RING;
+
+use crate::async_call::ResultWindow;
+
+pub const DOORBELL: *mut u64 = 0x13000 as *mut u64;
+
+#[repr(C)]
+struct Queue<T, const N: usize> {
+  header: async_queue_t,
+  entries: [T; N],
+}
+
+/// Submission and completion rings shared with the host.
+///
+/// Submissions are executed when the doorbell is rung, completions are signalled with interrupt 18.
+#[repr(C)]
+pub struct Ring<const S: usize, const C: usize> {
+  sq: Queue<ring_sqe_t, S>,
+  cq: Queue<ring_cqe_t, C>,
+}
+
+impl<const S: usize, const C: usize> Ring<S, C> {
+  pub const fn new() -> Self {
+    Self {
+      sq: Queue {
+        header: async_queue_t { head: 0, tail: 0, overflow: 0 },
+        entries: [ring_sqe_t { user_data: 0, syscall: 0, args: [0; 5] }; S],
+      },
+      cq: Queue {
+        header: async_queue_t { head: 0, tail: 0, overflow: 0 },
+        entries: [ring_cqe_t { ticket: 0, a0: 0, a1: 0 }; C],
+      },
+    }
+  }
+
+  /// Registers the rings with the host. The rings must not move while they are registered.
+  pub unsafe fn register(&mut self) -> bool {
+    self.setup(0)
+  }
+
+  /// Registers the rings along with a result window per completion entry. Neither may move while they are registered.
+  pub unsafe fn register_with_results(&mut self, results: &mut [ResultWindow; C]) -> bool {
+    self.setup(results.as_mut_ptr() as u64)
+  }
+
+  unsafe fn setup(&mut self, results: u64) -> bool {
+    let setup = ring_setup_t {
+      sq_address: &self.sq as *const _ as u64,
+      sq_entries: S as u64,
+      cq_address: &self.cq as *const _ as u64,
+      cq_entries: C as u64,
+      results,
+    };
+    __ring_setup(&setup) == status::OK
+  }
+
+  /// Queues a submission without notifying the host. Returns `false` if the submission queue is full.
+  pub fn push(&mut self, sqe: ring_sqe_t) -> bool {
+    unsafe {
+      let head = ptr::read_volatile(&self.sq.header.head);
+      let tail = ptr::read_volatile(&self.sq.header.tail);
+      if tail.wrapping_sub(head) >= S as u64 {
+        return false;
+      }
+
+      ptr::write_volatile(&mut self.sq.entries[(tail % S as u64) as usize], sqe);
+      ptr::write_volatile(&mut self.sq.header.tail, tail.wrapping_add(1));
+    }
+    true
+  }
+
+  /// Notifies the host that new submissions are available.
+  pub fn submit(&self) {
+    unsafe { ptr::write_volatile(DOORBELL, 1) };
+  }
+
+  pub fn poll(&mut self) -> Option<ring_cqe_t> {
+    unsafe {
+      let head = ptr::read_volatile(&self.cq.header.head);
+      let tail = ptr::read_volatile(&self.cq.header.tail);
+      if head == tail {
+        return None;
+      }
+
+      let completion = ptr::read_volatile(&self.cq.entries[(head % C as u64) as usize]);
+      ptr::write_volatile(&mut self.cq.header.head, head.wrapping_add(1));
+      Some(completion)
+    }
+  }
+}
+
+pub unsafe fn __ring_setup(setup: *const ring_setup_t) -> u64 {
+  let result: u64;
+  asm!(
+  "ecall",
+  inlateout("a0") setup as u64 => result,
+  in("a7") SYSCALL_RING,
+  );
+  result
+}
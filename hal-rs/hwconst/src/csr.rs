@@ -19,6 +19,8 @@ pub const MIE: usize = 0x304;
 pub const MTVEC: usize = 0x305;
 /// Machine counter enable.
 pub const MCOUNTEREN: usize = 0x306;
+/// Machine counter-inhibit register.
+pub const MCOUNTINHIBIT: usize = 0x320;
 /// Scratch register for machine trap handlers.
 pub const MSCRATCH: usize = 0x340;
 /// Machine exception program counter.
@@ -52,8 +54,16 @@ pub const SATP: usize = 0x180;
 
 /// Unprivileged-level CSRs.
 pub mod unprivileged {
+  /// Cycle counter for RDCYCLE instruction.
+  pub const CYCLE: usize = 0xC00;
   /// Timer for RDTIME instruction.
   pub const TIME: usize = 0xC01;
+  /// Instructions-retired counter for RDINSTRET instruction.
+  pub const INSTRET: usize = 0xC02;
+  /// First performance-monitoring counter, `hpmcounter4` to `hpmcounter31` follow.
+  pub const HPMCOUNTER3: usize = 0xC03;
+  /// Last performance-monitoring counter.
+  pub const HPMCOUNTER31: usize = 0xC1F;
 }
 
 // Machine-level CSRs.
@@ -65,10 +75,58 @@ pub mod machine {
   /// Hart power state. Non-standard.
   pub const POWERSTATE: usize = 0x7c0;
 
+  /// Machine cycle counter.
+  pub const CYCLE: usize = 0xB00;
   /// Machine instructions-retired counter.
   pub const INSTRET: usize = 0xB02;
+  /// First machine performance-monitoring counter, `mhpmcounter4` to `mhpmcounter31` follow.
+  pub const HPMCOUNTER3: usize = 0xB03;
+  /// Last machine performance-monitoring counter.
+  pub const HPMCOUNTER31: usize = 0xB1F;
+  /// Event selector of `mhpmcounter3`, `mhpmevent4` to `mhpmevent31` follow.
+  pub const HPMEVENT3: usize = 0x323;
+  /// Event selector of `mhpmcounter31`.
+  pub const HPMEVENT31: usize = 0x33F;
 }
 
+/// Values of [machine::POWERSTATE].
+pub mod power_state {
+  /// Limited to the CPU time limit between yields.
+  pub const DEFAULT: u64 = 1;
+  /// Not time limited, ignored unless the host grants it.
+  pub const BYPASS: u64 = 2;
+  /// Scheduled for a smaller share of each slice.
+  pub const LOW_PRIORITY: u64 = 3;
+  /// Waits for an interrupt and resumes in [DEFAULT], the host may write the memory to disk meanwhile.
+  pub const DEEP_SLEEP: u64 = 4;
+}
+
+/// Privilege modes, as encoded in `mstatus.MPP`.
+pub mod privilege {
+  pub const USER: u64 = 0b00;
+  pub const SUPERVISOR: u64 = 0b01;
+  pub const MACHINE: u64 = 0b11;
+}
+
+/// Events counted by the performance-monitoring counters, selected by writing them to `mhpmeventN`.
+pub mod hpm_event {
+  /// The counter does not count.
+  pub const NONE: u64 = 0;
+  /// Memory loads, including floating-point and atomic ones.
+  pub const LOADS: u64 = 1;
+  /// Memory stores, including floating-point and atomic ones.
+  pub const STORES: u64 = 2;
+  /// Conditional branches that were taken.
+  pub const BRANCHES_TAKEN: u64 = 3;
+  /// Exceptions and interrupts.
+  pub const TRAPS: u64 = 4;
+  /// Executed `ecall` instructions.
+  pub const SYSCALLS: u64 = 5;
+}
+
+/// Number of counters, `cycle`, `time`, `instret` and `hpmcounter3` to `hpmcounter31`.
+pub const NUM_COUNTERS: usize = 32;
+
 // mstatus and sstatus field mask
 pub const MASK_SIE: u64 = 1 << 1;
 pub const MASK_MIE: u64 = 1 << 3;
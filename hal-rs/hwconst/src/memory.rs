@@ -3,12 +3,20 @@ macro_rules! memory_segment {
     paste::paste! {
       pub const [<$name _BASE>]: u64 = $start;
       pub const [<$name _SIZE>]: u64 = $size;
-      pub const [<$name _END>]: u64 = [<$name _BASE>] + [<$name _SIZE>] - 1;
+      pub const [<$name _END>]: u64 = [<$name _BASE>] + ([<$name _SIZE>] - 1);
     }
   };
 }
 
 memory_segment!(DRAM, 0xffffffff_80000000, 1024 * 1024 * 128);
 memory_segment!(HARDWARE, 0x20000, 0x20000);
+// Part of the hardware area that host calls write their results to, relative to its base
+pub const RESULTS_OFFSET: u64 = 0x6000;
+pub const RESULTS_SIZE: u64 = HARDWARE_SIZE - RESULTS_OFFSET;
 memory_segment!(CPUID, 0x10000, 0x100);
 memory_segment!(RANDOM, 0x12000, 0x100);
+memory_segment!(DOORBELL, 0x13000, 0x100);
+// Regions mapped on demand by `SYSCALL_MEMORY`
+memory_segment!(MAPPED, 0xffffffff_c0000000, 1024 * 1024 * 1024);
+// Core-local interruptor, the `msip` register of hart N is the 32-bit word at offset `4 * N`
+memory_segment!(CLINT, 0x2000000, 0x4000);
@@ -0,0 +1,37 @@
+pub mod action {
+  /// Registers the completion queue: `a1` = queue address, `a2` = number of entries, `a3` = address of `a2` result
+  /// windows of [RESULT_WINDOW_SIZE](super::RESULT_WINDOW_SIZE) bytes or 0. Returns a [status](super::status) in `a0`.
+  pub const SETUP: u64 = 0;
+  /// Submits a host call: `a1` = syscall number, `a2..=a6` = arguments passed as `a0..=a4`. Returns the ticket in `a0`.
+  pub const SUBMIT: u64 = 1;
+}
+
+pub mod status {
+  pub const OK: u64 = 0;
+  /// The queue or the result windows are not in guest memory.
+  pub const INVALID_ADDRESS: u64 = 1;
+}
+
+/// Size of a result window. A call that completes into a window writes the data its result points to there, it stays
+/// valid until the completion is consumed.
+pub const RESULT_WINDOW_SIZE: u64 = 0x1a000;
+
+/// Completion queue header, followed by `entries` of [async_completion_t] in guest memory.
+///
+/// The host only writes `tail` and `overflow`, the guest only writes `head`.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct async_queue_t {
+  pub head: u64,
+  pub tail: u64,
+  /// Number of completions dropped because the queue was full.
+  pub overflow: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct async_completion_t {
+  pub ticket: u64,
+  pub a0: u64,
+  pub a1: u64,
+}
@@ -10,6 +10,61 @@ pub mod action {
   pub const CREATE_MESSAGE: u64 = 1;
   pub const CREATE_REACTION: u64 = 2;
   pub const GET_USER: u64 = 3;
+  /// Sets the [subscription] mask from `a1`, returns the previous mask.
+  pub const SET_EVENT_MASK: u64 = 4;
+  /// Waits for the next message from a user in the guild.
+  pub const POLL_MESSAGE: u64 = 10;
+}
+
+/// Status codes returned in `a1` by the calls above, `a0` is null unless the call succeeded.
+pub mod status {
+  pub const OK: u64 = 0;
+  /// The guest may not call the [operation](super::operation) whose code is in `a2`.
+  pub const DENIED: u64 = 1;
+  /// The guest may not act in the channel whose ID is in `a2`.
+  pub const CHANNEL_DENIED: u64 = 2;
+  pub const INVALID_ARGUMENT: u64 = 3;
+  /// The frontend has no equivalent for the call.
+  pub const UNAVAILABLE: u64 = 4;
+  /// The platform failed the request.
+  pub const FAILED: u64 = 5;
+}
+
+/// Operations the host may allow or deny a guest, reported along with [status::DENIED].
+pub mod operation {
+  pub const SEND_MESSAGE: u64 = 1;
+  pub const EDIT_MESSAGE: u64 = 2;
+  pub const DELETE_MESSAGE: u64 = 3;
+  pub const READ_MESSAGES: u64 = 4;
+  pub const PINS: u64 = 5;
+  pub const REACT: u64 = 6;
+  pub const THREADS: u64 = 7;
+  pub const TYPING: u64 = 8;
+  pub const READ_CHANNEL: u64 = 9;
+  pub const READ_GUILD: u64 = 10;
+  pub const READ_MEMBERS: u64 = 11;
+  pub const READ_USERS: u64 = 12;
+  pub const MANAGE_ROLES: u64 = 13;
+  pub const COMMANDS: u64 = 14;
+  pub const INTERACTIONS: u64 = 15;
+}
+
+/// Bits of the event subscription mask, only subscribed events raise interrupt 17.
+pub mod subscription {
+  pub const MESSAGE_CREATE: u64 = 1 << 0;
+  pub const REACTION_ADD: u64 = 1 << 1;
+  pub const MESSAGE_UPDATE: u64 = 1 << 2;
+  pub const MESSAGE_DELETE: u64 = 1 << 3;
+  pub const REACTION_REMOVE: u64 = 1 << 4;
+  /// Member events are only delivered if the host enabled the privileged members intent.
+  pub const MEMBER_ADD: u64 = 1 << 5;
+  pub const MEMBER_REMOVE: u64 = 1 << 6;
+  pub const THREAD_CREATE: u64 = 1 << 7;
+  pub const INTERACTION_CREATE: u64 = 1 << 8;
+
+  /// Mask of a freshly loaded isolate.
+  pub const DEFAULT: u64 = MESSAGE_CREATE | REACTION_ADD;
+  pub const ALL: u64 = (INTERACTION_CREATE << 1) - 1;
 }
 
 #[repr(C)]
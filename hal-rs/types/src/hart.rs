@@ -0,0 +1,30 @@
+pub mod action {
+  /// Returns the ID of the calling hart in `a0`.
+  pub const ID: u64 = 1;
+  /// Returns the number of harts in `a0`.
+  pub const COUNT: u64 = 2;
+  /// Returns the [state](super::state) of hart `a1` in `a0`.
+  pub const STATUS: u64 = 3;
+  /// Stops the calling hart. It only returns once another hart starts it, at the address given to [START].
+  pub const STOP: u64 = 4;
+  /// Starts the stopped hart `a1` at `a2`, with its ID in `a0` and `a3` in `a1`.
+  pub const START: u64 = 5;
+}
+
+/// Hart states, numbered as in the SBI hart state management extension.
+pub mod state {
+  pub const STARTED: u64 = 0;
+  /// Stopped with [action::STOP](super::action::STOP), or ended by a halt or a fault.
+  pub const STOPPED: u64 = 1;
+  /// Waiting for an interrupt.
+  pub const SUSPENDED: u64 = 4;
+}
+
+/// Status codes returned in `a1` by `SYSCALL_HART`.
+pub mod status {
+  pub const OK: u64 = 0;
+  pub const INVALID_HART: u64 = 1;
+  /// The hart is not stopped, or has ended and cannot be started again.
+  pub const ALREADY_STARTED: u64 = 2;
+  pub const INVALID_ARGUMENT: u64 = 3;
+}
@@ -0,0 +1,455 @@
+//! Heap of the guest allocator, independent of the target so the host can test it.
+//!
+//! Allocations up to [MAX_SMALL] bytes are served from power-of-two size classes, larger ones from an address-ordered
+//! first-fit free list that coalesces on free. Memory never handed out before is carved from the top of the heap, and
+//! once that is exhausted the heap grows by regions from its map function, at least [GROW_SIZE] bytes at a time. A
+//! region is unmapped again once all of it is free.
+
+use core::alloc::Layout;
+use core::ptr;
+
+use crate::memory::PAGE_SIZE;
+
+/// Smallest block, every block is aligned to it.
+pub const MIN_BLOCK: usize = 16;
+/// Largest size class.
+pub const MAX_SMALL: usize = 2048;
+const CLASSES: usize = (MAX_SMALL.trailing_zeros() - MIN_BLOCK.trailing_zeros() + 1) as usize;
+/// Smallest region mapped from the host.
+pub const GROW_SIZE: usize = 1024 * 1024;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+  pub start: usize,
+  pub end: usize,
+  /// Bytes mapped from the host in addition to `start..end`.
+  pub mapped: usize,
+  /// Bytes handed out, rounded up to their block sizes.
+  pub used: usize,
+  pub peak: usize,
+}
+
+impl HeapStats {
+  pub fn size(&self) -> usize {
+    self.end - self.start + self.mapped
+  }
+}
+
+struct FreeBlock {
+  size: usize,
+  next: *mut FreeBlock,
+}
+
+/// Header of a mapped region, its free blocks start after it so they never merge with another region.
+struct Region {
+  size: usize,
+  next: *mut Region,
+}
+
+pub struct Heap {
+  start: usize,
+  end: usize,
+  /// Start of the never allocated part of the heap.
+  top: usize,
+  classes: [*mut FreeBlock; CLASSES],
+  /// Free blocks outside of the size classes, ordered by address.
+  large: *mut FreeBlock,
+  /// Mapped regions, ordered by address.
+  regions: *mut Region,
+  /// Maps a zeroed region of the given size, a multiple of [PAGE_SIZE].
+  map: fn(usize) -> Option<usize>,
+  /// Unmaps a region returned by `map`.
+  unmap: fn(usize) -> bool,
+  mapped: usize,
+  used: usize,
+  peak: usize,
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+  (value + align - 1) & !(align - 1)
+}
+
+/// Size class of a layout, [None] for large allocations.
+fn class(layout: Layout) -> Option<usize> {
+  let size = layout.size().max(layout.align()).max(MIN_BLOCK).next_power_of_two();
+  if size > MAX_SMALL {
+    return None;
+  }
+  Some((size.trailing_zeros() - MIN_BLOCK.trailing_zeros()) as usize)
+}
+
+impl Heap {
+  /// An empty heap that only grows through `map` until [Heap::init].
+  pub const fn new(map: fn(usize) -> Option<usize>, unmap: fn(usize) -> bool) -> Self {
+    Self {
+      start: 0,
+      end: 0,
+      top: 0,
+      classes: [ptr::null_mut(); CLASSES],
+      large: ptr::null_mut(),
+      regions: ptr::null_mut(),
+      map,
+      unmap,
+      mapped: 0,
+      used: 0,
+      peak: 0,
+    }
+  }
+
+  pub fn is_initialized(&self) -> bool {
+    self.end != 0
+  }
+
+  /// Hands out `start..end`.
+  ///
+  /// # Safety
+  /// The range must be writable and unused for the lifetime of the heap.
+  pub unsafe fn init(&mut self, start: usize, end: usize) {
+    self.start = align_up(start, MIN_BLOCK);
+    self.end = end;
+    self.top = self.start;
+  }
+
+  /// # Safety
+  /// See [GlobalAlloc::alloc](core::alloc::GlobalAlloc::alloc).
+  pub unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+    let (ptr, size) = match class(layout) {
+      Some(class) => {
+        let size = MIN_BLOCK << class;
+        let head = self.classes[class];
+        if head.is_null() {
+          (self.alloc_large(size, size), size)
+        } else {
+          self.classes[class] = (*head).next;
+          (head as *mut u8, size)
+        }
+      }
+      None => {
+        let size = align_up(layout.size(), MIN_BLOCK);
+        (self.alloc_large(size, layout.align().max(MIN_BLOCK)), size)
+      }
+    };
+    if !ptr.is_null() {
+      self.used += size;
+      self.peak = self.peak.max(self.used);
+    }
+    ptr
+  }
+
+  /// # Safety
+  /// See [GlobalAlloc::dealloc](core::alloc::GlobalAlloc::dealloc).
+  pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+    match class(layout) {
+      Some(class) => {
+        let block = ptr as *mut FreeBlock;
+        (*block).next = self.classes[class];
+        self.classes[class] = block;
+        self.used -= MIN_BLOCK << class;
+      }
+      None => {
+        let size = align_up(layout.size(), MIN_BLOCK);
+        let block = self.free_large(ptr as usize, size);
+        if !self.release(block) {
+          self.trim();
+        }
+        self.used -= size;
+      }
+    }
+  }
+
+  unsafe fn alloc_large(&mut self, size: usize, align: usize) -> *mut u8 {
+    let ptr = self.first_fit(size, align);
+    if !ptr.is_null() {
+      return ptr;
+    }
+    let ptr = self.bump(size, align);
+    if !ptr.is_null() || !self.grow(size + align + MIN_BLOCK) {
+      return ptr;
+    }
+    self.first_fit(size, align)
+  }
+
+  /// Maps another region into the large free list.
+  unsafe fn grow(&mut self, size: usize) -> bool {
+    let size = align_up(size.max(GROW_SIZE), PAGE_SIZE as usize);
+    let Some(address) = (self.map)(size) else {
+      return false;
+    };
+    let mut link: *mut *mut Region = &mut self.regions;
+    while !(*link).is_null() && (*link as usize) < address {
+      link = &mut (**link).next;
+    }
+    let region = address as *mut Region;
+    region.write(Region { size, next: *link });
+    *link = region;
+
+    self.free_large(address + MIN_BLOCK, size - MIN_BLOCK);
+    self.mapped += size;
+    true
+  }
+
+  /// Unmaps the region of a free block if the block covers all of it.
+  unsafe fn release(&mut self, block: *mut FreeBlock) -> bool {
+    let address = block as usize - MIN_BLOCK;
+    let mut link: *mut *mut Region = &mut self.regions;
+    while !(*link).is_null() && (*link as usize) < address {
+      link = &mut (**link).next;
+    }
+    let region = *link;
+    if region as usize != address || (*region).size != (*block).size + MIN_BLOCK {
+      return false;
+    }
+
+    let mut free: *mut *mut FreeBlock = &mut self.large;
+    while *free != block {
+      free = &mut (**free).next;
+    }
+    let size = (*region).size;
+    if !(self.unmap)(address) {
+      return false;
+    }
+    *free = (*block).next;
+    *link = (*region).next;
+    self.mapped -= size;
+    true
+  }
+
+  unsafe fn first_fit(&mut self, size: usize, align: usize) -> *mut u8 {
+    let mut link: *mut *mut FreeBlock = &mut self.large;
+    while !(*link).is_null() {
+      let block = *link;
+      let block_start = block as usize;
+      let block_end = block_start + (*block).size;
+      let start = align_up(block_start, align);
+      if start + size <= block_end {
+        *link = (*block).next;
+        if start > block_start {
+          self.free_large(block_start, start - block_start);
+        }
+        if block_end > start + size {
+          self.free_large(start + size, block_end - start - size);
+        }
+        return start as *mut u8;
+      }
+      link = &mut (*block).next;
+    }
+    ptr::null_mut()
+  }
+
+  unsafe fn bump(&mut self, size: usize, align: usize) -> *mut u8 {
+    let start = align_up(self.top, align);
+    match start.checked_add(size) {
+      Some(end) if end <= self.end => {
+        // Keep the alignment gap usable
+        if start > self.top {
+          self.free_large(self.top, start - self.top);
+        }
+        self.top = end;
+        start as *mut u8
+      }
+      _ => ptr::null_mut(),
+    }
+  }
+
+  /// Inserts a block into the large free list, merging it with its neighbours. Returns the merged block.
+  unsafe fn free_large(&mut self, addr: usize, size: usize) -> *mut FreeBlock {
+    let mut previous: *mut FreeBlock = ptr::null_mut();
+    let mut next = self.large;
+    while !next.is_null() && (next as usize) < addr {
+      previous = next;
+      next = (*next).next;
+    }
+
+    let mut size = size;
+    if !next.is_null() && addr + size == next as usize {
+      size += (*next).size;
+      next = (*next).next;
+    }
+
+    if !previous.is_null() && previous as usize + (*previous).size == addr {
+      (*previous).size += size;
+      (*previous).next = next;
+      return previous;
+    }
+
+    let block = addr as *mut FreeBlock;
+    block.write(FreeBlock { size, next });
+    if previous.is_null() {
+      self.large = block;
+    } else {
+      (*previous).next = block;
+    }
+    block
+  }
+
+  /// Returns the free block touching the top of the heap to it.
+  unsafe fn trim(&mut self) {
+    let mut link: *mut *mut FreeBlock = &mut self.large;
+    while !(*link).is_null() {
+      let block = *link;
+      if block as usize + (*block).size == self.top {
+        self.top = block as usize;
+        *link = (*block).next;
+        return;
+      }
+      link = &mut (*block).next;
+    }
+  }
+
+  pub fn stats(&self) -> HeapStats {
+    HeapStats {
+      start: self.start,
+      end: self.end,
+      mapped: self.mapped,
+      used: self.used,
+      peak: self.peak,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate std;
+
+  use std::boxed::Box;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::vec;
+
+  use super::*;
+
+  const ARENA: usize = 64 * 1024;
+
+  fn arena() -> (usize, usize) {
+    let arena = Box::leak(vec![0u8; ARENA + MIN_BLOCK].into_boxed_slice());
+    let start = align_up(arena.as_ptr() as usize, MIN_BLOCK);
+    (start, start + ARENA)
+  }
+
+  fn no_map(_: usize) -> Option<usize> {
+    None
+  }
+
+  fn leak_map(size: usize) -> Option<usize> {
+    let region = Box::leak(vec![0u8; size + PAGE_SIZE as usize].into_boxed_slice());
+    Some(align_up(region.as_ptr() as usize, PAGE_SIZE as usize))
+  }
+
+  static UNMAPPED: AtomicUsize = AtomicUsize::new(0);
+
+  fn count_unmap(_: usize) -> bool {
+    UNMAPPED.fetch_add(1, Ordering::AcqRel);
+    true
+  }
+
+  fn heap(map: fn(usize) -> Option<usize>) -> Heap {
+    let mut heap = Heap::new(map, count_unmap);
+    let (start, end) = arena();
+    unsafe { heap.init(start, end) };
+    heap
+  }
+
+  fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 8).unwrap()
+  }
+
+  #[test]
+  fn small_blocks_are_reused() {
+    let mut heap = heap(no_map);
+    unsafe {
+      let a = heap.alloc(layout(24));
+      let b = heap.alloc(layout(24));
+      assert_eq!(b as usize - a as usize, 32);
+      assert_eq!(heap.stats().used, 64);
+
+      heap.dealloc(a, layout(24));
+      assert_eq!(heap.alloc(layout(20)), a);
+      // Another class does not take the freed block
+      heap.dealloc(a, layout(20));
+      assert_ne!(heap.alloc(layout(100)), a);
+    }
+  }
+
+  #[test]
+  fn large_blocks_split_and_coalesce() {
+    let mut heap = heap(no_map);
+    unsafe {
+      let a = heap.alloc(layout(8192));
+      let b = heap.alloc(layout(8192));
+      let guard = heap.alloc(layout(8192));
+      heap.dealloc(a, layout(8192));
+      heap.dealloc(b, layout(8192));
+
+      // The two freed blocks merged, a smaller allocation splits them
+      let c = heap.alloc(layout(12288));
+      assert_eq!(c, a);
+      let d = heap.alloc(layout(4096));
+      assert_eq!(d as usize, a as usize + 12288);
+
+      heap.dealloc(guard, layout(8192));
+      heap.dealloc(c, layout(12288));
+      heap.dealloc(d, layout(4096));
+      assert_eq!(heap.stats().used, 0);
+      // Everything was returned to the top
+      assert_eq!(heap.alloc(layout(ARENA)), a);
+    }
+  }
+
+  #[test]
+  fn aligned_large_blocks() {
+    let mut heap = heap(no_map);
+    unsafe {
+      heap.alloc(layout(16));
+      let ptr = heap.alloc(Layout::from_size_align(4096, 4096).unwrap());
+      assert_eq!(ptr as usize % 4096, 0);
+    }
+  }
+
+  #[test]
+  fn out_of_memory() {
+    let mut heap = heap(no_map);
+    unsafe {
+      assert!(heap.alloc(layout(ARENA + 1)).is_null());
+      let all = heap.alloc(layout(ARENA));
+      assert!(!all.is_null());
+      assert!(heap.alloc(layout(16)).is_null());
+      assert_eq!(heap.stats().used, ARENA);
+
+      heap.dealloc(all, layout(ARENA));
+      assert!(!heap.alloc(layout(16)).is_null());
+    }
+  }
+
+  #[test]
+  fn grows_when_exhausted() {
+    let mut heap = heap(leak_map);
+    unsafe {
+      heap.alloc(layout(ARENA));
+      let ptr = heap.alloc(layout(3 * GROW_SIZE / 2));
+      assert!(!ptr.is_null());
+      assert_eq!(heap.stats().mapped, align_up(3 * GROW_SIZE / 2 + 2 * MIN_BLOCK, PAGE_SIZE as usize));
+      assert!(!heap.alloc(layout(64)).is_null());
+    }
+  }
+
+  #[test]
+  fn unmaps_free_regions() {
+    let mut heap = heap(leak_map);
+    unsafe {
+      heap.alloc(layout(ARENA));
+      let a = heap.alloc(layout(GROW_SIZE / 2));
+      let b = heap.alloc(layout(GROW_SIZE / 4));
+      assert_eq!(heap.stats().mapped, GROW_SIZE);
+
+      let unmapped = UNMAPPED.load(Ordering::Acquire);
+      heap.dealloc(a, layout(GROW_SIZE / 2));
+      assert_eq!(heap.stats().mapped, GROW_SIZE);
+      heap.dealloc(b, layout(GROW_SIZE / 4));
+      assert_eq!(heap.stats().mapped, 0);
+      assert_eq!(UNMAPPED.load(Ordering::Acquire), unmapped + 1);
+
+      // Mapped again on the next allocation
+      assert!(!heap.alloc(layout(GROW_SIZE / 2)).is_null());
+      assert_eq!(heap.stats().mapped, GROW_SIZE);
+    }
+  }
+}
@@ -0,0 +1,12 @@
+/// Error codes reported in `http_response_t::error`.
+pub mod error {
+  pub const NONE: u64 = 0;
+  pub const INVALID_URL: u64 = 1;
+  pub const HOST_DENIED: u64 = 2;
+  pub const PRIVATE_ADDRESS: u64 = 3;
+  pub const RESOLVE_FAILED: u64 = 4;
+  pub const TOO_MANY_REDIRECTS: u64 = 5;
+  pub const RESPONSE_TOO_LARGE: u64 = 6;
+  pub const BUDGET_EXCEEDED: u64 = 7;
+  pub const REQUEST_FAILED: u64 = 8;
+}
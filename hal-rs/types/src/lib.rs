@@ -2,7 +2,16 @@
 
 use core::ffi::c_char;
 
+pub mod async_call;
 pub mod discord;
+pub mod hart;
+pub mod heap;
+pub mod http;
+pub mod log;
+pub mod mailbox;
+pub mod memory;
+pub mod object_storage;
+pub mod ring;
 pub mod syscall;
 
 #[repr(transparent)]
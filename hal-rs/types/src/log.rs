@@ -0,0 +1,10 @@
+/// Status codes returned in `a0` by `SYSCALL_LOG`.
+pub mod status {
+  pub const OK: u64 = 0;
+  /// The host could not keep up with the output, the line was dropped.
+  pub const BUSY: u64 = 1;
+  /// The isolate has sent all the messages it is allowed to, the line was dropped.
+  pub const BUDGET_EXHAUSTED: u64 = 2;
+  /// The message is not a string in guest memory.
+  pub const INVALID_ADDRESS: u64 = 3;
+}
@@ -0,0 +1,27 @@
+pub mod action {
+  /// Sends the `a3` bytes at `a2` to the mailbox of hart `a1`.
+  pub const SEND: u64 = 1;
+  /// Receives a message into the `a2` bytes at `a1`, returns its size in `a0` and the sending hart in `a2`. An empty
+  /// mailbox makes the hart wait for a message and returns [status::EMPTY](super::status::EMPTY), so the call is
+  /// repeated until it succeeds.
+  pub const RECEIVE: u64 = 2;
+  /// Like [RECEIVE], but returns [status::EMPTY](super::status::EMPTY) without waiting.
+  pub const TRY_RECEIVE: u64 = 3;
+}
+
+/// Status codes returned in `a1` by `SYSCALL_MAILBOX`.
+pub mod status {
+  pub const OK: u64 = 0;
+  pub const EMPTY: u64 = 1;
+  /// The mailbox holds [CAPACITY](super::CAPACITY) messages.
+  pub const FULL: u64 = 2;
+  pub const INVALID_HART: u64 = 3;
+  /// The message is larger than [MAX_MESSAGE_SIZE](super::MAX_MESSAGE_SIZE) or the receive buffer, its size is
+  /// returned in `a0` and a received message stays queued.
+  pub const TOO_LARGE: u64 = 4;
+  pub const INVALID_ARGUMENT: u64 = 5;
+}
+
+pub const MAX_MESSAGE_SIZE: u64 = 256;
+/// Messages queued per hart.
+pub const CAPACITY: usize = 32;
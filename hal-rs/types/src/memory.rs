@@ -0,0 +1,21 @@
+pub mod action {
+  /// Maps `a1` zeroed bytes, rounded up to [PAGE_SIZE](super::PAGE_SIZE), and returns the address in `a0`.
+  pub const MAP: u64 = 1;
+  /// Unmaps the region starting at `a1`.
+  pub const UNMAP: u64 = 2;
+  /// Returns the mapped bytes in `a0` and how many bytes the isolate may map besides its DRAM in `a2`.
+  pub const QUERY: u64 = 3;
+}
+
+/// Status codes returned in `a1` by `SYSCALL_MEMORY`.
+pub mod status {
+  pub const OK: u64 = 0;
+  pub const QUOTA_EXCEEDED: u64 = 1;
+  pub const INVALID_ARGUMENT: u64 = 2;
+  /// The address window for mapped regions is exhausted.
+  pub const NO_SPACE: u64 = 3;
+  pub const NOT_MAPPED: u64 = 4;
+}
+
+/// Mapped regions are multiples of this size.
+pub const PAGE_SIZE: u64 = 4096;
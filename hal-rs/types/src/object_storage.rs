@@ -0,0 +1,123 @@
+use core::ffi::c_char;
+
+use crate::StringPtr;
+
+pub mod action {
+  pub const GET: u64 = 1;
+  pub const PUT: u64 = 2;
+  pub const DELETE: u64 = 3;
+  pub const LIST: u64 = 4;
+  pub const STAT: u64 = 5;
+  pub const COMPARE_AND_SWAP: u64 = 6;
+  pub const INCREMENT: u64 = 7;
+  pub const EXPIRE: u64 = 8;
+}
+
+/// Status codes returned in `a1` by `SYSCALL_OBJECT_STORAGE`.
+pub mod status {
+  pub const OK: u64 = 0;
+  pub const NOT_FOUND: u64 = 1;
+  pub const INVALID_KEY: u64 = 2;
+  pub const QUOTA_EXCEEDED: u64 = 3;
+  pub const IO_ERROR: u64 = 4;
+  pub const INVALID_VALUE: u64 = 5;
+  pub const VERSION_MISMATCH: u64 = 6;
+  /// The value is larger than the host can return.
+  pub const VALUE_TOO_LARGE: u64 = 7;
+}
+
+/// Maximum number of keys returned by a single [action::LIST] call.
+pub const LIST_LIMIT: u64 = 64;
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct object_storage_item_t {
+  pub length: u64,
+  pub data: *const c_char,
+}
+
+/// Request for [action::GET], [action::DELETE] and [action::STAT].
+#[repr(C)]
+#[derive(Debug)]
+pub struct object_storage_get_t {
+  pub key: StringPtr,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct object_storage_put_t {
+  pub key: StringPtr,
+  pub item: object_storage_item_t,
+}
+
+/// Request for [action::EXPIRE].
+#[repr(C)]
+#[derive(Debug)]
+pub struct object_storage_expire_t {
+  pub key: StringPtr,
+  /// Time to live in milliseconds, 0 removes the expiry.
+  pub ttl_ms: u64,
+}
+
+/// Request for [action::COMPARE_AND_SWAP]. The object is written only if its current version equals
+/// `expected_version`, version 0 means that the object must not exist.
+#[repr(C)]
+#[derive(Debug)]
+pub struct object_storage_cas_t {
+  pub key: StringPtr,
+  pub expected_version: u64,
+  pub item: object_storage_item_t,
+  /// Time to live in milliseconds, 0 if the object never expires.
+  pub ttl_ms: u64,
+}
+
+/// Request for [action::INCREMENT]. Missing objects are treated as 0, existing ones must be 8-byte integers.
+#[repr(C)]
+#[derive(Debug)]
+pub struct object_storage_increment_t {
+  pub key: StringPtr,
+  pub delta: i64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct object_storage_list_t {
+  pub prefix: StringPtr,
+  /// Last key of the previous page, or null to start from the beginning.
+  pub cursor: StringPtr,
+  /// Capped at [LIST_LIMIT].
+  pub limit: u64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct object_storage_list_result_t {
+  pub count: u64,
+  pub keys: *const StringPtr,
+  /// Cursor for the next page, or null if there are no more keys.
+  pub next_cursor: StringPtr,
+}
+
+/// Result of [action::STAT], [action::PUT], [action::COMPARE_AND_SWAP] and [action::EXPIRE].
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct object_storage_metadata_t {
+  pub size: u64,
+  /// Unix time in milliseconds.
+  pub modified_ms: u64,
+  /// Incremented on every write, starting from 1.
+  pub version: u64,
+  /// Unix time in milliseconds, 0 if the object never expires.
+  pub expires_ms: u64,
+}
+
+/// Result of [action::INCREMENT].
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct object_storage_counter_t {
+  pub value: i64,
+  pub version: u64,
+}
+
+unsafe impl Send for object_storage_item_t {}
+unsafe impl Send for object_storage_list_result_t {}
@@ -0,0 +1,39 @@
+use crate::async_call::async_completion_t;
+
+pub mod status {
+  pub const OK: u64 = 0;
+  /// The core is not attached to an isolate.
+  pub const UNAVAILABLE: u64 = 1;
+  /// The setup, the queues or the result windows are not in guest memory.
+  pub const INVALID_ADDRESS: u64 = 2;
+}
+
+/// Passed by pointer in `a0` to `SYSCALL_RING`, returns a [status] in `a0`. A zero `sq_entries` unregisters the rings.
+///
+/// Both queues start with an [async_queue_t](crate::async_call::async_queue_t) header. The submission
+/// queue is followed by `sq_entries` of [ring_sqe_t], the completion queue by `cq_entries` of [ring_cqe_t].
+///
+/// `results` is 0 or the address of `cq_entries` windows of [RESULT_WINDOW_SIZE](crate::async_call::RESULT_WINDOW_SIZE)
+/// bytes. The window of a completion holds the data its result points to until the completion is consumed.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct ring_setup_t {
+  pub sq_address: u64,
+  pub sq_entries: u64,
+  pub cq_address: u64,
+  pub cq_entries: u64,
+  pub results: u64,
+}
+
+/// Submission queue entry. `args` are passed to the syscall as `a0..=a4`.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ring_sqe_t {
+  pub user_data: u64,
+  pub syscall: u64,
+  pub args: [u64; 5],
+}
+
+/// Completion queue entry, `ticket` holds the `user_data` of the submission.
+#[allow(non_camel_case_types)]
+pub type ring_cqe_t = async_completion_t;
@@ -9,3 +9,8 @@ pub const SYSCALL_TIME: u64 = 16;
 pub const SYSCALL_SIPI: u64 = 17;
 pub const SYSCALL_INT: u64 = 18;
 pub const SYSCALL_PNG: u64 = 19;
+pub const SYSCALL_ASYNC: u64 = 20;
+pub const SYSCALL_RING: u64 = 21;
+pub const SYSCALL_MEMORY: u64 = 22;
+pub const SYSCALL_HART: u64 = 23;
+pub const SYSCALL_MAILBOX: u64 = 24;
@@ -12,13 +12,14 @@ pub struct AddressDecoderEntry {
   pub store: StoreFn,
 }
 
+#[derive(Default)]
 pub struct AddressDecoder {
   segments: BTreeMap<u64, (u64, AddressDecoderEntry)>,
 }
 
 impl AddressDecoder {
   pub fn new() -> Self {
-    Self { segments: BTreeMap::new() }
+    Self::default()
   }
 
   pub fn insert(&mut self, range: RangeInclusive<u64>, entry: AddressDecoderEntry) {
@@ -26,6 +27,11 @@ impl AddressDecoder {
     self.segments.insert(start, (end, entry));
   }
 
+  /// Removes the segment starting at `start`.
+  pub fn remove(&mut self, start: u64) -> Option<AddressDecoderEntry> {
+    self.segments.remove(&start).map(|(_, entry)| entry)
+  }
+
   pub fn lookup(&self, address: u64) -> Option<(RangeInclusive<u64>, &AddressDecoderEntry)> {
     if let Some((start, (end, entry))) = self.segments.range(..=address).next_back() {
       if address <= *end {
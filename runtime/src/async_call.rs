@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+use std::mem::{offset_of, size_of};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use async_trait::async_trait;
+use mizu_hal_types::async_call::{action, async_completion_t, async_queue_t, status, RESULT_WINDOW_SIZE};
+use mizu_hal_types::syscall::SYSCALL_ASYNC;
+use mizu_hwconst::memory::{RESULTS_OFFSET, RESULTS_SIZE};
+use tracing::{debug, error, warn};
+
+use crate::apic::INTERRUPT_PRIORITY_NORMAL;
+use crate::bus::{Bus, BusMemoryExt};
+use crate::cpu::{Cpu, InterruptHandler};
+use crate::exception::Exception;
+use crate::interrupt::Interrupt;
+use crate::isolate::Isolate;
+
+/// Guest-registered completion queue.
+#[derive(Debug, Copy, Clone)]
+pub struct CompletionQueue {
+  pub address: u64,
+  pub entries: u64,
+}
+
+impl CompletionQueue {
+  /// Size of the header and the entries in guest memory, [None] if it overflows.
+  #[must_use]
+  pub fn size(&self) -> Option<u64> {
+    let entries = self.entries.checked_mul(size_of::<async_completion_t>() as u64)?;
+    entries.checked_add(size_of::<async_queue_t>() as u64)
+  }
+
+  pub fn head(&self, bus: &Bus) -> Result<u64, Exception> {
+    bus.load(self.address + offset_of!(async_queue_t, head) as u64, 64)
+  }
+
+  /// Position of the next completion.
+  pub fn tail(&self, bus: &Bus) -> Result<u64, Exception> {
+    bus.load(self.address + offset_of!(async_queue_t, tail) as u64, 64)
+  }
+
+  pub fn is_full(&self, bus: &Bus) -> Result<bool, Exception> {
+    Ok(self.tail(bus)?.wrapping_sub(self.head(bus)?) >= self.entries)
+  }
+
+  /// Appends a completion, returns its position in the queue or [None] if the queue is full.
+  pub fn push(&self, bus: &Bus, completion: &async_completion_t) -> Result<Option<u64>, Exception> {
+    let head = self.head(bus)?;
+    let tail = self.tail(bus)?;
+    if tail.wrapping_sub(head) >= self.entries {
+      let overflow = self.address + offset_of!(async_queue_t, overflow) as u64;
+      bus.store(overflow, 64, bus.load(overflow, 64)?.wrapping_add(1))?;
+      return Ok(None);
+    }
+
+    let entry = self.address + size_of::<async_queue_t>() as u64 + (tail % self.entries) * size_of::<async_completion_t>() as u64;
+    bus.write_struct(entry, completion)?;
+    // Publish the entry only after it has been written
+    bus.store(self.address + offset_of!(async_queue_t, tail) as u64, 64, tail.wrapping_add(1))?;
+    Ok(Some(tail))
+  }
+}
+
+/// Snapshot of a core's environment used to run host calls outside of it.
+#[derive(Clone)]
+pub struct DetachedCore {
+  pub id: u16,
+  pub pc: u64,
+  pub bus: Arc<Bus>,
+  /// See [Cpu::hardware].
+  pub hardware: u64,
+  pub isolate: Option<Weak<Isolate>>,
+  pub ivt: HashMap<u64, Arc<Box<dyn InterruptHandler>>>,
+}
+
+impl DetachedCore {
+  #[must_use]
+  pub fn new(cpu: &Cpu) -> Self {
+    Self {
+      id: cpu.id,
+      pc: cpu.pc,
+      bus: cpu.bus.clone(),
+      hardware: cpu.hardware,
+      isolate: cpu.isolate.clone(),
+      ivt: cpu.ivt.clone(),
+    }
+  }
+
+  /// Runs syscall `number` with arguments `a0..=a4`, returns resulting `a0` and `a1`,
+  /// or [None] if there is no such syscall.
+  pub async fn call(&self, number: u64, args: &[u64; 5]) -> Option<[u64; 2]> {
+    let handler = self.ivt.get(&number)?.clone();
+
+    let mut shadow = Cpu::new(self.id, self.bus.clone(), self.isolate.clone());
+    shadow.pc = self.pc;
+    shadow.hardware = self.hardware;
+    shadow.ivt = self.ivt.clone();
+    shadow.regs[10..15].copy_from_slice(args);
+    shadow.regs[17] = number;
+
+    handler.handle(&mut shadow).await;
+    Some([shadow.regs[10], shadow.regs[11]])
+  }
+}
+
+const _: () = assert!(RESULT_WINDOW_SIZE == RESULTS_SIZE);
+
+enum Window {
+  Free,
+  Running,
+  /// Holds the result of the completion at this queue position until the guest consumes it.
+  Completed(u64),
+}
+
+struct Registration {
+  generation: u64,
+  queue: CompletionQueue,
+  /// Address of the result windows, 0 if the guest did not register any.
+  results: u64,
+  windows: Vec<Window>,
+}
+
+impl Registration {
+  /// Takes a free result window, windows of consumed completions are freed first.
+  fn acquire(&mut self, bus: &Bus) -> Result<Option<usize>, Exception> {
+    let head = self.queue.head(bus)?;
+    for window in &mut self.windows {
+      if matches!(window, Window::Completed(position) if *position < head) {
+        *window = Window::Free;
+      }
+    }
+
+    let index = self.windows.iter().position(|window| matches!(window, Window::Free));
+    if let Some(index) = index {
+      self.windows[index] = Window::Running;
+    }
+    Ok(index)
+  }
+
+  fn window_address(&self, index: usize) -> u64 {
+    self.results + index as u64 * RESULT_WINDOW_SIZE
+  }
+}
+
+/// Runs host calls in the background so that the submitting core is not blocked.
+///
+/// The handler is executed on a detached copy of the core that shares its bus and interrupt vector table,
+/// the resulting `a0`/`a1` are written into the completion queue and [Interrupt::PlatformDefined18] is
+/// raised on the submitting core.
+///
+/// If the guest registered result windows, each call writes the data its result points to into a window of its own
+/// that is reused once the completion is consumed, and calls are rejected while every window is in use. Otherwise
+/// results that point into the hardware data area may be overwritten by later calls.
+///
+/// Completions are published to the queue immediately and the interrupt is delivered before the next
+/// step of the core, see [Isolate::post](crate::isolate::Isolate::post).
+pub struct AsyncCallHandler {
+  registration: Arc<Mutex<Option<Registration>>>,
+  next_ticket: AtomicU64,
+  next_generation: AtomicU64,
+}
+
+impl Default for AsyncCallHandler {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl AsyncCallHandler {
+  #[must_use]
+  pub fn new() -> Self {
+    Self {
+      registration: Arc::new(Mutex::new(None)),
+      next_ticket: AtomicU64::new(1),
+      next_generation: AtomicU64::new(0),
+    }
+  }
+
+  fn setup(&self, bus: &Bus, address: u64, entries: u64, results: u64) -> u64 {
+    debug!("async setup: queue=0x{:x} entries={} results=0x{:x}", address, entries, results);
+    if entries == 0 {
+      *self.registration.lock().unwrap() = None;
+      return status::OK;
+    }
+
+    let queue = CompletionQueue { address, entries };
+    if !queue.size().is_some_and(|size| bus.is_memory(address, size)) {
+      warn!("async setup: queue at 0x{:x} is not in guest memory", address);
+      return status::INVALID_ADDRESS;
+    }
+    if results != 0 && !entries.checked_mul(RESULT_WINDOW_SIZE).is_some_and(|size| bus.is_memory(results, size)) {
+      warn!("async setup: result windows at 0x{:x} are not in guest memory", results);
+      return status::INVALID_ADDRESS;
+    }
+
+    let windows = if results != 0 {
+      (0..entries).map(|_| Window::Free).collect()
+    } else {
+      Vec::new()
+    };
+    *self.registration.lock().unwrap() = Some(Registration {
+      generation: self.next_generation.fetch_add(1, Ordering::AcqRel),
+      queue,
+      results,
+      windows,
+    });
+    status::OK
+  }
+}
+
+#[async_trait]
+impl InterruptHandler for AsyncCallHandler {
+  async fn handle(&self, cpu: &mut Cpu) {
+    match cpu.regs[10] {
+      action::SETUP => {
+        cpu.regs[10] = self.setup(&cpu.bus, cpu.regs[11], cpu.regs[12], cpu.regs[13]);
+      }
+      action::SUBMIT => {
+        let number = cpu.regs[11];
+        if number == SYSCALL_ASYNC || !cpu.ivt.contains_key(&number) {
+          warn!("async submit: unknown syscall {}", number);
+          cpu.regs[10] = 0;
+          return;
+        }
+        let Some(isolate) = cpu.isolate.as_ref().and_then(|isolate| isolate.upgrade()) else {
+          error!("async submit: core {} is not attached to an isolate", cpu.id);
+          cpu.regs[10] = 0;
+          return;
+        };
+
+        let mut detached = DetachedCore::new(cpu);
+        let (generation, window) = {
+          let mut registration = self.registration.lock().unwrap();
+          let Some(registration) = registration.as_mut() else {
+            warn!("async submit: no completion queue registered");
+            cpu.regs[10] = 0;
+            return;
+          };
+          let window = if registration.results == 0 {
+            None
+          } else {
+            match registration.acquire(&cpu.bus) {
+              Ok(Some(index)) => {
+                detached.hardware = registration.window_address(index).wrapping_sub(RESULTS_OFFSET);
+                Some(index)
+              }
+              Ok(None) => {
+                warn!("async submit: every result window is in use");
+                cpu.regs[10] = 0;
+                return;
+              }
+              Err(exception) => {
+                warn!("async submit: failed to read the completion queue: {:?}", exception);
+                cpu.regs[10] = 0;
+                return;
+              }
+            }
+          };
+          (registration.generation, window)
+        };
+
+        let ticket = self.next_ticket.fetch_add(1, Ordering::AcqRel);
+        debug!("async submit: ticket={} syscall={}", ticket, number);
+
+        let args: [u64; 5] = cpu.regs[12..17].try_into().unwrap();
+        let registration = self.registration.clone();
+        let id = cpu.id;
+        tokio::spawn(async move {
+          let [a0, a1] = detached.call(number, &args).await.unwrap();
+
+          let completion = async_completion_t { ticket, a0, a1 };
+          let pushed = match registration.lock().unwrap().as_mut() {
+            Some(registration) if registration.generation == generation => {
+              let pushed = registration.queue.push(&detached.bus, &completion);
+              if let Some(index) = window {
+                registration.windows[index] = match pushed {
+                  Ok(Some(position)) => Window::Completed(position),
+                  _ => Window::Free,
+                };
+              }
+              pushed
+            }
+            // The queue was registered again, the result may point into windows that are gone
+            _ => Ok(None),
+          };
+          match pushed {
+            Ok(Some(_)) => {}
+            Ok(None) => warn!("async completion {} dropped", ticket),
+            Err(exception) => warn!("async completion {} dropped: {:?}", ticket, exception),
+          }
+
+          isolate.post(
+            id,
+            Box::new(|cpu| {
+              cpu.apic.dispatch(Interrupt::PlatformDefined18, INTERRUPT_PRIORITY_NORMAL);
+              cpu.wfi.set(false);
+            }),
+          );
+        });
+
+        cpu.regs[10] = ticket;
+      }
+      action => {
+        warn!("async: unknown action {}", action);
+        cpu.regs[10] = 0;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use mizu_hwconst::memory::DRAM_BASE;
+
+  use super::*;
+
+  const QUEUE: u64 = DRAM_BASE + 0x1000;
+  const RESULTS: u64 = DRAM_BASE + 0x10000;
+  const SYSCALL_RESULT: u64 = 0x1000;
+
+  /// Returns the address its result is written to and echoes its argument.
+  struct ResultCall;
+
+  #[async_trait]
+  impl InterruptHandler for ResultCall {
+    async fn handle(&self, cpu: &mut Cpu) {
+      cpu.regs[11] = cpu.regs[10];
+      cpu.regs[10] = cpu.hardware.wrapping_add(RESULTS_OFFSET);
+    }
+  }
+
+  fn core(isolate: &Arc<Isolate>) -> Cpu {
+    let mut cpu = Cpu::new(0, isolate.bus.clone(), Some(Arc::downgrade(isolate)));
+    cpu.ivt.insert(SYSCALL_RESULT, Arc::new(Box::new(ResultCall)));
+    cpu
+  }
+
+  async fn submit(handler: &AsyncCallHandler, cpu: &mut Cpu, arg: u64) -> u64 {
+    (cpu.regs[10], cpu.regs[11], cpu.regs[12]) = (action::SUBMIT, SYSCALL_RESULT, arg);
+    handler.handle(cpu).await;
+    cpu.regs[10]
+  }
+
+  async fn wait_for(queue: &CompletionQueue, bus: &Bus, tail: u64) {
+    for _ in 0..1000 {
+      if queue.tail(bus).unwrap() == tail {
+        return;
+      }
+      tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    panic!("the completion queue did not reach {}", tail);
+  }
+
+  fn completion(queue: &CompletionQueue, bus: &Bus, position: u64) -> async_completion_t {
+    let entry = queue.address + size_of::<async_queue_t>() as u64 + (position % queue.entries) * size_of::<async_completion_t>() as u64;
+    bus.read_struct(entry).unwrap()
+  }
+
+  #[test]
+  fn pushes_and_overflows() {
+    let bus = Bus::new(Vec::new());
+    let queue = CompletionQueue { address: QUEUE, entries: 2 };
+    for ticket in 1..=2 {
+      assert_eq!(queue.push(&bus, &async_completion_t { ticket, a0: 0, a1: 0 }).unwrap(), Some(ticket - 1));
+    }
+    assert!(queue.is_full(&bus).unwrap());
+    assert_eq!(queue.push(&bus, &async_completion_t { ticket: 3, a0: 0, a1: 0 }).unwrap(), None);
+    assert_eq!(bus.load(QUEUE + offset_of!(async_queue_t, overflow) as u64, 64).unwrap(), 1);
+
+    // Consuming an entry frees the slot the tail wraps around to
+    bus.store(QUEUE + offset_of!(async_queue_t, head) as u64, 64, 1).unwrap();
+    assert_eq!(queue.push(&bus, &async_completion_t { ticket: 4, a0: 0, a1: 0 }).unwrap(), Some(2));
+    assert_eq!(completion(&queue, &bus, 2).ticket, 4);
+    assert_eq!(completion(&queue, &bus, 1).ticket, 2);
+  }
+
+  #[test]
+  fn validates_setup() {
+    let bus = Bus::new(Vec::new());
+    let handler = AsyncCallHandler::new();
+    assert_eq!(handler.setup(&bus, 0, 2, 0), status::INVALID_ADDRESS);
+    assert_eq!(handler.setup(&bus, QUEUE, u64::MAX, 0), status::INVALID_ADDRESS);
+    assert_eq!(handler.setup(&bus, QUEUE, 2, DRAM_BASE - RESULT_WINDOW_SIZE), status::INVALID_ADDRESS);
+    assert_eq!(handler.setup(&bus, QUEUE, 2, 0), status::OK);
+    assert!(handler.registration.lock().unwrap().is_some());
+    assert_eq!(handler.setup(&bus, 0, 0, 0), status::OK);
+    assert!(handler.registration.lock().unwrap().is_none());
+  }
+
+  #[tokio::test]
+  async fn completes_into_result_windows() {
+    let isolate = Isolate::new(Arc::new(Bus::new(Vec::new())));
+    let mut cpu = core(&isolate);
+    let handler = AsyncCallHandler::new();
+    assert_eq!(handler.setup(&cpu.bus, QUEUE, 2, RESULTS), status::OK);
+    let queue = CompletionQueue { address: QUEUE, entries: 2 };
+
+    let first = submit(&handler, &mut cpu, 10).await;
+    let second = submit(&handler, &mut cpu, 20).await;
+    assert!(first != 0 && second != 0);
+    wait_for(&queue, &cpu.bus, 2).await;
+    let results = [completion(&queue, &cpu.bus, 0), completion(&queue, &cpu.bus, 1)];
+    assert_eq!(results.map(|result| (result.ticket, result.a0, result.a1)), [
+      (first, RESULTS, 10),
+      (second, RESULTS + RESULT_WINDOW_SIZE, 20),
+    ]);
+
+    // Every window holds an unconsumed result
+    assert_eq!(submit(&handler, &mut cpu, 30).await, 0);
+
+    // Consuming the first completion frees its window
+    cpu.bus.store(QUEUE + offset_of!(async_queue_t, head) as u64, 64, 1).unwrap();
+    let third = submit(&handler, &mut cpu, 30).await;
+    wait_for(&queue, &cpu.bus, 3).await;
+    let result = completion(&queue, &cpu.bus, 2);
+    assert_eq!((result.ticket, result.a0, result.a1), (third, RESULTS, 30));
+  }
+
+  #[tokio::test]
+  async fn drops_completions_of_replaced_queues() {
+    let isolate = Isolate::new(Arc::new(Bus::new(Vec::new())));
+    let mut cpu = core(&isolate);
+    let handler = AsyncCallHandler::new();
+    assert_eq!(handler.setup(&cpu.bus, QUEUE, 2, 0), status::OK);
+    let queue = CompletionQueue { address: QUEUE, entries: 2 };
+
+    // The call is still pending when the queue is unregistered
+    assert_ne!(submit(&handler, &mut cpu, 10).await, 0);
+    assert_eq!(handler.setup(&cpu.bus, 0, 0, 0), status::OK);
+    assert_eq!(submit(&handler, &mut cpu, 20).await, 0);
+
+    // A call pending across a new registration is dropped as well
+    assert_eq!(handler.setup(&cpu.bus, QUEUE, 2, 0), status::OK);
+    let ticket = submit(&handler, &mut cpu, 30).await;
+    assert_eq!(handler.setup(&cpu.bus, QUEUE, 2, 0), status::OK);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(queue.tail(&cpu.bus).unwrap(), 0);
+
+    let last = submit(&handler, &mut cpu, 40).await;
+    wait_for(&queue, &cpu.bus, 1).await;
+    assert!(last > ticket);
+    assert_eq!(completion(&queue, &cpu.bus, 0).ticket, last);
+  }
+}
@@ -1,21 +1,99 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::ffi::CString;
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::mem::size_of;
 use std::ops::RangeInclusive;
-use std::sync::RwLock;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, RwLockReadGuard};
 use std::{ptr, slice};
 
+use mizu_hal_types::memory::PAGE_SIZE;
 use mizu_hwconst::memory::*;
 use rand::{thread_rng, RngCore};
-use tracing::{debug, error, trace};
+use tokio::sync::watch;
+use tracing::{error, info, trace, warn};
 
 use crate::address_decoder::{AddressDecoder, AddressDecoderEntry};
+use crate::clint::{self, Clint};
 use crate::dram::Dram;
 use crate::exception::Exception;
+use crate::mmap::{MemoryError, DEFAULT_MEMORY_LIMIT};
+use crate::power::PowerControl;
+use crate::replay::{Session, Store};
+
+tokio::task_local! {
+  /// Stores made by the task running a syscall handler, see [Bus::journal].
+  static JOURNAL: RefCell<Vec<Store>>;
+}
 
 pub struct Bus {
   pub dram: RwLock<Dram>,
   pub hardware: RwLock<Dram>,
   pub address_decoder: RwLock<AddressDecoder>,
+  /// Incremented on every store to the doorbell register.
+  pub doorbell: watch::Sender<u64>,
+  /// Regions mapped by the guest, by start address. Stores only take the read lock of the map.
+  pub mappings: RwLock<BTreeMap<u64, RwLock<Dram>>>,
+  memory_limit: AtomicU64,
+  pub power: PowerControl,
+  pub clint: Clint,
+  /// File of the memory while it is written to disk, see [Bus::swap_out].
+  swap: Mutex<Option<SwapFile>>,
+  /// Whether the memory is on disk, only changed while holding the address decoder for writing.
+  swapped: AtomicBool,
+  session: OnceLock<Arc<Session>>,
+  /// Number of running [Bus::journal]s, stores only look for a journal while there are some.
+  journals: AtomicUsize,
+}
+
+/// Memory written to disk by [Bus::swap_out], only the pages with a non-zero byte are kept.
+struct SwapFile {
+  path: PathBuf,
+  dram: usize,
+  /// Sizes of the mapped regions, by start address.
+  mappings: Vec<(u64, usize)>,
+}
+
+impl Drop for SwapFile {
+  fn drop(&mut self) {
+    if let Err(error) = fs::remove_file(&self.path) {
+      error!("failed to remove {}: {}", self.path.display(), error);
+    }
+  }
+}
+
+/// Ends the pages of a region in a swap file.
+const SWAP_END: u64 = u64::MAX;
+
+fn write_pages(writer: &mut impl Write, memory: &[u8]) -> io::Result<()> {
+  for (index, page) in memory.chunks(PAGE_SIZE as usize).enumerate() {
+    if page.iter().any(|byte| *byte != 0) {
+      writer.write_all(&(index as u64).to_le_bytes())?;
+      writer.write_all(page)?;
+    }
+  }
+  writer.write_all(&SWAP_END.to_le_bytes())
+}
+
+fn read_pages(reader: &mut impl Read, size: usize) -> io::Result<Vec<u8>> {
+  let mut memory = vec![0; size];
+  loop {
+    let mut index = [0; 8];
+    reader.read_exact(&mut index)?;
+    let index = u64::from_le_bytes(index);
+    if index == SWAP_END {
+      return Ok(memory);
+    }
+    let start = index as usize * PAGE_SIZE as usize;
+    let page = memory
+      .get_mut(start..(start + PAGE_SIZE as usize).min(size))
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "page out of range"))?;
+    reader.read_exact(page)?;
+  }
 }
 
 pub fn store_fail(_bus: &Bus, addr: u64, _range: RangeInclusive<u64>, _size: u64, _value: u64) -> Result<(), Exception> {
@@ -35,15 +113,21 @@ impl Bus {
       store: |bus, addr, range, size, value| bus.hardware.write().unwrap().store(addr - range.start(), size, value),
     });
     address_decoder.insert(RANDOM_BASE..=RANDOM_END, AddressDecoderEntry {
-      load: |bus, addr, range, size| {
-        let mut random = [0u8; 8];
-        thread_rng().fill_bytes(&mut random[..(size / 8) as usize]);
-        Ok(u64::from_le_bytes(random))
+      load: |bus, addr, _range, size| {
+        let random = || {
+          let mut random = [0u8; 8];
+          thread_rng().fill_bytes(&mut random[..(size / 8) as usize]);
+          u64::from_le_bytes(random)
+        };
+        match bus.session() {
+          Some(session) => session.input(random).ok_or(Exception::LoadAccessFault(addr)),
+          None => Ok(random()),
+        }
       },
       store: store_fail,
     });
     address_decoder.insert(CPUID_BASE..=CPUID_END, AddressDecoderEntry {
-      load: |bus, addr, range, size| {
+      load: |_bus, addr, range, _size| {
         let offset = (addr - range.start()) as usize;
         match offset {
           // name
@@ -61,6 +145,19 @@ impl Bus {
       store: store_fail,
     });
 
+    address_decoder.insert(DOORBELL_BASE..=DOORBELL_END, AddressDecoderEntry {
+      load: |bus, _addr, _range, _size| Ok(*bus.doorbell.borrow()),
+      store: |bus, _addr, _range, _size, _value| {
+        bus.doorbell.send_modify(|value| *value = value.wrapping_add(1));
+        Ok(())
+      },
+    });
+
+    address_decoder.insert(CLINT_BASE..=CLINT_END, AddressDecoderEntry {
+      load: clint::load,
+      store: clint::store,
+    });
+
     let mut dram = Dram::new(DRAM_SIZE as usize);
     dram.init(&code);
 
@@ -68,13 +165,221 @@ impl Bus {
       dram: RwLock::new(dram),
       hardware: RwLock::new(Dram::new(HARDWARE_SIZE as usize)),
       address_decoder: RwLock::new(address_decoder),
+      doorbell: watch::channel(0).0,
+      mappings: RwLock::new(BTreeMap::new()),
+      memory_limit: AtomicU64::new(DEFAULT_MEMORY_LIMIT),
+      power: PowerControl::default(),
+      clint: Clint::default(),
+      swap: Mutex::new(None),
+      swapped: AtomicBool::new(false),
+      session: OnceLock::new(),
+      journals: AtomicUsize::new(0),
+    }
+  }
+
+  /// Maximum number of bytes of memory, the DRAM included.
+  pub fn memory_limit(&self) -> u64 {
+    self.memory_limit.load(Ordering::Acquire)
+  }
+
+  /// Maximum number of bytes the guest may map in addition to the DRAM.
+  pub fn mapping_limit(&self) -> u64 {
+    self.memory_limit().saturating_sub(DRAM_SIZE)
+  }
+
+  pub fn set_memory_limit(&self, limit: u64) {
+    self.memory_limit.store(limit, Ordering::Release);
+  }
+
+  pub fn mapped_bytes(&self) -> u64 {
+    if let Some(swap) = self.swap.lock().unwrap().as_ref() {
+      return swap.mappings.iter().map(|(_, size)| *size as u64).sum();
+    }
+    self.mappings.read().unwrap().values().map(|region| region.read().unwrap().dram.len() as u64).sum()
+  }
+
+  /// Maps a zeroed region of at least `size` bytes into the first free range of the mapped window.
+  pub fn map(&self, size: u64) -> Result<u64, MemoryError> {
+    if size == 0 || size > MAPPED_SIZE {
+      return Err(MemoryError::InvalidArgument);
+    }
+    let size = size.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+
+    self.ensure_resident().map_err(|_| MemoryError::NoSpace)?;
+    let mut address_decoder = self.address_decoder.write().unwrap();
+    let mut mappings = self.mappings.write().unwrap();
+    let mapped = mappings.values_mut().map(|region| region.get_mut().unwrap().dram.len() as u64).sum::<u64>();
+    if mapped + size > self.mapping_limit() {
+      return Err(MemoryError::QuotaExceeded);
+    }
+
+    let mut start = MAPPED_BASE;
+    for (base, region) in mappings.iter_mut() {
+      if base - start >= size {
+        break;
+      }
+      start = base + region.get_mut().unwrap().dram.len() as u64;
+    }
+    // The window ends at the top of the address space
+    let Some(end) = start.checked_add(size - 1) else {
+      return Err(MemoryError::NoSpace);
+    };
+
+    mappings.insert(start, RwLock::new(Dram::new(size as usize)));
+    address_decoder.insert(start..=end, AddressDecoderEntry {
+      load: |bus, addr, range, size| match bus.mappings.read().unwrap().get(range.start()) {
+        Some(region) => region.read().unwrap().load(addr - range.start(), size),
+        None => Err(Exception::LoadAccessFault(addr)),
+      },
+      store: |bus, addr, range, size, value| match bus.mappings.read().unwrap().get(range.start()) {
+        Some(region) => region.write().unwrap().store(addr - range.start(), size, value),
+        None => Err(Exception::StoreAMOAccessFault(addr)),
+      },
+    });
+    Ok(start)
+  }
+
+  /// Unmaps the region starting at `address`, returns its size.
+  pub fn unmap(&self, address: u64) -> Result<u64, MemoryError> {
+    self.ensure_resident().map_err(|_| MemoryError::NotMapped)?;
+    let mut address_decoder = self.address_decoder.write().unwrap();
+    let mut mappings = self.mappings.write().unwrap();
+    let region = mappings.remove(&address).ok_or(MemoryError::NotMapped)?;
+    address_decoder.remove(address);
+    Ok(region.into_inner().unwrap().dram.len() as u64)
+  }
+
+  /// Whether `len` bytes at `addr` lie in the DRAM or in a single mapped region.
+  pub fn is_memory(&self, addr: u64, len: u64) -> bool {
+    let Some(end) = len.checked_sub(1).and_then(|last| addr.checked_add(last)) else {
+      return false;
+    };
+    let address_decoder = self.address_decoder.read().unwrap();
+    address_decoder
+      .lookup(addr)
+      .is_some_and(|(range, _)| end <= *range.end() && (*range.start() == DRAM_BASE || *range.start() >= MAPPED_BASE))
+  }
+
+  pub fn is_swapped_out(&self) -> bool {
+    self.swapped.load(Ordering::Acquire)
+  }
+
+  /// Writes the DRAM and the mapped regions to a file in `directory` and releases them, they are read back by
+  /// [Bus::swap_in]. Returns the number of bytes released. Blocks on file I/O.
+  pub fn swap_out(&self, directory: &Path) -> io::Result<u64> {
+    let _address_decoder = self.address_decoder.write().unwrap();
+    let mut swap = self.swap.lock().unwrap();
+    if swap.is_some() {
+      return Ok(0);
+    }
+    let mut dram = self.dram.write().unwrap();
+    let mut mappings = self.mappings.write().unwrap();
+
+    fs::create_dir_all(directory)?;
+    let path = directory.join(format!("{:x}-{}.swap", self as *const Bus as usize, std::process::id()));
+    let mut writer = BufWriter::new(File::create(&path)?);
+    // Removes the file if writing fails
+    let file = SwapFile {
+      path,
+      dram: dram.dram.len(),
+      mappings: mappings.iter_mut().map(|(start, region)| (*start, region.get_mut().unwrap().dram.len())).collect(),
+    };
+    write_pages(&mut writer, &dram.dram)?;
+    for region in mappings.values_mut() {
+      write_pages(&mut writer, &region.get_mut().unwrap().dram)?;
+    }
+    writer.flush()?;
+
+    let released = file.dram as u64 + file.mappings.iter().map(|(_, size)| *size as u64).sum::<u64>();
+    dram.dram = Vec::new();
+    for region in mappings.values_mut() {
+      region.get_mut().unwrap().dram = Vec::new();
+    }
+    info!("swapped out {} bytes to {}", released, file.path.display());
+    *swap = Some(file);
+    self.swapped.store(true, Ordering::Release);
+    Ok(released)
+  }
+
+  /// Reads the memory written by [Bus::swap_out] back on a blocking thread, called before a hart runs.
+  pub async fn swap_in(self: &Arc<Self>) -> io::Result<()> {
+    if !self.is_swapped_out() {
+      return Ok(());
+    }
+    let this = self.clone();
+    tokio::task::spawn_blocking(move || this.swap_in_blocking()).await?
+  }
+
+  fn swap_in_blocking(&self) -> io::Result<()> {
+    let _address_decoder = self.address_decoder.write().unwrap();
+    let mut swap = self.swap.lock().unwrap();
+    let Some(file) = swap.as_ref() else {
+      return Ok(());
+    };
+    let mut dram = self.dram.write().unwrap();
+    let mut mappings = self.mappings.write().unwrap();
+
+    let mut reader = BufReader::new(File::open(&file.path)?);
+    dram.dram = read_pages(&mut reader, file.dram)?;
+    for (start, size) in &file.mappings {
+      if let Some(region) = mappings.get_mut(start) {
+        region.get_mut().unwrap().dram = read_pages(&mut reader, *size)?;
+      }
+    }
+    info!("swapped in {}", file.path.display());
+    *swap = None;
+    self.swapped.store(false, Ordering::Release);
+    Ok(())
+  }
+
+  /// Harts run with the memory swapped in, only host tasks may find it on disk.
+  fn ensure_resident(&self) -> io::Result<()> {
+    if self.is_swapped_out() {
+      warn!("memory accessed by the host while swapped out");
+      self.swap_in_blocking().inspect_err(|error| error!("failed to swap in: {}", error))?;
+    }
+    Ok(())
+  }
+
+  /// Locks the address decoder with the memory resident, it cannot be swapped out while the guard is held.
+  fn decoder(&self) -> io::Result<RwLockReadGuard<'_, AddressDecoder>> {
+    loop {
+      let address_decoder = self.address_decoder.read().unwrap();
+      if !self.is_swapped_out() {
+        return Ok(address_decoder);
+      }
+      drop(address_decoder);
+      self.ensure_resident()?;
     }
   }
 
+  /// Attaches a record or replay session, returns false if one is already attached.
+  pub fn attach_session(&self, session: Arc<Session>) -> bool {
+    self.session.set(session).is_ok()
+  }
+
+  pub fn session(&self) -> Option<&Arc<Session>> {
+    self.session.get()
+  }
+
+  /// Runs `future` and collects the stores it makes through the bus, used to record the effects of syscalls. Stores
+  /// of other tasks, such as the other cores, are not collected.
+  pub(crate) async fn journal<T>(&self, future: impl Future<Output = T>) -> (T, Vec<Store>) {
+    self.journals.fetch_add(1, Ordering::AcqRel);
+    let result = JOURNAL
+      .scope(RefCell::new(Vec::new()), async {
+        let output = future.await;
+        (output, JOURNAL.with(RefCell::take))
+      })
+      .await;
+    self.journals.fetch_sub(1, Ordering::AcqRel);
+    result
+  }
+
   pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
     trace!("bus load at 0x{addr:x}");
 
-    let address_decoder = self.address_decoder.read().unwrap();
+    let address_decoder = self.decoder().map_err(|_| Exception::LoadAccessFault(addr))?;
     match address_decoder.lookup(addr) {
       Some((range, entry)) => (entry.load)(self, addr, range, size),
       None => {
@@ -85,9 +390,12 @@ impl Bus {
   }
 
   pub fn store(&self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-    debug!("writing {value:x} at {addr:x}");
+    trace!("writing {value:x} at {addr:x}");
+    if self.journals.load(Ordering::Acquire) > 0 {
+      let _ = JOURNAL.try_with(|journal| Store::push(&mut journal.borrow_mut(), addr, size, value));
+    }
 
-    let address_decoder = self.address_decoder.read().unwrap();
+    let address_decoder = self.decoder().map_err(|_| Exception::StoreAMOAccessFault(addr))?;
     match address_decoder.lookup(addr) {
       Some((range, entry)) => (entry.store)(self, addr, range, size, value),
       None => Err(Exception::StoreAMOAccessFault(addr)),
@@ -149,7 +457,7 @@ impl BusMemoryExt for Bus {
     let mut address = addr;
     let mut data = Vec::new();
     loop {
-      let byte = self.load(address, 8).unwrap() as u8;
+      let byte = self.load(address, 8)? as u8;
       data.push(byte);
       if byte == 0 {
         break;
@@ -161,10 +469,8 @@ impl BusMemoryExt for Bus {
   }
 
   fn write(&self, addr: u64, value: &[u8]) -> Result<(), Exception> {
-    let mut address = addr;
-    for byte in value {
-      self.store(address, 8, *byte as u64).unwrap();
-      address += 1;
+    for (offset, byte) in value.iter().enumerate() {
+      self.store(addr.wrapping_add(offset as u64), 8, *byte as u64)?;
     }
     Ok(())
   }
@@ -177,10 +483,38 @@ impl BusMemoryExt for Bus {
   fn write_string(&self, addr: u64, value: &str) -> Result<(), Exception> {
     let mut address = addr;
     for byte in value.as_bytes() {
-      self.store(address, 8, *byte as u64).unwrap();
+      self.store(address, 8, *byte as u64)?;
       address += 1;
     }
-    self.store(address, 8, 0).unwrap();
-    Ok(())
+    self.store(address, 8, 0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn swaps_memory_out_and_in() {
+    let directory = env::temp_dir().join(format!("mizu-bus-test-{}", std::process::id()));
+    let bus = Arc::new(Bus::new(vec![1, 2, 3, 4]));
+    let region = bus.map(PAGE_SIZE).unwrap();
+    bus.store(region + 8, 64, 0x1122334455667788).unwrap();
+
+    assert!(bus.swap_out(&directory).unwrap() >= DRAM_SIZE);
+    assert!(bus.is_swapped_out());
+    assert_eq!(bus.mapped_bytes(), PAGE_SIZE);
+    bus.swap_in().await.unwrap();
+    assert!(!bus.is_swapped_out());
+    assert_eq!(bus.load(DRAM_BASE, 32).unwrap(), 0x04030201);
+    assert_eq!(bus.load(region + 8, 64).unwrap(), 0x1122334455667788);
+
+    // A host access reads it back as well
+    bus.swap_out(&directory).unwrap();
+    assert_eq!(bus.load(DRAM_BASE + 1, 8).unwrap(), 2);
+    assert!(!bus.is_swapped_out());
+    fs::remove_dir_all(&directory).unwrap();
   }
 }
@@ -0,0 +1,92 @@
+//! Core-local interruptor with the `msip` registers of the harts.
+//!
+//! Writing 1 to the `msip` of a hart sets its MIP.MSIP and wakes it from `wfi` if MIE.MSIE is set, writing 0 clears
+//! the request. The register reads as 1 until the hart takes the interrupt. Registers of missing harts read as 0 and
+//! ignore writes.
+
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+use tracing::debug;
+
+use crate::bus::Bus;
+use crate::csr::{MASK_MSIP, MIE, MIP};
+use crate::exception::Exception;
+use crate::isolate::Isolate;
+
+#[derive(Default)]
+pub struct Clint {
+  isolate: OnceLock<Weak<Isolate>>,
+  /// Harts with a software interrupt that was not taken yet.
+  pending: Mutex<BTreeSet<u16>>,
+}
+
+impl Clint {
+  pub(crate) fn attach(&self, isolate: &Arc<Isolate>) {
+    let _ = self.isolate.set(Arc::downgrade(isolate));
+  }
+
+  pub fn is_pending(&self, hart: u16) -> bool {
+    self.pending.lock().unwrap().contains(&hart)
+  }
+
+  /// Requests a software interrupt on `hart`, returns false if there is no such hart.
+  pub fn raise(&self, hart: u16) -> bool {
+    let Some(isolate) = self.isolate.get().and_then(Weak::upgrade) else {
+      return false;
+    };
+    let delivered = isolate.post(
+      hart,
+      Box::new(|cpu| {
+        cpu.csr.store(MIP, cpu.csr.load(MIP) | MASK_MSIP);
+        if cpu.csr.load(MIE) & MASK_MSIP != 0 {
+          cpu.wfi.set(false);
+        }
+      }),
+    );
+    if delivered {
+      debug!("software interrupt raised on hart {}", hart);
+      self.pending.lock().unwrap().insert(hart);
+    }
+    delivered
+  }
+
+  /// Withdraws a software interrupt that was not taken yet.
+  pub fn clear(&self, hart: u16) {
+    let Some(isolate) = self.isolate.get().and_then(Weak::upgrade) else {
+      return;
+    };
+    if self.pending.lock().unwrap().remove(&hart) {
+      isolate.post(hart, Box::new(|cpu| cpu.csr.store(MIP, cpu.csr.load(MIP) & !MASK_MSIP)));
+    }
+  }
+
+  /// Called when `hart` takes the software interrupt.
+  pub(crate) fn acknowledge(&self, hart: u16) {
+    self.pending.lock().unwrap().remove(&hart);
+  }
+}
+
+fn hart(addr: u64, range: &RangeInclusive<u64>, size: u64) -> Option<u16> {
+  let offset = addr - range.start();
+  if size != 32 || !offset.is_multiple_of(4) {
+    return None;
+  }
+  u16::try_from(offset / 4).ok()
+}
+
+pub fn load(bus: &Bus, addr: u64, range: RangeInclusive<u64>, size: u64) -> Result<u64, Exception> {
+  let hart = hart(addr, &range, size).ok_or(Exception::LoadAccessFault(addr))?;
+  Ok(bus.clint.is_pending(hart) as u64)
+}
+
+pub fn store(bus: &Bus, addr: u64, range: RangeInclusive<u64>, size: u64, value: u64) -> Result<(), Exception> {
+  let hart = hart(addr, &range, size).ok_or(Exception::StoreAMOAccessFault(addr))?;
+  if value & 1 != 0 {
+    bus.clint.raise(hart);
+  } else {
+    bus.clint.clear(hart);
+  }
+  Ok(())
+}
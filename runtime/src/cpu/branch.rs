@@ -1,3 +1,5 @@
+use std::sync::atomic::Ordering;
+
 use crate::cpu::{Cpu, Instruction};
 use crate::exception::Exception;
 
@@ -9,64 +11,24 @@ pub fn branch(inst: Instruction, cpu: &mut Cpu) -> Result<u64, Exception> {
     | ((*inst >> 20) & 0x7e0) // imm[10:5]
     | ((*inst >> 7) & 0x1e); // imm[4:1]
 
-  match inst.funct3() {
-    0x0 => {
-      // beq
-      if cpu.regs[inst.rs1()] == cpu.regs[inst.rs2()] {
-        cpu.perf.end_cpu_time();
-        return Ok(cpu.pc.wrapping_add(imm));
-      }
-      cpu.perf.end_cpu_time();
-      cpu.update_pc()
-    }
-    0x1 => {
-      // bne
-      if cpu.regs[inst.rs1()] != cpu.regs[inst.rs2()] {
-        cpu.perf.end_cpu_time();
-        return Ok(cpu.pc.wrapping_add(imm));
-      }
-      cpu.perf.end_cpu_time();
-      cpu.update_pc()
-    }
-    0x4 => {
-      // blt
-      if (cpu.regs[inst.rs1()] as i64) < (cpu.regs[inst.rs2()] as i64) {
-        cpu.perf.end_cpu_time();
-        return Ok(cpu.pc.wrapping_add(imm));
-      }
-      cpu.perf.end_cpu_time();
-      cpu.update_pc()
-    }
-    0x5 => {
-      // bge
-      if (cpu.regs[inst.rs1()] as i64) >= (cpu.regs[inst.rs2()] as i64) {
-        cpu.perf.end_cpu_time();
-        return Ok(cpu.pc.wrapping_add(imm));
-      }
-      cpu.perf.end_cpu_time();
-      cpu.update_pc()
-    }
-    0x6 => {
-      // bltu
-      if cpu.regs[inst.rs1()] < cpu.regs[inst.rs2()] {
-        cpu.perf.end_cpu_time();
-        return Ok(cpu.pc.wrapping_add(imm));
-      }
-      cpu.perf.end_cpu_time();
-      cpu.update_pc()
-    }
-    0x7 => {
-      // bgeu
-      if cpu.regs[inst.rs1()] >= cpu.regs[inst.rs2()] {
-        cpu.perf.end_cpu_time();
-        return Ok(cpu.pc.wrapping_add(imm));
-      }
-      cpu.perf.end_cpu_time();
-      cpu.update_pc()
-    }
+  let (rs1, rs2) = (cpu.regs[inst.rs1()], cpu.regs[inst.rs2()]);
+  let taken = match inst.funct3() {
+    0x0 => rs1 == rs2,                   // beq
+    0x1 => rs1 != rs2,                   // bne
+    0x4 => (rs1 as i64) < (rs2 as i64),  // blt
+    0x5 => (rs1 as i64) >= (rs2 as i64), // bge
+    0x6 => rs1 < rs2,                    // bltu
+    0x7 => rs1 >= rs2,                   // bgeu
     _ => {
       cpu.perf.end_cpu_time();
-      Err(Exception::IllegalInstruction(*inst))
+      return Err(Exception::IllegalInstruction(*inst));
     }
+  };
+
+  cpu.perf.end_cpu_time();
+  if taken {
+    cpu.perf.branches_taken.fetch_add(1, Ordering::AcqRel);
+    return Ok(cpu.pc.wrapping_add(imm));
   }
+  cpu.update_pc()
 }
@@ -16,21 +16,19 @@ mod store;
 mod store_fp;
 mod system;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Write;
 use std::sync::{Arc, Weak};
 use std::sync::atomic::Ordering;
-use std::time::Instant;
 
 use async_trait::async_trait;
-use itertools::Itertools;
 use numfmt::Formatter;
 pub use instruction::Instruction;
-use mizu_hwconst::memory::DRAM_BASE;
-use tracing::{debug, info, trace};
+use mizu_hwconst::memory::{DRAM_BASE, HARDWARE_BASE};
+use tracing::{debug, error, info, trace};
 
-use crate::apic::Apic;
-use crate::bus::Bus;
+use crate::apic::{Apic, INTERRUPT_PRIORITY_NORMAL};
+use crate::bus::{Bus, BusMemoryExt};
 use crate::cpu::amo::amo;
 use crate::cpu::auipc::auipc;
 use crate::cpu::branch::branch;
@@ -52,10 +50,14 @@ use crate::csr::{
   Csr, MASK_MEIP, MASK_MIE, MASK_MPIE, MASK_MPP, MASK_MSIP, MASK_MTIP, MASK_SEIP, MASK_SSIP, MASK_STIP, MCAUSE, MEPC, MIE, MIP, MSTATUS, MTVAL, MTVEC,
 };
 use crate::exception::Exception;
+use crate::hart::Lifecycle;
 use crate::interrupt::Interrupt;
 use crate::isolate::Isolate;
 use crate::perf_counter::PerformanceCounter;
+use crate::profiler::Profiler;
+use crate::scheduler::Inbox;
 use crate::state_flow::StateFlow;
+use crate::trace::{TraceRecord, Tracer, FP_REGISTER_BASE};
 
 #[async_trait]
 pub trait InterruptHandler: Send + Sync {
@@ -70,12 +72,26 @@ pub struct Cpu {
   pub fp_regs: [f64; 32],
   pub pc: u64,
   pub bus: Arc<Bus>,
+  /// Base of the hardware area that handlers write their results to, [HARDWARE_BASE] unless the call runs detached.
+  pub hardware: u64,
   pub apic: Apic,
   pub csr: Csr,
+  /// Current [csr::privilege] mode, guests run in M-mode.
+  pub mode: u64,
   pub ivt: HashMap<u64, Arc<Box<dyn InterruptHandler>>>,
   pub perf: Arc<PerformanceCounter>,
   pub halt: bool,
   pub wfi: StateFlow<bool>,
+  pub tracer: Option<Arc<Tracer>>,
+  /// Profiler of the isolate, set by [Isolate::add_core].
+  pub profiler: Option<Arc<Profiler>>,
+  /// Records of the current instruction if it passed the filter of [Cpu::tracer].
+  traced: Option<Vec<TraceRecord>>,
+  /// Messages applied before the next step, see [Isolate::post].
+  pub inbox: Arc<Inbox>,
+  /// Addresses and data of raised events, written to the guest when it takes [Interrupt::PlatformDefined17].
+  events: VecDeque<(u64, Vec<u8>)>,
+  pub lifecycle: Arc<Lifecycle>,
 }
 
 impl Cpu {
@@ -92,6 +108,10 @@ impl Cpu {
 
     let mut csr = Csr::new(perf.clone());
     csr.store(csr::machine::POWERSTATE, 1);
+    // Guests may read every counter until they restrict it
+    csr.store(csr::MCOUNTEREN, u32::MAX as u64);
+    // Interrupts are enabled until the guest clears mstatus.MIE, for example while holding a lock
+    csr.store(MSTATUS, MASK_MIE);
 
     let apic = Apic::new();
     let ivt = HashMap::new();
@@ -104,25 +124,148 @@ impl Cpu {
       fp_regs: [0.0; 32],
       pc,
       bus,
+      hardware: HARDWARE_BASE,
       apic,
       csr,
+      mode: csr::privilege::MACHINE,
       ivt,
       perf,
       halt: false,
       wfi: StateFlow::new(false),
+      tracer: None,
+      profiler: None,
+      traced: None,
+      inbox: Arc::new(Inbox::default()),
+      events: VecDeque::new(),
+      lifecycle: Arc::new(Lifecycle::default()),
     }
   }
 
   /// Load a value from a dram.
   pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
     self.perf.loads.fetch_add(1, Ordering::Acquire);
-    self.bus.load(addr, size)
+    let value = self.bus.load(addr, size)?;
+    if let Some(records) = &mut self.traced {
+      records.push(TraceRecord::Load {
+        hart: self.id,
+        address: addr,
+        size: size as u8,
+        value,
+      });
+    }
+    Ok(value)
   }
 
   /// Store a value to a dram.
   pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
     self.perf.stores.fetch_add(1, Ordering::Acquire);
-    self.bus.store(addr, size, value)
+    self.bus.store(addr, size, value)?;
+    if let Some(records) = &mut self.traced {
+      records.push(TraceRecord::Store {
+        hart: self.id,
+        address: addr,
+        size: size as u8,
+        value,
+      });
+    }
+    Ok(())
+  }
+
+  /// Traces the instruction about to execute, returns the registers to compare with in [Cpu::end_trace].
+  pub(crate) fn begin_trace(&mut self, inst: u64) -> Option<([u64; 32], [f64; 32])> {
+    let tracer = self.tracer.as_ref()?;
+    if !tracer.matches(self.id, self.pc) {
+      return None;
+    }
+    self.traced = Some(vec![TraceRecord::Instruction {
+      hart: self.id,
+      index: self.perf.instructions_retired.load(Ordering::Acquire),
+      pc: self.pc,
+      inst: inst as u32,
+    }]);
+    Some((self.regs, self.fp_regs))
+  }
+
+  /// Traces the registers written by the instruction and writes its records.
+  pub(crate) fn end_trace(&mut self, (regs, fp_regs): ([u64; 32], [f64; 32])) {
+    let Some(mut records) = self.traced.take() else {
+      return;
+    };
+    for (register, (before, after)) in regs.iter().zip(self.regs).enumerate() {
+      if *before != after {
+        records.push(TraceRecord::Register {
+          hart: self.id,
+          register: register as u8,
+          value: after,
+        });
+      }
+    }
+    for (register, (before, after)) in fp_regs.iter().zip(self.fp_regs).enumerate() {
+      if before.to_bits() != after.to_bits() {
+        records.push(TraceRecord::Register {
+          hart: self.id,
+          register: FP_REGISTER_BASE + register as u8,
+          value: after.to_bits(),
+        });
+      }
+    }
+    if let Some(tracer) = &self.tracer {
+      tracer.emit_instruction(&records);
+    }
+  }
+
+  /// Traps pass the address filter of the tracer with their value, the address of an access fault.
+  fn trace_trap(&self, pc: u64, cause: u64, value: u64) {
+    let tracer = self.tracer.as_ref().filter(|tracer| tracer.matches(self.id, pc) && tracer.filter().matches_address(value, 8));
+    if let Some(tracer) = tracer {
+      tracer.emit(TraceRecord::Trap {
+        hart: self.id,
+        pc,
+        cause,
+        value,
+        target: self.pc,
+      });
+    }
+  }
+
+  /// Raises [Interrupt::PlatformDefined17] for `data`. Once the interrupt is taken, `data` is written to `address`
+  /// and the handler gets its length in `a0` and address in `a1`. Events are recorded by an attached record session
+  /// and dropped by a replaying one.
+  pub fn raise_event(&mut self, address: u64, data: &[u8]) {
+    if let Some(session) = self.bus.session().cloned() {
+      if !session.event(self, address, data) {
+        return;
+      }
+    }
+    self.deliver_event(address, data);
+  }
+
+  /// Queues an event, the running code is not disturbed until the interrupt is taken.
+  pub(crate) fn deliver_event(&mut self, address: u64, data: &[u8]) {
+    self.halt = false;
+    self.events.push_back((address, data.to_vec()));
+    self.apic.dispatch(Interrupt::PlatformDefined17, INTERRUPT_PRIORITY_NORMAL);
+    self.wfi.set(false);
+  }
+
+  /// Passes the next queued event to the handler of [Interrupt::PlatformDefined17].
+  fn take_event(&mut self) {
+    let Some((address, data)) = self.events.pop_front() else {
+      return;
+    };
+    let len = match self.bus.write(address, &data) {
+      Ok(()) => data.len() as u64,
+      Err(exception) => {
+        error!("dropping event of {} bytes: {:?}", data.len(), exception);
+        0
+      }
+    };
+    self.regs[10] = len;
+    self.regs[11] = address;
+    // The controller holds an interrupt once, the next event raises it again
+    if !self.events.is_empty() {
+      self.apic.dispatch(Interrupt::PlatformDefined17, INTERRUPT_PRIORITY_NORMAL);
+    }
   }
 
   /// Get an instruction from the dram.
@@ -135,12 +278,6 @@ impl Cpu {
   }
 
   pub fn dump(&self) -> String {
-    let returns = self.perf.returns.lock().unwrap();
-    let returns = returns.iter().sorted_by_key(|(_, count)| **count);
-    for (address, count) in returns {
-      info!("return to {:#18x}: {:<6} times", address, count);
-    }
-
     let mut output = String::new();
     output.write_fmt(format_args!("cpu={:<#18}\n", self.id)).unwrap();
     {
@@ -164,6 +301,21 @@ impl Cpu {
           Formatter::default().fmt2(stores)
         ))
         .unwrap();
+      output
+        .write_fmt(format_args!(
+          "branches_taken={:<12} traps={:<13} syscalls={}\n",
+          Formatter::default().fmt2(self.perf.branches_taken.load(Ordering::Acquire)),
+          Formatter::default().fmt2(self.perf.traps.load(Ordering::Acquire)),
+          Formatter::default().fmt2(self.perf.syscalls.load(Ordering::Acquire))
+        ))
+        .unwrap();
+      output
+        .write_fmt(format_args!(
+          "mapped={:<20} peak_mapped={}\n",
+          Formatter::default().fmt2(self.perf.mapped_bytes.load(Ordering::Acquire)),
+          Formatter::default().fmt2(self.perf.peak_mapped_bytes.load(Ordering::Acquire))
+        ))
+        .unwrap();
     }
     output
       .write_fmt(format_args!("pc={:<#18x}       mepc={:<#18x}\n", self.pc, self.csr.load(MEPC)))
@@ -238,6 +390,7 @@ impl Cpu {
   }
 
   pub fn handle_exception(&mut self, exception: Exception) {
+    self.perf.traps.fetch_add(1, Ordering::AcqRel);
     // the process to handle exception in S-mode and M-mode is similar,
     // includes following steps:
     // 0. set xPP to current mode.
@@ -282,15 +435,20 @@ impl Cpu {
     // set SPP / MPP = previous mode
     status = (status & !MASK_MPP) | (0b11 << 11);
     self.csr.store(MSTATUS, status);
+    self.trace_trap(pc, cause, exception.value());
   }
 
   pub fn handle_interrupt(&mut self, interrupt: Interrupt) {
+    self.perf.traps.fetch_add(1, Ordering::AcqRel);
     // similar to handle exception
     let pc = self.pc;
     let cause = interrupt.code();
 
     // Save registers
     self.saved_regs.copy_from_slice(&self.regs);
+    if interrupt == Interrupt::PlatformDefined17 {
+      self.take_event();
+    }
 
     // 3.1.7 & 4.1.2
     // When MODE=Direct, all traps into machine mode cause the pc to be set to the address in the BASE field.
@@ -336,6 +494,7 @@ impl Cpu {
     // set SPP / MPP = previous mode
     // status = (status & !MASK_MPP) | (3 << 11);
     self.csr.store(MSTATUS, status);
+    self.trace_trap(pc, cause, 0);
   }
 
   pub fn check_pending_interrupt(&mut self) -> Option<Interrupt> {
@@ -343,6 +502,10 @@ impl Cpu {
     // 3.1.9 & 4.1.3
     // Multiple simultaneous interrupts destined for M-mode are handled in the following decreasing
     // priority order: MEI, MSI, MTI, SEI, SSI, STI.
+    // Interrupts stay pending while mstatus.MIE is clear, which includes running a trap handler
+    if self.csr.load(MSTATUS) & MASK_MIE == 0 {
+      return None;
+    }
     let pending = self.csr.load(MIE) & self.csr.load(MIP);
 
     if (pending & MASK_MEIP) != 0 {
@@ -351,6 +514,7 @@ impl Cpu {
     }
     if (pending & MASK_MSIP) != 0 {
       self.csr.store(MIP, self.csr.load(MIP) & !MASK_MSIP);
+      self.bus.clint.acknowledge(self.id);
       return Some(MachineSoftwareInterrupt);
     }
     if (pending & MASK_MTIP) != 0 {
@@ -384,6 +548,7 @@ impl Cpu {
 
   pub async fn execute(&mut self, inst: u64) -> Result<u64, Exception> {
     self.perf.start_cpu_time();
+    self.perf.cycles.fetch_add(1, Ordering::AcqRel);
     let opcode = inst & 0x0000007f;
     let rd = ((inst & 0x00000f80) >> 7) as usize;
     let rs1 = ((inst & 0x000f8000) >> 15) as usize;
@@ -403,11 +568,6 @@ impl Cpu {
       self.regs[2]
     );
 
-    {
-      let mut returns = self.perf.returns.lock().unwrap();
-      *returns.entry(self.pc).or_insert(0) += 1;
-    }
-
     // let opcode = Opcode::from(opcode);
     // trace!("executing opcode {:?}", opcode);
 
@@ -471,3 +631,38 @@ pub mod opcode {
   pub const SYSTEM: u64 = 0b11_100_11;
   pub const OP_VE: u64 = 0b11_101_11;
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn writes_events_when_taken() {
+    let mut cpu = Cpu::new(0, Arc::new(Bus::new(Vec::new())), None);
+    cpu.regs[10] = 7;
+    cpu.deliver_event(HARDWARE_BASE + 0x16000, b"first");
+    cpu.deliver_event(HARDWARE_BASE + 0x16000, b"second!");
+    assert_eq!(cpu.regs[10], 7);
+    assert_eq!(cpu.bus.load(HARDWARE_BASE + 0x16000, 8).unwrap(), 0);
+
+    // Masked while mstatus.MIE is clear
+    cpu.csr.store(MSTATUS, 0);
+    assert_eq!(cpu.check_pending_interrupt(), None);
+    cpu.csr.store(MSTATUS, MASK_MIE);
+
+    let interrupt = cpu.check_pending_interrupt().unwrap();
+    cpu.handle_interrupt(interrupt);
+    assert_eq!((cpu.regs[10], cpu.regs[11]), (5, HARDWARE_BASE + 0x16000));
+    assert_eq!(cpu.saved_regs[10], 7);
+    assert_eq!(cpu.bus.read(HARDWARE_BASE + 0x16000, 5).unwrap(), b"first");
+
+    // Taken again for the second event once the handler returns
+    assert_eq!(cpu.check_pending_interrupt(), None);
+    cpu.csr.store(MSTATUS, MASK_MIE);
+    let interrupt = cpu.check_pending_interrupt().unwrap();
+    cpu.handle_interrupt(interrupt);
+    assert_eq!(cpu.regs[10], 7);
+    assert_eq!(cpu.bus.read(HARDWARE_BASE + 0x16000, 7).unwrap(), b"second!");
+    assert_eq!(cpu.apic.get(), None);
+  }
+}
@@ -1,12 +1,40 @@
+use std::sync::atomic::Ordering;
+
 use tracing::{debug, info};
 
 use crate::cpu::{Cpu, Instruction};
 use crate::csr;
 use crate::exception::Exception;
+use crate::power;
+
+/// Reads a CSR, the time is an input of a record or replay session.
+fn load_csr(cpu: &Cpu, addr: usize) -> u64 {
+  match cpu.bus.session() {
+    Some(session) if addr == csr::unprivileged::TIME => session.input(|| cpu.csr.load(addr)).unwrap_or_else(|| cpu.csr.load(addr)),
+    _ => cpu.csr.load(addr),
+  }
+}
+
+/// Writes a CSR, the power state is validated and audited.
+fn store_csr(cpu: &mut Cpu, addr: usize, value: u64) {
+  match addr {
+    csr::machine::POWERSTATE => power::set_power_state(cpu, value),
+    _ => cpu.csr.store(addr, value),
+  }
+}
 
 #[inline(always)]
 pub async fn system(inst: Instruction, cpu: &mut Cpu) -> Result<u64, Exception> {
   let csr_addr = ((*inst & 0xfff00000) >> 20) as usize;
+  if inst.funct3() != 0x0 {
+    // csrrs and csrrc (and their immediate forms) with x0 / zero only read
+    let writes = matches!(inst.funct3(), 0x1 | 0x5) || inst.rs1() != 0;
+    if !cpu.csr.is_accessible(csr_addr, cpu.mode) || (writes && csr::Csr::is_read_only(csr_addr)) {
+      cpu.perf.end_cpu_time();
+      return Err(Exception::IllegalInstruction(*inst));
+    }
+  }
+
   match inst.funct3() {
     0x0 => {
       match (inst.rs2(), inst.funct7()) {
@@ -16,16 +44,26 @@ pub async fn system(inst: Instruction, cpu: &mut Cpu) -> Result<u64, Exception>
           // ecall
           let num = cpu.regs[17];
           debug!("executing ecall {}", num);
-          if let Some(handler) = cpu.ivt.get(&num) {
-            cpu.perf.end_cpu_time();
-            let handler = handler.clone();
-            // syscalls are not cpu time limited
-            handler.handle(cpu).await;
-            cpu.perf.start_cpu_time();
-          } else {
+          cpu.perf.syscalls.fetch_add(1, Ordering::AcqRel);
+          let handler = cpu.ivt.get(&num).cloned();
+          let session = cpu.bus.session().cloned();
+          // A replaying session applies the recorded effects, the handler may be missing
+          if handler.is_none() && !session.as_ref().is_some_and(|session| session.is_replaying()) {
             return Err(Exception::RuntimeFault(num));
           }
           cpu.perf.end_cpu_time();
+          // syscalls are not cpu time limited
+          let result = match (session, handler) {
+            (Some(session), handler) => session.syscall(cpu, num, handler).await,
+            (None, Some(handler)) => {
+              handler.handle(cpu).await;
+              Ok(())
+            }
+            (None, None) => unreachable!(),
+          };
+          cpu.perf.start_cpu_time();
+          cpu.perf.end_cpu_time();
+          result?;
           cpu.update_pc()
         }
         (0x1, 0x0) => {
@@ -69,7 +107,10 @@ pub async fn system(inst: Instruction, cpu: &mut Cpu) -> Result<u64, Exception>
         (0x5, 0x8) => {
           // wfi
           info!("waiting for interrupt");
-          cpu.wfi.set(true);
+          // An enabled interrupt that is already pending would be lost while waiting
+          if cpu.csr.load(csr::MIE) & cpu.csr.load(csr::MIP) == 0 {
+            cpu.wfi.set(true);
+          }
           cpu.perf.end_cpu_time();
           cpu.update_pc()
         }
@@ -87,24 +128,27 @@ pub async fn system(inst: Instruction, cpu: &mut Cpu) -> Result<u64, Exception>
     }
     0x1 => {
       // csrrw
-      let t = cpu.csr.load(csr_addr);
-      cpu.csr.store(csr_addr, cpu.regs[inst.rs1()]);
+      let t = load_csr(cpu, csr_addr);
+      let value = cpu.regs[inst.rs1()];
+      store_csr(cpu, csr_addr, value);
       cpu.regs[inst.rd()] = t;
       cpu.perf.end_cpu_time();
       cpu.update_pc()
     }
     0x2 => {
       // csrrs
-      let t = cpu.csr.load(csr_addr);
-      cpu.csr.store(csr_addr, t | cpu.regs[inst.rs1()]);
+      let t = load_csr(cpu, csr_addr);
+      let value = t | cpu.regs[inst.rs1()];
+      store_csr(cpu, csr_addr, value);
       cpu.regs[inst.rd()] = t;
       cpu.perf.end_cpu_time();
       cpu.update_pc()
     }
     0x3 => {
       // csrrc
-      let t = cpu.csr.load(csr_addr);
-      cpu.csr.store(csr_addr, t & (!cpu.regs[inst.rs1()]));
+      let t = load_csr(cpu, csr_addr);
+      let value = t & (!cpu.regs[inst.rs1()]);
+      store_csr(cpu, csr_addr, value);
       cpu.regs[inst.rd()] = t;
       cpu.perf.end_cpu_time();
       cpu.update_pc()
@@ -112,16 +156,16 @@ pub async fn system(inst: Instruction, cpu: &mut Cpu) -> Result<u64, Exception>
     0x5 => {
       // csrrwi
       let zimm = inst.rs1() as u64;
-      cpu.regs[inst.rd()] = cpu.csr.load(csr_addr);
-      cpu.csr.store(csr_addr, zimm);
+      cpu.regs[inst.rd()] = load_csr(cpu, csr_addr);
+      store_csr(cpu, csr_addr, zimm);
       cpu.perf.end_cpu_time();
       cpu.update_pc()
     }
     0x6 => {
       // csrrsi
       let zimm = inst.rs1() as u64;
-      let t = cpu.csr.load(csr_addr);
-      cpu.csr.store(csr_addr, t | zimm);
+      let t = load_csr(cpu, csr_addr);
+      store_csr(cpu, csr_addr, t | zimm);
       cpu.regs[inst.rd()] = t;
       cpu.perf.end_cpu_time();
       cpu.update_pc()
@@ -129,8 +173,8 @@ pub async fn system(inst: Instruction, cpu: &mut Cpu) -> Result<u64, Exception>
     0x7 => {
       // csrrci
       let zimm = inst.rs1() as u64;
-      let t = cpu.csr.load(csr_addr);
-      cpu.csr.store(csr_addr, t & (!zimm));
+      let t = load_csr(cpu, csr_addr);
+      store_csr(cpu, csr_addr, t & (!zimm));
       cpu.regs[inst.rd()] = t;
       cpu.perf.end_cpu_time();
       cpu.update_pc()
@@ -11,12 +11,21 @@ use crate::perf_counter::PerformanceCounter;
 pub struct Csr {
   csrs: [u64; NUM_CSRS],
   perf: Arc<PerformanceCounter>,
+  /// Counters read as their source minus the offset, so writes do not disturb [PerformanceCounter].
+  counter_offsets: [u64; NUM_COUNTERS],
+  /// Values of counters inhibited by `mcountinhibit`.
+  counter_frozen: [u64; NUM_COUNTERS],
 }
 
 impl Csr {
   #[must_use]
   pub fn new(perf: Arc<PerformanceCounter>) -> Self {
-    Self { csrs: [0; NUM_CSRS], perf }
+    Self {
+      csrs: [0; NUM_CSRS],
+      perf,
+      counter_offsets: [0; NUM_COUNTERS],
+      counter_frozen: [0; NUM_COUNTERS],
+    }
   }
 
   #[must_use]
@@ -47,8 +56,11 @@ impl Csr {
       SIP => self.csrs[MIP] & self.csrs[MIDELEG],
       SSTATUS => self.csrs[MSTATUS] & MASK_SSTATUS,
       machine::CONFIGPTR => CPUID_BASE,
-      machine::INSTRET => self.perf.instructions_retired.load(Ordering::Acquire),
-      unprivileged::TIME => self.perf.cpu_time.lock().unwrap().as_nanos() as u64,
+      machine::CYCLE | unprivileged::CYCLE => self.counter(0),
+      machine::INSTRET | unprivileged::INSTRET => self.counter(2),
+      machine::HPMCOUNTER3..=machine::HPMCOUNTER31 => self.counter(addr - machine::CYCLE),
+      unprivileged::HPMCOUNTER3..=unprivileged::HPMCOUNTER31 => self.counter(addr - unprivileged::CYCLE),
+      unprivileged::TIME => self.perf.total_cpu_time().as_nanos() as u64,
       _ => self.csrs[addr],
     }
   }
@@ -58,10 +70,70 @@ impl Csr {
       SIE => self.csrs[MIE] = (self.csrs[MIE] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG]),
       SIP => self.csrs[MIP] = (self.csrs[MIE] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG]),
       SSTATUS => self.csrs[MSTATUS] = (self.csrs[MSTATUS] & !MASK_SSTATUS) | (value & MASK_SSTATUS),
+      machine::CYCLE => self.set_counter(0, value),
+      machine::INSTRET => self.set_counter(2, value),
+      machine::HPMCOUNTER3..=machine::HPMCOUNTER31 => self.set_counter(addr - machine::CYCLE, value),
+      machine::HPMEVENT3..=machine::HPMEVENT31 => {
+        // Switching the event keeps the count
+        let index = addr - MCOUNTINHIBIT;
+        let count = self.counter(index);
+        self.csrs[addr] = value;
+        self.set_counter(index, count);
+      }
+      MCOUNTINHIBIT => {
+        let counts: [u64; NUM_COUNTERS] = std::array::from_fn(|index| self.counter(index));
+        self.csrs[addr] = value;
+        for (index, count) in counts.into_iter().enumerate() {
+          self.set_counter(index, count);
+        }
+      }
+      // Read-only
+      unprivileged::CYCLE..=unprivileged::HPMCOUNTER31 => {}
       _ => self.csrs[addr] = value,
     }
   }
 
+  /// Whether a CSR is accessible in the [privilege] mode. Below M-mode, `mcounteren` enables `cycle`, `time`,
+  /// `instret` and `hpmcounterN` by bits 0, 1, 2 and N.
+  #[must_use]
+  pub fn is_accessible(&self, addr: usize, mode: u64) -> bool {
+    match addr {
+      _ if mode == privilege::MACHINE => true,
+      unprivileged::CYCLE..=unprivileged::HPMCOUNTER31 => (self.csrs[MCOUNTEREN] >> (addr - unprivileged::CYCLE)) & 1 == 1,
+      _ => true,
+    }
+  }
+
+  /// CSRs with the top two address bits set are read-only.
+  #[must_use]
+  pub const fn is_read_only(addr: usize) -> bool {
+    (addr >> 10) & 0b11 == 0b11
+  }
+
+  fn counter_source(&self, index: usize) -> u64 {
+    match index {
+      0 => self.perf.cycles.load(Ordering::Acquire),
+      2 => self.perf.instructions_retired.load(Ordering::Acquire),
+      3.. => self.perf.event(self.csrs[MCOUNTINHIBIT + index]),
+      _ => 0,
+    }
+  }
+
+  fn counter(&self, index: usize) -> u64 {
+    if (self.csrs[MCOUNTINHIBIT] >> index) & 1 == 1 {
+      return self.counter_frozen[index];
+    }
+    self.counter_source(index).wrapping_sub(self.counter_offsets[index])
+  }
+
+  fn set_counter(&mut self, index: usize, value: u64) {
+    if (self.csrs[MCOUNTINHIBIT] >> index) & 1 == 1 {
+      self.counter_frozen[index] = value;
+    } else {
+      self.counter_offsets[index] = self.counter_source(index).wrapping_sub(value);
+    }
+  }
+
   #[inline]
   #[must_use]
   pub const fn is_medelegated(&self, cause: u64) -> bool {
@@ -74,3 +146,34 @@ impl Csr {
     (self.csrs[MIDELEG].wrapping_shr(cause as u32) & 1) == 1
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reset_keeps_counters() {
+    let perf = Arc::new(PerformanceCounter::new());
+    let mut csr = Csr::new(perf.clone());
+    perf.cycles.store(100, Ordering::Release);
+    perf.instructions_retired.store(90, Ordering::Release);
+    csr.store(machine::CYCLE, 10);
+
+    perf.reset();
+    perf.cycles.fetch_add(5, Ordering::AcqRel);
+    assert_eq!(csr.load(unprivileged::CYCLE), 15);
+    assert_eq!(csr.load(unprivileged::INSTRET), 90);
+  }
+
+  #[test]
+  fn counter_enable_below_machine_mode() {
+    let mut csr = Csr::new(Arc::new(PerformanceCounter::new()));
+    csr.store(MCOUNTEREN, 0b101);
+
+    assert!(csr.is_accessible(unprivileged::TIME, privilege::MACHINE));
+    assert!(csr.is_accessible(unprivileged::CYCLE, privilege::USER));
+    assert!(!csr.is_accessible(unprivileged::TIME, privilege::USER));
+    assert!(!csr.is_accessible(unprivileged::HPMCOUNTER3, privilege::SUPERVISOR));
+    assert!(csr.is_accessible(MSTATUS, privilege::USER));
+  }
+}
@@ -1,23 +1,49 @@
-use std::ops::Range;
-
-use tracing::{error, info, warn};
+use mizu_hal_types::memory::PAGE_SIZE;
+use tracing::{error, info};
 
 use crate::exception::Exception;
 
 pub struct Dram {
   pub dram: Vec<u8>,
+  /// One bit per page that was written to, kept across swapping.
+  written: Vec<u64>,
+  used_pages: usize,
 }
 
 impl Dram {
   pub fn new(size: usize) -> Self {
-    Self { dram: vec![0; size] }
+    Self {
+      dram: vec![0; size],
+      written: vec![0; size.div_ceil(PAGE_SIZE as usize).div_ceil(64)],
+      used_pages: 0,
+    }
   }
 
   pub fn init(&mut self, data: &[u8]) {
     self.dram[..data.len()].copy_from_slice(data);
+    self.mark(0, data.len());
     info!("initialized dram with {} bytes of data", data.len());
   }
 
+  /// Number of pages written since the memory was created, an estimate of the memory used by the guest.
+  pub fn used_pages(&self) -> usize {
+    self.used_pages
+  }
+
+  /// Marks the pages in `index..index + len` as written.
+  fn mark(&mut self, index: usize, len: usize) {
+    if len == 0 {
+      return;
+    }
+    for page in index / PAGE_SIZE as usize..=(index + len - 1) / PAGE_SIZE as usize {
+      let (word, bit) = (page / 64, 1 << (page % 64));
+      if self.written[word] & bit == 0 {
+        self.written[word] |= bit;
+        self.used_pages += 1;
+      }
+    }
+  }
+
   pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
     let index = addr as usize;
     match size {
@@ -44,7 +70,27 @@ impl Dram {
         return Err(Exception::StoreAMOAccessFault(addr));
       }
     }
+    self.mark(index, size as usize / 8);
 
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn counts_written_pages() {
+    let mut dram = Dram::new(4 * PAGE_SIZE as usize);
+    dram.init(&[1; 10]);
+    assert_eq!(dram.used_pages(), 1);
+    dram.store(8, 64, 0).unwrap();
+    assert_eq!(dram.used_pages(), 1);
+    // Straddles the second and the third page
+    dram.store(2 * PAGE_SIZE - 4, 64, u64::MAX).unwrap();
+    assert_eq!(dram.used_pages(), 3);
+    dram.load(3 * PAGE_SIZE, 64).unwrap();
+    assert_eq!(dram.used_pages(), 3);
+  }
+}
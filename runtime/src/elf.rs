@@ -0,0 +1,357 @@
+//! Loader for guest programs, either a statically linked RISC-V ELF executable or a flat binary.
+
+use thiserror::Error;
+
+use crate::memory::{DRAM_BASE, DRAM_END, DRAM_SIZE};
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 0xf3;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+/// How far the loaded bytes may extend past the size of the file, room for the alignment of the segments.
+const MAX_PADDING: usize = 1024 * 1024;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ElfError {
+  #[error("image is empty")]
+  Empty,
+  #[error("image is larger than the guest memory")]
+  TooLarge,
+  #[error("truncated elf file")]
+  Truncated,
+  #[error("not a 64-bit little-endian elf file")]
+  UnsupportedClass,
+  #[error("not a risc-v executable")]
+  UnsupportedMachine,
+  #[error("not a static executable")]
+  UnsupportedType,
+  #[error("segment at {0:#x} is outside of the guest memory")]
+  SegmentOutOfBounds(u64),
+  #[error("segment at {0:#x} is too far from the start of the image for the size of the file")]
+  SegmentTooSparse(u64),
+  #[error("entry point {0:#x} is outside of the guest memory")]
+  EntryOutOfBounds(u64),
+  #[error("no loadable segments")]
+  NoSegments,
+}
+
+/// Initial contents of the guest memory.
+#[derive(Debug, Clone)]
+pub struct Image {
+  /// Bytes placed at [DRAM_BASE].
+  pub code: Vec<u8>,
+  pub entry: u64,
+  /// Functions of the symbol table, empty for flat binaries and stripped executables.
+  pub symbols: Symbols,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+  pub address: u64,
+  pub size: u64,
+  /// Demangled name.
+  pub name: String,
+}
+
+/// Function symbols sorted by address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbols {
+  symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+  pub fn new(mut symbols: Vec<Symbol>) -> Self {
+    symbols.sort_by_key(|symbol| symbol.address);
+    Self { symbols }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.symbols.is_empty()
+  }
+
+  /// Function containing `address`, a symbol without a size extends to the next one.
+  pub fn lookup(&self, address: u64) -> Option<&Symbol> {
+    let index = self.symbols.partition_point(|symbol| symbol.address <= address).checked_sub(1)?;
+    let symbol = &self.symbols[index];
+    let end = match symbol.size {
+      0 => self.symbols.get(index + 1).map_or(u64::MAX, |next| next.address),
+      size => symbol.address + size,
+    };
+    (address < end).then_some(symbol)
+  }
+}
+
+impl Image {
+  /// Loads an ELF executable, anything else is treated as a flat binary that starts at [DRAM_BASE].
+  pub fn parse(data: &[u8]) -> Result<Image, ElfError> {
+    if data.starts_with(ELF_MAGIC) {
+      return load_elf(data);
+    }
+
+    if data.is_empty() {
+      return Err(ElfError::Empty);
+    }
+    if data.len() as u64 > DRAM_SIZE {
+      return Err(ElfError::TooLarge);
+    }
+    Ok(Image {
+      code: data.to_vec(),
+      entry: DRAM_BASE,
+      symbols: Symbols::default(),
+    })
+  }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+  let bytes = data.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+  Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+  let bytes = data.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+  Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+  let bytes = data.get(offset..offset + 8).ok_or(ElfError::Truncated)?;
+  Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn load_elf(data: &[u8]) -> Result<Image, ElfError> {
+  if data.len() < HEADER_SIZE {
+    return Err(ElfError::Truncated);
+  }
+  if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+    return Err(ElfError::UnsupportedClass);
+  }
+  if u16_at(data, 18)? != EM_RISCV {
+    return Err(ElfError::UnsupportedMachine);
+  }
+  if u16_at(data, 16)? != ET_EXEC {
+    return Err(ElfError::UnsupportedType);
+  }
+
+  let entry = u64_at(data, 24)?;
+  let program_headers = u64_at(data, 32)? as usize;
+  let program_header_size = u16_at(data, 54)? as usize;
+  let program_header_count = u16_at(data, 56)? as usize;
+  if program_header_size < PROGRAM_HEADER_SIZE {
+    return Err(ElfError::Truncated);
+  }
+
+  let mut code = Vec::new();
+  for index in 0..program_header_count {
+    let header = program_headers
+      .checked_add(index * program_header_size)
+      .ok_or(ElfError::Truncated)?;
+    if u32_at(data, header)? != PT_LOAD {
+      continue;
+    }
+
+    let offset = u64_at(data, header + 8)?;
+    let address = u64_at(data, header + 16)?;
+    let file_size = u64_at(data, header + 32)?;
+    let memory_size = u64_at(data, header + 40)?;
+
+    let end = address.checked_add(memory_size).ok_or(ElfError::SegmentOutOfBounds(address))?;
+    if address < DRAM_BASE || end > DRAM_END + 1 || file_size > memory_size {
+      return Err(ElfError::SegmentOutOfBounds(address));
+    }
+
+    let contents = offset
+      .checked_add(file_size)
+      .and_then(|end| data.get(offset as usize..end as usize))
+      .ok_or(ElfError::Truncated)?;
+
+    // The rest of the segment (.bss) is already zeroed in a fresh DRAM
+    if contents.is_empty() {
+      continue;
+    }
+    let start = (address - DRAM_BASE) as usize;
+    if start + contents.len() > data.len() + MAX_PADDING {
+      return Err(ElfError::SegmentTooSparse(address));
+    }
+    if code.len() < start + contents.len() {
+      code.resize(start + contents.len(), 0);
+    }
+    code[start..start + contents.len()].copy_from_slice(contents);
+  }
+
+  if code.is_empty() {
+    return Err(ElfError::NoSegments);
+  }
+  if !(DRAM_BASE..=DRAM_END).contains(&entry) {
+    return Err(ElfError::EntryOutOfBounds(entry));
+  }
+  // Symbols only help profiling, an unreadable table is ignored
+  let symbols = load_symbols(data).unwrap_or_default();
+  Ok(Image { code, entry, symbols })
+}
+
+fn c_string(data: &[u8], offset: usize) -> Option<&str> {
+  let bytes = data.get(offset..)?;
+  let end = bytes.iter().position(|byte| *byte == 0)?;
+  std::str::from_utf8(&bytes[..end]).ok()
+}
+
+fn load_symbols(data: &[u8]) -> Result<Symbols, ElfError> {
+  let section_headers = u64_at(data, 40)? as usize;
+  let section_header_size = u16_at(data, 58)? as usize;
+  let section_header_count = u16_at(data, 60)? as usize;
+  if section_header_size < SECTION_HEADER_SIZE {
+    return Err(ElfError::Truncated);
+  }
+  let section = |index: usize| section_headers.checked_add(index * section_header_size).ok_or(ElfError::Truncated);
+
+  let mut symbols = Vec::new();
+  for index in 0..section_header_count {
+    let header = section(index)?;
+    if u32_at(data, header + 4)? != SHT_SYMTAB {
+      continue;
+    }
+
+    let offset = u64_at(data, header + 24)? as usize;
+    let size = u64_at(data, header + 32)? as usize;
+    let strings = u64_at(data, section(u32_at(data, header + 40)? as usize)? + 24)? as usize;
+    let end = offset.checked_add(size).ok_or(ElfError::Truncated)?;
+    for symbol in (offset..end).step_by(SYMBOL_SIZE) {
+      let info = *data.get(symbol + 4).ok_or(ElfError::Truncated)?;
+      if info & 0xf != STT_FUNC {
+        continue;
+      }
+      let address = u64_at(data, symbol + 8)?;
+      if address == 0 {
+        continue;
+      }
+      let Some(name) = c_string(data, strings + u32_at(data, symbol)? as usize) else {
+        continue;
+      };
+      symbols.push(Symbol {
+        address,
+        size: u64_at(data, symbol + 16)?,
+        // The alternate format drops the hash suffix
+        name: format!("{:#}", rustc_demangle::demangle(name)),
+      });
+    }
+  }
+  Ok(Symbols::new(symbols))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Builds an executable with one `PT_LOAD` segment per `(address, contents, memory size)`.
+  fn elf(entry: u64, segments: &[(u64, &[u8], u64)]) -> Vec<u8> {
+    let mut data = vec![0; HEADER_SIZE + segments.len() * PROGRAM_HEADER_SIZE];
+    data[..4].copy_from_slice(ELF_MAGIC);
+    data[4] = ELFCLASS64;
+    data[5] = ELFDATA2LSB;
+    data[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    data[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+    data[24..32].copy_from_slice(&entry.to_le_bytes());
+    data[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    data[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    data[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+    for (index, (address, contents, memory_size)) in segments.iter().enumerate() {
+      let header = HEADER_SIZE + index * PROGRAM_HEADER_SIZE;
+      let offset = data.len() as u64;
+      data[header..header + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+      data[header + 8..header + 16].copy_from_slice(&offset.to_le_bytes());
+      data[header + 16..header + 24].copy_from_slice(&address.to_le_bytes());
+      data[header + 32..header + 40].copy_from_slice(&(contents.len() as u64).to_le_bytes());
+      data[header + 40..header + 48].copy_from_slice(&memory_size.to_le_bytes());
+      data.extend_from_slice(contents);
+    }
+    data
+  }
+
+  #[test]
+  fn loads_flat_binaries() {
+    let image = Image::parse(&[0x13, 0, 0, 0]).unwrap();
+    assert_eq!(image.code, [0x13, 0, 0, 0]);
+    assert_eq!(image.entry, DRAM_BASE);
+    assert_eq!(Image::parse(&[]).unwrap_err(), ElfError::Empty);
+  }
+
+  #[test]
+  fn loads_segments() {
+    let data = elf(DRAM_BASE + 4, &[(DRAM_BASE, &[1, 2], 2), (DRAM_BASE + 8, &[3], 0x100)]);
+    let image = Image::parse(&data).unwrap();
+    assert_eq!(image.entry, DRAM_BASE + 4);
+    assert_eq!(image.code, [1, 2, 0, 0, 0, 0, 0, 0, 3]);
+    assert!(image.symbols.is_empty());
+  }
+
+  #[test]
+  fn does_not_load_bss() {
+    let data = elf(DRAM_BASE, &[(DRAM_BASE, &[1], 1), (DRAM_END - 0xfff, &[], 0x1000)]);
+    assert_eq!(Image::parse(&data).unwrap().code, [1]);
+  }
+
+  #[test]
+  fn rejects_invalid_executables() {
+    let data = elf(DRAM_BASE, &[(DRAM_BASE, &[1], 1)]);
+    assert_eq!(Image::parse(&data[..HEADER_SIZE - 1]).unwrap_err(), ElfError::Truncated);
+    assert_eq!(Image::parse(&data[..data.len() - 1]).unwrap_err(), ElfError::Truncated);
+
+    let mut class = data.clone();
+    class[4] = 1;
+    assert_eq!(Image::parse(&class).unwrap_err(), ElfError::UnsupportedClass);
+    let mut machine = data.clone();
+    machine[18] = 0x3e;
+    assert_eq!(Image::parse(&machine).unwrap_err(), ElfError::UnsupportedMachine);
+    let mut kind = data.clone();
+    kind[16] = 3;
+    assert_eq!(Image::parse(&kind).unwrap_err(), ElfError::UnsupportedType);
+
+    assert_eq!(Image::parse(&elf(DRAM_BASE, &[])).unwrap_err(), ElfError::NoSegments);
+    assert_eq!(
+      Image::parse(&elf(DRAM_END + 1, &[(DRAM_BASE, &[1], 1)])).unwrap_err(),
+      ElfError::EntryOutOfBounds(DRAM_END + 1)
+    );
+  }
+
+  #[test]
+  fn rejects_segments_outside_of_the_memory() {
+    let below = elf(DRAM_BASE, &[(DRAM_BASE - 1, &[1], 1)]);
+    assert_eq!(Image::parse(&below).unwrap_err(), ElfError::SegmentOutOfBounds(DRAM_BASE - 1));
+    let above = elf(DRAM_BASE, &[(DRAM_END, &[1, 2], 2)]);
+    assert_eq!(Image::parse(&above).unwrap_err(), ElfError::SegmentOutOfBounds(DRAM_END));
+    let overflow = elf(DRAM_BASE, &[(DRAM_BASE, &[1], u64::MAX)]);
+    assert_eq!(Image::parse(&overflow).unwrap_err(), ElfError::SegmentOutOfBounds(DRAM_BASE));
+    let larger_file = elf(DRAM_BASE, &[(DRAM_BASE, &[1, 2], 1)]);
+    assert_eq!(Image::parse(&larger_file).unwrap_err(), ElfError::SegmentOutOfBounds(DRAM_BASE));
+  }
+
+  #[test]
+  fn rejects_sparse_segments() {
+    let address = DRAM_END - 0xfff;
+    let data = elf(DRAM_BASE, &[(address, &[1], 1)]);
+    assert_eq!(Image::parse(&data).unwrap_err(), ElfError::SegmentTooSparse(address));
+  }
+
+  #[test]
+  fn looks_up_symbols() {
+    let symbol = |address, size, name: &str| Symbol {
+      address,
+      size,
+      name: name.to_owned(),
+    };
+    let symbols = Symbols::new(vec![symbol(0x200, 0, "b"), symbol(0x100, 0x10, "a")]);
+    assert_eq!(symbols.lookup(0x108).map(|symbol| symbol.name.as_str()), Some("a"));
+    assert_eq!(symbols.lookup(0x110), None);
+    assert_eq!(symbols.lookup(0xff), None);
+    assert_eq!(symbols.lookup(0x1000).map(|symbol| symbol.name.as_str()), Some("b"));
+  }
+}
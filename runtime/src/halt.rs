@@ -1,5 +1,5 @@
 use async_trait::async_trait;
-use runtime::cpu::{Cpu, InterruptHandler};
+use crate::cpu::{Cpu, InterruptHandler};
 use tracing::debug;
 
 pub struct HaltHandler {}
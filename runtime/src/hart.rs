@@ -0,0 +1,143 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Weak;
+
+use async_trait::async_trait;
+use mizu_hal_types::hart::*;
+use thiserror::Error;
+use tracing::{debug, info};
+
+use crate::cpu::{Cpu, InterruptHandler};
+use crate::isolate::Isolate;
+
+const RUNNING: u8 = 0;
+const STOPPED: u8 = 1;
+const ENDED: u8 = 2;
+
+/// Whether a hart runs, readable by the other harts while it does.
+#[derive(Debug, Default)]
+pub struct Lifecycle(AtomicU8);
+
+impl Lifecycle {
+  pub fn is_running(&self) -> bool {
+    self.0.load(Ordering::Acquire) == RUNNING
+  }
+
+  /// Stopped by [action::STOP], or ended.
+  pub fn is_stopped(&self) -> bool {
+    !self.is_running()
+  }
+
+  pub fn stop(&self) {
+    let _ = self.0.compare_exchange(RUNNING, STOPPED, Ordering::AcqRel, Ordering::Acquire);
+  }
+
+  /// The hart will not run again, set when the scheduler stops it.
+  pub fn end(&self) {
+    self.0.store(ENDED, Ordering::Release);
+  }
+
+  /// Returns false unless the hart was stopped by [action::STOP].
+  pub fn start(&self) -> bool {
+    self.0.compare_exchange(STOPPED, RUNNING, Ordering::AcqRel, Ordering::Acquire).is_ok()
+  }
+}
+
+#[derive(Error, Debug)]
+pub enum HartError {
+  #[error("invalid hart")]
+  InvalidHart,
+  #[error("hart is not stopped")]
+  AlreadyStarted,
+  #[error("invalid argument")]
+  InvalidArgument,
+}
+
+impl HartError {
+  /// Status code reported to the guest.
+  #[must_use]
+  pub fn code(&self) -> u64 {
+    match self {
+      HartError::InvalidHart => status::INVALID_HART,
+      HartError::AlreadyStarted => status::ALREADY_STARTED,
+      HartError::InvalidArgument => status::INVALID_ARGUMENT,
+    }
+  }
+}
+
+/// Hart state management, similar to the SBI HSM extension.
+pub struct HartHandler {}
+
+/// Returns the result in `a0` and a status code in `a1`.
+#[async_trait]
+impl InterruptHandler for HartHandler {
+  async fn handle(&self, cpu: &mut Cpu) {
+    let action = cpu.regs[10];
+    let isolate = cpu.isolate.as_ref().and_then(Weak::upgrade);
+
+    let result = match action {
+      action::ID => Ok(cpu.id as u64),
+      action::COUNT => Ok(isolate.as_ref().map_or(1, |isolate| isolate.cores.lock().unwrap().len() as u64)),
+      action::STATUS => hart_status(cpu, isolate.as_deref(), cpu.regs[11]),
+      action::STOP => {
+        info!("hart {} stopped", cpu.id);
+        cpu.lifecycle.stop();
+        cpu.wfi.set(true);
+        Ok(0)
+      }
+      action::START => start_hart(cpu, isolate.as_deref(), cpu.regs[11], cpu.regs[12], cpu.regs[13]),
+      _ => Err(HartError::InvalidArgument),
+    };
+    debug!("hart action {}: {:?}", action, result);
+
+    (cpu.regs[10], cpu.regs[11]) = match result {
+      Ok(value) => (value, status::OK),
+      Err(error) => (0, error.code()),
+    };
+  }
+}
+
+fn hart_id(hart: u64) -> Result<u16, HartError> {
+  u16::try_from(hart).map_err(|_| HartError::InvalidHart)
+}
+
+fn hart_status(cpu: &Cpu, isolate: Option<&Isolate>, hart: u64) -> Result<u64, HartError> {
+  let hart = hart_id(hart)?;
+  if hart == cpu.id {
+    return Ok(state::STARTED);
+  }
+  let link = isolate.and_then(|isolate| isolate.link(hart)).ok_or(HartError::InvalidHart)?;
+  Ok(if link.lifecycle.is_stopped() {
+    state::STOPPED
+  } else if link.wfi.get() {
+    state::SUSPENDED
+  } else {
+    state::STARTED
+  })
+}
+
+fn start_hart(cpu: &Cpu, isolate: Option<&Isolate>, hart: u64, pc: u64, opaque: u64) -> Result<u64, HartError> {
+  let hart = hart_id(hart)?;
+  if !pc.is_multiple_of(2) {
+    return Err(HartError::InvalidArgument);
+  }
+  if hart == cpu.id {
+    return Err(HartError::AlreadyStarted);
+  }
+  let isolate = isolate.ok_or(HartError::InvalidHart)?;
+  let link = isolate.link(hart).ok_or(HartError::InvalidHart)?;
+  if !link.lifecycle.start() {
+    return Err(HartError::AlreadyStarted);
+  }
+
+  info!("hart {} starting hart {} at {:#x}", cpu.id, hart, pc);
+  isolate.post(
+    hart,
+    Box::new(move |cpu| {
+      cpu.pc = pc;
+      cpu.regs[10] = hart as u64;
+      cpu.regs[11] = opaque;
+      cpu.wfi.set(false);
+    }),
+  );
+  Ok(0)
+}
@@ -11,6 +11,8 @@ pub enum Interrupt {
 
   PlatformDefined16,
   PlatformDefined17,
+  /// Asynchronous host call completed.
+  PlatformDefined18,
 }
 
 impl Interrupt {
@@ -26,6 +28,7 @@ impl Interrupt {
       MachineExternalInterrupt => 11 | MASK_INTERRUPT_BIT,
       PlatformDefined16 => 16 | MASK_INTERRUPT_BIT,
       PlatformDefined17 => 17 | MASK_INTERRUPT_BIT,
+      PlatformDefined18 => 18 | MASK_INTERRUPT_BIT,
     }
   }
 }
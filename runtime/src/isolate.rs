@@ -1,16 +1,36 @@
-use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
 
 use tokio::sync::Mutex;
-use tracing::info;
+use tracing::{debug, info, warn};
 
 use crate::bus::Bus;
 use crate::cpu::Cpu;
+use crate::csr::{self, power_state};
+use crate::hart::Lifecycle;
+use crate::mailbox::Mailbox;
+use crate::profiler::Profiler;
+use crate::scheduler::{Inbox, Message, Scheduler};
+use crate::state_flow::StateFlow;
+
+/// Parts of a core usable while it is running.
+#[derive(Clone)]
+pub struct CoreLink {
+  pub inbox: Arc<Inbox>,
+  pub mailbox: Arc<Mailbox>,
+  pub wfi: StateFlow<bool>,
+  pub lifecycle: Arc<Lifecycle>,
+}
 
 pub struct Isolate {
   pub bus: Arc<Bus>,
   pub cores: std::sync::Mutex<Vec<Arc<Mutex<Cpu>>>>,
-  pub exploded: AtomicBool
+  pub exploded: AtomicBool,
+  /// Cores stop fetching instructions while set, see [Isolate::pause].
+  pub paused: StateFlow<bool>,
+  pub profiler: Arc<Profiler>,
+  links: std::sync::Mutex<Vec<CoreLink>>,
+  scheduler: OnceLock<Weak<Scheduler>>,
 }
 
 impl Isolate {
@@ -19,8 +39,13 @@ impl Isolate {
       bus,
       cores: std::sync::Mutex::new(Vec::new()),
       exploded: AtomicBool::new(false),
+      paused: StateFlow::new(false),
+      profiler: Arc::new(Profiler::new()),
+      links: std::sync::Mutex::new(Vec::new()),
+      scheduler: OnceLock::new(),
     });
 
+    this.bus.clint.attach(&this);
     this.add_core(Cpu::new(0, this.bus.clone(), Some(Arc::downgrade(&this))));
     this
   }
@@ -29,20 +54,103 @@ impl Isolate {
     self.cores.lock().unwrap()[0].clone()
   }
 
-  pub fn add_core(&self, core: Cpu) -> Arc<Mutex<Cpu>> {
+  pub fn add_core(&self, mut core: Cpu) -> Arc<Mutex<Cpu>> {
+    core.profiler = Some(self.profiler.clone());
     let mut cores = self.cores.lock().unwrap();
+    self.links.lock().unwrap().push(CoreLink {
+      inbox: core.inbox.clone(),
+      mailbox: Arc::new(Mailbox::default()),
+      wfi: core.wfi.clone(),
+      lifecycle: core.lifecycle.clone(),
+    });
     let core = Arc::new(Mutex::new(core));
     cores.push(core.clone());
     info!("added core {}", cores.len() - 1);
     core
   }
 
-  pub fn get_core(&self, id: u16) -> Arc<Mutex<Cpu>> {
-    let cores = self.cores.lock().unwrap();
-    cores[id as usize].clone()
+  pub fn get_core(&self, id: u16) -> Option<Arc<Mutex<Cpu>>> {
+    self.cores.lock().unwrap().get(id as usize).cloned()
+  }
+
+  pub fn link(&self, id: u16) -> Option<CoreLink> {
+    self.links.lock().unwrap().get(id as usize).cloned()
+  }
+
+  /// Scheduler running the cores, set by [Scheduler::spawn].
+  pub fn scheduler(&self) -> Option<Arc<Scheduler>> {
+    self.scheduler.get().and_then(Weak::upgrade)
+  }
+
+  /// Runs the cores started later on `scheduler`, [Scheduler::spawn] attaches it as well.
+  pub fn attach_scheduler(&self, scheduler: &Arc<Scheduler>) {
+    let _ = self.scheduler.set(Arc::downgrade(scheduler));
+  }
+
+  /// Applies `message` to core `id` before its next step without waiting for it to yield, returns false if there is
+  /// no such core.
+  pub fn post(&self, id: u16, message: Message) -> bool {
+    let Some(link) = self.link(id) else {
+      return false;
+    };
+    link.inbox.post(message);
+    self.wake();
+    true
   }
 
+  /// Lets parked cores run again if they were interrupted or resumed.
   pub fn wake(&self) {
-    info!("waking isolate");
+    debug!("waking isolate");
+    if let Some(scheduler) = self.scheduler() {
+      scheduler.wake(self);
+    }
+  }
+
+  /// Swaps the memory out on a blocking thread if every core is in deep sleep or stopped and none is running, returns
+  /// whether it started.
+  pub fn try_sleep(&self) -> bool {
+    let Some(directory) = self.bus.power.swap_directory().cloned() else {
+      return false;
+    };
+    let cores = self.cores.lock().unwrap().clone();
+    // Holding every core keeps them asleep until the memory is written
+    let Ok(cores) = cores.into_iter().map(|core| core.try_lock_owned()).collect::<Result<Vec<_>, _>>() else {
+      return false;
+    };
+    let sleeping = cores
+      .iter()
+      .all(|cpu| cpu.wfi.get() && !cpu.inbox.is_pending() && (cpu.csr.load(csr::machine::POWERSTATE) == power_state::DEEP_SLEEP || cpu.lifecycle.is_stopped()));
+    if !sleeping || self.bus.is_swapped_out() {
+      return false;
+    }
+
+    let bus = self.bus.clone();
+    tokio::task::spawn_blocking(move || {
+      match bus.swap_out(&directory) {
+        Ok(released) => info!("isolate asleep, released {} bytes", released),
+        Err(error) => warn!("failed to swap out isolate: {}", error),
+      }
+      drop(cores);
+    });
+    true
+  }
+
+  pub fn pause(&self) {
+    info!("pausing isolate");
+    self.paused.set(true);
+  }
+
+  pub fn resume(&self) {
+    info!("resuming isolate");
+    self.paused.set(false);
+    self.wake();
+  }
+
+  /// Marks the isolate as exploded and wakes all cores, so that they observe it and stop.
+  pub async fn stop(&self) {
+    info!("stopping isolate");
+    self.exploded.store(true, Ordering::Release);
+    self.paused.set(false);
+    self.wake();
   }
 }
@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+use std::sync::{Mutex, Weak};
+
+use async_trait::async_trait;
+use mizu_hal_types::mailbox::*;
+use thiserror::Error;
+use tracing::debug;
+
+use crate::bus::BusMemoryExt;
+use crate::cpu::{Cpu, InterruptHandler};
+use crate::isolate::Isolate;
+
+#[derive(Error, Debug)]
+pub enum MailboxError {
+  #[error("mailbox is empty")]
+  Empty,
+  #[error("mailbox is full")]
+  Full,
+  #[error("invalid hart")]
+  InvalidHart,
+  #[error("message of {0} bytes is too large")]
+  TooLarge(u64),
+  #[error("invalid argument")]
+  InvalidArgument,
+}
+
+impl MailboxError {
+  /// Status code reported to the guest.
+  #[must_use]
+  pub fn code(&self) -> u64 {
+    match self {
+      MailboxError::Empty => status::EMPTY,
+      MailboxError::Full => status::FULL,
+      MailboxError::InvalidHart => status::INVALID_HART,
+      MailboxError::TooLarge(_) => status::TOO_LARGE,
+      MailboxError::InvalidArgument => status::INVALID_ARGUMENT,
+    }
+  }
+}
+
+pub struct Envelope {
+  pub sender: u16,
+  pub data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Queue {
+  messages: VecDeque<Envelope>,
+  /// The receiver waits for a message and is woken by the next send.
+  waiting: bool,
+}
+
+/// Messages for a hart. They are kept by the host since atomic instructions are not atomic across harts.
+#[derive(Default)]
+pub struct Mailbox {
+  queue: Mutex<Queue>,
+}
+
+impl Mailbox {
+  pub fn len(&self) -> usize {
+    self.queue.lock().unwrap().messages.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Queues a message, returns whether the receiver waits for it.
+  pub fn push(&self, envelope: Envelope) -> Result<bool, MailboxError> {
+    let mut queue = self.queue.lock().unwrap();
+    if queue.messages.len() >= CAPACITY {
+      return Err(MailboxError::Full);
+    }
+    queue.messages.push_back(envelope);
+    Ok(std::mem::take(&mut queue.waiting))
+  }
+
+  /// Takes the next message if it fits in `capacity` bytes. If there is none and `wait` is set, the next
+  /// [Mailbox::push] reports that the receiver waits.
+  pub fn pop(&self, capacity: u64, wait: bool) -> Result<Envelope, MailboxError> {
+    let mut queue = self.queue.lock().unwrap();
+    let Some(envelope) = queue.messages.front() else {
+      queue.waiting = wait;
+      return Err(MailboxError::Empty);
+    };
+    if envelope.data.len() as u64 > capacity {
+      return Err(MailboxError::TooLarge(envelope.data.len() as u64));
+    }
+    queue.waiting = false;
+    Ok(queue.messages.pop_front().unwrap())
+  }
+
+  /// Puts a message taken by [Mailbox::pop] back in front, when it could not be delivered.
+  pub fn unpop(&self, envelope: Envelope) {
+    self.queue.lock().unwrap().messages.push_front(envelope);
+  }
+}
+
+/// Sends messages between the harts of an isolate, a hart receives from its own mailbox.
+pub struct MailboxHandler {}
+
+/// Returns the result in `a0` and a status code in `a1`.
+#[async_trait]
+impl InterruptHandler for MailboxHandler {
+  async fn handle(&self, cpu: &mut Cpu) {
+    let action = cpu.regs[10];
+    let (a1, a2, a3) = (cpu.regs[11], cpu.regs[12], cpu.regs[13]);
+    let result = match cpu.isolate.as_ref().and_then(Weak::upgrade) {
+      Some(isolate) => match action {
+        action::SEND => send(cpu, &isolate, a1, a2, a3),
+        action::RECEIVE => receive(cpu, &isolate, a1, a2, true),
+        action::TRY_RECEIVE => receive(cpu, &isolate, a1, a2, false),
+        _ => Err(MailboxError::InvalidArgument),
+      },
+      None => Err(MailboxError::InvalidHart),
+    };
+    debug!("mailbox action {}: {:?}", action, result);
+
+    (cpu.regs[10], cpu.regs[11]) = match result {
+      Ok(value) => (value, status::OK),
+      Err(MailboxError::TooLarge(size)) => (size, status::TOO_LARGE),
+      Err(error) => (0, error.code()),
+    };
+  }
+}
+
+fn send(cpu: &Cpu, isolate: &Isolate, hart: u64, address: u64, size: u64) -> Result<u64, MailboxError> {
+  let hart = u16::try_from(hart).map_err(|_| MailboxError::InvalidHart)?;
+  if size > MAX_MESSAGE_SIZE {
+    return Err(MailboxError::TooLarge(size));
+  }
+  let link = isolate.link(hart).ok_or(MailboxError::InvalidHart)?;
+  let data = cpu.bus.read(address, size).map_err(|_| MailboxError::InvalidArgument)?;
+
+  let waiting = link.mailbox.push(Envelope { sender: cpu.id, data })?;
+  if waiting {
+    isolate.post(hart, Box::new(|cpu| cpu.wfi.set(false)));
+  }
+  Ok(0)
+}
+
+fn receive(cpu: &mut Cpu, isolate: &Isolate, address: u64, capacity: u64, wait: bool) -> Result<u64, MailboxError> {
+  let link = isolate.link(cpu.id).ok_or(MailboxError::InvalidHart)?;
+  let envelope = match link.mailbox.pop(capacity, wait) {
+    Ok(envelope) => envelope,
+    Err(MailboxError::Empty) if wait => {
+      // Woken by the sender through the inbox, so a message sent meanwhile is not missed
+      cpu.wfi.set(true);
+      return Err(MailboxError::Empty);
+    }
+    Err(error) => return Err(error),
+  };
+
+  // The message stays queued if the buffer is not memory
+  let len = envelope.data.len() as u64;
+  if len > 0 && (!cpu.bus.is_memory(address, len) || cpu.bus.write(address, &envelope.data).is_err()) {
+    link.mailbox.unpop(envelope);
+    return Err(MailboxError::InvalidArgument);
+  }
+  cpu.regs[12] = envelope.sender as u64;
+  Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+  use mizu_hwconst::memory::{DRAM_BASE, HARDWARE_BASE};
+
+  use super::*;
+  use crate::bus::Bus;
+
+  #[test]
+  fn keeps_undelivered_messages() {
+    let isolate = Isolate::new(std::sync::Arc::new(Bus::new(Vec::new())));
+    let link = isolate.link(0).unwrap();
+    link.mailbox.push(Envelope { sender: 1, data: b"hello".to_vec() }).unwrap();
+    let core = isolate.get_bootstrap_core();
+    let mut cpu = core.try_lock().unwrap();
+
+    // Not memory, or not large enough
+    assert!(matches!(receive(&mut cpu, &isolate, HARDWARE_BASE, 16, false), Err(MailboxError::InvalidArgument)));
+    assert!(matches!(receive(&mut cpu, &isolate, DRAM_BASE, 4, false), Err(MailboxError::TooLarge(5))));
+    assert_eq!(link.mailbox.len(), 1);
+
+    assert_eq!(receive(&mut cpu, &isolate, DRAM_BASE, 16, false).unwrap(), 5);
+    assert_eq!(cpu.regs[12], 1);
+    assert_eq!(cpu.bus.read(DRAM_BASE, 5).unwrap(), b"hello");
+    assert!(link.mailbox.is_empty());
+  }
+}
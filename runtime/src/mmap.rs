@@ -0,0 +1,101 @@
+use std::env;
+use std::sync::atomic::Ordering;
+
+use async_trait::async_trait;
+use mizu_hal_types::memory::*;
+use thiserror::Error;
+use tracing::debug;
+
+use crate::cpu::{Cpu, InterruptHandler};
+
+/// Bytes of memory an isolate may use unless configured with `MIZU_MEMORY_MAX_BYTES`, its DRAM included.
+pub const DEFAULT_MEMORY_LIMIT: u64 = 256 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum MemoryError {
+  #[error("memory quota exceeded")]
+  QuotaExceeded,
+  #[error("invalid argument")]
+  InvalidArgument,
+  #[error("no free address range")]
+  NoSpace,
+  #[error("region is not mapped")]
+  NotMapped,
+}
+
+impl MemoryError {
+  /// Status code reported to the guest.
+  #[must_use]
+  pub fn code(&self) -> u64 {
+    match self {
+      MemoryError::QuotaExceeded => status::QUOTA_EXCEEDED,
+      MemoryError::InvalidArgument => status::INVALID_ARGUMENT,
+      MemoryError::NoSpace => status::NO_SPACE,
+      MemoryError::NotMapped => status::NOT_MAPPED,
+    }
+  }
+}
+
+/// Reads the memory limit of an isolate from `MIZU_MEMORY_MAX_BYTES`.
+pub fn memory_limit_from_env() -> u64 {
+  env::var("MIZU_MEMORY_MAX_BYTES")
+    .ok()
+    .and_then(|value| value.parse().ok())
+    .unwrap_or(DEFAULT_MEMORY_LIMIT)
+}
+
+/// Maps and unmaps regions of the bus, limited by [Bus::mapping_limit](crate::bus::Bus::mapping_limit).
+pub struct MemoryHandler {}
+
+/// Returns the result in `a0` and a status code in `a1`.
+#[async_trait]
+impl InterruptHandler for MemoryHandler {
+  async fn handle(&self, cpu: &mut Cpu) {
+    let action = cpu.regs[10];
+    let argument = cpu.regs[11];
+
+    let result = match action {
+      action::MAP => cpu.bus.map(argument),
+      action::UNMAP => cpu.bus.unmap(argument).map(|_| 0),
+      action::QUERY => {
+        cpu.regs[12] = cpu.bus.mapping_limit();
+        Ok(cpu.bus.mapped_bytes())
+      }
+      _ => Err(MemoryError::InvalidArgument),
+    };
+    debug!("memory action {} {:#x}: {:?}", action, argument, result);
+
+    let mapped = cpu.bus.mapped_bytes();
+    cpu.perf.mapped_bytes.store(mapped, Ordering::Release);
+    cpu.perf.peak_mapped_bytes.fetch_max(mapped, Ordering::AcqRel);
+    (cpu.regs[10], cpu.regs[11]) = match result {
+      Ok(value) => (value, status::OK),
+      Err(error) => (0, error.code()),
+    };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use mizu_hwconst::memory::{DRAM_SIZE, MAPPED_BASE};
+
+  use super::*;
+  use crate::bus::Bus;
+
+  #[test]
+  fn limit_includes_dram() {
+    let bus = Bus::new(Vec::new());
+    bus.set_memory_limit(DRAM_SIZE + 2 * PAGE_SIZE);
+    assert_eq!(bus.mapping_limit(), 2 * PAGE_SIZE);
+    assert!(matches!(bus.map(3 * PAGE_SIZE), Err(MemoryError::QuotaExceeded)));
+
+    let region = bus.map(2 * PAGE_SIZE).unwrap();
+    assert_eq!(region, MAPPED_BASE);
+    bus.store(region + PAGE_SIZE, 64, 7).unwrap();
+    assert_eq!(bus.load(region + PAGE_SIZE, 64).unwrap(), 7);
+    assert!(matches!(bus.map(1), Err(MemoryError::QuotaExceeded)));
+
+    assert_eq!(bus.unmap(region).unwrap(), 2 * PAGE_SIZE);
+    assert_eq!(bus.mapped_bytes(), 0);
+  }
+}
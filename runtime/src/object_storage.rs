@@ -0,0 +1,845 @@
+use std::collections::HashMap;
+use std::env;
+use std::ffi::c_char;
+use std::io::{self, ErrorKind};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use mizu_hal_types::object_storage::*;
+use mizu_hal_types::StringPtr;
+use thiserror::Error;
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tracing::{debug, warn};
+
+use crate::bus::{Bus, BusMemoryExt};
+use crate::cpu::{Cpu, InterruptHandler};
+use crate::exception::Exception;
+
+/// Keys are stored hex-encoded as file names, which are limited to 255 bytes on most filesystems.
+pub const MAX_KEY_LENGTH: usize = 120;
+/// Size of the guest-visible value buffer, `HARDWARE_BASE + 0x9900..HARDWARE_BASE + 0x16000`.
+pub const MAX_VALUE_SIZE: u64 = 0x16000 - 0x9900;
+
+/// Object files start with the magic, the format, the object version and the expiry time. Files written before the
+/// header existed hold only the value.
+const MAGIC: &[u8; 7] = b"mizuobj";
+const FORMAT: u8 = 2;
+const HEADER_SIZE: usize = 24;
+
+#[derive(Debug, Copy, Clone)]
+pub struct StorageQuota {
+  pub max_keys: u64,
+  pub max_bytes: u64,
+}
+
+impl Default for StorageQuota {
+  fn default() -> Self {
+    Self {
+      max_keys: 1024,
+      max_bytes: 16 * 1024 * 1024,
+    }
+  }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct NamespaceUsage {
+  keys: u64,
+  bytes: u64,
+}
+
+#[derive(Error, Debug)]
+pub enum ObjectStorageError {
+  #[error("object not found")]
+  NotFound,
+  #[error("invalid key")]
+  InvalidKey,
+  #[error("quota exceeded")]
+  QuotaExceeded,
+  #[error("invalid value")]
+  InvalidValue,
+  #[error("value of {0} bytes is too large")]
+  ValueTooLarge(u64),
+  #[error("version mismatch: expected {expected}, got {actual}")]
+  VersionMismatch { expected: u64, actual: u64 },
+  #[error("io error: {0}")]
+  Io(#[from] io::Error),
+}
+
+impl ObjectStorageError {
+  /// Status code reported to the guest.
+  #[must_use]
+  pub fn code(&self) -> u64 {
+    match self {
+      ObjectStorageError::NotFound => status::NOT_FOUND,
+      ObjectStorageError::InvalidKey => status::INVALID_KEY,
+      ObjectStorageError::QuotaExceeded => status::QUOTA_EXCEEDED,
+      ObjectStorageError::InvalidValue => status::INVALID_VALUE,
+      ObjectStorageError::ValueTooLarge(_) => status::VALUE_TOO_LARGE,
+      ObjectStorageError::VersionMismatch { .. } => status::VERSION_MISMATCH,
+      ObjectStorageError::Io(_) => status::IO_ERROR,
+    }
+  }
+}
+
+pub struct Object {
+  pub data: Vec<u8>,
+  pub metadata: object_storage_metadata_t,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Header {
+  version: u64,
+  expires_ms: u64,
+  /// Offset of the value in the file.
+  length: usize,
+}
+
+impl Header {
+  /// Parses the header at the start of `file`, which needs at most [HEADER_SIZE] bytes.
+  fn parse(file: &[u8]) -> io::Result<Self> {
+    if file.len() < MAGIC.len() + 1 || file[..MAGIC.len()] != MAGIC[..] {
+      return Ok(Self {
+        version: 1,
+        expires_ms: 0,
+        length: 0,
+      });
+    }
+    let format = file[MAGIC.len()];
+    if format != FORMAT {
+      return Err(io::Error::new(ErrorKind::InvalidData, format!("unsupported object format {}", format)));
+    }
+    if file.len() < HEADER_SIZE {
+      return Err(io::Error::new(ErrorKind::InvalidData, "truncated object header"));
+    }
+    Ok(Self {
+      version: u64::from_le_bytes(file[8..16].try_into().unwrap()),
+      expires_ms: u64::from_le_bytes(file[16..24].try_into().unwrap()),
+      length: HEADER_SIZE,
+    })
+  }
+
+  fn is_expired(&self, now_ms: u64) -> bool {
+    self.expires_ms != 0 && self.expires_ms <= now_ms
+  }
+
+  fn metadata(&self, file_size: u64, modified: SystemTime) -> object_storage_metadata_t {
+    object_storage_metadata_t {
+      size: file_size.saturating_sub(self.length as u64),
+      modified_ms: unix_millis(modified),
+      version: self.version,
+      expires_ms: self.expires_ms,
+    }
+  }
+}
+
+/// Reads the header and the metadata of an object without its value, expired objects included.
+async fn read_header(path: &Path) -> Result<(Header, object_storage_metadata_t), ObjectStorageError> {
+  let mut file = match fs::File::open(path).await {
+    Ok(file) => file,
+    Err(error) if error.kind() == ErrorKind::NotFound => return Err(ObjectStorageError::NotFound),
+    Err(error) => return Err(error.into()),
+  };
+  let metadata = file.metadata().await?;
+
+  let mut prefix = [0; HEADER_SIZE];
+  let mut length = 0;
+  while length < HEADER_SIZE {
+    match file.read(&mut prefix[length..]).await? {
+      0 => break,
+      read => length += read,
+    }
+  }
+  let header = Header::parse(&prefix[..length])?;
+  let object_metadata = header.metadata(metadata.len(), metadata.modified()?);
+  Ok((header, object_metadata))
+}
+
+/// Persistent key-value storage, one directory per namespace and one file per object.
+pub struct ObjectStorage {
+  root: PathBuf,
+  quota: StorageQuota,
+  /// Usage of each namespace, computed when the namespace is first locked. The lock serializes the writes to the
+  /// namespace, so that writes to other namespaces do not wait for them.
+  usage: std::sync::Mutex<HashMap<u64, Arc<Mutex<Option<NamespaceUsage>>>>>,
+}
+
+impl ObjectStorage {
+  pub fn new(root: impl Into<PathBuf>, quota: StorageQuota) -> Self {
+    Self {
+      root: root.into(),
+      quota,
+      usage: std::sync::Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Reads the storage directory and quotas from `MIZU_STORAGE_*` environment variables.
+  pub fn from_env() -> Self {
+    let mut quota = StorageQuota::default();
+    if let Some(value) = env::var("MIZU_STORAGE_MAX_KEYS").ok().and_then(|value| value.parse().ok()) {
+      quota.max_keys = value;
+    }
+    if let Some(value) = env::var("MIZU_STORAGE_MAX_BYTES").ok().and_then(|value| value.parse().ok()) {
+      quota.max_bytes = value;
+    }
+    Self::new(env::var("MIZU_STORAGE_DIR").unwrap_or_else(|_| "storage".to_owned()), quota)
+  }
+
+  fn namespace_path(&self, namespace: u64) -> PathBuf {
+    self.root.join(namespace.to_string())
+  }
+
+  fn object_path(&self, namespace: u64, key: &str) -> Result<PathBuf, ObjectStorageError> {
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+      return Err(ObjectStorageError::InvalidKey);
+    }
+    Ok(self.namespace_path(namespace).join(encode_key(key)))
+  }
+
+  async fn load_usage(path: &Path) -> io::Result<NamespaceUsage> {
+    let mut usage = NamespaceUsage::default();
+    let mut entries = match fs::read_dir(path).await {
+      Ok(entries) => entries,
+      Err(error) if error.kind() == ErrorKind::NotFound => return Ok(usage),
+      Err(error) => return Err(error),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+      if entry.file_name().to_string_lossy().starts_with('.') {
+        continue;
+      }
+      usage.keys += 1;
+      usage.bytes += entry.metadata().await?.len();
+    }
+    Ok(usage)
+  }
+
+  /// Locks a namespace for writing, the usage is always set while the lock is held.
+  async fn lock(&self, namespace: u64) -> io::Result<OwnedMutexGuard<Option<NamespaceUsage>>> {
+    let lock = self.usage.lock().unwrap().entry(namespace).or_default().clone();
+    let mut usage = lock.lock_owned().await;
+    if usage.is_none() {
+      *usage = Some(Self::load_usage(&self.namespace_path(namespace)).await?);
+    }
+    Ok(usage)
+  }
+
+  /// Reads an object, expired objects included.
+  async fn read(&self, path: &Path) -> Result<(Header, Object), ObjectStorageError> {
+    let (file, modified) = match tokio::try_join!(fs::read(path), fs::metadata(path)) {
+      Ok((file, metadata)) => (file, metadata.modified()?),
+      Err(error) if error.kind() == ErrorKind::NotFound => return Err(ObjectStorageError::NotFound),
+      Err(error) => return Err(error.into()),
+    };
+
+    let header = Header::parse(&file[..file.len().min(HEADER_SIZE)])?;
+    let object = Object {
+      metadata: header.metadata(file.len() as u64, modified),
+      data: file[header.length..].to_vec(),
+    };
+    Ok((header, object))
+  }
+
+  /// Deletes an object that expired, unless it was written again meanwhile.
+  async fn reap(&self, namespace: u64, path: &Path) -> Result<(), ObjectStorageError> {
+    let mut usage = self.lock(namespace).await?;
+    let namespace_usage = usage.unwrap_or_default();
+    let size = match read_header(path).await {
+      Ok((header, metadata)) if header.is_expired(unix_millis(SystemTime::now())) => metadata.size + header.length as u64,
+      Ok(_) | Err(ObjectStorageError::NotFound) => return Ok(()),
+      Err(error) => return Err(error),
+    };
+
+    debug!("reaping expired object {}", path.display());
+    fs::remove_file(path).await?;
+    *usage = Some(NamespaceUsage {
+      keys: namespace_usage.keys.saturating_sub(1),
+      bytes: namespace_usage.bytes.saturating_sub(size),
+    });
+    Ok(())
+  }
+
+  /// Deletes the expired objects of a namespace other than `except`, the caller holds the namespace lock.
+  async fn sweep(&self, namespace: u64, except: &Path, usage: &mut NamespaceUsage) -> Result<(), ObjectStorageError> {
+    let mut entries = match fs::read_dir(self.namespace_path(namespace)).await {
+      Ok(entries) => entries,
+      Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+      Err(error) => return Err(error.into()),
+    };
+
+    let now_ms = unix_millis(SystemTime::now());
+    while let Some(entry) = entries.next_entry().await? {
+      let path = entry.path();
+      if decode_key(entry.file_name().as_encoded_bytes()).is_none() || path == except {
+        continue;
+      }
+      let Ok((header, metadata)) = read_header(&path).await else {
+        continue;
+      };
+      if header.is_expired(now_ms) {
+        debug!("reaping expired object {}", path.display());
+        fs::remove_file(&path).await?;
+        usage.keys = usage.keys.saturating_sub(1);
+        usage.bytes = usage.bytes.saturating_sub(metadata.size + header.length as u64);
+      }
+    }
+    Ok(())
+  }
+
+  /// Reads an object, expired objects are deleted and reported as missing.
+  pub async fn get(&self, namespace: u64, key: &str) -> Result<Object, ObjectStorageError> {
+    let path = self.object_path(namespace, key)?;
+    let (header, object) = self.read(&path).await?;
+    if header.is_expired(unix_millis(SystemTime::now())) {
+      self.reap(namespace, &path).await?;
+      return Err(ObjectStorageError::NotFound);
+    }
+    Ok(object)
+  }
+
+  pub async fn stat(&self, namespace: u64, key: &str) -> Result<object_storage_metadata_t, ObjectStorageError> {
+    let path = self.object_path(namespace, key)?;
+    let (header, metadata) = read_header(&path).await?;
+    if header.is_expired(unix_millis(SystemTime::now())) {
+      self.reap(namespace, &path).await?;
+      return Err(ObjectStorageError::NotFound);
+    }
+    Ok(metadata)
+  }
+
+  pub async fn put(&self, namespace: u64, key: &str, value: &[u8]) -> Result<object_storage_metadata_t, ObjectStorageError> {
+    self.write(namespace, key, |_| Ok((value.to_vec(), None))).await
+  }
+
+  /// Writes the object only if its current version is `expected_version` (0 if it must not exist).
+  pub async fn compare_and_swap(
+    &self,
+    namespace: u64,
+    key: &str,
+    expected_version: u64,
+    value: &[u8],
+    ttl: Option<Duration>,
+  ) -> Result<object_storage_metadata_t, ObjectStorageError> {
+    self
+      .write(namespace, key, |current| {
+        let actual = current.map(|object| object.metadata.version).unwrap_or(0);
+        if actual != expected_version {
+          return Err(ObjectStorageError::VersionMismatch {
+            expected: expected_version,
+            actual,
+          });
+        }
+        Ok((value.to_vec(), Some(ttl)))
+      })
+      .await
+  }
+
+  /// Atomically adds `delta` to an 8-byte little-endian counter.
+  pub async fn increment(&self, namespace: u64, key: &str, delta: i64) -> Result<(i64, object_storage_metadata_t), ObjectStorageError> {
+    let mut value = 0;
+    let metadata = self
+      .write(namespace, key, |current| {
+        let current = match current {
+          Some(object) => i64::from_le_bytes(object.data.as_slice().try_into().map_err(|_| ObjectStorageError::InvalidValue)?),
+          None => 0,
+        };
+        value = current.wrapping_add(delta);
+        Ok((value.to_le_bytes().to_vec(), None))
+      })
+      .await?;
+    Ok((value, metadata))
+  }
+
+  /// Sets (or with [None], removes) the expiry time of an existing object.
+  pub async fn expire(&self, namespace: u64, key: &str, ttl: Option<Duration>) -> Result<object_storage_metadata_t, ObjectStorageError> {
+    self
+      .write(namespace, key, |current| match current {
+        Some(object) => Ok((object.data.clone(), Some(ttl))),
+        None => Err(ObjectStorageError::NotFound),
+      })
+      .await
+  }
+
+  pub async fn delete(&self, namespace: u64, key: &str) -> Result<(), ObjectStorageError> {
+    let path = self.object_path(namespace, key)?;
+
+    let mut usage = self.lock(namespace).await?;
+    let namespace_usage = usage.unwrap_or_default();
+    let size = match fs::metadata(&path).await {
+      Ok(metadata) => metadata.len(),
+      Err(error) if error.kind() == ErrorKind::NotFound => return Err(ObjectStorageError::NotFound),
+      Err(error) => return Err(error.into()),
+    };
+    let expired = match read_header(&path).await {
+      Ok((header, _)) => header.is_expired(unix_millis(SystemTime::now())),
+      Err(_) => false,
+    };
+
+    fs::remove_file(&path).await?;
+    *usage = Some(NamespaceUsage {
+      keys: namespace_usage.keys.saturating_sub(1),
+      bytes: namespace_usage.bytes.saturating_sub(size),
+    });
+
+    if expired {
+      return Err(ObjectStorageError::NotFound);
+    }
+    Ok(())
+  }
+
+  /// Lists keys starting with `prefix` in lexicographic order, after `cursor` if given.
+  /// Returns the keys and the cursor for the next page.
+  pub async fn list(&self, namespace: u64, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<(Vec<String>, Option<String>), ObjectStorageError> {
+    let mut entries = match fs::read_dir(self.namespace_path(namespace)).await {
+      Ok(entries) => entries,
+      Err(error) if error.kind() == ErrorKind::NotFound => return Ok((Vec::new(), None)),
+      Err(error) => return Err(error.into()),
+    };
+
+    let mut keys = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+      let Some(key) = decode_key(entry.file_name().as_encoded_bytes()) else {
+        continue;
+      };
+      if key.starts_with(prefix) && cursor.is_none_or(|cursor| key.as_str() > cursor) {
+        keys.push(key);
+      }
+    }
+    keys.sort_unstable();
+
+    let mut page = Vec::new();
+    let mut remaining = keys.into_iter();
+    let now_ms = unix_millis(SystemTime::now());
+    for key in remaining.by_ref() {
+      if page.len() == limit {
+        break;
+      }
+      let path = self.object_path(namespace, &key)?;
+      match read_header(&path).await {
+        Ok((header, _)) if header.is_expired(now_ms) => self.reap(namespace, &path).await?,
+        Ok(_) => page.push(key),
+        Err(ObjectStorageError::NotFound) => {}
+        Err(error) => return Err(error),
+      }
+    }
+
+    let next_cursor = if page.len() == limit && remaining.len() > 0 {
+      page.last().cloned()
+    } else {
+      None
+    };
+    Ok((page, next_cursor))
+  }
+
+  /// Read-modify-write under the namespace lock. `block` receives the current object and returns the new data
+  /// and, if it should change, the new time to live.
+  async fn write(
+    &self,
+    namespace: u64,
+    key: &str,
+    block: impl FnOnce(Option<&Object>) -> Result<(Vec<u8>, Option<Option<Duration>>), ObjectStorageError>,
+  ) -> Result<object_storage_metadata_t, ObjectStorageError> {
+    let path = self.object_path(namespace, key)?;
+
+    let mut usage = self.lock(namespace).await?;
+    let namespace_usage = usage.unwrap_or_default();
+
+    let previous_size = match fs::metadata(&path).await {
+      Ok(metadata) => Some(metadata.len()),
+      Err(error) if error.kind() == ErrorKind::NotFound => None,
+      Err(error) => return Err(error.into()),
+    };
+    let current = match self.read(&path).await {
+      Ok((header, _)) if header.is_expired(unix_millis(SystemTime::now())) => None,
+      Ok((_, object)) => Some(object),
+      Err(ObjectStorageError::NotFound) => None,
+      Err(error) => return Err(error),
+    };
+
+    let (data, ttl) = block(current.as_ref())?;
+    if data.len() as u64 > MAX_VALUE_SIZE {
+      return Err(ObjectStorageError::ValueTooLarge(data.len() as u64));
+    }
+
+    let now = SystemTime::now();
+    let expires_ms = match ttl {
+      Some(Some(ttl)) => unix_millis(now + ttl),
+      Some(None) => 0,
+      None => current.as_ref().map(|object| object.metadata.expires_ms).unwrap_or(0),
+    };
+    let metadata = object_storage_metadata_t {
+      size: data.len() as u64,
+      modified_ms: unix_millis(now),
+      version: current.as_ref().map(|object| object.metadata.version).unwrap_or(0) + 1,
+      expires_ms,
+    };
+
+    let mut namespace_usage = namespace_usage;
+    let mut new_usage = usage_after_write(namespace_usage, previous_size, data.len());
+    if new_usage.keys > self.quota.max_keys || new_usage.bytes > self.quota.max_bytes {
+      // Expired objects still take space until they are deleted
+      self.sweep(namespace, &path, &mut namespace_usage).await?;
+      new_usage = usage_after_write(namespace_usage, previous_size, data.len());
+    }
+    if new_usage.keys > self.quota.max_keys || new_usage.bytes > self.quota.max_bytes {
+      *usage = Some(namespace_usage);
+      return Err(ObjectStorageError::QuotaExceeded);
+    }
+
+    let mut file = Vec::with_capacity(HEADER_SIZE + data.len());
+    file.extend_from_slice(MAGIC);
+    file.push(FORMAT);
+    file.extend_from_slice(&metadata.version.to_le_bytes());
+    file.extend_from_slice(&metadata.expires_ms.to_le_bytes());
+    file.extend_from_slice(&data);
+
+    // Write to a temporary file first so that a crash never leaves a truncated object behind
+    fs::create_dir_all(self.namespace_path(namespace)).await?;
+    let temporary = self.namespace_path(namespace).join(format!(".{}.tmp", encode_key(key)));
+    fs::write(&temporary, &file).await?;
+    fs::rename(&temporary, &path).await?;
+
+    *usage = Some(new_usage);
+    Ok(metadata)
+  }
+}
+
+fn usage_after_write(usage: NamespaceUsage, previous_size: Option<u64>, value_size: usize) -> NamespaceUsage {
+  NamespaceUsage {
+    keys: usage.keys + u64::from(previous_size.is_none()),
+    // The cache may lag behind files changed outside of the bot
+    bytes: usage.bytes.saturating_sub(previous_size.unwrap_or(0)) + (HEADER_SIZE + value_size) as u64,
+  }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+  time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+fn ttl_from_millis(ttl_ms: u64) -> Option<Duration> {
+  (ttl_ms != 0).then(|| Duration::from_millis(ttl_ms))
+}
+
+fn encode_key(key: &str) -> String {
+  key.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Returns [None] for temporary files and names that are not encoded keys.
+fn decode_key(name: &[u8]) -> Option<String> {
+  if name.first() == Some(&b'.') || !name.len().is_multiple_of(2) {
+    return None;
+  }
+  let bytes = name
+    .chunks_exact(2)
+    .map(|pair| Some((char::from(pair[0]).to_digit(16)? * 16 + char::from(pair[1]).to_digit(16)?) as u8))
+    .collect::<Option<Vec<_>>>()?;
+  String::from_utf8(bytes).ok()
+}
+
+/// Requests and values are read from guest pointers, which may point anywhere.
+fn guest<T>(result: Result<T, Exception>) -> Result<T, ObjectStorageError> {
+  result.map_err(|_| ObjectStorageError::InvalidValue)
+}
+
+fn read_string(bus: &Bus, pointer: &StringPtr) -> Result<String, ObjectStorageError> {
+  Ok(guest(bus.read_string(pointer.0 as u64))?.to_string_lossy().into_owned())
+}
+
+pub struct ObjectStorageHandler {
+  pub object_storage: Arc<ObjectStorage>,
+  /// Objects of different namespaces never see each other, the bot uses the guild ID.
+  pub namespace: u64,
+}
+
+impl ObjectStorageHandler {
+  async fn run(&self, cpu: &mut Cpu, action: u64, address: u64, response: u64) -> Result<(), ObjectStorageError> {
+    let namespace = self.namespace;
+    let buffer = cpu.hardware + 0x9900;
+
+    match action {
+      action::GET => {
+        let request = guest(cpu.bus.read_struct::<object_storage_get_t>(address))?;
+        let key = read_string(&cpu.bus, &request.key)?;
+        debug!("get by key: {}", key);
+
+        self.object_storage.get(namespace, &key).await.map(|object| {
+          cpu.bus.write(buffer, &object.data).unwrap();
+          cpu
+            .bus
+            .write_struct(response, &object_storage_item_t {
+              length: object.data.len() as u64,
+              data: buffer as *const c_char,
+            })
+            .unwrap();
+        })
+      }
+      action::PUT => {
+        let request = guest(cpu.bus.read_struct::<object_storage_put_t>(address))?;
+        let key = read_string(&cpu.bus, &request.key)?;
+        debug!("put by key: {}", key);
+
+        if request.item.length > MAX_VALUE_SIZE {
+          Err(ObjectStorageError::ValueTooLarge(request.item.length))
+        } else {
+          let data = guest(cpu.bus.read(request.item.data as u64, request.item.length))?;
+          self
+            .object_storage
+            .put(namespace, &key, &data)
+            .await
+            .map(|metadata| cpu.bus.write_struct(response, &metadata).unwrap())
+        }
+      }
+      action::DELETE => {
+        let request = guest(cpu.bus.read_struct::<object_storage_get_t>(address))?;
+        let key = read_string(&cpu.bus, &request.key)?;
+        debug!("delete by key: {}", key);
+
+        self.object_storage.delete(namespace, &key).await
+      }
+      action::STAT => {
+        let request = guest(cpu.bus.read_struct::<object_storage_get_t>(address))?;
+        let key = read_string(&cpu.bus, &request.key)?;
+        debug!("stat by key: {}", key);
+
+        self
+          .object_storage
+          .stat(namespace, &key)
+          .await
+          .map(|metadata| cpu.bus.write_struct(response, &metadata).unwrap())
+      }
+      action::LIST => {
+        let request = guest(cpu.bus.read_struct::<object_storage_list_t>(address))?;
+        let prefix = if request.prefix.is_null() {
+          String::new()
+        } else {
+          read_string(&cpu.bus, &request.prefix)?
+        };
+        let cursor = if request.cursor.is_null() {
+          None
+        } else {
+          Some(read_string(&cpu.bus, &request.cursor)?)
+        };
+        debug!("list by prefix: {} after {:?}", prefix, cursor);
+
+        let limit = request.limit.clamp(1, LIST_LIMIT) as usize;
+        self.object_storage.list(namespace, &prefix, cursor.as_deref(), limit).await.map(|(keys, next_cursor)| {
+          // [StringPtr; count], followed by the null-terminated keys
+          let mut string_address = buffer + (keys.len() * size_of::<StringPtr>()) as u64;
+          for (index, key) in keys.iter().enumerate() {
+            cpu.bus.write_string(string_address, key).unwrap();
+            cpu.bus.write_struct(buffer + (index * size_of::<StringPtr>()) as u64, &string_address).unwrap();
+            string_address += key.len() as u64 + 1;
+          }
+
+          let next_cursor = match &next_cursor {
+            Some(cursor) => {
+              cpu.bus.write_string(string_address, cursor).unwrap();
+              StringPtr(string_address as *const c_char)
+            }
+            None => StringPtr(ptr::null()),
+          };
+          cpu
+            .bus
+            .write_struct(response, &object_storage_list_result_t {
+              count: keys.len() as u64,
+              keys: buffer as *const StringPtr,
+              next_cursor,
+            })
+            .unwrap();
+        })
+      }
+      action::COMPARE_AND_SWAP => {
+        let request = guest(cpu.bus.read_struct::<object_storage_cas_t>(address))?;
+        let key = read_string(&cpu.bus, &request.key)?;
+        debug!("compare and swap by key: {}, expected version {}", key, request.expected_version);
+
+        if request.item.length > MAX_VALUE_SIZE {
+          Err(ObjectStorageError::ValueTooLarge(request.item.length))
+        } else {
+          let data = guest(cpu.bus.read(request.item.data as u64, request.item.length))?;
+          self
+            .object_storage
+            .compare_and_swap(namespace, &key, request.expected_version, &data, ttl_from_millis(request.ttl_ms))
+            .await
+            .map(|metadata| cpu.bus.write_struct(response, &metadata).unwrap())
+        }
+      }
+      action::INCREMENT => {
+        let request = guest(cpu.bus.read_struct::<object_storage_increment_t>(address))?;
+        let key = read_string(&cpu.bus, &request.key)?;
+        debug!("increment by key: {}, delta {}", key, request.delta);
+
+        self.object_storage.increment(namespace, &key, request.delta).await.map(|(value, metadata)| {
+          cpu
+            .bus
+            .write_struct(response, &object_storage_counter_t {
+              value,
+              version: metadata.version,
+            })
+            .unwrap()
+        })
+      }
+      action::EXPIRE => {
+        let request = guest(cpu.bus.read_struct::<object_storage_expire_t>(address))?;
+        let key = read_string(&cpu.bus, &request.key)?;
+        debug!("expire by key: {}, ttl {} ms", key, request.ttl_ms);
+
+        self
+          .object_storage
+          .expire(namespace, &key, ttl_from_millis(request.ttl_ms))
+          .await
+          .map(|metadata| cpu.bus.write_struct(response, &metadata).unwrap())
+      }
+      _ => {
+        warn!("unknown object storage action {}", action);
+        Err(ObjectStorageError::InvalidValue)
+      }
+    }
+  }
+}
+
+/// Returns a pointer to the action result in `a0` and a status code in `a1`.
+#[async_trait]
+impl InterruptHandler for ObjectStorageHandler {
+  async fn handle(&self, cpu: &mut Cpu) {
+    let action = cpu.regs[10];
+    let response = cpu.hardware + 0x6000;
+    let result = self.run(cpu, action, cpu.regs[11], response).await;
+
+    cpu.regs[10] = response;
+    cpu.regs[11] = match result {
+      Ok(()) => status::OK,
+      Err(error) => {
+        debug!("object storage action {} failed: {}", action, error);
+        error.code()
+      }
+    };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use mizu_hwconst::memory::DRAM_BASE;
+
+  use super::*;
+  use crate::isolate::Isolate;
+
+  fn storage(name: &str, quota: StorageQuota) -> ObjectStorage {
+    let root = env::temp_dir().join(format!("mizu-object-storage-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    ObjectStorage::new(root, quota)
+  }
+
+  #[test]
+  fn decodes_keys() {
+    for key in ["key", "ключ", "a/b c"] {
+      assert_eq!(decode_key(encode_key(key).as_bytes()).as_deref(), Some(key));
+    }
+    assert_eq!(decode_key(b".6b6579.tmp"), None);
+    assert_eq!(decode_key(b"6b6"), None);
+    assert_eq!(decode_key(b"zz"), None);
+    assert_eq!(decode_key("éé".as_bytes()), None);
+    assert_eq!(decode_key(b"fffe"), None);
+  }
+
+  #[test]
+  fn parses_headers() {
+    let legacy = Header {
+      version: 1,
+      expires_ms: 0,
+      length: 0,
+    };
+    assert_eq!(Header::parse(b"").unwrap(), legacy);
+    assert_eq!(Header::parse(b"value").unwrap(), legacy);
+
+    let mut file = MAGIC.to_vec();
+    file.push(FORMAT);
+    file.extend_from_slice(&3u64.to_le_bytes());
+    file.extend_from_slice(&1000u64.to_le_bytes());
+    assert_eq!(Header::parse(&file).unwrap(), Header {
+      version: 3,
+      expires_ms: 1000,
+      length: HEADER_SIZE,
+    });
+    assert!(Header::parse(&file[..HEADER_SIZE - 1]).is_err());
+
+    file[MAGIC.len()] = FORMAT + 1;
+    assert!(Header::parse(&file).is_err());
+  }
+
+  #[tokio::test]
+  async fn reads_objects_without_a_header() {
+    let storage = storage("legacy", StorageQuota::default());
+    std::fs::create_dir_all(storage.namespace_path(1)).unwrap();
+    std::fs::write(storage.object_path(1, "key").unwrap(), b"value").unwrap();
+
+    let object = storage.get(1, "key").await.unwrap();
+    assert_eq!(object.data, b"value");
+    assert_eq!(object.metadata.version, 1);
+    assert_eq!(storage.list(1, "", None, 10).await.unwrap().0, vec!["key"]);
+
+    let metadata = storage.put(1, "key", b"new").await.unwrap();
+    assert_eq!(metadata.version, 2);
+    assert_eq!(storage.get(1, "key").await.unwrap().data, b"new");
+    let _ = std::fs::remove_dir_all(&storage.root);
+  }
+
+  #[tokio::test]
+  async fn reaps_expired_objects() {
+    let storage = storage("expired", StorageQuota {
+      max_keys: 2,
+      max_bytes: 1024,
+    });
+    storage.compare_and_swap(1, "a", 0, b"value", Some(Duration::from_millis(1))).await.unwrap();
+    storage.compare_and_swap(1, "b", 0, b"value", Some(Duration::from_millis(1))).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    // The expired objects no longer count toward the quota
+    storage.put(1, "c", b"value").await.unwrap();
+    assert!(matches!(storage.get(1, "a").await, Err(ObjectStorageError::NotFound)));
+    assert_eq!(storage.list(1, "", None, 10).await.unwrap().0, vec!["c"]);
+    assert_eq!(storage.lock(1).await.unwrap().unwrap_or_default().keys, 1);
+    let _ = std::fs::remove_dir_all(&storage.root);
+  }
+
+  #[tokio::test]
+  async fn reports_invalid_guest_pointers() {
+    let storage = Arc::new(storage("pointers", StorageQuota::default()));
+    let handler = ObjectStorageHandler {
+      object_storage: storage.clone(),
+      namespace: 1,
+    };
+    let isolate = Isolate::new(Arc::new(Bus::new(Vec::new())));
+    let core = isolate.get_bootstrap_core();
+    let mut cpu = core.lock().await;
+
+    // The request itself, then the key it points to, are not memory
+    let request = DRAM_BASE;
+    for (address, key) in [(0, 0), (request, 0)] {
+      cpu.bus.write_struct(request, &StringPtr(key as *const c_char)).unwrap();
+      (cpu.regs[10], cpu.regs[11]) = (action::GET, address);
+      handler.handle(&mut cpu).await;
+      assert_eq!(cpu.regs[11], status::INVALID_VALUE);
+    }
+    let _ = std::fs::remove_dir_all(&storage.root);
+  }
+
+  #[tokio::test]
+  async fn writes_namespaces_independently() {
+    let storage = storage("namespaces", StorageQuota::default());
+    let locked = storage.lock(1).await.unwrap();
+
+    // A write holding the lock of another namespace does not delay this one
+    let put = tokio::time::timeout(Duration::from_secs(5), storage.put(2, "key", b"value")).await;
+    assert!(put.unwrap().is_ok());
+    assert!(tokio::time::timeout(Duration::from_millis(50), storage.put(1, "key", b"value")).await.is_err());
+
+    drop(locked);
+    storage.put(1, "key", b"value").await.unwrap();
+    let _ = std::fs::remove_dir_all(&storage.root);
+  }
+}
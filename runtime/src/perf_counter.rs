@@ -1,20 +1,30 @@
-use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
 use std::time::Duration;
 
 use minstant::Instant;
+use mizu_hwconst::csr::hpm_event;
 
 pub const CPU_TIME_LIMIT: Duration = Duration::from_millis(10);
 
 #[derive(Debug)]
 pub struct PerformanceCounter {
+  /// Cpu time since the last [PerformanceCounter::reset], limited by [CPU_TIME_LIMIT].
   pub cpu_time: Mutex<Duration>,
+  /// Cpu time before the last reset.
+  cpu_time_before: Mutex<Duration>,
   cpu_time_start: Mutex<Option<Instant>>,
+  /// Executed instructions, including ones that trapped.
+  pub cycles: AtomicU64,
   pub instructions_retired: AtomicU64,
-  pub returns: Mutex<BTreeMap<u64, u64>>,
   pub loads: AtomicU64,
   pub stores: AtomicU64,
+  pub branches_taken: AtomicU64,
+  pub traps: AtomicU64,
+  pub syscalls: AtomicU64,
+  /// Bytes mapped by the isolate as of the last memory syscall of this core, kept on reset.
+  pub mapped_bytes: AtomicU64,
+  pub peak_mapped_bytes: AtomicU64,
 }
 
 impl Default for PerformanceCounter {
@@ -28,21 +38,44 @@ impl PerformanceCounter {
   pub fn new() -> Self {
     Self {
       cpu_time: Mutex::new(Duration::default()),
+      cpu_time_before: Mutex::new(Duration::default()),
       cpu_time_start: Mutex::new(None),
+      cycles: AtomicU64::new(0),
       instructions_retired: AtomicU64::new(0),
-      returns: Mutex::new(BTreeMap::new()),
       loads: AtomicU64::new(0),
       stores: AtomicU64::new(0),
+      branches_taken: AtomicU64::new(0),
+      traps: AtomicU64::new(0),
+      syscalls: AtomicU64::new(0),
+      mapped_bytes: AtomicU64::new(0),
+      peak_mapped_bytes: AtomicU64::new(0),
     }
   }
 
+  /// Restarts the cpu time limit and the peak of mapped bytes. The counters read through CSRs keep counting, so
+  /// the guest never sees them go backwards.
   pub fn reset(&self) {
-    *self.cpu_time.lock().unwrap() = Duration::default();
+    *self.cpu_time_before.lock().unwrap() += std::mem::take(&mut *self.cpu_time.lock().unwrap());
     *self.cpu_time_start.lock().unwrap() = None;
-    self.instructions_retired.store(0, Ordering::Release);
-    self.returns.lock().unwrap().clear();
-    self.loads.store(0, Ordering::Release);
-    self.stores.store(0, Ordering::Release);
+    self.peak_mapped_bytes.store(self.mapped_bytes.load(Ordering::Acquire), Ordering::Release);
+  }
+
+  /// Cpu time of the whole run, read by the `time` CSR.
+  pub fn total_cpu_time(&self) -> Duration {
+    *self.cpu_time_before.lock().unwrap() + *self.cpu_time.lock().unwrap()
+  }
+
+  /// Total count of an [hpm_event], unknown events never happen.
+  pub fn event(&self, event: u64) -> u64 {
+    let counter = match event {
+      hpm_event::LOADS => &self.loads,
+      hpm_event::STORES => &self.stores,
+      hpm_event::BRANCHES_TAKEN => &self.branches_taken,
+      hpm_event::TRAPS => &self.traps,
+      hpm_event::SYSCALLS => &self.syscalls,
+      _ => return 0,
+    };
+    counter.load(Ordering::Acquire)
   }
 
   pub fn start_cpu_time(&self) {
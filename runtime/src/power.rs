@@ -0,0 +1,166 @@
+//! Power states of the harts, selected by the guest through `mpowerstate`.
+//!
+//! - [power_state::DEFAULT] is limited to [CPU_TIME_LIMIT](crate::perf_counter::CPU_TIME_LIMIT) between yields.
+//! - [power_state::BYPASS] lifts the limit, but only while the host grants it with [PowerControl::set_allow_bypass].
+//! - [power_state::LOW_PRIORITY] runs for a [LOW_PRIORITY_SHARE](crate::scheduler::LOW_PRIORITY_SHARE) of each slice.
+//! - [power_state::DEEP_SLEEP] waits for an interrupt and resumes in the default state. Once every hart of an isolate
+//!   sleeps, its memory is written to the swap directory and read back before a hart runs again, see [Bus::swap_out].
+//!
+//! Every requested transition is logged and kept in [PowerControl::audit], including the denied ones.
+
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use tracing::{info, warn};
+
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use crate::csr::{self, power_state};
+
+/// Transitions kept by [PowerControl::audit].
+pub const AUDIT_LENGTH: usize = 64;
+
+/// Reads the directory of the memory written to disk during deep sleep from `MIZU_SWAP_DIR`.
+pub fn swap_directory_from_env() -> PathBuf {
+  env::var("MIZU_SWAP_DIR")
+    .map(PathBuf::from)
+    .unwrap_or_else(|_| env::temp_dir().join("mizu-swap"))
+}
+
+#[must_use]
+pub fn state_name(state: u64) -> &'static str {
+  match state {
+    power_state::DEFAULT => "default",
+    power_state::BYPASS => "bypass",
+    power_state::LOW_PRIORITY => "low-priority",
+    power_state::DEEP_SLEEP => "deep-sleep",
+    _ => "invalid",
+  }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Outcome {
+  Applied,
+  /// Bypass was requested without the privilege.
+  Denied,
+  /// Not a known state, the write is ignored.
+  Invalid,
+  /// The hart woke up from deep sleep.
+  Woken,
+}
+
+#[derive(Debug, Clone)]
+pub struct Transition {
+  pub time: SystemTime,
+  pub hart: u16,
+  pub from: u64,
+  pub to: u64,
+  pub outcome: Outcome,
+}
+
+impl fmt::Display for Transition {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "hart {}: {} -> {}", self.hart, state_name(self.from), state_name(self.to))?;
+    match self.outcome {
+      Outcome::Applied => Ok(()),
+      Outcome::Denied => write!(f, " (denied)"),
+      Outcome::Invalid => write!(f, " ({:#x} invalid)", self.to),
+      Outcome::Woken => write!(f, " (woken)"),
+    }
+  }
+}
+
+/// Host side of the power states of an isolate.
+#[derive(Default)]
+pub struct PowerControl {
+  allow_bypass: AtomicBool,
+  swap_directory: OnceLock<PathBuf>,
+  audit: Mutex<VecDeque<Transition>>,
+}
+
+impl PowerControl {
+  pub fn allows_bypass(&self) -> bool {
+    self.allow_bypass.load(Ordering::Acquire)
+  }
+
+  /// Grants or revokes [power_state::BYPASS], a revoked hart keeps the state but is time limited again.
+  pub fn set_allow_bypass(&self, allow: bool) {
+    if self.allow_bypass.swap(allow, Ordering::AcqRel) != allow {
+      info!("power bypass {}", if allow { "granted" } else { "revoked" });
+    }
+  }
+
+  /// Directory for the memory of sleeping isolates, deep sleep keeps the memory resident unless it is set.
+  pub fn swap_directory(&self) -> Option<&PathBuf> {
+    self.swap_directory.get()
+  }
+
+  pub fn set_swap_directory(&self, directory: PathBuf) {
+    let _ = self.swap_directory.set(directory);
+  }
+
+  /// Whether [CPU_TIME_LIMIT](crate::perf_counter::CPU_TIME_LIMIT) applies in `state`.
+  pub fn is_time_limited(&self, state: u64) -> bool {
+    state != power_state::BYPASS || !self.allows_bypass()
+  }
+
+  /// The last [AUDIT_LENGTH] transitions, oldest first.
+  pub fn audit(&self) -> Vec<Transition> {
+    self.audit.lock().unwrap().iter().cloned().collect()
+  }
+
+  fn record(&self, transition: Transition) {
+    match transition.outcome {
+      Outcome::Applied | Outcome::Woken => info!("power state of {}", transition),
+      Outcome::Denied | Outcome::Invalid => warn!("power state of {}", transition),
+    }
+    let mut audit = self.audit.lock().unwrap();
+    if audit.len() == AUDIT_LENGTH {
+      audit.pop_front();
+    }
+    audit.push_back(transition);
+  }
+}
+
+/// Applies a write to `mpowerstate`, which is ignored if the state is unknown or not allowed.
+pub fn set_power_state(cpu: &mut Cpu, state: u64) {
+  let from = cpu.csr.load(csr::machine::POWERSTATE);
+  if state == from {
+    return;
+  }
+  let outcome = match state {
+    power_state::DEFAULT | power_state::LOW_PRIORITY | power_state::DEEP_SLEEP => Outcome::Applied,
+    power_state::BYPASS if cpu.bus.power.allows_bypass() => Outcome::Applied,
+    power_state::BYPASS => Outcome::Denied,
+    _ => Outcome::Invalid,
+  };
+  record(&cpu.bus, cpu.id, from, state, outcome);
+
+  if outcome == Outcome::Applied {
+    cpu.csr.store(csr::machine::POWERSTATE, state);
+    if state == power_state::DEEP_SLEEP {
+      cpu.wfi.set(true);
+    }
+  }
+}
+
+/// Returns a hart that was interrupted in deep sleep to the default state.
+pub fn wake(cpu: &mut Cpu) {
+  record(&cpu.bus, cpu.id, power_state::DEEP_SLEEP, power_state::DEFAULT, Outcome::Woken);
+  cpu.csr.store(csr::machine::POWERSTATE, power_state::DEFAULT);
+}
+
+fn record(bus: &Bus, hart: u16, from: u64, to: u64, outcome: Outcome) {
+  bus.power.record(Transition {
+    time: SystemTime::now(),
+    hart,
+    from,
+    to,
+    outcome,
+  });
+}
@@ -0,0 +1,352 @@
+//! Sampling profiler for guest code.
+//!
+//! Every core of an isolate shares its [Profiler]. While running, a core samples its pc and walks the frame pointer
+//! chain once per [Profiler::interval] retired instructions, so guests must keep frame pointers
+//! (`-C force-frame-pointers=yes`, `-fno-omit-frame-pointer`) for complete stacks. Samples are aggregated by stack
+//! and exported as collapsed stacks for flamegraph tools or as a pprof profile, symbolized with [Symbols].
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use prost::Message;
+
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use crate::elf::Symbols;
+use crate::memory::{DRAM_BASE, DRAM_END};
+
+pub const DEFAULT_SAMPLE_INTERVAL: u64 = 10_000;
+/// Sampling more often slows the guest down too much.
+pub const MIN_SAMPLE_INTERVAL: u64 = 1_000;
+/// Deeper stacks are truncated.
+pub const MAX_FRAMES: usize = 64;
+
+#[derive(Debug, Default)]
+pub struct Profiler {
+  /// Retired instructions between samples of a core, 0 while stopped.
+  interval: AtomicU64,
+  samples: AtomicU64,
+  /// Sample counts by stack, innermost frame first.
+  stacks: Mutex<HashMap<Vec<u64>, u64>>,
+}
+
+impl Profiler {
+  #[must_use]
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Samples every `interval` retired instructions, at least [MIN_SAMPLE_INTERVAL].
+  pub fn start(&self, interval: u64) {
+    self.interval.store(interval.max(MIN_SAMPLE_INTERVAL), Ordering::Release);
+  }
+
+  pub fn stop(&self) {
+    self.interval.store(0, Ordering::Release);
+  }
+
+  pub fn interval(&self) -> u64 {
+    self.interval.load(Ordering::Acquire)
+  }
+
+  pub fn is_running(&self) -> bool {
+    self.interval() != 0
+  }
+
+  pub fn samples(&self) -> u64 {
+    self.samples.load(Ordering::Acquire)
+  }
+
+  pub fn reset(&self) {
+    self.stacks.lock().unwrap().clear();
+    self.samples.store(0, Ordering::Release);
+  }
+
+  /// Called after every instruction, `retired` is the number of instructions the core retired so far.
+  #[inline]
+  pub fn tick(&self, cpu: &Cpu, retired: u64) {
+    let interval = self.interval();
+    if interval != 0 && retired.is_multiple_of(interval) {
+      self.sample(cpu);
+    }
+  }
+
+  pub fn sample(&self, cpu: &Cpu) {
+    let stack = walk(&cpu.bus, cpu.pc, cpu.regs[8]);
+    *self.stacks.lock().unwrap().entry(stack).or_default() += 1;
+    self.samples.fetch_add(1, Ordering::AcqRel);
+  }
+
+  /// Stacks with their sample counts, most sampled first.
+  pub fn stacks(&self) -> Vec<(Vec<u64>, u64)> {
+    let mut stacks = self
+      .stacks
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(stack, count)| (stack.clone(), *count))
+      .collect::<Vec<_>>();
+    stacks.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+    stacks
+  }
+
+  /// One `outer;...;inner count` line per stack, the input format of `flamegraph.pl` and `inferno`.
+  pub fn collapsed(&self, symbols: &Symbols) -> String {
+    let mut output = String::new();
+    for (stack, count) in self.stacks() {
+      let frames = stack
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(depth, address)| frame_name(symbols, *address, depth))
+        .collect::<Vec<_>>();
+      writeln!(output, "{} {}", frames.join(";"), count).unwrap();
+    }
+    output
+  }
+
+  /// Uncompressed `perftools.profiles.Profile` protobuf, which `go tool pprof` reads as is.
+  pub fn pprof(&self, symbols: &Symbols) -> Vec<u8> {
+    let mut strings = StringTable::default();
+    let mut profile = pprof::Profile {
+      sample_type: vec![pprof::ValueType {
+        r#type: strings.index("samples"),
+        unit: strings.index("count"),
+      }],
+      period_type: Some(pprof::ValueType {
+        r#type: strings.index("instructions"),
+        unit: strings.index("count"),
+      }),
+      period: self.interval().max(1) as i64,
+      ..Default::default()
+    };
+
+    let mut locations = HashMap::<(u64, bool), u64>::new();
+    let mut functions = HashMap::<String, u64>::new();
+    for (stack, sampled) in self.stacks() {
+      let location_id = stack
+        .iter()
+        .enumerate()
+        .map(|(depth, address)| {
+          let next = locations.len() as u64 + 1;
+          *locations.entry((*address, depth > 0)).or_insert_with(|| {
+            let next_function = functions.len() as u64 + 1;
+            let function_id = *functions.entry(frame_name(symbols, *address, depth)).or_insert_with_key(|name| {
+              profile.function.push(pprof::Function {
+                id: next_function,
+                name: strings.index(name),
+              });
+              next_function
+            });
+            profile.location.push(pprof::Location {
+              id: next,
+              address: *address,
+              line: vec![pprof::Line { function_id }],
+            });
+            next
+          })
+        })
+        .collect();
+      profile.sample.push(pprof::Sample {
+        location_id,
+        value: vec![sampled as i64],
+      });
+    }
+    profile.string_table = strings.strings;
+    profile.encode_to_vec()
+  }
+}
+
+/// Walks the frame pointer chain: the return address is saved at `fp - 8` and the caller's frame pointer at `fp - 16`.
+fn walk(bus: &Bus, pc: u64, fp: u64) -> Vec<u64> {
+  let mut stack = vec![pc];
+  let mut fp = fp;
+  while stack.len() < MAX_FRAMES {
+    if !(DRAM_BASE + 16..=DRAM_END).contains(&fp) || !fp.is_multiple_of(8) {
+      break;
+    }
+    let (Ok(ra), Ok(next)) = (bus.load(fp - 8, 64), bus.load(fp - 16, 64)) else {
+      break;
+    };
+    if ra == 0 {
+      break;
+    }
+    stack.push(ra);
+    // The stack grows down, so callers have higher frames
+    if next <= fp {
+      break;
+    }
+    fp = next;
+  }
+  stack
+}
+
+/// Symbol of a frame, return addresses of outer frames are looked up at the call instead.
+fn frame_name(symbols: &Symbols, address: u64, depth: usize) -> String {
+  let lookup = if depth > 0 { address.wrapping_sub(1) } else { address };
+  match symbols.lookup(lookup) {
+    Some(symbol) => symbol.name.clone(),
+    None => format!("{:#x}", address),
+  }
+}
+
+#[derive(Default)]
+struct StringTable {
+  strings: Vec<String>,
+  indices: HashMap<String, i64>,
+}
+
+impl StringTable {
+  fn index(&mut self, string: &str) -> i64 {
+    // Index 0 is always the empty string
+    if self.strings.is_empty() {
+      self.strings.push(String::new());
+      self.indices.insert(String::new(), 0);
+    }
+    if let Some(index) = self.indices.get(string) {
+      return *index;
+    }
+    let index = self.strings.len() as i64;
+    self.strings.push(string.to_owned());
+    self.indices.insert(string.to_owned(), index);
+    index
+  }
+}
+
+/// Messages of `perftools.profiles`, with only the fields [Profiler::pprof] writes.
+mod pprof {
+  #[derive(Clone, PartialEq, prost::Message)]
+  pub struct Profile {
+    #[prost(message, repeated, tag = "1")]
+    pub sample_type: Vec<ValueType>,
+    #[prost(message, repeated, tag = "2")]
+    pub sample: Vec<Sample>,
+    #[prost(message, repeated, tag = "4")]
+    pub location: Vec<Location>,
+    #[prost(message, repeated, tag = "5")]
+    pub function: Vec<Function>,
+    /// Strings referenced by index, the first one is empty.
+    #[prost(string, repeated, tag = "6")]
+    pub string_table: Vec<String>,
+    #[prost(message, optional, tag = "11")]
+    pub period_type: Option<ValueType>,
+    #[prost(int64, tag = "12")]
+    pub period: i64,
+  }
+
+  #[derive(Clone, PartialEq, prost::Message)]
+  pub struct ValueType {
+    #[prost(int64, tag = "1")]
+    pub r#type: i64,
+    #[prost(int64, tag = "2")]
+    pub unit: i64,
+  }
+
+  #[derive(Clone, PartialEq, prost::Message)]
+  pub struct Sample {
+    /// Innermost frame first.
+    #[prost(uint64, repeated, tag = "1")]
+    pub location_id: Vec<u64>,
+    #[prost(int64, repeated, tag = "2")]
+    pub value: Vec<i64>,
+  }
+
+  #[derive(Clone, PartialEq, prost::Message)]
+  pub struct Location {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(uint64, tag = "3")]
+    pub address: u64,
+    #[prost(message, repeated, tag = "4")]
+    pub line: Vec<Line>,
+  }
+
+  #[derive(Clone, PartialEq, prost::Message)]
+  pub struct Line {
+    #[prost(uint64, tag = "1")]
+    pub function_id: u64,
+  }
+
+  #[derive(Clone, PartialEq, prost::Message)]
+  pub struct Function {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(int64, tag = "2")]
+    pub name: i64,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::elf::Symbol;
+
+  use super::*;
+
+  fn profiler() -> (Profiler, Symbols) {
+    let profiler = Profiler::new();
+    profiler.start(1);
+    let mut stacks = profiler.stacks.lock().unwrap();
+    stacks.insert(vec![0x1010, 0x2008], 3);
+    stacks.insert(vec![0x1020, 0x2008], 1);
+    drop(stacks);
+    let symbols = Symbols::new(vec![
+      Symbol {
+        address: 0x1000,
+        size: 0x100,
+        name: "inner".to_owned(),
+      },
+      Symbol {
+        address: 0x2000,
+        size: 0x100,
+        name: "outer".to_owned(),
+      },
+    ]);
+    (profiler, symbols)
+  }
+
+  #[test]
+  fn enforces_the_minimum_interval() {
+    let (profiler, _) = profiler();
+    assert_eq!(profiler.interval(), MIN_SAMPLE_INTERVAL);
+  }
+
+  #[test]
+  fn collapses_stacks() {
+    let (profiler, symbols) = profiler();
+    assert_eq!(profiler.collapsed(&symbols), "outer;inner 3\nouter;inner 1\n");
+  }
+
+  #[test]
+  fn encodes_pprof() {
+    let (profiler, symbols) = profiler();
+    let profile = pprof::Profile::decode(profiler.pprof(&symbols).as_slice()).unwrap();
+    let string = |index: i64| profile.string_table[index as usize].as_str();
+
+    assert_eq!(profile.string_table[0], "");
+    assert_eq!(string(profile.sample_type[0].r#type), "samples");
+    assert_eq!(string(profile.period_type.as_ref().unwrap().r#type), "instructions");
+    assert_eq!(profile.period, MIN_SAMPLE_INTERVAL as i64);
+
+    // Both samples share the outer frame and the function of the inner one
+    assert_eq!(profile.sample.len(), 2);
+    assert_eq!(profile.location.len(), 3);
+    assert_eq!(profile.function.len(), 2);
+    let frames = |sample: &pprof::Sample| {
+      sample
+        .location_id
+        .iter()
+        .map(|id| {
+          let location = profile.location.iter().find(|location| location.id == *id).unwrap();
+          let function = profile.function.iter().find(|function| function.id == location.line[0].function_id).unwrap();
+          (location.address, string(function.name))
+        })
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(frames(&profile.sample[0]), [(0x1010, "inner"), (0x2008, "outer")]);
+    assert_eq!(profile.sample[0].value, [3]);
+    assert_eq!(frames(&profile.sample[1]), [(0x1020, "inner"), (0x2008, "outer")]);
+    assert_eq!(profile.sample[1].value, [1]);
+  }
+}
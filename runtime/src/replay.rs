@@ -0,0 +1,748 @@
+//! Record and replay of guest executions.
+//!
+//! A [Session] attached to the [Bus] of an isolate sees every nondeterministic input of the guest: loads from the
+//! random device, reads of the time CSR, the effects of syscalls and the events raised by the host. While recording,
+//! cores execute one at a time in slices of up to [SLICE] steps, so the interleaving of cores is part of the trace.
+//! A replaying session feeds the inputs back in the same order and drops live events; only the syscalls in
+//! [PASSTHROUGH_SYSCALLS] run again since their effects stay within the isolate.
+//!
+//! Syscall handlers run without the slice, so a blocking handler does not stall the other cores. Only the stores made
+//! by the handler itself are recorded, asynchronous calls and rings complete on worker tasks and are not reproduced.
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use mizu_hal_types::syscall;
+use thiserror::Error;
+use tokio::sync::Notify;
+use tracing::{debug, error, warn};
+
+use crate::bus::Bus;
+use crate::cpu::{Cpu, InterruptHandler};
+use crate::exception::Exception;
+
+const MAGIC: &[u8; 8] = b"MIZUREC2";
+
+/// Maximum number of steps a core executes before another core may run while recording.
+pub const SLICE: u64 = 4096;
+
+/// Size of a trace file unless configured with `MIZU_RECORD_MAX_BYTES`.
+pub const DEFAULT_RECORD_LIMIT: u64 = 64 << 20;
+
+/// Syscalls that only change the isolate itself and are executed again on replay.
+pub const PASSTHROUGH_SYSCALLS: [u64; 6] = [
+  syscall::SYSCALL_HALT,
+  syscall::SYSCALL_SIPI,
+  syscall::SYSCALL_INT,
+  syscall::SYSCALL_MEMORY,
+  syscall::SYSCALL_HART,
+  syscall::SYSCALL_MAILBOX,
+];
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+  #[error("io error: {0}")]
+  Io(#[from] io::Error),
+  #[error("not a trace file")]
+  InvalidTrace,
+  #[error("unknown record type {0}")]
+  UnknownRecord(u8),
+  #[error("hart {hart} diverged at step {position}: {reason}")]
+  Diverged { hart: u16, position: u64, reason: String },
+  #[error("end of trace")]
+  Exhausted,
+}
+
+/// Stores of `size` bits made by a syscall handler to consecutive addresses from `address` on, `data` holds their
+/// little-endian values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Store {
+  pub address: u64,
+  pub size: u64,
+  pub data: Vec<u8>,
+}
+
+impl Store {
+  /// Appends a store to `journal`, extending the last range if the store continues it.
+  pub(crate) fn push(journal: &mut Vec<Store>, address: u64, size: u64, value: u64) {
+    let bytes = &value.to_le_bytes()[..(size / 8) as usize];
+    match journal.last_mut() {
+      Some(last) if last.size == size && last.address.wrapping_add(last.data.len() as u64) == address => last.data.extend_from_slice(bytes),
+      _ => journal.push(Store {
+        address,
+        size,
+        data: bytes.to_vec(),
+      }),
+    }
+  }
+
+  /// Repeats the stores with their original size.
+  pub fn apply(&self, bus: &Bus) -> Result<(), Exception> {
+    let width = (self.size / 8) as usize;
+    for (index, chunk) in self.data.chunks(width).enumerate() {
+      let mut value = [0; 8];
+      value[..chunk.len()].copy_from_slice(chunk);
+      bus.store(self.address.wrapping_add((index * width) as u64), self.size, u64::from_le_bytes(value))?;
+    }
+    Ok(())
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+  /// `hart` executed its next `count` steps.
+  Slice { hart: u16, count: u64 },
+  /// Value of a random device load or a time CSR read, in the order they happened.
+  Input { value: u64 },
+  /// Registers and stores of a syscall made at step `position` of `hart`.
+  Syscall {
+    hart: u16,
+    position: u64,
+    number: u64,
+    regs: Box<[u64; 32]>,
+    halt: bool,
+    wfi: bool,
+    stores: Vec<Store>,
+  },
+  /// Event raised by the host before step `position` of `hart`, see [Cpu::raise_event].
+  Event { hart: u16, position: u64, address: u64, data: Vec<u8> },
+}
+
+const RECORD_SLICE: u8 = 1;
+const RECORD_INPUT: u8 = 2;
+const RECORD_SYSCALL: u8 = 3;
+const RECORD_EVENT: u8 = 4;
+
+fn read_u8(input: &mut impl Read) -> io::Result<u8> {
+  let mut buffer = [0; 1];
+  input.read_exact(&mut buffer)?;
+  Ok(buffer[0])
+}
+
+fn read_u16(input: &mut impl Read) -> io::Result<u16> {
+  let mut buffer = [0; 2];
+  input.read_exact(&mut buffer)?;
+  Ok(u16::from_le_bytes(buffer))
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+  let mut buffer = [0; 4];
+  input.read_exact(&mut buffer)?;
+  Ok(u32::from_le_bytes(buffer))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+  let mut buffer = [0; 8];
+  input.read_exact(&mut buffer)?;
+  Ok(u64::from_le_bytes(buffer))
+}
+
+impl Record {
+  /// Little-endian encoding: a type byte followed by the fields in declaration order, lists are prefixed with a
+  /// `u32` length.
+  pub fn encode(&self, output: &mut impl Write) -> io::Result<()> {
+    match self {
+      Record::Slice { hart, count } => {
+        output.write_all(&[RECORD_SLICE])?;
+        output.write_all(&hart.to_le_bytes())?;
+        output.write_all(&count.to_le_bytes())?;
+      }
+      Record::Input { value } => {
+        output.write_all(&[RECORD_INPUT])?;
+        output.write_all(&value.to_le_bytes())?;
+      }
+      Record::Syscall {
+        hart,
+        position,
+        number,
+        regs,
+        halt,
+        wfi,
+        stores,
+      } => {
+        output.write_all(&[RECORD_SYSCALL])?;
+        output.write_all(&hart.to_le_bytes())?;
+        output.write_all(&position.to_le_bytes())?;
+        output.write_all(&number.to_le_bytes())?;
+        for reg in regs.iter() {
+          output.write_all(&reg.to_le_bytes())?;
+        }
+        output.write_all(&[(*halt as u8) | ((*wfi as u8) << 1)])?;
+        output.write_all(&(stores.len() as u32).to_le_bytes())?;
+        for store in stores {
+          output.write_all(&store.address.to_le_bytes())?;
+          output.write_all(&store.size.to_le_bytes())?;
+          output.write_all(&(store.data.len() as u32).to_le_bytes())?;
+          output.write_all(&store.data)?;
+        }
+      }
+      Record::Event { hart, position, address, data } => {
+        output.write_all(&[RECORD_EVENT])?;
+        output.write_all(&hart.to_le_bytes())?;
+        output.write_all(&position.to_le_bytes())?;
+        output.write_all(&address.to_le_bytes())?;
+        output.write_all(&(data.len() as u32).to_le_bytes())?;
+        output.write_all(data)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Reads the next record, `None` at the end of the input.
+  pub fn decode(input: &mut impl Read) -> Result<Option<Self>, ReplayError> {
+    let kind = match read_u8(input) {
+      Ok(kind) => kind,
+      Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+      Err(error) => return Err(error.into()),
+    };
+
+    let record = match kind {
+      RECORD_SLICE => Record::Slice {
+        hart: read_u16(input)?,
+        count: read_u64(input)?,
+      },
+      RECORD_INPUT => Record::Input { value: read_u64(input)? },
+      RECORD_SYSCALL => {
+        let hart = read_u16(input)?;
+        let position = read_u64(input)?;
+        let number = read_u64(input)?;
+        let mut regs = [0; 32];
+        for reg in &mut regs {
+          *reg = read_u64(input)?;
+        }
+        let flags = read_u8(input)?;
+        let count = read_u32(input)?;
+        let stores = (0..count)
+          .map(|_| -> Result<Store, ReplayError> {
+            let address = read_u64(input)?;
+            let size = read_u64(input)?;
+            if ![8, 16, 32, 64].contains(&size) {
+              return Err(ReplayError::InvalidTrace);
+            }
+            let mut data = vec![0; read_u32(input)? as usize];
+            input.read_exact(&mut data)?;
+            Ok(Store { address, size, data })
+          })
+          .collect::<Result<Vec<_>, _>>()?;
+        Record::Syscall {
+          hart,
+          position,
+          number,
+          regs: Box::new(regs),
+          halt: flags & 1 != 0,
+          wfi: flags & 2 != 0,
+          stores,
+        }
+      }
+      RECORD_EVENT => {
+        let hart = read_u16(input)?;
+        let position = read_u64(input)?;
+        let address = read_u64(input)?;
+        let mut data = vec![0; read_u32(input)? as usize];
+        input.read_exact(&mut data)?;
+        Record::Event { hart, position, address, data }
+      }
+      kind => return Err(ReplayError::UnknownRecord(kind)),
+    };
+    Ok(Some(record))
+  }
+}
+
+/// Reads the size limit of a recorded trace file from `MIZU_RECORD_MAX_BYTES`.
+pub fn record_limit_from_env() -> u64 {
+  env::var("MIZU_RECORD_MAX_BYTES")
+    .ok()
+    .and_then(|value| value.parse().ok())
+    .unwrap_or(DEFAULT_RECORD_LIMIT)
+}
+
+/// Reads all records of a trace file.
+pub fn read_trace(path: &Path) -> Result<Vec<Record>, ReplayError> {
+  let mut input = BufReader::new(File::open(path)?);
+  let mut magic = [0; 8];
+  input.read_exact(&mut magic).map_err(|_| ReplayError::InvalidTrace)?;
+  if &magic != MAGIC {
+    return Err(ReplayError::InvalidTrace);
+  }
+
+  let mut records = Vec::new();
+  while let Some(record) = Record::decode(&mut input)? {
+    records.push(record);
+  }
+  Ok(records)
+}
+
+/// Core that currently owns the slice while recording.
+#[derive(Default)]
+struct Owner {
+  hart: Option<u16>,
+  count: u64,
+}
+
+struct Recording {
+  output: Mutex<BufWriter<File>>,
+  owner: Mutex<Owner>,
+  /// Bytes written to the trace file.
+  written: AtomicU64,
+  /// Records are dropped once the trace file would grow beyond this many bytes.
+  limit: u64,
+  full: AtomicBool,
+}
+
+#[derive(Default)]
+struct Replaying {
+  /// Remaining steps of each slice.
+  slices: Mutex<VecDeque<(u16, u64)>>,
+  inputs: Mutex<VecDeque<u64>>,
+  syscalls: Mutex<HashMap<u16, VecDeque<Record>>>,
+  events: Mutex<HashMap<u16, VecDeque<Record>>>,
+}
+
+enum Mode {
+  Record(Recording),
+  Replay(Replaying),
+}
+
+pub struct Session {
+  mode: Mode,
+  /// Steps executed by each core.
+  positions: Mutex<HashMap<u16, u64>>,
+  /// Notified whenever the slice changes hands.
+  turn: Notify,
+  records: AtomicU64,
+}
+
+impl Session {
+  /// Starts recording into a new trace file at `path`. Recording stops once the file reaches `limit` bytes, the trace
+  /// then replays the execution up to that point.
+  pub fn record(path: &Path, limit: u64) -> Result<Self, ReplayError> {
+    let mut output = BufWriter::new(File::create(path)?);
+    output.write_all(MAGIC)?;
+    Ok(Self::new(Mode::Record(Recording {
+      output: Mutex::new(output),
+      owner: Mutex::new(Owner::default()),
+      written: AtomicU64::new(MAGIC.len() as u64),
+      limit,
+      full: AtomicBool::new(false),
+    })))
+  }
+
+  /// Replays the trace file at `path`.
+  pub fn replay(path: &Path) -> Result<Self, ReplayError> {
+    let replaying = Replaying::default();
+    for record in read_trace(path)? {
+      match record {
+        Record::Slice { hart, count } => replaying.slices.lock().unwrap().push_back((hart, count)),
+        Record::Input { value } => replaying.inputs.lock().unwrap().push_back(value),
+        Record::Syscall { hart, .. } => replaying.syscalls.lock().unwrap().entry(hart).or_default().push_back(record),
+        Record::Event { hart, .. } => replaying.events.lock().unwrap().entry(hart).or_default().push_back(record),
+      }
+    }
+    Ok(Self::new(Mode::Replay(replaying)))
+  }
+
+  fn new(mode: Mode) -> Self {
+    Self {
+      mode,
+      positions: Mutex::new(HashMap::new()),
+      turn: Notify::new(),
+      records: AtomicU64::new(0),
+    }
+  }
+
+  pub fn is_replaying(&self) -> bool {
+    matches!(self.mode, Mode::Replay(_))
+  }
+
+  /// Number of records written so far while recording.
+  pub fn records(&self) -> u64 {
+    self.records.load(Ordering::Acquire)
+  }
+
+  fn position(&self, hart: u16) -> u64 {
+    self.positions.lock().unwrap().get(&hart).copied().unwrap_or_default()
+  }
+
+  fn write(&self, record: Record) {
+    let Mode::Record(recording) = &self.mode else {
+      return;
+    };
+    let mut encoded = Vec::new();
+    // Writing to a vector does not fail
+    let _ = record.encode(&mut encoded);
+
+    let mut output = recording.output.lock().unwrap();
+    let written = recording.written.load(Ordering::Acquire) + encoded.len() as u64;
+    if written > recording.limit {
+      if !recording.full.swap(true, Ordering::AcqRel) {
+        warn!("trace reached its limit of {} bytes, recording stopped", recording.limit);
+      }
+      return;
+    }
+    if let Err(error) = output.write_all(&encoded) {
+      warn!("failed to write trace record: {}", error);
+    }
+    recording.written.store(written, Ordering::Release);
+    self.records.fetch_add(1, Ordering::AcqRel);
+  }
+
+  /// Waits until `hart` may execute its next step.
+  pub async fn begin(&self, hart: u16) -> Result<(), ReplayError> {
+    loop {
+      let turn = self.turn.notified();
+      match &self.mode {
+        Mode::Record(recording) => {
+          let mut owner = recording.owner.lock().unwrap();
+          match owner.hart {
+            None => {
+              owner.hart = Some(hart);
+              owner.count = 0;
+              return Ok(());
+            }
+            Some(owner) if owner == hart => return Ok(()),
+            Some(_) => {}
+          }
+        }
+        Mode::Replay(replaying) => {
+          let slices = replaying.slices.lock().unwrap();
+          match slices.front() {
+            Some((owner, _)) if *owner == hart => return Ok(()),
+            Some(_) => {}
+            None => return Err(ReplayError::Exhausted),
+          }
+        }
+      }
+      turn.await;
+    }
+  }
+
+  /// Finishes a step of `hart`, `yield_slice` lets other cores run while recording.
+  pub fn end(&self, hart: u16, yield_slice: bool) {
+    *self.positions.lock().unwrap().entry(hart).or_default() += 1;
+    match &self.mode {
+      Mode::Record(recording) => {
+        let mut owner = recording.owner.lock().unwrap();
+        owner.count += 1;
+        if yield_slice || owner.count >= SLICE {
+          self.release(&mut owner, hart);
+        }
+      }
+      Mode::Replay(replaying) => {
+        let mut slices = replaying.slices.lock().unwrap();
+        if let Some((_, remaining)) = slices.front_mut() {
+          *remaining -= 1;
+          if *remaining == 0 {
+            slices.pop_front();
+            self.turn.notify_waiters();
+          }
+        }
+      }
+    }
+  }
+
+  /// Ends the slice of `hart` early while recording, so that other cores may run.
+  pub fn yield_slice(&self, hart: u16) {
+    if let Mode::Record(recording) = &self.mode {
+      let mut owner = recording.owner.lock().unwrap();
+      if owner.hart == Some(hart) {
+        self.release(&mut owner, hart);
+      }
+    }
+  }
+
+  fn release(&self, owner: &mut Owner, hart: u16) {
+    if owner.count > 0 {
+      self.write(Record::Slice { hart, count: owner.count });
+    }
+    owner.hart = None;
+    owner.count = 0;
+    self.turn.notify_waiters();
+  }
+
+  /// Value of a nondeterministic read, `generate` produces it while recording.
+  pub fn input(&self, generate: impl FnOnce() -> u64) -> Option<u64> {
+    match &self.mode {
+      Mode::Record(_) => {
+        let value = generate();
+        self.write(Record::Input { value });
+        Some(value)
+      }
+      Mode::Replay(replaying) => {
+        let value = replaying.inputs.lock().unwrap().pop_front();
+        if value.is_none() {
+          error!("replay diverged: no recorded input left");
+        }
+        value
+      }
+    }
+  }
+
+  /// Executes the syscall `number` of `cpu`, or applies its recorded effects when replaying.
+  pub async fn syscall(&self, cpu: &mut Cpu, number: u64, handler: Option<Arc<Box<dyn InterruptHandler>>>) -> Result<(), Exception> {
+    let hart = cpu.id;
+    let position = self.position(hart);
+
+    let passthrough = PASSTHROUGH_SYSCALLS.contains(&number);
+    match &self.mode {
+      Mode::Replay(_) if passthrough => {
+        let handler = handler.ok_or(Exception::RuntimeFault(number))?;
+        handler.handle(cpu).await;
+        Ok(())
+      }
+      Mode::Record(recording) => {
+        let handler = handler.ok_or(Exception::RuntimeFault(number))?;
+        // Handlers may block or wait for other cores, which must be able to run meanwhile
+        self.release(&mut recording.owner.lock().unwrap(), hart);
+        if passthrough {
+          handler.handle(cpu).await;
+          self.begin(hart).await.map_err(|_| Exception::RuntimeFault(number))?;
+          return Ok(());
+        }
+        let bus = cpu.bus.clone();
+        let ((), stores) = bus.journal(handler.handle(cpu)).await;
+        self.begin(hart).await.map_err(|_| Exception::RuntimeFault(number))?;
+        self.write(Record::Syscall {
+          hart,
+          position,
+          number,
+          regs: Box::new(cpu.regs),
+          halt: cpu.halt,
+          wfi: cpu.wfi.get(),
+          stores,
+        });
+        Ok(())
+      }
+      Mode::Replay(replaying) => {
+        let record = replaying.syscalls.lock().unwrap().get_mut(&hart).and_then(|records| records.pop_front());
+        let Some(Record::Syscall {
+          position: recorded_position,
+          number: recorded_number,
+          regs,
+          halt,
+          wfi,
+          stores,
+          ..
+        }) = record
+        else {
+          error!(
+            "{}",
+            ReplayError::Diverged {
+              hart,
+              position,
+              reason: format!("unexpected syscall {}", number)
+            }
+          );
+          return Err(Exception::RuntimeFault(number));
+        };
+        if recorded_position != position || recorded_number != number {
+          let reason = format!("syscall {} instead of {} at step {}", number, recorded_number, recorded_position);
+          error!("{}", ReplayError::Diverged { hart, position, reason });
+          return Err(Exception::RuntimeFault(number));
+        }
+
+        for store in &stores {
+          store.apply(&cpu.bus)?;
+        }
+        cpu.regs = *regs;
+        cpu.halt = halt;
+        if wfi {
+          cpu.wfi.set(true);
+        }
+        debug!("replayed syscall {} of hart {} at step {}", number, hart, position);
+        Ok(())
+      }
+    }
+  }
+
+  /// Records an event raised by the host, returns false if it must not be delivered because the session replays.
+  pub fn event(&self, cpu: &Cpu, address: u64, data: &[u8]) -> bool {
+    match &self.mode {
+      Mode::Record(_) => {
+        self.write(Record::Event {
+          hart: cpu.id,
+          position: self.position(cpu.id),
+          address,
+          data: data.to_vec(),
+        });
+        true
+      }
+      Mode::Replay(_) => {
+        debug!("dropping live event while replaying");
+        false
+      }
+    }
+  }
+
+  /// Delivers the recorded events that happened before the next step of `cpu`.
+  pub fn inject(&self, cpu: &mut Cpu) {
+    let Mode::Replay(replaying) = &self.mode else {
+      return;
+    };
+    let position = self.position(cpu.id);
+    loop {
+      let record = {
+        let mut events = replaying.events.lock().unwrap();
+        let Some(events) = events.get_mut(&cpu.id) else {
+          return;
+        };
+        match events.front() {
+          Some(Record::Event { position: at, .. }) if *at <= position => events.pop_front(),
+          _ => return,
+        }
+      };
+      if let Some(Record::Event { address, data, .. }) = record {
+        cpu.deliver_event(address, &data);
+      }
+    }
+  }
+
+  /// Writes the current slice and flushes the trace file.
+  pub fn flush(&self) {
+    let Mode::Record(recording) = &self.mode else {
+      return;
+    };
+    let mut owner = recording.owner.lock().unwrap();
+    if let Some(hart) = owner.hart {
+      if owner.count > 0 {
+        self.write(Record::Slice { hart, count: owner.count });
+        owner.count = 0;
+      }
+    }
+    if let Err(error) = recording.output.lock().unwrap().flush() {
+      warn!("failed to flush trace: {}", error);
+    }
+  }
+}
+
+impl Drop for Session {
+  fn drop(&mut self) {
+    self.flush();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use mizu_hwconst::memory::DRAM_BASE;
+
+  use super::*;
+
+  fn round_trip(record: Record) {
+    let mut encoded = Vec::new();
+    record.encode(&mut encoded).unwrap();
+    let mut input = encoded.as_slice();
+    assert_eq!(Record::decode(&mut input).unwrap(), Some(record));
+    assert!(Record::decode(&mut input).unwrap().is_none());
+  }
+
+  #[test]
+  fn round_trips_records() {
+    round_trip(Record::Slice { hart: 3, count: SLICE });
+    round_trip(Record::Input { value: u64::MAX });
+    let mut regs = [0; 32];
+    regs[10] = 42;
+    round_trip(Record::Syscall {
+      hart: 1,
+      position: 1234,
+      number: syscall::SYSCALL_TIME,
+      regs: Box::new(regs),
+      halt: false,
+      wfi: true,
+      stores: vec![Store {
+        address: DRAM_BASE,
+        size: 32,
+        data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+      }],
+    });
+    round_trip(Record::Event {
+      hart: 0,
+      position: 7,
+      address: 0x1000,
+      data: b"event".to_vec(),
+    });
+  }
+
+  #[test]
+  fn rejects_invalid_records() {
+    assert!(matches!(Record::decode(&mut [9u8].as_slice()), Err(ReplayError::UnknownRecord(9))));
+
+    let mut encoded = Vec::new();
+    Record::Syscall {
+      hart: 0,
+      position: 0,
+      number: 0,
+      regs: Box::new([0; 32]),
+      halt: false,
+      wfi: false,
+      stores: vec![Store {
+        address: 0,
+        size: 0,
+        data: Vec::new(),
+      }],
+    }
+    .encode(&mut encoded)
+    .unwrap();
+    assert!(matches!(Record::decode(&mut encoded.as_slice()), Err(ReplayError::InvalidTrace)));
+  }
+
+  #[test]
+  fn merges_consecutive_stores() {
+    let mut journal = Vec::new();
+    for (offset, byte) in b"hello".iter().enumerate() {
+      Store::push(&mut journal, DRAM_BASE + offset as u64, 8, *byte as u64);
+    }
+    Store::push(&mut journal, DRAM_BASE + 8, 64, 0x1122334455667788);
+    Store::push(&mut journal, DRAM_BASE + 16, 64, 0x99);
+    Store::push(&mut journal, DRAM_BASE + 5, 8, b'!' as u64);
+    assert_eq!(journal.len(), 3);
+    assert_eq!(journal[0].data, b"hello");
+    assert_eq!(journal[1].data.len(), 16);
+
+    let bus = Bus::new(Vec::new());
+    for store in &journal {
+      store.apply(&bus).unwrap();
+    }
+    assert_eq!(bus.load(DRAM_BASE, 32).unwrap(), u32::from_le_bytes(*b"hell") as u64);
+    assert_eq!(bus.load(DRAM_BASE + 4, 16).unwrap(), u16::from_le_bytes(*b"o!") as u64);
+    assert_eq!(bus.load(DRAM_BASE + 8, 64).unwrap(), 0x1122334455667788);
+    assert_eq!(bus.load(DRAM_BASE + 16, 64).unwrap(), 0x99);
+  }
+
+  #[tokio::test]
+  async fn journals_only_the_stores_of_the_task() {
+    let bus = Arc::new(Bus::new(Vec::new()));
+    let other = bus.clone();
+    let ((), stores) = bus
+      .journal(async {
+        bus.store(DRAM_BASE, 32, 1).unwrap();
+        tokio::spawn(async move { other.store(DRAM_BASE + 64, 32, 2).unwrap() }).await.unwrap();
+      })
+      .await;
+    assert_eq!(stores, vec![Store {
+      address: DRAM_BASE,
+      size: 32,
+      data: vec![1, 0, 0, 0],
+    }]);
+
+    bus.store(DRAM_BASE, 32, 3).unwrap();
+    let ((), stores) = bus.journal(async {}).await;
+    assert!(stores.is_empty());
+  }
+
+  #[test]
+  fn stops_recording_at_the_limit() {
+    let path = env::temp_dir().join(format!("mizu-replay-test-{}.mizurec", std::process::id()));
+    let session = Session::record(&path, MAGIC.len() as u64 + 20).unwrap();
+    session.write(Record::Input { value: 1 });
+    session.write(Record::Input { value: 2 });
+    session.write(Record::Input { value: 3 });
+    session.flush();
+    assert_eq!(session.records(), 2);
+    assert_eq!(read_trace(&path).unwrap(), vec![Record::Input { value: 1 }, Record::Input { value: 2 }]);
+    std::fs::remove_file(&path).unwrap();
+  }
+}
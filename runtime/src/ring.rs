@@ -0,0 +1,385 @@
+use std::mem::{offset_of, size_of};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use mizu_hal_types::async_call::{async_completion_t, async_queue_t, RESULT_WINDOW_SIZE};
+use mizu_hal_types::ring::{ring_setup_t, ring_sqe_t, status};
+use mizu_hal_types::syscall::{SYSCALL_ASYNC, SYSCALL_RING};
+use mizu_hwconst::memory::RESULTS_OFFSET;
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+
+use crate::apic::INTERRUPT_PRIORITY_NORMAL;
+use crate::async_call::{CompletionQueue, DetachedCore};
+use crate::bus::{Bus, BusMemoryExt};
+use crate::cpu::{Cpu, InterruptHandler};
+use crate::exception::Exception;
+use crate::interrupt::Interrupt;
+
+/// How often an idle ring worker checks whether its isolate is still alive.
+const LIVENESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Guest-registered submission queue.
+#[derive(Debug, Copy, Clone)]
+pub struct SubmissionQueue {
+  pub address: u64,
+  pub entries: u64,
+}
+
+impl SubmissionQueue {
+  /// Size of the header and the entries in guest memory, [None] if it overflows.
+  #[must_use]
+  pub fn size(&self) -> Option<u64> {
+    let entries = self.entries.checked_mul(size_of::<ring_sqe_t>() as u64)?;
+    entries.checked_add(size_of::<async_queue_t>() as u64)
+  }
+
+  /// Returns the next unconsumed entry without consuming it.
+  pub fn peek(&self, bus: &Bus) -> Result<Option<ring_sqe_t>, Exception> {
+    let head = bus.load(self.address + offset_of!(async_queue_t, head) as u64, 64)?;
+    let tail = bus.load(self.address + offset_of!(async_queue_t, tail) as u64, 64)?;
+    if head == tail {
+      return Ok(None);
+    }
+
+    let entry = self.address + size_of::<async_queue_t>() as u64 + (head % self.entries) * size_of::<ring_sqe_t>() as u64;
+    bus.read_struct::<ring_sqe_t>(entry).map(Some)
+  }
+
+  pub fn advance(&self, bus: &Bus) -> Result<(), Exception> {
+    let head = self.address + offset_of!(async_queue_t, head) as u64;
+    bus.store(head, 64, bus.load(head, 64)?.wrapping_add(1))
+  }
+}
+
+/// Checks that the rings and the result windows lie in guest memory.
+fn validate(bus: &Bus, setup: &ring_setup_t, sq: &SubmissionQueue, cq: &CompletionQueue) -> bool {
+  let windows = cq.entries.checked_mul(RESULT_WINDOW_SIZE);
+  let results = setup.results == 0 || windows.is_some_and(|size| bus.is_memory(setup.results, size));
+  let queues = sq.size().is_some_and(|size| bus.is_memory(sq.address, size)) && cq.size().is_some_and(|size| bus.is_memory(cq.address, size));
+  results && queues
+}
+
+/// Shared-memory submission/completion rings.
+///
+/// After registration a worker task waits for stores to the doorbell register, then executes every
+/// pending submission in order through the core's interrupt vector table and posts the results to the
+/// completion queue. [Interrupt::PlatformDefined18] is raised on the registering core after each batch.
+///
+/// If the guest registered result windows, the call of the completion at position `n` writes the data its result
+/// points to into window `n % cq_entries`. Submissions only run while the completion queue has room, so a window is
+/// not reused before its completion is consumed.
+pub struct RingHandler {
+  generation: Arc<AtomicU64>,
+}
+
+impl Default for RingHandler {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl RingHandler {
+  #[must_use]
+  pub fn new() -> Self {
+    Self {
+      generation: Arc::new(AtomicU64::new(0)),
+    }
+  }
+}
+
+#[async_trait]
+impl InterruptHandler for RingHandler {
+  async fn handle(&self, cpu: &mut Cpu) {
+    let setup = match cpu.bus.read_struct::<ring_setup_t>(cpu.regs[10]) {
+      Ok(setup) => setup,
+      Err(exception) => {
+        warn!("ring setup: failed to read the setup: {:?}", exception);
+        cpu.regs[10] = status::INVALID_ADDRESS;
+        return;
+      }
+    };
+    debug!("ring setup: {:?}", setup);
+
+    // Stops the previous worker, if any
+    let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+    if setup.sq_entries == 0 || setup.cq_entries == 0 {
+      cpu.regs[10] = status::OK;
+      return;
+    }
+
+    let Some(isolate) = cpu.isolate.as_ref().and_then(|isolate| isolate.upgrade()) else {
+      warn!("ring setup: core {} is not attached to an isolate", cpu.id);
+      cpu.regs[10] = status::UNAVAILABLE;
+      return;
+    };
+
+    let sq = SubmissionQueue {
+      address: setup.sq_address,
+      entries: setup.sq_entries,
+    };
+    let cq = CompletionQueue {
+      address: setup.cq_address,
+      entries: setup.cq_entries,
+    };
+    if !validate(&cpu.bus, &setup, &sq, &cq) {
+      warn!("ring setup: rings are not in guest memory");
+      cpu.regs[10] = status::INVALID_ADDRESS;
+      return;
+    }
+    let results = setup.results;
+    let detached = DetachedCore::new(cpu);
+    let current_generation = self.generation.clone();
+    let isolate = Arc::downgrade(&isolate);
+    let mut doorbell = cpu.bus.doorbell.subscribe();
+    tokio::spawn(async move {
+      info!("ring worker {} started", generation);
+      loop {
+        let mut completed = 0;
+        // A new setup stops the worker before its next submission
+        while current_generation.load(Ordering::Acquire) == generation && matches!(cq.is_full(&detached.bus), Ok(false)) {
+          let sqe = match sq.peek(&detached.bus) {
+            Ok(Some(sqe)) => sqe,
+            Ok(None) => break,
+            Err(exception) => {
+              warn!("ring: failed to read the submission queue: {:?}", exception);
+              break;
+            }
+          };
+          let Ok(position) = cq.tail(&detached.bus) else {
+            break;
+          };
+
+          let result = if sqe.syscall == SYSCALL_RING || sqe.syscall == SYSCALL_ASYNC {
+            None
+          } else if results != 0 {
+            let window = results + (position % cq.entries) * RESULT_WINDOW_SIZE;
+            let core = DetachedCore {
+              hardware: window - RESULTS_OFFSET,
+              ..detached.clone()
+            };
+            core.call(sqe.syscall, &sqe.args).await
+          } else {
+            detached.call(sqe.syscall, &sqe.args).await
+          };
+          let [a0, a1] = result.unwrap_or_else(|| {
+            warn!("ring: unknown syscall {}", sqe.syscall);
+            [u64::MAX, 0]
+          });
+
+          let completion = async_completion_t { ticket: sqe.user_data, a0, a1 };
+          if let Err(exception) = cq.push(&detached.bus, &completion) {
+            warn!("ring completion {} dropped: {:?}", sqe.user_data, exception);
+          }
+          completed += 1;
+          if let Err(exception) = sq.advance(&detached.bus) {
+            warn!("ring: failed to advance the submission queue: {:?}", exception);
+            break;
+          }
+        }
+
+        let Some(isolate) = isolate.upgrade() else {
+          break;
+        };
+        if current_generation.load(Ordering::Acquire) != generation {
+          break;
+        }
+
+        if completed > 0 {
+          debug!("ring: completed {} entries", completed);
+          isolate.post(
+            detached.id,
+            Box::new(|cpu| {
+              cpu.apic.dispatch(Interrupt::PlatformDefined18, INTERRUPT_PRIORITY_NORMAL);
+              cpu.wfi.set(false);
+            }),
+          );
+        }
+        drop(isolate);
+
+        // Timeout is not an error, it only gives a chance to notice that the isolate is gone
+        let _ = timeout(LIVENESS_INTERVAL, doorbell.changed()).await;
+      }
+      info!("ring worker {} stopped", generation);
+    });
+
+    cpu.regs[10] = status::OK;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use mizu_hwconst::memory::{DOORBELL_BASE, DRAM_BASE};
+
+  use super::*;
+  use crate::isolate::Isolate;
+
+  const SETUP: u64 = DRAM_BASE;
+  const SQ: u64 = DRAM_BASE + 0x1000;
+  const CQ: u64 = DRAM_BASE + 0x2000;
+  const RESULTS: u64 = DRAM_BASE + 0x10000;
+  const SYSCALL_DOUBLE: u64 = 0x1000;
+
+  /// Doubles its argument and returns the address its result is written to.
+  struct DoubleCall;
+
+  #[async_trait]
+  impl InterruptHandler for DoubleCall {
+    async fn handle(&self, cpu: &mut Cpu) {
+      cpu.regs[10] *= 2;
+      cpu.regs[11] = cpu.hardware.wrapping_add(RESULTS_OFFSET);
+    }
+  }
+
+  fn core(isolate: &Arc<Isolate>) -> Cpu {
+    let mut cpu = Cpu::new(0, isolate.bus.clone(), Some(Arc::downgrade(isolate)));
+    cpu.ivt.insert(SYSCALL_DOUBLE, Arc::new(Box::new(DoubleCall)));
+    cpu
+  }
+
+  async fn setup(handler: &RingHandler, cpu: &mut Cpu, setup: &ring_setup_t) -> u64 {
+    cpu.bus.write_struct(SETUP, setup).unwrap();
+    cpu.regs[10] = SETUP;
+    handler.handle(cpu).await;
+    cpu.regs[10]
+  }
+
+  fn submit(bus: &Bus, sq: &SubmissionQueue, user_data: u64, arg: u64) {
+    let tail = bus.load(sq.address + offset_of!(async_queue_t, tail) as u64, 64).unwrap();
+    let entry = sq.address + size_of::<async_queue_t>() as u64 + (tail % sq.entries) * size_of::<ring_sqe_t>() as u64;
+    bus
+      .write_struct(entry, &ring_sqe_t {
+        user_data,
+        syscall: SYSCALL_DOUBLE,
+        args: [arg, 0, 0, 0, 0],
+      })
+      .unwrap();
+    bus.store(sq.address + offset_of!(async_queue_t, tail) as u64, 64, tail.wrapping_add(1)).unwrap();
+  }
+
+  fn completion(bus: &Bus, cq: &CompletionQueue, position: u64) -> async_completion_t {
+    let entry = cq.address + size_of::<async_queue_t>() as u64 + (position % cq.entries) * size_of::<async_completion_t>() as u64;
+    bus.read_struct(entry).unwrap()
+  }
+
+  #[tokio::test]
+  async fn validates_setup() {
+    let isolate = Isolate::new(Arc::new(Bus::new(Vec::new())));
+    let mut cpu = core(&isolate);
+    let handler = RingHandler::new();
+    let valid = ring_setup_t {
+      sq_address: SQ,
+      sq_entries: 2,
+      cq_address: CQ,
+      cq_entries: 2,
+      results: 0,
+    };
+
+    cpu.regs[10] = 0;
+    handler.handle(&mut cpu).await;
+    assert_eq!(cpu.regs[10], status::INVALID_ADDRESS);
+
+    for invalid in [
+      ring_setup_t { sq_address: 0, ..valid },
+      ring_setup_t { cq_address: 0, ..valid },
+      ring_setup_t { sq_entries: u64::MAX, ..valid },
+      ring_setup_t { cq_entries: u64::MAX, ..valid },
+      ring_setup_t {
+        results: DRAM_BASE - RESULT_WINDOW_SIZE,
+        ..valid
+      },
+    ] {
+      assert_eq!(setup(&handler, &mut cpu, &invalid).await, status::INVALID_ADDRESS, "{:?}", invalid);
+    }
+    assert_eq!(setup(&handler, &mut cpu, &ring_setup_t { sq_entries: 0, ..valid }).await, status::OK);
+    assert_eq!(setup(&handler, &mut cpu, &valid).await, status::OK);
+
+    let mut detached = Cpu::new(1, cpu.bus.clone(), None);
+    assert_eq!(setup(&handler, &mut detached, &valid).await, status::UNAVAILABLE);
+  }
+
+  #[test]
+  fn wraps_around() {
+    let bus = Bus::new(Vec::new());
+    let sq = SubmissionQueue { address: SQ, entries: 2 };
+    let cq = CompletionQueue { address: CQ, entries: 2 };
+    for address in [SQ, CQ] {
+      bus.store(address + offset_of!(async_queue_t, head) as u64, 64, u64::MAX).unwrap();
+      bus.store(address + offset_of!(async_queue_t, tail) as u64, 64, u64::MAX).unwrap();
+    }
+
+    submit(&bus, &sq, 1, 0);
+    submit(&bus, &sq, 2, 0);
+    assert_eq!(bus.load(SQ + offset_of!(async_queue_t, tail) as u64, 64).unwrap(), 1);
+    for user_data in 1..=2 {
+      let sqe = sq.peek(&bus).unwrap().unwrap();
+      assert_eq!(sqe.user_data, user_data);
+      sq.advance(&bus).unwrap();
+      cq.push(&bus, &async_completion_t { ticket: user_data, a0: 0, a1: 0 }).unwrap().unwrap();
+    }
+    assert!(sq.peek(&bus).unwrap().is_none());
+    assert!(cq.is_full(&bus).unwrap());
+    assert_eq!([completion(&bus, &cq, u64::MAX).ticket, completion(&bus, &cq, 0).ticket], [1, 2]);
+  }
+
+  #[tokio::test]
+  async fn drains_submissions_on_doorbell() {
+    let isolate = Isolate::new(Arc::new(Bus::new(Vec::new())));
+    let mut cpu = core(&isolate);
+    let bus = cpu.bus.clone();
+    let handler = RingHandler::new();
+    let sq = SubmissionQueue { address: SQ, entries: 4 };
+    let cq = CompletionQueue { address: CQ, entries: 2 };
+    let rings = ring_setup_t {
+      sq_address: SQ,
+      sq_entries: sq.entries,
+      cq_address: CQ,
+      cq_entries: cq.entries,
+      results: RESULTS,
+    };
+    assert_eq!(setup(&handler, &mut cpu, &rings).await, status::OK);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    // Submissions wait for the doorbell, then run while the completion queue has room
+    for user_data in 1..=3 {
+      submit(&bus, &sq, user_data, user_data * 10);
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(cq.tail(&bus).unwrap(), 0);
+    bus.store(DOORBELL_BASE, 32, 1).unwrap();
+    wait_for(&bus, &cq, 2).await;
+    let completions = [completion(&bus, &cq, 0), completion(&bus, &cq, 1)];
+    assert_eq!(completions.map(|completion| (completion.ticket, completion.a0, completion.a1)), [
+      (1, 20, RESULTS),
+      (2, 40, RESULTS + RESULT_WINDOW_SIZE),
+    ]);
+
+    // Consuming a completion lets the remaining submission run into the freed window
+    bus.store(CQ + offset_of!(async_queue_t, head) as u64, 64, 1).unwrap();
+    bus.store(DOORBELL_BASE, 32, 1).unwrap();
+    wait_for(&bus, &cq, 3).await;
+    let completion = completion(&bus, &cq, 2);
+    assert_eq!((completion.ticket, completion.a0, completion.a1), (3, 60, RESULTS));
+    assert!(sq.peek(&bus).unwrap().is_none());
+
+    // The worker stops once the rings are unregistered
+    assert_eq!(setup(&handler, &mut cpu, &ring_setup_t { sq_entries: 0, ..rings }).await, status::OK);
+    bus.store(CQ + offset_of!(async_queue_t, head) as u64, 64, 3).unwrap();
+    submit(&bus, &sq, 4, 0);
+    bus.store(DOORBELL_BASE, 32, 1).unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(cq.tail(&bus).unwrap(), 3);
+  }
+
+  async fn wait_for(bus: &Bus, cq: &CompletionQueue, tail: u64) {
+    for _ in 0..500 {
+      if cq.tail(bus).unwrap() == tail {
+        return;
+      }
+      tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    panic!("the completion queue did not reach {}", tail);
+  }
+}
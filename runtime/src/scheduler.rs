@@ -0,0 +1,427 @@
+//! Runs the harts of all isolates on a fixed pool of workers.
+//!
+//! Runnable harts wait in one queue per group, the bot uses a group per guild, and workers serve the groups
+//! round-robin so that a guild with many harts cannot starve the others. A worker runs a hart for a slice of
+//! [SchedulerConfig::slice] steps, or a [LOW_PRIORITY_SHARE] of it in [power_state::LOW_PRIORITY], and then puts it
+//! back at the end of its group. Harts that wait for an interrupt or are paused park until [Isolate::wake], and once
+//! every hart of an isolate is in [power_state::DEEP_SLEEP] its memory is swapped out, see [Isolate::try_sleep].
+//!
+//! Messages for a hart go through its [Inbox] and are applied between two steps, so delivering an interrupt never
+//! waits for the running slice to end.
+//!
+//! A slice that waits for a syscall, such as an HTTP request, is detached from its worker and finishes on a task of
+//! its own. The hart is requeued once the syscall completes, so the workers keep running the other harts meanwhile.
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::error::Error;
+use std::future::{poll_fn, Future};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::Poll;
+use std::thread;
+
+use async_trait::async_trait;
+use tokio::sync::{Mutex, Notify};
+use tracing::{debug, info};
+
+use crate::cpu::Cpu;
+use crate::csr::{self, power_state};
+use crate::isolate::Isolate;
+use crate::state_flow::StateFlow;
+use crate::tick::{CpuExt, TickResult};
+
+/// Steps of a slice unless configured with `MIZU_SCHEDULER_SLICE`.
+pub const DEFAULT_SLICE: u64 = 10_000;
+/// Low-priority harts run for `1 / LOW_PRIORITY_SHARE` of a slice.
+pub const LOW_PRIORITY_SHARE: u64 = 4;
+
+pub type Message = Box<dyn FnOnce(&mut Cpu) + Send>;
+
+/// Messages for a hart, applied by [CpuExt::run_tick] before the next step.
+#[derive(Default)]
+pub struct Inbox {
+  pending: AtomicBool,
+  messages: std::sync::Mutex<Vec<Message>>,
+}
+
+impl Inbox {
+  pub fn post(&self, message: Message) {
+    self.messages.lock().unwrap().push(message);
+    self.pending.store(true, Ordering::Release);
+  }
+
+  #[inline]
+  pub fn is_pending(&self) -> bool {
+    self.pending.load(Ordering::Acquire)
+  }
+
+  pub fn take(&self) -> Vec<Message> {
+    let mut messages = self.messages.lock().unwrap();
+    self.pending.store(false, Ordering::Release);
+    std::mem::take(&mut *messages)
+  }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct SchedulerConfig {
+  pub workers: usize,
+  pub slice: u64,
+}
+
+impl Default for SchedulerConfig {
+  fn default() -> Self {
+    Self {
+      workers: thread::available_parallelism().map(|workers| workers.get()).unwrap_or(4),
+      slice: DEFAULT_SLICE,
+    }
+  }
+}
+
+impl SchedulerConfig {
+  /// Reads the configuration from `MIZU_SCHEDULER_*` environment variables.
+  pub fn from_env() -> Self {
+    let mut config = Self::default();
+    if let Some(value) = env::var("MIZU_SCHEDULER_WORKERS").ok().and_then(|value| value.parse().ok()) {
+      config.workers = value;
+    }
+    if let Some(value) = env::var("MIZU_SCHEDULER_SLICE").ok().and_then(|value| value.parse().ok()) {
+      config.slice = value;
+    }
+    config
+  }
+}
+
+/// Receives the results that end the execution of a hart, or pause it until it is woken.
+#[async_trait]
+pub trait HartObserver: Send + Sync {
+  /// Called with every result other than [TickResult::Continue]. The hart parks after
+  /// [TickResult::WaitForInterrupt] and [TickResult::Paused] and stops after anything else.
+  async fn on_result(&self, cpu: &mut Cpu, result: &TickResult);
+
+  /// The hart stops afterwards.
+  async fn on_error(&self, cpu: &mut Cpu, error: &(dyn Error + Send + Sync));
+}
+
+pub struct Hart {
+  pub id: u16,
+  pub cpu: Arc<Mutex<Cpu>>,
+  isolate: Weak<Isolate>,
+  group: u64,
+  observer: Arc<dyn HartObserver>,
+  wfi: StateFlow<bool>,
+  inbox: Arc<Inbox>,
+  stopped: StateFlow<bool>,
+  /// The slice waits for a syscall off the workers, see [Scheduler::work].
+  detached: AtomicBool,
+}
+
+impl Hart {
+  pub fn is_stopped(&self) -> bool {
+    self.stopped.get()
+  }
+
+  /// Waits until the hart stops.
+  pub async fn join(&self) {
+    self.stopped.wait_for(|stopped| *stopped).await;
+  }
+
+  fn is_runnable(&self) -> bool {
+    let Some(isolate) = self.isolate.upgrade() else {
+      // Runs once more to notice that it is gone
+      return true;
+    };
+    if isolate.exploded.load(Ordering::Acquire) {
+      return true;
+    }
+    !isolate.paused.get() && (!self.wfi.get() || self.inbox.is_pending())
+  }
+}
+
+enum Slice {
+  Expired,
+  Parked,
+  /// Parked in deep sleep.
+  Sleeping,
+  Stopped,
+}
+
+#[derive(Default)]
+struct RunQueue {
+  /// Groups with runnable harts, served round-robin.
+  groups: VecDeque<u64>,
+  harts: HashMap<u64, VecDeque<Arc<Hart>>>,
+  /// Parked harts by the address of their isolate.
+  parked: HashMap<usize, Vec<Arc<Hart>>>,
+}
+
+impl RunQueue {
+  fn push(&mut self, hart: Arc<Hart>) {
+    let harts = self.harts.entry(hart.group).or_default();
+    if harts.is_empty() {
+      self.groups.push_back(hart.group);
+    }
+    harts.push_back(hart);
+  }
+
+  fn pop(&mut self) -> Option<Arc<Hart>> {
+    while let Some(group) = self.groups.pop_front() {
+      let Some(harts) = self.harts.get_mut(&group) else {
+        continue;
+      };
+      let hart = harts.pop_front();
+      if harts.is_empty() {
+        self.harts.remove(&group);
+      } else {
+        self.groups.push_back(group);
+      }
+      if hart.is_some() {
+        return hart;
+      }
+    }
+    None
+  }
+}
+
+pub struct Scheduler {
+  config: SchedulerConfig,
+  queue: std::sync::Mutex<RunQueue>,
+  runnable: Notify,
+}
+
+impl Scheduler {
+  /// Starts the workers on the current tokio runtime.
+  pub fn start(config: SchedulerConfig) -> Arc<Self> {
+    let this = Arc::new(Self {
+      config,
+      queue: std::sync::Mutex::new(RunQueue::default()),
+      runnable: Notify::new(),
+    });
+    for worker in 0..config.workers.max(1) {
+      let this = this.clone();
+      tokio::spawn(this.work(worker));
+    }
+    info!("started scheduler: {:?}", config);
+    this
+  }
+
+  pub fn config(&self) -> SchedulerConfig {
+    self.config
+  }
+
+  /// Number of queued and parked harts.
+  pub fn counts(&self) -> (usize, usize) {
+    let queue = self.queue.lock().unwrap();
+    (queue.harts.values().map(VecDeque::len).sum(), queue.parked.values().map(Vec::len).sum())
+  }
+
+  /// Schedules a core of `isolate`, it runs until it stops or the isolate is stopped.
+  pub async fn spawn(self: &Arc<Self>, isolate: &Arc<Isolate>, cpu: Arc<Mutex<Cpu>>, group: u64, observer: Arc<dyn HartObserver>) -> Arc<Hart> {
+    isolate.attach_scheduler(self);
+    let (id, wfi, inbox) = {
+      let cpu = cpu.lock().await;
+      (cpu.id, cpu.wfi.clone(), cpu.inbox.clone())
+    };
+    let hart = Arc::new(Hart {
+      id,
+      cpu,
+      isolate: Arc::downgrade(isolate),
+      group,
+      observer,
+      wfi,
+      inbox,
+      stopped: StateFlow::new(false),
+      detached: AtomicBool::new(false),
+    });
+    debug!("spawned hart {} in group {}", id, group);
+    self.enqueue(hart.clone());
+    hart
+  }
+
+  /// Queues the parked harts of `isolate` that became runnable.
+  pub fn wake(&self, isolate: &Isolate) {
+    let key = isolate as *const Isolate as usize;
+    let mut queue = self.queue.lock().unwrap();
+    let Some(parked) = queue.parked.remove(&key) else {
+      return;
+    };
+    let (runnable, parked) = parked.into_iter().partition::<Vec<_>, _>(|hart| hart.is_runnable());
+    if !parked.is_empty() {
+      queue.parked.insert(key, parked);
+    }
+    for hart in runnable {
+      queue.push(hart);
+      self.runnable.notify_one();
+    }
+  }
+
+  fn enqueue(&self, hart: Arc<Hart>) {
+    self.queue.lock().unwrap().push(hart);
+    self.runnable.notify_one();
+  }
+
+  fn park(&self, hart: Arc<Hart>) {
+    let mut queue = self.queue.lock().unwrap();
+    // A wake may have happened since the hart stopped, it takes the queue lock too
+    if hart.is_runnable() {
+      queue.push(hart);
+      self.runnable.notify_one();
+      return;
+    }
+    let key = hart.isolate.as_ptr() as usize;
+    queue.parked.entry(key).or_default().push(hart);
+  }
+
+  async fn next(&self) -> Arc<Hart> {
+    loop {
+      if let Some(hart) = self.queue.lock().unwrap().pop() {
+        return hart;
+      }
+      self.runnable.notified().await;
+    }
+  }
+
+  async fn work(self: Arc<Self>, worker: usize) {
+    debug!("scheduler worker {} started", worker);
+    loop {
+      let hart = self.next().await;
+      let mut slice = Box::pin(self.clone().run_slice(hart.clone()));
+      // A slice only yields while a syscall, the observer or the lock of the core is pending
+      match poll_fn(|cx| Poll::Ready(slice.as_mut().poll(cx))).await {
+        Poll::Ready(slice) => self.end_slice(hart, slice),
+        Poll::Pending => {
+          debug!("hart {} detached from worker {}", hart.id, worker);
+          hart.detached.store(true, Ordering::Release);
+          let this = self.clone();
+          tokio::spawn(async move {
+            let slice = slice.await;
+            hart.detached.store(false, Ordering::Release);
+            this.end_slice(hart, slice);
+          });
+        }
+      }
+    }
+  }
+
+  fn end_slice(&self, hart: Arc<Hart>, slice: Slice) {
+    match slice {
+      Slice::Expired => self.enqueue(hart),
+      Slice::Parked => self.park(hart),
+      Slice::Sleeping => {
+        let isolate = hart.isolate.upgrade();
+        self.park(hart);
+        if let Some(isolate) = isolate {
+          isolate.try_sleep();
+        }
+      }
+      Slice::Stopped => {
+        debug!("hart {} stopped", hart.id);
+        hart.stopped.set(true);
+      }
+    }
+  }
+
+  async fn run_slice(self: Arc<Self>, hart: Arc<Hart>) -> Slice {
+    if hart.isolate.strong_count() == 0 {
+      return Slice::Stopped;
+    }
+    let mut cpu = hart.cpu.lock().await;
+    if let Err(error) = cpu.bus.swap_in().await {
+      hart.observer.on_error(&mut cpu, &error).await;
+      cpu.lifecycle.end();
+      return Slice::Stopped;
+    }
+    let budget = match cpu.csr.load(csr::machine::POWERSTATE) {
+      power_state::LOW_PRIORITY => self.config.slice / LOW_PRIORITY_SHARE,
+      _ => self.config.slice,
+    };
+
+    for _ in 0..budget.max(1) {
+      let result = match cpu.run_tick().await {
+        // A detached slice ends after the syscall, the rest runs on a worker
+        Ok(TickResult::Continue) if hart.detached.load(Ordering::Acquire) => break,
+        Ok(TickResult::Continue) => continue,
+        Ok(result) => result,
+        Err(error) => {
+          hart.observer.on_error(&mut cpu, error.as_ref()).await;
+          cpu.lifecycle.end();
+          return Slice::Stopped;
+        }
+      };
+      hart.observer.on_result(&mut cpu, &result).await;
+      return match result {
+        TickResult::WaitForInterrupt if cpu.csr.load(csr::machine::POWERSTATE) == power_state::DEEP_SLEEP => Slice::Sleeping,
+        TickResult::WaitForInterrupt | TickResult::Paused => Slice::Parked,
+        _ => {
+          cpu.lifecycle.end();
+          Slice::Stopped
+        }
+      };
+    }
+
+    if let Some(session) = cpu.bus.session() {
+      session.yield_slice(cpu.id);
+    }
+    Slice::Expired
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use mizu_hwconst::memory::DRAM_BASE;
+  use tokio::time::{sleep, timeout};
+
+  use super::*;
+  use crate::bus::Bus;
+  use crate::cpu::InterruptHandler;
+
+  struct Observer;
+
+  #[async_trait]
+  impl HartObserver for Observer {
+    async fn on_result(&self, _cpu: &mut Cpu, _result: &TickResult) {}
+
+    async fn on_error(&self, _cpu: &mut Cpu, _error: &(dyn Error + Send + Sync)) {}
+  }
+
+  struct WaitHandler {
+    release: Arc<Notify>,
+  }
+
+  #[async_trait]
+  impl InterruptHandler for WaitHandler {
+    async fn handle(&self, _cpu: &mut Cpu) {
+      self.release.notified().await;
+    }
+  }
+
+  #[tokio::test]
+  async fn runs_other_harts_during_syscalls() {
+    // ecall, then loop with `j .`
+    let code = [0x00000073u32, 0x0000006f].iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let isolate = Isolate::new(Arc::new(Bus::new(code)));
+    let release = Arc::new(Notify::new());
+    let waiting = isolate.get_bootstrap_core();
+    waiting.lock().await.ivt.insert(0, Arc::new(Box::new(WaitHandler { release: release.clone() })));
+    let mut looping = Cpu::new(1, isolate.bus.clone(), Some(Arc::downgrade(&isolate)));
+    looping.pc = DRAM_BASE + 4;
+    let perf = looping.perf.clone();
+    let looping = isolate.add_core(looping);
+
+    let scheduler = Scheduler::start(SchedulerConfig { workers: 1, slice: 100 });
+    let first = scheduler.spawn(&isolate, waiting, 0, Arc::new(Observer)).await;
+    scheduler.spawn(&isolate, looping, 0, Arc::new(Observer)).await;
+
+    timeout(Duration::from_secs(5), async {
+      while perf.cycles.load(Ordering::Acquire) < 100 {
+        sleep(Duration::from_millis(1)).await;
+      }
+    })
+    .await
+    .expect("the syscall blocked the only worker");
+    release.notify_one();
+    timeout(Duration::from_secs(5), first.join()).await.unwrap();
+  }
+}
@@ -0,0 +1,148 @@
+//! Single step of a core's execution loop.
+
+use std::error::Error;
+use std::sync::atomic::Ordering;
+
+use async_trait::async_trait;
+use tracing::error;
+
+use crate::cpu::Cpu;
+use crate::csr::{self, power_state};
+use crate::exception::Exception;
+use crate::perf_counter::CPU_TIME_LIMIT;
+use crate::power;
+
+#[async_trait]
+pub trait CpuExt {
+  async fn run_tick(&mut self) -> Result<TickResult, Box<dyn Error + Send + Sync>>;
+}
+
+/// Outcome of a single [CpuExt::run_tick].
+pub enum TickResult {
+  Continue,
+  Exception(Exception),
+  Eof,
+  Halt,
+  TimeLimit,
+  WaitForInterrupt,
+  Explosion,
+  Paused,
+}
+
+#[async_trait]
+impl CpuExt for Cpu {
+  async fn run_tick(&mut self) -> Result<TickResult, Box<dyn Error + Send + Sync>> {
+    if let Some(isolate) = self.isolate.as_ref().and_then(|isolate| isolate.upgrade()) {
+      if isolate.exploded.load(Ordering::Acquire) {
+        return Ok(TickResult::Explosion);
+      }
+      if isolate.paused.get() {
+        return Ok(TickResult::Paused);
+      }
+    }
+
+    if self.inbox.is_pending() {
+      for message in self.inbox.take() {
+        message(self);
+      }
+    }
+
+    let session = self.bus.session().cloned();
+    if let Some(session) = &session {
+      session.inject(self);
+    }
+
+    if !self.lifecycle.is_running() {
+      // Interrupts do not wake a stopped hart
+      self.wfi.set(true);
+      return Ok(TickResult::WaitForInterrupt);
+    }
+    if self.wfi.get() {
+      return Ok(TickResult::WaitForInterrupt);
+    }
+    if self.csr.load(csr::machine::POWERSTATE) == power_state::DEEP_SLEEP {
+      power::wake(self);
+    }
+
+    let Some(session) = session else {
+      return step(self).await;
+    };
+    session.begin(self.id).await?;
+    let result = step(self).await;
+    session.end(self.id, !matches!(result, Ok(TickResult::Continue)));
+    result
+  }
+}
+
+/// Executes a single instruction and takes a pending interrupt.
+async fn step(cpu: &mut Cpu) -> Result<TickResult, Box<dyn Error + Send + Sync>> {
+  let inst = match cpu.fetch() {
+    Ok(inst) => inst,
+    Err(exception) => {
+      cpu.handle_exception(exception);
+      if matches!(&exception, Exception::InstructionAccessFault(0)) {
+        return Ok(TickResult::Eof);
+      }
+      if exception.is_fatal() {
+        error!("fetch failed: {:?}", exception);
+        return Ok(TickResult::Exception(exception));
+      }
+
+      return Ok(TickResult::Exception(exception));
+    }
+  };
+
+  let trace = cpu.begin_trace(inst);
+  let result = cpu.execute(inst).await;
+  if let Some(trace) = trace {
+    cpu.end_trace(trace);
+  }
+
+  match result {
+    Ok(new_pc) => cpu.pc = new_pc,
+    Err(exception) => {
+      if let Exception::Explosion(_) = exception {
+        error!("execute failed: {:?}", exception);
+        return Ok(TickResult::Exception(exception));
+      }
+
+      cpu.handle_exception(exception);
+      if exception.is_fatal() {
+        error!("execute failed: {:?}", exception);
+        return Ok(TickResult::Exception(exception));
+      }
+
+      return Ok(TickResult::Exception(exception));
+    }
+  };
+
+  let retired = cpu.perf.instructions_retired.fetch_add(1, Ordering::AcqRel) + 1;
+  if let Some(profiler) = &cpu.profiler {
+    profiler.tick(cpu, retired);
+  }
+  if cpu.halt {
+    return Ok(TickResult::Halt);
+  }
+
+  let time_limited = cpu.bus.power.is_time_limited(cpu.csr.load(csr::machine::POWERSTATE));
+  if time_limited && *cpu.perf.cpu_time.lock().unwrap() > CPU_TIME_LIMIT {
+    error!(
+      "running too long without yield: {:?} > {:?}",
+      cpu.perf.cpu_time.lock().unwrap(),
+      CPU_TIME_LIMIT
+    );
+    return Ok(TickResult::TimeLimit);
+  }
+
+  // if cpu.csr.load(MCAUSE) == 0 {
+  //   error!("exited from trap");
+  //   cpu.saved_regs.fill(0);
+  //   return Ok(TickResult::Continue); // Exited from trap
+  // }
+
+  if let Some(interrupt) = cpu.check_pending_interrupt() {
+    cpu.handle_interrupt(interrupt);
+  }
+
+  Ok(TickResult::Continue)
+}
@@ -1,7 +1,7 @@
 use std::time::SystemTime;
 
 use async_trait::async_trait;
-use runtime::cpu::{Cpu, InterruptHandler};
+use crate::cpu::{Cpu, InterruptHandler};
 
 pub struct TimeHandler {}
 
@@ -0,0 +1,546 @@
+//! Instruction and memory tracing.
+//!
+//! A [Tracer] attached to [Cpu::tracer] writes compact binary [TraceRecord]s for every instruction that passes its
+//! [TraceFilter]: the instruction itself followed by the registers it wrote and the memory it accessed, and every
+//! trap taken. The records of an instruction are written together once it completes, so that the filter can select
+//! instructions by the data addresses they access. [decode_text] and [decode_chrome] turn a trace file into a listing or the Chrome trace-event format,
+//! where the timestamp of an instruction is its index on the hart.
+//!
+//! [Cpu::tracer]: crate::cpu::Cpu::tracer
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use tracing::warn;
+
+const MAGIC: &[u8; 8] = b"MIZUTRC1";
+
+const RECORD_INSTRUCTION: u8 = 1;
+const RECORD_REGISTER: u8 = 2;
+const RECORD_LOAD: u8 = 3;
+const RECORD_STORE: u8 = 4;
+const RECORD_TRAP: u8 = 5;
+
+/// Floating-point registers are numbered after the integer registers.
+pub const FP_REGISTER_BASE: u8 = 32;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TraceRecord {
+  /// `index` is the number of instructions the hart retired before this one.
+  Instruction {
+    hart: u16,
+    index: u64,
+    pc: u64,
+    inst: u32,
+  },
+  /// Write of the previous instruction of `hart`, see [FP_REGISTER_BASE].
+  Register {
+    hart: u16,
+    register: u8,
+    value: u64,
+  },
+  /// Access of the previous instruction of `hart`, `size` is in bits.
+  Load {
+    hart: u16,
+    address: u64,
+    size: u8,
+    value: u64,
+  },
+  Store {
+    hart: u16,
+    address: u64,
+    size: u8,
+    value: u64,
+  },
+  /// `cause` has the interrupt bit set for interrupts, `target` is the address of the trap handler.
+  Trap {
+    hart: u16,
+    pc: u64,
+    cause: u64,
+    value: u64,
+    target: u64,
+  },
+}
+
+impl TraceRecord {
+  pub fn hart(&self) -> u16 {
+    match self {
+      TraceRecord::Instruction { hart, .. }
+      | TraceRecord::Register { hart, .. }
+      | TraceRecord::Load { hart, .. }
+      | TraceRecord::Store { hart, .. }
+      | TraceRecord::Trap { hart, .. } => *hart,
+    }
+  }
+
+  /// Little-endian encoding, a type byte and the hart followed by the other fields in declaration order.
+  pub fn encode(&self, output: &mut impl Write) -> io::Result<()> {
+    match *self {
+      TraceRecord::Instruction { hart, index, pc, inst } => {
+        output.write_all(&[RECORD_INSTRUCTION])?;
+        output.write_all(&hart.to_le_bytes())?;
+        output.write_all(&index.to_le_bytes())?;
+        output.write_all(&pc.to_le_bytes())?;
+        output.write_all(&inst.to_le_bytes())?;
+      }
+      TraceRecord::Register { hart, register, value } => {
+        output.write_all(&[RECORD_REGISTER])?;
+        output.write_all(&hart.to_le_bytes())?;
+        output.write_all(&[register])?;
+        output.write_all(&value.to_le_bytes())?;
+      }
+      TraceRecord::Load { hart, address, size, value } | TraceRecord::Store { hart, address, size, value } => {
+        let kind = if matches!(self, TraceRecord::Load { .. }) {
+          RECORD_LOAD
+        } else {
+          RECORD_STORE
+        };
+        output.write_all(&[kind])?;
+        output.write_all(&hart.to_le_bytes())?;
+        output.write_all(&address.to_le_bytes())?;
+        output.write_all(&[size])?;
+        output.write_all(&value.to_le_bytes())?;
+      }
+      TraceRecord::Trap {
+        hart,
+        pc,
+        cause,
+        value,
+        target,
+      } => {
+        output.write_all(&[RECORD_TRAP])?;
+        output.write_all(&hart.to_le_bytes())?;
+        output.write_all(&pc.to_le_bytes())?;
+        output.write_all(&cause.to_le_bytes())?;
+        output.write_all(&value.to_le_bytes())?;
+        output.write_all(&target.to_le_bytes())?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Reads the next record, `None` at the end of the input.
+  pub fn decode(input: &mut impl Read) -> io::Result<Option<Self>> {
+    let mut kind = [0; 1];
+    match input.read_exact(&mut kind) {
+      Ok(()) => {}
+      Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+      Err(error) => return Err(error),
+    }
+
+    let hart = u16::from_le_bytes(read(input)?);
+    let record = match kind[0] {
+      RECORD_INSTRUCTION => TraceRecord::Instruction {
+        hart,
+        index: u64::from_le_bytes(read(input)?),
+        pc: u64::from_le_bytes(read(input)?),
+        inst: u32::from_le_bytes(read(input)?),
+      },
+      RECORD_REGISTER => TraceRecord::Register {
+        hart,
+        register: read::<1>(input)?[0],
+        value: u64::from_le_bytes(read(input)?),
+      },
+      kind @ (RECORD_LOAD | RECORD_STORE) => {
+        let address = u64::from_le_bytes(read(input)?);
+        let size = read::<1>(input)?[0];
+        let value = u64::from_le_bytes(read(input)?);
+        if kind == RECORD_LOAD {
+          TraceRecord::Load { hart, address, size, value }
+        } else {
+          TraceRecord::Store { hart, address, size, value }
+        }
+      }
+      RECORD_TRAP => TraceRecord::Trap {
+        hart,
+        pc: u64::from_le_bytes(read(input)?),
+        cause: u64::from_le_bytes(read(input)?),
+        value: u64::from_le_bytes(read(input)?),
+        target: u64::from_le_bytes(read(input)?),
+      },
+      kind => return Err(io::Error::new(ErrorKind::InvalidData, format!("unknown record type {}", kind))),
+    };
+    Ok(Some(record))
+  }
+}
+
+fn read<const N: usize>(input: &mut impl Read) -> io::Result<[u8; N]> {
+  let mut buffer = [0; N];
+  input.read_exact(&mut buffer)?;
+  Ok(buffer)
+}
+
+/// Selects the instructions to trace, empty lists match everything.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+  pub harts: Vec<u16>,
+  /// Ranges of the program counter.
+  pub ranges: Vec<RangeInclusive<u64>>,
+  /// Ranges of data addresses, only instructions that load or store in one of them are traced.
+  pub addresses: Vec<RangeInclusive<u64>>,
+}
+
+impl TraceFilter {
+  pub fn matches(&self, hart: u16, pc: u64) -> bool {
+    (self.harts.is_empty() || self.harts.contains(&hart)) && (self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&pc)))
+  }
+
+  /// Whether an access of `size` bits at `address` overlaps one of [TraceFilter::addresses].
+  pub fn matches_address(&self, address: u64, size: u64) -> bool {
+    let end = address.saturating_add((size / 8).max(1) - 1);
+    self.addresses.is_empty() || self.addresses.iter().any(|range| address <= *range.end() && *range.start() <= end)
+  }
+
+  /// Whether the records of an instruction pass the address filter.
+  fn matches_accesses(&self, records: &[TraceRecord]) -> bool {
+    self.addresses.is_empty()
+      || records.iter().any(|record| match *record {
+        TraceRecord::Load { address, size, .. } | TraceRecord::Store { address, size, .. } => self.matches_address(address, size as u64),
+        _ => false,
+      })
+  }
+
+  /// Parses an address range, `0x80000000-0x80001000` with an inclusive end.
+  pub fn parse_range(value: &str) -> Option<RangeInclusive<u64>> {
+    let parse = |value: &str| {
+      let value = value.trim();
+      match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+      }
+    };
+    let (start, end) = value.split_once('-')?;
+    Some(parse(start)?..=parse(end)?)
+  }
+}
+
+pub struct Tracer {
+  output: Mutex<BufWriter<Box<dyn Write + Send>>>,
+  filter: TraceFilter,
+  records: AtomicU64,
+}
+
+impl Tracer {
+  pub fn new(output: impl Write + Send + 'static, filter: TraceFilter) -> io::Result<Self> {
+    let mut output = BufWriter::new(Box::new(output) as Box<dyn Write + Send>);
+    output.write_all(MAGIC)?;
+    Ok(Self {
+      output: Mutex::new(output),
+      filter,
+      records: AtomicU64::new(0),
+    })
+  }
+
+  /// Traces into a new file at `path`.
+  pub fn create(path: &Path, filter: TraceFilter) -> io::Result<Self> {
+    Self::new(File::create(path)?, filter)
+  }
+
+  #[inline]
+  pub fn matches(&self, hart: u16, pc: u64) -> bool {
+    self.filter.matches(hart, pc)
+  }
+
+  pub fn filter(&self) -> &TraceFilter {
+    &self.filter
+  }
+
+  /// Number of records written so far.
+  pub fn records(&self) -> u64 {
+    self.records.load(Ordering::Acquire)
+  }
+
+  pub fn emit(&self, record: TraceRecord) {
+    self.emit_all(&[record]);
+  }
+
+  /// Writes the records of an instruction, unless none of its accesses passes [TraceFilter::addresses].
+  pub fn emit_instruction(&self, records: &[TraceRecord]) {
+    if self.filter.matches_accesses(records) {
+      self.emit_all(records);
+    }
+  }
+
+  fn emit_all(&self, records: &[TraceRecord]) {
+    let mut output = self.output.lock().unwrap();
+    for record in records {
+      if let Err(error) = record.encode(&mut *output) {
+        warn!("failed to write trace record: {}", error);
+        return;
+      }
+      self.records.fetch_add(1, Ordering::AcqRel);
+    }
+  }
+
+  pub fn flush(&self) {
+    if let Err(error) = self.output.lock().unwrap().flush() {
+      warn!("failed to flush trace: {}", error);
+    }
+  }
+}
+
+impl Drop for Tracer {
+  fn drop(&mut self) {
+    self.flush();
+  }
+}
+
+/// Opens a trace file and checks its header.
+pub fn open(path: &Path) -> io::Result<BufReader<File>> {
+  let mut input = BufReader::new(File::open(path)?);
+  if &read::<8>(&mut input)? != MAGIC {
+    return Err(io::Error::new(ErrorKind::InvalidData, "not a trace file"));
+  }
+  Ok(input)
+}
+
+fn register_name(register: u8) -> String {
+  if register >= FP_REGISTER_BASE {
+    format!("f{}", register - FP_REGISTER_BASE)
+  } else {
+    format!("x{}", register)
+  }
+}
+
+/// Writes one line per record.
+pub fn decode_text(input: &mut impl Read, output: &mut impl Write) -> io::Result<()> {
+  while let Some(record) = TraceRecord::decode(input)? {
+    match record {
+      TraceRecord::Instruction { hart, index, pc, inst } => writeln!(output, "[{}] #{} {:#x}: {:08x}", hart, index, pc, inst)?,
+      TraceRecord::Register { hart, register, value } => writeln!(output, "[{}]   {} = {:#x}", hart, register_name(register), value)?,
+      TraceRecord::Load { hart, address, size, value } => writeln!(output, "[{}]   load{} {:#x} -> {:#x}", hart, size, address, value)?,
+      TraceRecord::Store { hart, address, size, value } => writeln!(output, "[{}]   store{} {:#x} <- {:#x}", hart, size, address, value)?,
+      TraceRecord::Trap {
+        hart,
+        pc,
+        cause,
+        value,
+        target,
+      } => writeln!(output, "[{}] trap cause={:#x} value={:#x} at {:#x} -> {:#x}", hart, cause, value, pc, target)?,
+    }
+  }
+  Ok(())
+}
+
+/// Instruction of a hart whose registers and memory accesses are still being collected.
+struct PendingInstruction {
+  index: u64,
+  pc: u64,
+  inst: u32,
+  args: Vec<(String, String)>,
+}
+
+fn chrome_instruction(output: &mut impl Write, first: &mut bool, hart: u16, instruction: PendingInstruction) -> io::Result<()> {
+  let args = instruction
+    .args
+    .iter()
+    .map(|(key, value)| format!(r#""{}":"{}""#, key, value))
+    .chain([format!(r#""inst":"{:08x}""#, instruction.inst)])
+    .collect::<Vec<_>>()
+    .join(",");
+  chrome_event(
+    output,
+    first,
+    &format!(
+      r#"{{"name":"{:#x}","cat":"instruction","ph":"X","ts":{},"dur":1,"pid":0,"tid":{},"args":{{{}}}}}"#,
+      instruction.pc, instruction.index, hart, args
+    ),
+  )
+}
+
+fn chrome_event(output: &mut impl Write, first: &mut bool, event: &str) -> io::Result<()> {
+  if !*first {
+    output.write_all(b",\n")?;
+  }
+  *first = false;
+  output.write_all(event.as_bytes())
+}
+
+/// Writes a Chrome trace-event JSON object: instructions are complete events on the thread of their hart with the
+/// registers and memory they touched as arguments, traps are instant events.
+pub fn decode_chrome(input: &mut impl Read, output: &mut impl Write) -> io::Result<()> {
+  let mut pending = BTreeMap::<u16, PendingInstruction>::new();
+  let mut first = true;
+  output.write_all(b"{\"traceEvents\":[\n")?;
+
+  while let Some(record) = TraceRecord::decode(input)? {
+    let hart = record.hart();
+    let argument = match record {
+      TraceRecord::Instruction { index, pc, inst, .. } => {
+        let instruction = PendingInstruction {
+          index,
+          pc,
+          inst,
+          args: Vec::new(),
+        };
+        if let Some(previous) = pending.insert(hart, instruction) {
+          chrome_instruction(output, &mut first, hart, previous)?;
+        }
+        continue;
+      }
+      TraceRecord::Register { register, value, .. } => (register_name(register), format!("{:#x}", value)),
+      TraceRecord::Load { address, size, value, .. } => (format!("load{} {:#x}", size, address), format!("{:#x}", value)),
+      TraceRecord::Store { address, size, value, .. } => (format!("store{} {:#x}", size, address), format!("{:#x}", value)),
+      TraceRecord::Trap { pc, cause, value, target, .. } => {
+        let ts = pending.get(&hart).map(|instruction| instruction.index + 1).unwrap_or_default();
+        chrome_event(
+          output,
+          &mut first,
+          &format!(
+            r#"{{"name":"trap {:#x}","cat":"trap","ph":"i","s":"t","ts":{},"pid":0,"tid":{},"args":{{"pc":"{:#x}","value":"{:#x}","target":"{:#x}"}}}}"#,
+            cause, ts, hart, pc, value, target
+          ),
+        )?;
+        continue;
+      }
+    };
+    if let Some(instruction) = pending.get_mut(&hart) {
+      instruction.args.push(argument);
+    }
+  }
+
+  for (hart, instruction) in pending {
+    chrome_instruction(output, &mut first, hart, instruction)?;
+  }
+  output.write_all(b"\n]}\n")
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::*;
+
+  /// Output shared with the test, since the tracer owns its writer.
+  #[derive(Clone, Default)]
+  struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for Buffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+      self.0.lock().unwrap().write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  fn decode_all(mut input: &[u8]) -> Vec<TraceRecord> {
+    let mut records = Vec::new();
+    while let Some(record) = TraceRecord::decode(&mut input).unwrap() {
+      records.push(record);
+    }
+    records
+  }
+
+  fn instruction(pc: u64, address: u64) -> [TraceRecord; 3] {
+    [
+      TraceRecord::Instruction {
+        hart: 0,
+        index: 1,
+        pc,
+        inst: 0x0005_3503,
+      },
+      TraceRecord::Load {
+        hart: 0,
+        address,
+        size: 64,
+        value: 7,
+      },
+      TraceRecord::Register {
+        hart: 0,
+        register: 10,
+        value: 7,
+      },
+    ]
+  }
+
+  #[test]
+  fn round_trips_records() {
+    let records = vec![
+      TraceRecord::Instruction {
+        hart: 2,
+        index: 99,
+        pc: 0x8000_0000,
+        inst: 0x0000_0073,
+      },
+      TraceRecord::Register {
+        hart: 2,
+        register: FP_REGISTER_BASE + 1,
+        value: 1.5f64.to_bits(),
+      },
+      TraceRecord::Load {
+        hart: 2,
+        address: 0x8000_1000,
+        size: 32,
+        value: u32::MAX as u64,
+      },
+      TraceRecord::Store {
+        hart: 2,
+        address: 0x8000_1004,
+        size: 8,
+        value: 0xff,
+      },
+      TraceRecord::Trap {
+        hart: 2,
+        pc: 0x8000_0000,
+        cause: (1 << 63) | 7,
+        value: 0,
+        target: 0x8000_0100,
+      },
+    ];
+    let mut encoded = Vec::new();
+    for record in &records {
+      record.encode(&mut encoded).unwrap();
+    }
+    assert_eq!(decode_all(&encoded), records);
+    assert!(TraceRecord::decode(&mut [9u8, 0, 0].as_slice()).is_err());
+  }
+
+  #[test]
+  fn decodes_text() {
+    let mut encoded = Vec::new();
+    for record in instruction(0x8000_0000, 0x8000_1000) {
+      record.encode(&mut encoded).unwrap();
+    }
+    let mut output = Vec::new();
+    decode_text(&mut encoded.as_slice(), &mut output).unwrap();
+    assert_eq!(
+      String::from_utf8(output).unwrap(),
+      "[0] #1 0x80000000: 00053503\n[0]   load64 0x80001000 -> 0x7\n[0]   x10 = 0x7\n"
+    );
+  }
+
+  #[test]
+  fn filters_by_data_address() {
+    let filter = TraceFilter {
+      addresses: vec![0x8000_1000..=0x8000_1fff],
+      ..Default::default()
+    };
+    assert!(filter.matches_address(0x8000_0ffc, 64));
+    assert!(!filter.matches_address(0x8000_0ff8, 64));
+    assert!(!filter.matches_address(0x8000_2000, 8));
+
+    let buffer = Buffer::default();
+    let tracer = Tracer::new(buffer.clone(), filter).unwrap();
+    tracer.emit_instruction(&instruction(0x8000_0000, 0x8000_1800));
+    tracer.emit_instruction(&instruction(0x8000_0004, 0x8000_3000));
+    tracer.flush();
+    assert_eq!(tracer.records(), 3);
+    let output = buffer.0.lock().unwrap();
+    assert_eq!(&output[..MAGIC.len()], MAGIC);
+    assert_eq!(decode_all(&output[MAGIC.len()..]), instruction(0x8000_0000, 0x8000_1800));
+  }
+
+  #[test]
+  fn parses_ranges() {
+    assert_eq!(TraceFilter::parse_range("0x1000-0x1fff"), Some(0x1000..=0x1fff));
+    assert_eq!(TraceFilter::parse_range("16 - 32"), Some(16..=32));
+    assert_eq!(TraceFilter::parse_range("0x1000"), None);
+  }
+}
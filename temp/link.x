@@ -7,6 +7,9 @@ MEMORY
 
 STACK_SIZE = 0x16000;
 
+/* Bounds of the mizu-hal allocator */
+INCLUDE heap.x
+
 SECTIONS
 {
   . = 0xffffffff80000000;
@@ -14,4 +14,6 @@ pub use mizu_hal::types::StringPtr;
 pub use mizu_hal::time::*;
 pub use mizu_hal::device::*;
 pub use mizu_hal::perf::*;
+pub use mizu_hal::async_call::*;
+pub use mizu_hal::ring::*;
 pub use mizu_hal::mini_backtrace as mini_backtrace;